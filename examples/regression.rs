@@ -0,0 +1,275 @@
+//! Render representative scenes offscreen and compare their hashes with an
+//! earlier run, as a quick regression signal for changes to blending,
+//! clipping or text rendering.
+//!
+//! Record the hashes and reference images on a known good revision, then
+//! check a change against them:
+//!
+//! `cargo run --example regression --features testing -- --update`
+//! `cargo run --example regression --features testing`
+//!
+//! The output differs between adapters and drivers, so only compare runs
+//! on the same machine. Everything is written to `target/regression`, or to
+//! the directory given with `--out <dir>`. A scene whose hash changed gets
+//! its rendering and a diff with the reference written next to it.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    fs,
+    path::{Path, PathBuf},
+    process,
+    str::FromStr,
+};
+
+use piet::{
+    kurbo::{Affine, Circle, Line, Point, Rect, RoundedRect, Vec2},
+    Color, FontFamily, RenderContext, Text, TextLayoutBuilder,
+};
+use piet_wgpu::{
+    testing::{self, Snapshot, SnapshotOptions},
+    Piet, Svg,
+};
+use sha2::{Digest, Sha256};
+
+const HASHES_FILE: &str = "hashes.txt";
+
+const ICON: &str = r##"<svg xmlns="http://www.w3.org/2000/svg" width="16" height="16" viewBox="0 0 16 16">
+<path fill="#4a90d9" d="M2 3h5l1 2h6v8H2z"/>
+<circle cx="11" cy="10" r="2.5" fill="none" stroke="#f5a623" stroke-width="1.5"/>
+</svg>"##;
+
+struct Scene {
+    name: String,
+    width: u32,
+    height: u32,
+    scale: f64,
+    draw: fn(&mut Piet),
+}
+
+fn scenes() -> Vec<Scene> {
+    let mut scenes = Vec::new();
+    // Fractional scales snap glyphs differently from integer ones.
+    for &(suffix, scale) in &[("1x", 1.0), ("1.25x", 1.25), ("1.5x", 1.5), ("2x", 2.0)] {
+        scenes.push(Scene {
+            name: format!("text-{}", suffix),
+            width: (320.0 * scale) as u32,
+            height: (120.0 * scale) as u32,
+            scale,
+            draw: draw_text,
+        });
+    }
+    scenes.push(Scene {
+        name: "nested-clips".to_string(),
+        width: 240,
+        height: 240,
+        scale: 1.0,
+        draw: draw_nested_clips,
+    });
+    scenes.push(Scene {
+        name: "blurred-rects".to_string(),
+        width: 320,
+        height: 160,
+        scale: 1.0,
+        draw: draw_blurred_rects,
+    });
+    for &(suffix, scale) in &[("1x", 1.0), ("1.5x", 1.5)] {
+        scenes.push(Scene {
+            name: format!("svgs-{}", suffix),
+            width: (200.0 * scale) as u32,
+            height: (80.0 * scale) as u32,
+            scale,
+            draw: draw_svgs,
+        });
+    }
+    scenes
+}
+
+fn draw_text(ctx: &mut Piet) {
+    ctx.clear(None, Color::WHITE);
+    let mut y = 8.0;
+    for &size in &[9.0, 12.0, 13.5, 16.0, 24.0] {
+        let layout = ctx
+            .text()
+            .new_text_layout("The quick brown fox jumps over the lazy dog")
+            .font(FontFamily::SANS_SERIF, size)
+            .text_color(Color::BLACK)
+            .build()
+            .unwrap();
+        ctx.draw_text(&layout, Point::new(8.0, y));
+        y += size * 1.4;
+    }
+}
+
+fn draw_nested_clips(ctx: &mut Piet) {
+    ctx.clear(None, Color::WHITE);
+    ctx.with_save(|ctx| {
+        ctx.clip(Rect::new(20.0, 20.0, 220.0, 220.0));
+        ctx.fill(
+            Rect::new(0.0, 0.0, 240.0, 240.0),
+            &Color::rgb8(0xe0, 0xe0, 0xe0),
+        );
+        ctx.with_save(|ctx| {
+            ctx.clip(RoundedRect::new(40.0, 40.0, 200.0, 200.0, 24.0));
+            ctx.fill(
+                Rect::new(0.0, 0.0, 240.0, 240.0),
+                &Color::rgb8(0x4a, 0x90, 0xd9),
+            );
+            ctx.with_save(|ctx| {
+                let center = Vec2::new(120.0, 120.0);
+                ctx.transform(
+                    Affine::translate(center) * Affine::rotate(0.3) * Affine::translate(-center),
+                );
+                ctx.clip(Rect::new(70.0, 70.0, 170.0, 170.0));
+                ctx.fill(
+                    Circle::new((120.0, 120.0), 90.0),
+                    &Color::rgb8(0xf5, 0xa6, 0x23),
+                );
+                ctx.stroke(Line::new((0.0, 0.0), (240.0, 240.0)), &Color::BLACK, 3.0);
+                Ok(())
+            })
+        })
+    })
+    .unwrap();
+}
+
+fn draw_blurred_rects(ctx: &mut Piet) {
+    ctx.clear(None, Color::WHITE);
+    for (i, &radius) in [0.5, 2.0, 6.0, 12.0].iter().enumerate() {
+        let x = 20.0 + i as f64 * 75.0;
+        let rect = Rect::new(x, 40.0, x + 50.0, 120.0);
+        ctx.blurred_rect(rect, radius, &Color::rgba8(0, 0, 0, 0x80));
+        ctx.fill(rect - Vec2::new(2.0, 2.0), &Color::WHITE);
+    }
+}
+
+fn draw_svgs(ctx: &mut Piet) {
+    ctx.clear(None, Color::WHITE);
+    let svg = Svg::from_str(ICON).unwrap();
+    let mut x = 8.0;
+    for &size in &[12.0, 16.0, 24.0, 32.0, 48.0] {
+        ctx.draw_svg(&svg, Rect::from_origin_size((x, 8.0), (size, size)), None);
+        x += size + 8.0;
+    }
+    ctx.draw_svg(
+        &svg,
+        Rect::new(8.0, 60.0, 20.0, 72.0),
+        Some(&Color::rgb8(0xd0, 0x02, 0x1b)),
+    );
+    ctx.draw_svg_rotated(&svg, Rect::new(28.0, 56.0, 48.0, 76.0), 0.5, None);
+}
+
+fn hash(snapshot: &Snapshot) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(snapshot.width.to_le_bytes());
+    hasher.update(snapshot.height.to_le_bytes());
+    hasher.update(&snapshot.pixels);
+    hasher.finalize().iter().fold(String::new(), |mut s, b| {
+        let _ = write!(s, "{:02x}", b);
+        s
+    })
+}
+
+fn read_hashes(path: &Path) -> BTreeMap<String, String> {
+    fs::read_to_string(path)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| {
+            let (name, hash) = line.split_once(' ')?;
+            Some((name.to_string(), hash.to_string()))
+        })
+        .collect()
+}
+
+fn main() {
+    let mut update = false;
+    let mut out = PathBuf::from("target/regression");
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--update" => update = true,
+            "--out" => match args.next() {
+                Some(dir) => out = dir.into(),
+                None => {
+                    eprintln!("--out needs a directory");
+                    process::exit(2);
+                }
+            },
+            _ => {
+                eprintln!("usage: regression [--update] [--out <dir>]");
+                process::exit(2);
+            }
+        }
+    }
+    if let Err(e) = fs::create_dir_all(&out) {
+        eprintln!("can't create {}: {}", out.display(), e);
+        process::exit(1);
+    }
+
+    let hashes_path = out.join(HASHES_FILE);
+    let mut hashes = read_hashes(&hashes_path);
+    if !update && hashes.is_empty() {
+        eprintln!(
+            "no hashes in {}, record them first with --update",
+            hashes_path.display()
+        );
+        process::exit(1);
+    }
+
+    let mut failed = 0;
+    for scene in scenes() {
+        let snapshot = match testing::render(scene.width, scene.height, scene.scale, scene.draw) {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                println!("{}: render failed: {}", scene.name, e);
+                failed += 1;
+                continue;
+            }
+        };
+        let hash = hash(&snapshot);
+        let reference = out.join(format!("{}.png", scene.name));
+        if update {
+            if let Err(e) = snapshot.save(&reference) {
+                eprintln!("can't write {}: {}", reference.display(), e);
+                process::exit(1);
+            }
+            hashes.insert(scene.name, hash);
+            continue;
+        }
+        match hashes.get(&scene.name) {
+            Some(expected) if *expected == hash => println!("{}: ok", scene.name),
+            Some(_) => {
+                failed += 1;
+                // Any change fails the hash, the diff shows where it is.
+                let options = SnapshotOptions {
+                    threshold: 0.0,
+                    max_different_pixels: 0,
+                };
+                match testing::compare(&reference, &snapshot, options) {
+                    Err(e) => println!("{}: changed, {}", scene.name, e),
+                    Ok(()) => println!("{}: changed", scene.name),
+                }
+            }
+            None => {
+                failed += 1;
+                println!("{}: no recorded hash", scene.name);
+            }
+        }
+    }
+
+    if update {
+        let contents: String = hashes
+            .iter()
+            .map(|(name, hash)| format!("{} {}\n", name, hash))
+            .collect();
+        if let Err(e) = fs::write(&hashes_path, contents) {
+            eprintln!("can't write {}: {}", hashes_path.display(), e);
+            process::exit(1);
+        }
+        println!("recorded hashes in {}", hashes_path.display());
+    }
+    if failed > 0 {
+        println!("{} scene(s) failed", failed);
+        process::exit(1);
+    }
+}
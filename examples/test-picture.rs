@@ -0,0 +1,35 @@
+//! Render piet's sample pictures with this backend, to compare them with
+//! other backends or with an earlier run.
+//!
+//! `cargo run --example test-picture --features samples -- --all --out <dir>`
+
+use std::{
+    panic::{self, AssertUnwindSafe},
+    path::Path,
+};
+
+use piet::samples;
+use piet_wgpu::{testing, Piet};
+
+const FILE_PREFIX: &str = "wgpu-test-";
+
+fn main() {
+    samples::samples_main(run_sample, FILE_PREFIX, None);
+}
+
+fn run_sample(number: usize, base_dir: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let size = samples::get::<Piet>(number)?.size();
+    let mut result = Ok(());
+    let snapshot = testing::render(size.width as u32, size.height as u32, 1.0, |ctx| {
+        let sample = samples::get(number).unwrap();
+        // Parts of the piet API are still unimplemented and panic. Report
+        // those as a failed sample instead of aborting the whole run.
+        result = match panic::catch_unwind(AssertUnwindSafe(|| sample.draw(ctx))) {
+            Ok(result) => result.map_err(|e| e.to_string()),
+            Err(_) => Err("the sample uses an unimplemented feature".to_string()),
+        };
+    })?;
+    result?;
+    snapshot.save(base_dir.join(format!("{}{}.png", FILE_PREFIX, number)))?;
+    Ok(())
+}
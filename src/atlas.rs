@@ -0,0 +1,771 @@
+use std::hash::Hash;
+use std::num::{NonZeroU32, NonZeroU64};
+use std::ops::Range;
+
+use hashbrown::HashMap;
+
+/// Shelf heights are rounded up to a multiple of this, so that items of
+/// slightly different heights share a shelf.
+const SHELF_HEIGHT_STEP: u32 = 4;
+
+/// An item may go on a shelf up to this many times its own height.
+const SHELF_MAX_WASTE: f32 = 1.5;
+
+/// The spacing of the grid [`Atlas::annotate`] draws, in texels.
+const DEBUG_GRID: u32 = 64;
+
+/// Atlas textures are copied into a larger one when they grow.
+const TEXTURE_USAGE: wgpu::TextureUsages = wgpu::TextureUsages::from_bits_truncate(
+    wgpu::TextureUsages::COPY_DST.bits()
+        | wgpu::TextureUsages::COPY_SRC.bits()
+        | wgpu::TextureUsages::TEXTURE_BINDING.bits(),
+);
+
+/// The position of an item in an [`Atlas`], in texels.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub(crate) struct AtlasRect {
+    pub(crate) x: u32,
+    pub(crate) y: u32,
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+}
+
+struct Shelf {
+    y: u32,
+    height: u32,
+    /// The x where the next item on this shelf goes.
+    width: u32,
+    /// Spans left by removed items before `width`, sorted and apart.
+    free: Vec<Range<u32>>,
+    items: usize,
+}
+
+impl Shelf {
+    /// Where an item of `width` goes on the shelf: the first freed span
+    /// it fits in, or the end.
+    fn place(&self, width: u32, atlas_width: u32) -> Option<u32> {
+        self.free
+            .iter()
+            .find(|span| span.end - span.start >= width)
+            .map(|span| span.start)
+            .or_else(|| (atlas_width - self.width >= width).then_some(self.width))
+    }
+
+    fn take(&mut self, x: u32, width: u32) {
+        self.items += 1;
+        if x == self.width {
+            self.width += width;
+        } else if let Some(i) = self.free.iter().position(|span| span.start == x) {
+            self.free[i].start += width;
+            if self.free[i].is_empty() {
+                self.free.remove(i);
+            }
+        }
+    }
+
+    fn release(&mut self, span: Range<u32>) {
+        self.items -= 1;
+        if self.items == 0 {
+            self.width = 0;
+            self.free.clear();
+            return;
+        }
+        if span.is_empty() {
+            return;
+        }
+        let i = self.free.partition_point(|free| free.start < span.start);
+        self.free.insert(i, span);
+        // Merge with the spans on either side.
+        if i + 1 < self.free.len() && self.free[i].end == self.free[i + 1].start {
+            self.free[i].end = self.free.remove(i + 1).end;
+        }
+        if i > 0 && self.free[i - 1].end == self.free[i].start {
+            self.free[i - 1].end = self.free.remove(i).end;
+        }
+        if self.free.last().is_some_and(|free| free.end == self.width) {
+            self.width = self.free.pop().unwrap().start;
+        }
+    }
+}
+
+/// Shelf packing of items keyed by `K` into a `width` by `height` area.
+///
+/// Items go on the existing shelf that wastes the least height; a new
+/// shelf is opened below the last one when none fits. Removed items leave
+/// a span their shelf reuses, and an empty shelf takes items of any
+/// height up to its own.
+pub(crate) struct Atlas<K> {
+    width: u32,
+    height: u32,
+    shelves: Vec<Shelf>,
+    /// Every item with the index of its shelf.
+    items: HashMap<K, (AtlasRect, usize)>,
+}
+
+impl<K: Hash + Eq> Atlas<K> {
+    pub(crate) fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            shelves: Vec::new(),
+            items: HashMap::new(),
+        }
+    }
+
+    /// Reserve `width` by `height` for `key`, or hand the key back if the
+    /// atlas is full.
+    pub(crate) fn insert(&mut self, key: K, width: u32, height: u32) -> Result<AtlasRect, K> {
+        if width > self.width {
+            return Err(key);
+        }
+
+        let atlas_width = self.width;
+        let shelf = self
+            .shelves
+            .iter()
+            .enumerate()
+            .filter(|(_, shelf)| {
+                shelf.height >= height
+                    && (shelf.items == 0
+                        || shelf.height as f32
+                            <= height as f32 * SHELF_MAX_WASTE + SHELF_HEIGHT_STEP as f32)
+            })
+            .filter_map(|(i, shelf)| Some((i, shelf.place(width, atlas_width)?)))
+            .min_by_key(|(i, _)| self.shelves[*i].height - height);
+
+        let (index, x) = match shelf {
+            Some(shelf) => shelf,
+            None => {
+                let y = self.used_height();
+                let shelf_height = round_up(height, SHELF_HEIGHT_STEP);
+                if self.height < y + shelf_height {
+                    return Err(key);
+                }
+                self.shelves.push(Shelf {
+                    y,
+                    height: shelf_height,
+                    width: 0,
+                    free: Vec::new(),
+                    items: 0,
+                });
+                (self.shelves.len() - 1, 0)
+            }
+        };
+
+        let shelf = &mut self.shelves[index];
+        shelf.take(x, width);
+        let rect = AtlasRect {
+            x,
+            y: shelf.y,
+            width,
+            height,
+        };
+        self.items.insert(key, (rect, index));
+        Ok(rect)
+    }
+
+    /// Free the space of `key`, for later items to reuse.
+    pub(crate) fn remove(&mut self, key: &K) -> Option<AtlasRect> {
+        let (rect, index) = self.items.remove(key)?;
+        self.shelves[index].release(rect.x..rect.x + rect.width);
+        // Only empty shelves at the end are dropped, which keeps the
+        // indices of the others.
+        while self.shelves.last().is_some_and(|shelf| shelf.items == 0) {
+            self.shelves.pop();
+        }
+        Some(rect)
+    }
+
+    /// The items and where they are.
+    pub(crate) fn items(&self) -> impl Iterator<Item = (&K, &AtlasRect)> {
+        self.items.iter().map(|(key, (rect, _))| (key, rect))
+    }
+
+    /// Make more room below the existing shelves.
+    pub(crate) fn grow(&mut self, height: u32) {
+        self.height = self.height.max(height);
+    }
+
+    pub(crate) fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub(crate) fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// The height covered by shelves; the rest of the atlas is free.
+    pub(crate) fn used_height(&self) -> u32 {
+        self.shelves
+            .last()
+            .map(|shelf| shelf.y + shelf.height)
+            .unwrap_or(0)
+    }
+
+    pub(crate) fn shelves(&self) -> usize {
+        self.shelves.len()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    /// `pixels` of the atlas, one byte of coverage per texel, as RGBA with
+    /// the layout drawn over them for debugging: a dark blue grid every
+    /// [`DEBUG_GRID`] texels in free space, a red line at the top of every
+    /// shelf and a green outline around every item.
+    pub(crate) fn annotate(&self, pixels: &[u8]) -> Vec<u8> {
+        let (width, height) = (self.width as usize, self.height as usize);
+        let mut rgba: Vec<u8> = pixels
+            .iter()
+            .take(width * height)
+            .flat_map(|&v| [v, v, v, 255])
+            .collect();
+        rgba.resize(width * height * 4, 255);
+        let mut tint = |x: usize, y: usize, color: [u8; 3]| {
+            if x < width && y < height {
+                let i = (y * width + x) * 4;
+                for c in 0..3 {
+                    rgba[i + c] = rgba[i + c].max(color[c]);
+                }
+            }
+        };
+
+        let grid = DEBUG_GRID as usize;
+        for y in 0..height {
+            for x in 0..width {
+                if x % grid == 0 || y % grid == 0 {
+                    tint(x, y, [0, 0, 96]);
+                }
+            }
+        }
+        for shelf in &self.shelves {
+            for x in 0..width {
+                tint(x, shelf.y as usize, [192, 0, 0]);
+            }
+        }
+        for (rect, _) in self.items.values() {
+            if rect.width == 0 || rect.height == 0 {
+                continue;
+            }
+            let (x0, y0) = (rect.x as usize, rect.y as usize);
+            let (x1, y1) = (x0 + rect.width as usize - 1, y0 + rect.height as usize - 1);
+            for x in x0..=x1 {
+                tint(x, y0, [0, 160, 0]);
+                tint(x, y1, [0, 160, 0]);
+            }
+            for y in y0..=y1 {
+                tint(x0, y, [0, 160, 0]);
+                tint(x1, y, [0, 160, 0]);
+            }
+        }
+        rgba
+    }
+}
+
+fn round_up(n: u32, step: u32) -> u32 {
+    n.div_ceil(step) * step
+}
+
+/// Occupancy of a texture atlas, in physical pixels.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AtlasStats {
+    pub width: u32,
+    pub height: u32,
+    /// The largest size the atlas can grow to on this device.
+    pub max_size: u32,
+    /// Height covered by shelves; the rest of the atlas is free.
+    pub used_height: u32,
+    pub shelves: usize,
+    pub items: usize,
+}
+
+/// A texture packed with an [`Atlas`], which grows up to the device limit
+/// when it is full. Positions are in texels, so they stay valid when the
+/// texture is reallocated.
+pub(crate) struct GpuAtlas<K> {
+    label: &'static str,
+    format: wgpu::TextureFormat,
+    texture: wgpu::Texture,
+    view: wgpu::TextureView,
+    upload_buffer: wgpu::Buffer,
+    upload_buffer_size: u64,
+    allocator: Atlas<K>,
+    /// The height the texture starts at, and compacts down to.
+    min_height: u32,
+    /// The largest side the texture may grow to.
+    max_size: u32,
+    /// Bumped whenever the texture is reallocated, so that bind groups
+    /// referring to the old view can be rebuilt.
+    generation: u64,
+    /// Items inserted since the last flush, with their pixels.
+    pending: Vec<(AtlasRect, Vec<u8>)>,
+}
+
+impl<K: Hash + Eq> GpuAtlas<K> {
+    const INITIAL_UPLOAD_BUFFER_SIZE: u64 = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT as u64 * 100;
+
+    /// `format` must be R8 or RGBA8.
+    pub(crate) fn new(
+        device: &wgpu::Device,
+        label: &'static str,
+        format: wgpu::TextureFormat,
+        width: u32,
+        height: u32,
+    ) -> Self {
+        let texture = create_texture(device, label, format, width, height);
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let upload_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some(label),
+            size: Self::INITIAL_UPLOAD_BUFFER_SIZE,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+
+        Self {
+            label,
+            format,
+            texture,
+            view,
+            upload_buffer,
+            upload_buffer_size: Self::INITIAL_UPLOAD_BUFFER_SIZE,
+            allocator: Atlas::new(width, height),
+            min_height: height,
+            max_size: device.limits().max_texture_dimension_2d,
+            generation: 0,
+            pending: Vec::new(),
+        }
+    }
+
+    pub(crate) fn view(&self) -> &wgpu::TextureView {
+        &self.view
+    }
+
+    pub(crate) fn texture(&self) -> &wgpu::Texture {
+        &self.texture
+    }
+
+    pub(crate) fn allocator(&self) -> &Atlas<K> {
+        &self.allocator
+    }
+
+    pub(crate) fn format(&self) -> wgpu::TextureFormat {
+        self.format
+    }
+
+    pub(crate) fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// The bytes of the texture and of the buffer uploads go through.
+    pub(crate) fn memory_bytes(&self) -> u64 {
+        let texels = self.allocator.width() as u64 * self.allocator.height() as u64;
+        texels * bytes_per_pixel(self.format) as u64 + self.upload_buffer_size
+    }
+
+    pub(crate) fn stats(&self) -> AtlasStats {
+        AtlasStats {
+            width: self.allocator.width(),
+            height: self.allocator.height(),
+            max_size: self.max_size,
+            used_height: self.allocator.used_height(),
+            shelves: self.allocator.shelves(),
+            items: self.allocator.len(),
+        }
+    }
+
+    /// Allocate `size` for `key` and queue `data` for upload there, growing
+    /// the texture if needed. Returns `None` when the item is wider than
+    /// the atlas or the atlas can't grow anymore. The data reaches the texture on the next [`GpuAtlas::flush`].
+    pub(crate) fn insert(
+        &mut self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        mut key: K,
+        size: [u32; 2],
+        data: &[u8],
+    ) -> Option<AtlasRect> {
+        // Growing only adds rows, so an item wider than the atlas never fits.
+        if size[0] > self.allocator.width() {
+            return None;
+        }
+        let rect = loop {
+            match self.allocator.insert(key, size[0], size[1]) {
+                Ok(rect) => break rect,
+                Err(k) => key = k,
+            }
+            if !self.grow(device, encoder) {
+                return None;
+            }
+        };
+        if rect.width > 0 && rect.height > 0 {
+            self.pending.push((rect, data.to_vec()));
+        }
+        Some(rect)
+    }
+
+    /// Free the space of `key`, dropping its upload if it hasn't been
+    /// flushed yet.
+    pub(crate) fn remove(&mut self, key: &K) {
+        if let Some(rect) = self.allocator.remove(key) {
+            self.pending.retain(|(pending, _)| *pending != rect);
+        }
+    }
+
+    /// Pack the items again from the top, tallest first, into a texture
+    /// only as tall as they need, to give back the space of removed items.
+    /// Returns where every item went, or nothing if it didn't shrink.
+    pub(crate) fn compact(
+        &mut self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+    ) -> Vec<(K, AtlasRect)>
+    where
+        K: Clone,
+    {
+        let width = self.allocator.width();
+        let mut items: Vec<(K, AtlasRect)> = self
+            .allocator
+            .items()
+            .map(|(key, rect)| (key.clone(), *rect))
+            .collect();
+        items.sort_by_key(|(_, rect)| std::cmp::Reverse(rect.height));
+
+        let mut height = self.min_height;
+        let allocator = loop {
+            let mut allocator = Atlas::new(width, height);
+            if items.iter().all(|(key, rect)| {
+                allocator
+                    .insert(key.clone(), rect.width, rect.height)
+                    .is_ok()
+            }) {
+                break allocator;
+            }
+            height = height.saturating_mul(2);
+        };
+        if height >= self.allocator.height() {
+            return Vec::new();
+        }
+
+        let texture = create_texture(device, self.label, self.format, width, height);
+        let mut moved = Vec::with_capacity(items.len());
+        let mut new_rects = HashMap::with_capacity(items.len());
+        for (key, old) in items {
+            let new = allocator.items.get(&key).unwrap().0;
+            if old.width > 0 && old.height > 0 {
+                encoder.copy_texture_to_texture(
+                    wgpu::ImageCopyTexture {
+                        origin: wgpu::Origin3d {
+                            x: old.x,
+                            y: old.y,
+                            z: 0,
+                        },
+                        ..self.texture.as_image_copy()
+                    },
+                    wgpu::ImageCopyTexture {
+                        origin: wgpu::Origin3d {
+                            x: new.x,
+                            y: new.y,
+                            z: 0,
+                        },
+                        ..texture.as_image_copy()
+                    },
+                    wgpu::Extent3d {
+                        width: old.width,
+                        height: old.height,
+                        depth_or_array_layers: 1,
+                    },
+                );
+            }
+            new_rects.insert(old, new);
+            moved.push((key, new));
+        }
+        // Uploads that haven't been flushed go to the new place.
+        for (pending, _) in self.pending.iter_mut() {
+            if let Some(new) = new_rects.get(pending) {
+                *pending = *new;
+            }
+        }
+        log::info!(
+            "{} compacted from {}x{} to {}x{}",
+            self.label,
+            width,
+            self.allocator.height(),
+            width,
+            height
+        );
+
+        self.view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        self.texture = texture;
+        self.allocator = allocator;
+        self.generation += 1;
+        moved
+    }
+
+    /// Reallocate the texture at twice its height, up to the device limit,
+    /// and copy the existing items over.
+    fn grow(&mut self, device: &wgpu::Device, encoder: &mut wgpu::CommandEncoder) -> bool {
+        let (width, old_height) = (self.allocator.width(), self.allocator.height());
+        if old_height >= self.max_size {
+            return false;
+        }
+        let height = old_height.saturating_mul(2).min(self.max_size);
+
+        let texture = create_texture(device, self.label, self.format, width, height);
+        encoder.copy_texture_to_texture(
+            self.texture.as_image_copy(),
+            texture.as_image_copy(),
+            wgpu::Extent3d {
+                width,
+                height: old_height,
+                depth_or_array_layers: 1,
+            },
+        );
+        log::info!(
+            "{} grown from {}x{} to {}x{}",
+            self.label,
+            width,
+            old_height,
+            width,
+            height
+        );
+
+        // The old texture is still read by the copy above, so it is only
+        // dropped here and released once the encoder has been submitted.
+        self.view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        self.texture = texture;
+        self.allocator.grow(height);
+        self.generation += 1;
+        true
+    }
+
+    /// Upload everything inserted since the last flush, with one copy per
+    /// run of new items side by side on a shelf. Items mostly go at the end
+    /// of their shelf, so that is usually one copy per shelf.
+    pub(crate) fn flush(
+        &mut self,
+        device: &wgpu::Device,
+        staging_belt: &mut wgpu::util::StagingBelt,
+        encoder: &mut wgpu::CommandEncoder,
+    ) {
+        if self.pending.is_empty() {
+            return;
+        }
+        let mut pending = std::mem::take(&mut self.pending);
+        pending.sort_by_key(|(rect, _)| (rect.y, rect.x));
+
+        let bytes_per_pixel = bytes_per_pixel(self.format);
+        // It is a webgpu requirement that:
+        //  BufferCopyView.layout.bytes_per_row % wgpu::COPY_BYTES_PER_ROW_ALIGNMENT == 0
+        // So each span's rows are padded to a multiple of the alignment,
+        // which also keeps every span's offset aligned.
+        let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT as usize;
+
+        let mut spans: Vec<(AtlasRect, usize, usize, Range<usize>)> = Vec::new();
+        let mut size = 0;
+        let mut start = 0;
+        while start < pending.len() {
+            let y = pending[start].0.y;
+            let mut end = start + 1;
+            while end < pending.len()
+                && pending[end].0.y == y
+                && pending[end].0.x == pending[end - 1].0.x + pending[end - 1].0.width
+            {
+                end += 1;
+            }
+            let items = &pending[start..end];
+            let x = items[0].0.x;
+            let width = items
+                .iter()
+                .map(|(rect, _)| rect.x + rect.width)
+                .max()
+                .unwrap()
+                - x;
+            let height = items.iter().map(|(rect, _)| rect.height).max().unwrap();
+            let bytes_per_row = width as usize * bytes_per_pixel;
+            let bytes_per_row = bytes_per_row + (align - bytes_per_row % align) % align;
+            spans.push((
+                AtlasRect {
+                    x,
+                    y,
+                    width,
+                    height,
+                },
+                size,
+                bytes_per_row,
+                start..end,
+            ));
+            size += bytes_per_row * height as usize;
+            start = end;
+        }
+
+        let size = size as u64;
+        if self.upload_buffer_size < size {
+            self.upload_buffer.destroy();
+            self.upload_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some(self.label),
+                size,
+                usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::COPY_SRC,
+                mapped_at_creation: false,
+            });
+
+            self.upload_buffer_size = size;
+        }
+
+        {
+            let mut data = staging_belt.write_buffer(
+                encoder,
+                &self.upload_buffer,
+                0,
+                NonZeroU64::new(size).unwrap(),
+                device,
+            );
+            data.fill(0);
+            for (span, offset, bytes_per_row, items) in spans.iter() {
+                for (rect, pixels) in &pending[items.clone()] {
+                    let width = rect.width as usize * bytes_per_pixel;
+                    let x = (rect.x - span.x) as usize * bytes_per_pixel;
+                    for row in 0..rect.height as usize {
+                        let start = offset + row * bytes_per_row + x;
+                        data[start..start + width]
+                            .copy_from_slice(&pixels[row * width..(row + 1) * width]);
+                    }
+                }
+            }
+        }
+
+        for (span, offset, bytes_per_row, _) in spans.iter() {
+            encoder.copy_buffer_to_texture(
+                wgpu::ImageCopyBuffer {
+                    buffer: &self.upload_buffer,
+                    layout: wgpu::ImageDataLayout {
+                        offset: *offset as u64,
+                        bytes_per_row: NonZeroU32::new(*bytes_per_row as u32),
+                        rows_per_image: NonZeroU32::new(span.height),
+                    },
+                },
+                wgpu::ImageCopyTexture {
+                    texture: &self.texture,
+                    mip_level: 0,
+                    origin: wgpu::Origin3d {
+                        x: span.x,
+                        y: span.y,
+                        z: 0,
+                    },
+                    aspect: wgpu::TextureAspect::All,
+                },
+                wgpu::Extent3d {
+                    width: span.width,
+                    height: span.height,
+                    depth_or_array_layers: 1,
+                },
+            );
+        }
+    }
+}
+
+impl<K> Drop for GpuAtlas<K> {
+    fn drop(&mut self) {
+        self.texture.destroy();
+        self.upload_buffer.destroy();
+    }
+}
+
+fn create_texture(
+    device: &wgpu::Device,
+    label: &str,
+    format: wgpu::TextureFormat,
+    width: u32,
+    height: u32,
+) -> wgpu::Texture {
+    device.create_texture(&wgpu::TextureDescriptor {
+        label: Some(label),
+        size: wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+        dimension: wgpu::TextureDimension::D2,
+        format,
+        usage: TEXTURE_USAGE,
+        mip_level_count: 1,
+        sample_count: 1,
+    })
+}
+
+pub(crate) fn bytes_per_pixel(format: wgpu::TextureFormat) -> usize {
+    match format {
+        wgpu::TextureFormat::R8Unorm => 1,
+        _ => 4,
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// A device to test GPU resources with, if there is an adapter. The
+    /// tests that need one are ignored unless run with `--ignored`.
+    pub(crate) fn device() -> Option<(wgpu::Device, wgpu::Queue)> {
+        let instance = wgpu::Instance::new(wgpu::Backends::all());
+        let adapter =
+            futures::executor::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::default(),
+                compatible_surface: None,
+                force_fallback_adapter: false,
+            }))?;
+        futures::executor::block_on(
+            adapter.request_device(&wgpu::DeviceDescriptor::default(), None),
+        )
+        .ok()
+    }
+
+    #[test]
+    fn removed_space_is_reused() {
+        let mut atlas = Atlas::new(64, 64);
+        let a = atlas.insert(0, 20, 10).unwrap();
+        atlas.insert(1, 20, 10).unwrap();
+        atlas.remove(&0);
+        let c = atlas.insert(2, 16, 10).unwrap();
+        assert_eq!((c.x, c.y), (a.x, a.y));
+        assert_eq!(atlas.shelves(), 1);
+    }
+
+    #[test]
+    fn emptied_shelves_are_dropped() {
+        let mut atlas = Atlas::new(64, 64);
+        for key in 0..8 {
+            atlas.insert(key, 32, 16).unwrap();
+        }
+        assert_eq!(atlas.used_height(), 64);
+        assert!(atlas.insert(8, 32, 16).is_err());
+        for key in 2..8 {
+            atlas.remove(&key);
+        }
+        assert_eq!(atlas.used_height(), 16);
+        // An empty shelf takes items of any height up to its own.
+        atlas.remove(&1);
+        atlas.remove(&0);
+        assert_eq!(atlas.used_height(), 0);
+        assert!(atlas.insert(9, 64, 64).is_ok());
+    }
+
+    #[test]
+    fn over_wide_items_are_refused() {
+        let mut atlas = Atlas::new(64, 64);
+        assert_eq!(atlas.insert(0, 65, 8), Err(0));
+        assert_eq!(atlas.shelves(), 0);
+    }
+
+    #[test]
+    #[ignore = "needs a GPU adapter"]
+    fn over_wide_glyphs_are_refused() {
+        let (device, _queue) = device().expect("no GPU adapter");
+        let mut atlas = GpuAtlas::new(&device, "test", wgpu::TextureFormat::R8Unorm, 64, 64);
+        let mut encoder = device.create_command_encoder(&Default::default());
+        let data = vec![0; 65 * 8];
+        assert_eq!(atlas.insert(&device, &mut encoder, 0, [65, 8], &data), None);
+        assert_eq!(atlas.allocator().height(), 64);
+        assert_eq!(atlas.generation(), 0);
+    }
+}
@@ -0,0 +1,400 @@
+use std::num::NonZeroU64;
+
+use piet::kurbo::{Rect, Size};
+
+/// A request to blur everything drawn before `index` inside `rect`.
+#[derive(Clone)]
+pub(crate) struct Backdrop {
+    /// The number of indices drawn before the blur is applied.
+    pub(crate) index: u32,
+    /// The blurred region in logical coordinates, with the translation of
+    /// the render context already applied.
+    pub(crate) rect: Rect,
+    pub(crate) radius: f64,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct Params {
+    rect: [f32; 4],
+    resolution: [f32; 2],
+    direction: [f32; 2],
+    sigma: f32,
+    _pad: [f32; 3],
+}
+
+unsafe impl bytemuck::Pod for Params {}
+unsafe impl bytemuck::Zeroable for Params {}
+
+/// Separable gaussian blur of the already rendered frame, used for
+/// frosted glass panels.
+///
+/// For each backdrop the multisampled frame is resolved into `scratch`,
+/// blurred horizontally into `horizontal`, and blurred vertically back into
+/// the multisampled frame, after which the remaining geometry is drawn on
+/// top.
+pub(crate) struct BackdropPipeline {
+    format: wgpu::TextureFormat,
+    horizontal_pipeline: wgpu::RenderPipeline,
+    vertical_pipeline: wgpu::RenderPipeline,
+    bind_group_layout: wgpu::BindGroupLayout,
+    sampler: wgpu::Sampler,
+    params: wgpu::Buffer,
+    supported_params: usize,
+    params_stride: u64,
+    targets: Option<Targets>,
+    regions: Vec<Option<[[u32; 4]; 2]>>,
+}
+
+struct Targets {
+    size: [u32; 2],
+    scratch: wgpu::Texture,
+    scratch_view: wgpu::TextureView,
+    horizontal: wgpu::Texture,
+    horizontal_view: wgpu::TextureView,
+    horizontal_bind_group: wgpu::BindGroup,
+    vertical_bind_group: wgpu::BindGroup,
+}
+
+impl Drop for Targets {
+    fn drop(&mut self) {
+        self.scratch.destroy();
+        self.horizontal.destroy();
+    }
+}
+
+impl BackdropPipeline {
+    pub(crate) fn new(device: &wgpu::Device, format: wgpu::TextureFormat) -> Self {
+        let params_size = std::mem::size_of::<Params>() as u64;
+        let alignment = device.limits().min_uniform_buffer_offset_alignment as u64;
+        let params_stride = params_size.div_ceil(alignment) * alignment;
+
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("backdrop bind group layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: true,
+                        min_binding_size: wgpu::BufferSize::new(params_size),
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+            ],
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("backdrop pipeline layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let shader = device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+            label: Some("backdrop shader"),
+            source: wgpu::ShaderSource::Wgsl(std::borrow::Cow::Borrowed(include_str!(
+                "shader/backdrop.wgsl"
+            ))),
+        });
+
+        let create_pipeline = |sample_count: u32| {
+            device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: Some("backdrop pipeline"),
+                layout: Some(&pipeline_layout),
+                vertex: wgpu::VertexState {
+                    module: &shader,
+                    entry_point: "vs_main",
+                    buffers: &[],
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: &shader,
+                    entry_point: "fs_main",
+                    targets: &[wgpu::ColorTargetState {
+                        format,
+                        blend: Some(wgpu::BlendState::REPLACE),
+                        write_mask: wgpu::ColorWrites::ALL,
+                    }],
+                }),
+                primitive: wgpu::PrimitiveState {
+                    topology: wgpu::PrimitiveTopology::TriangleStrip,
+                    ..Default::default()
+                },
+                depth_stencil: None,
+                multisample: wgpu::MultisampleState {
+                    count: sample_count,
+                    mask: !0,
+                    alpha_to_coverage_enabled: false,
+                },
+                multiview: None,
+            })
+        };
+        let horizontal_pipeline = create_pipeline(1);
+        let vertical_pipeline = create_pipeline(4);
+
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+
+        let supported_params = 2;
+        let params = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("backdrop params ubo"),
+            size: params_stride * supported_params as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        Self {
+            format,
+            horizontal_pipeline,
+            vertical_pipeline,
+            bind_group_layout,
+            sampler,
+            params,
+            supported_params,
+            params_stride,
+            targets: None,
+            regions: Vec::new(),
+        }
+    }
+
+    fn create_targets(&self, device: &wgpu::Device, size: [u32; 2]) -> Targets {
+        let create_texture = |label| {
+            device.create_texture(&wgpu::TextureDescriptor {
+                label: Some(label),
+                size: wgpu::Extent3d {
+                    width: size[0],
+                    height: size[1],
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: self.format,
+                usage: wgpu::TextureUsages::RENDER_ATTACHMENT
+                    | wgpu::TextureUsages::TEXTURE_BINDING,
+            })
+        };
+        let scratch = create_texture("backdrop scratch texture");
+        let scratch_view = scratch.create_view(&wgpu::TextureViewDescriptor::default());
+        let horizontal = create_texture("backdrop horizontal blur texture");
+        let horizontal_view = horizontal.create_view(&wgpu::TextureViewDescriptor::default());
+
+        let params_size = std::mem::size_of::<Params>() as u64;
+        let create_bind_group = |view: &wgpu::TextureView| {
+            device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("backdrop bind group"),
+                layout: &self.bind_group_layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                            buffer: &self.params,
+                            offset: 0,
+                            size: wgpu::BufferSize::new(params_size),
+                        }),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::Sampler(&self.sampler),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: wgpu::BindingResource::TextureView(view),
+                    },
+                ],
+            })
+        };
+        let horizontal_bind_group = create_bind_group(&scratch_view);
+        let vertical_bind_group = create_bind_group(&horizontal_view);
+
+        Targets {
+            size,
+            scratch,
+            scratch_view,
+            horizontal,
+            horizontal_view,
+            horizontal_bind_group,
+            vertical_bind_group,
+        }
+    }
+
+    pub(crate) fn upload_data(
+        &mut self,
+        device: &wgpu::Device,
+        staging_belt: &mut wgpu::util::StagingBelt,
+        encoder: &mut wgpu::CommandEncoder,
+        backdrops: &[Backdrop],
+        size: Size,
+        scale: f64,
+    ) {
+        self.regions.clear();
+        let size = [size.width as u32, size.height as u32];
+        if backdrops.is_empty() || size[0] == 0 || size[1] == 0 {
+            return;
+        }
+
+        let mut recreate_targets = self.targets.as_ref().map(|t| t.size) != Some(size);
+        if backdrops.len() * 2 > self.supported_params {
+            self.supported_params = backdrops.len() * 2;
+            self.params.destroy();
+            self.params = device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("backdrop params ubo"),
+                size: self.params_stride * self.supported_params as u64,
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            });
+            recreate_targets = true;
+        }
+        if recreate_targets {
+            self.targets = Some(self.create_targets(device, size));
+        }
+
+        let resolution = [size[0] as f32, size[1] as f32];
+        let mut data = vec![0u8; self.params_stride as usize * backdrops.len() * 2];
+        for (i, backdrop) in backdrops.iter().enumerate() {
+            let sigma = backdrop.radius * scale;
+            let extent = (sigma * 3.0).ceil();
+            let rect = Rect::new(
+                backdrop.rect.x0 * scale,
+                backdrop.rect.y0 * scale,
+                backdrop.rect.x1 * scale,
+                backdrop.rect.y1 * scale,
+            );
+            let horizontal_rect = rect.inflate(0.0, extent);
+
+            let region = clamp_region(rect, size).and_then(|vertical| {
+                clamp_region(horizontal_rect, size).map(|horizontal| [horizontal, vertical])
+            });
+            self.regions.push(region);
+
+            let passes = [(horizontal_rect, [1.0, 0.0]), (rect, [0.0, 1.0])];
+            for (j, (rect, direction)) in passes.iter().enumerate() {
+                let params = Params {
+                    rect: [rect.x0 as f32, rect.y0 as f32, rect.x1 as f32, rect.y1 as f32],
+                    resolution,
+                    direction: *direction,
+                    sigma: sigma as f32,
+                    _pad: [0.0; 3],
+                };
+                let offset = (i * 2 + j) * self.params_stride as usize;
+                let bytes = bytemuck::bytes_of(&params);
+                data[offset..offset + bytes.len()].copy_from_slice(bytes);
+            }
+        }
+
+        let mut params = staging_belt.write_buffer(
+            encoder,
+            &self.params,
+            0,
+            NonZeroU64::new(data.len() as u64).unwrap(),
+            device,
+        );
+        params.copy_from_slice(&data);
+    }
+
+    /// Whether backdrop `index` has an on-screen region to blur.
+    pub(crate) fn is_visible(&self, index: usize) -> bool {
+        self.targets.is_some() && matches!(self.regions.get(index), Some(Some(_)))
+    }
+
+    /// Blur the region of backdrop `index` in the multisampled frame.
+    pub(crate) fn draw(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        msaa: &wgpu::TextureView,
+        index: usize,
+    ) {
+        let (targets, [horizontal, vertical]) =
+            match (self.targets.as_ref(), self.regions.get(index).copied().flatten()) {
+                (Some(targets), Some(regions)) => (targets, regions),
+                _ => return,
+            };
+
+        // A pass without draws still resolves the multisampled frame.
+        let _ = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("backdrop resolve"),
+            color_attachments: &[wgpu::RenderPassColorAttachment {
+                view: msaa,
+                resolve_target: Some(&targets.scratch_view),
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: true,
+                },
+            }],
+            depth_stencil_attachment: None,
+        });
+
+        let passes = [
+            (
+                &targets.horizontal_view,
+                &self.horizontal_pipeline,
+                &targets.horizontal_bind_group,
+                horizontal,
+            ),
+            (
+                msaa,
+                &self.vertical_pipeline,
+                &targets.vertical_bind_group,
+                vertical,
+            ),
+        ];
+        for (j, (view, pipeline, bind_group, region)) in passes.iter().enumerate() {
+            let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("backdrop blur"),
+                color_attachments: &[wgpu::RenderPassColorAttachment {
+                    view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Load,
+                        store: true,
+                    },
+                }],
+                depth_stencil_attachment: None,
+            });
+            let offset = (index * 2 + j) as u64 * self.params_stride;
+            pass.set_pipeline(pipeline);
+            pass.set_bind_group(0, bind_group, &[offset as u32]);
+            pass.set_scissor_rect(region[0], region[1], region[2], region[3]);
+            pass.draw(0..4, 0..1);
+        }
+    }
+}
+
+/// Convert a physical pixel rect into a scissor rect `[x, y, width, height]`
+/// inside the frame, or `None` if nothing of it is visible.
+fn clamp_region(rect: Rect, size: [u32; 2]) -> Option<[u32; 4]> {
+    let rect = rect
+        .expand()
+        .intersect(Rect::new(0.0, 0.0, size[0] as f64, size[1] as f64));
+    if rect.width() <= 0.0 || rect.height() <= 0.0 {
+        return None;
+    }
+    Some([
+        rect.x0 as u32,
+        rect.y0 as u32,
+        rect.width() as u32,
+        rect.height() as u32,
+    ])
+}
@@ -0,0 +1,68 @@
+use piet::kurbo::Rect;
+
+/// What a draw call was, as far as a [`RenderCapture`] is concerned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum DrawKind {
+    /// A filled rect.
+    Quad,
+    /// A text layout.
+    Text,
+    /// An image.
+    Image,
+    /// Any other geometry: paths, strokes, circles, svgs and shadows.
+    Triangles,
+}
+
+/// A draw call recorded by a [`RenderCapture`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct CapturedDraw {
+    pub kind: DrawKind,
+    /// The bounds of what was drawn, in logical pixels with the transform
+    /// in effect applied.
+    pub bounds: Rect,
+    /// How many triangles it added to the frame.
+    pub triangles: usize,
+}
+
+/// The draw calls of a frame that produced geometry, in the order they
+/// were made, for tests to check what a widget drew without comparing
+/// pixels. Started with [`WgpuRenderContext::start_capture`] and taken
+/// with [`WgpuRenderContext::take_capture`].
+///
+/// Draws culled because they are outside of the viewport or clip aren't
+/// recorded. Draws hidden under opaque quads are, since they are only
+/// dropped when the frame is finished.
+///
+/// [`WgpuRenderContext::start_capture`]: crate::Piet::start_capture
+/// [`WgpuRenderContext::take_capture`]: crate::Piet::take_capture
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RenderCapture {
+    pub draws: Vec<CapturedDraw>,
+}
+
+impl RenderCapture {
+    /// The draws of `kind`.
+    pub fn of_kind(&self, kind: DrawKind) -> impl Iterator<Item = &CapturedDraw> {
+        self.draws.iter().filter(move |draw| draw.kind == kind)
+    }
+
+    pub fn quads(&self) -> impl Iterator<Item = &CapturedDraw> {
+        self.of_kind(DrawKind::Quad)
+    }
+
+    pub fn texts(&self) -> impl Iterator<Item = &CapturedDraw> {
+        self.of_kind(DrawKind::Text)
+    }
+
+    /// The number of triangles drawn, by draws of any kind.
+    pub fn triangles(&self) -> usize {
+        self.draws.iter().map(|draw| draw.triangles).sum()
+    }
+
+    /// The number of draws of `kind` that lie entirely within `rect`.
+    pub fn count_within(&self, kind: DrawKind, rect: Rect) -> usize {
+        self.of_kind(kind)
+            .filter(|draw| rect.union(draw.bounds) == rect)
+            .count()
+    }
+}
@@ -1,22 +1,56 @@
-use std::borrow::Cow;
+use std::{borrow::Cow, cmp::Ordering, time::Instant};
 
 use crate::{
-    pipeline::{GpuVertex, Primitive},
-    svg::Svg,
-    text::{WgpuText, WgpuTextLayout},
+    backdrop::Backdrop,
+    capture::{CapturedDraw, DrawKind, RenderCapture},
+    fringe,
+    gradient::{bake_lut, sorted_stops, GradientBrush, GradientSpace, GRADIENT_LUT_WIDTH},
+    image::WgpuImage,
+    layer::RecordedLayer,
+    occlusion::{cull_occluded, Quad},
+    path_cache::{append_path, PathCacheKey, PathGeometry},
+    pipeline::{GlyphMetricInfo, GpuVertex, Primitive},
+    svg::{Svg, SvgError},
+    text::{add_glyph_quad, is_visible_char, missing_glyph_box, Cell, WgpuText, WgpuTextLayout},
     WgpuRenderer,
 };
-use futures::task::SpawnExt;
 use lyon::lyon_tessellation::{
     BuffersBuilder, FillOptions, FillTessellator, FillVertex, StrokeOptions, StrokeTessellator,
     StrokeVertex, VertexBuffers,
 };
 use lyon::tessellation;
 use piet::{
-    kurbo::{Affine, Point, Rect, Shape, Size, Vec2},
-    Color, FontFamily, Image, IntoBrush, RenderContext,
+    kurbo::{Affine, Arc, Line, ParamCurve, PathEl, Point, Rect, RoundedRect, Shape, Size, Vec2},
+    Color, FontFamily, FontWeight, GradientStop, Image, InterpolationMode, IntoBrush, LineCap,
+    LineJoin, RenderContext, StrokeStyle, TextAlignment,
 };
 
+/// The `tex` of image vertices, which sample the image atlas at `tex_pos`
+/// and multiply the vertex color by it.
+const IMAGE_TEX: f32 = 2.0;
+/// The `tex` of image vertices sampled at the nearest texel.
+const IMAGE_NEAREST_TEX: f32 = 3.0;
+/// Added to the `tex` of images in the grayscale atlas, which the shader
+/// samples as luminance.
+const GRAY_IMAGE_TEX_OFFSET: f32 = 2.0;
+/// The rows and columns of the grid an image quad is drawn as. Inside each
+/// of its triangles the image is mapped linearly, not in perspective.
+const IMAGE_QUAD_CELLS: u32 = 8;
+
+/// Draws a frame of a [`WgpuRenderer`].
+///
+/// Everything is drawn in the order it was submitted, text included: text
+/// covers what was drawn before it and is covered by what is drawn after
+/// it, opaque or transparent. There is no depth buffer; all geometry goes
+/// into one vertex and index buffer per frame, and only backdrop blurs
+/// split it into passes, at the position they were requested.
+///
+/// So there is no depth precision to run out of either, however many
+/// draws a frame has. What bounds a frame is the number of primitives,
+/// the per-draw state that every clip, transform and effect adds. They
+/// go in a storage buffer that grows to the device's
+/// `max_storage_buffer_binding_size`, 128 MiB by default, which holds
+/// hundreds of thousands of them; primitives past that are dropped.
 pub struct WgpuRenderContext<'a> {
     pub(crate) renderer: &'a mut WgpuRenderer,
     pub(crate) fill_tess: FillTessellator,
@@ -25,8 +59,23 @@ pub struct WgpuRenderContext<'a> {
     inner_text: WgpuText,
     pub(crate) cur_transform: Affine,
     state_stack: Vec<State>,
-    clip_stack: Vec<Rect>,
+    /// The clips in effect, with how far in from their edge content fades
+    /// out.
+    clip_stack: Vec<(RoundedRect, f64)>,
     pub(crate) primitives: Vec<Primitive>,
+    pub(crate) backdrops: Vec<Backdrop>,
+    quads: Vec<Quad>,
+    pixel_snapping: bool,
+    /// Set while a layer is recorded, which is replayed whatever the
+    /// viewport, so nothing may be culled from it.
+    recording_layer: bool,
+    capture: Option<RenderCapture>,
+    started: Instant,
+    /// How many saves `finish` found without a restore, reported once by
+    /// `status`.
+    unbalanced_saves: usize,
+    /// The last svg that couldn't be drawn, reported once by `status`.
+    svg_error: Option<SvgError>,
 }
 
 #[derive(Default)]
@@ -40,146 +89,1453 @@ struct State {
     n_clip: usize,
 }
 
+/// The buffers a render context fills during a frame, handed back to the
+/// renderer when the context is dropped, so that the next frame reuses
+/// their allocations instead of growing new ones.
+pub(crate) struct FrameBuffers {
+    fill_tess: FillTessellator,
+    stroke_tess: StrokeTessellator,
+    geometry: VertexBuffers<GpuVertex, u32>,
+    state_stack: Vec<State>,
+    clip_stack: Vec<(RoundedRect, f64)>,
+    primitives: Vec<Primitive>,
+    backdrops: Vec<Backdrop>,
+    quads: Vec<Quad>,
+}
+
+impl FrameBuffers {
+    fn new() -> Self {
+        Self {
+            fill_tess: FillTessellator::new(),
+            stroke_tess: StrokeTessellator::new(),
+            geometry: VertexBuffers::new(),
+            state_stack: Vec::new(),
+            clip_stack: Vec::new(),
+            primitives: Vec::new(),
+            backdrops: Vec::new(),
+            quads: Vec::new(),
+        }
+    }
+
+    /// What the buffers have allocated, in bytes.
+    pub(crate) fn memory_bytes(&self) -> u64 {
+        (self.geometry.vertices.capacity() * std::mem::size_of::<GpuVertex>()
+            + self.geometry.indices.capacity() * std::mem::size_of::<u32>()
+            + self.state_stack.capacity() * std::mem::size_of::<State>()
+            + self.clip_stack.capacity() * std::mem::size_of::<(RoundedRect, f64)>()
+            + self.primitives.capacity() * std::mem::size_of::<Primitive>()
+            + self.backdrops.capacity() * std::mem::size_of::<Backdrop>()
+            + self.quads.capacity() * std::mem::size_of::<Quad>()) as u64
+    }
+}
+
 impl<'a> WgpuRenderContext<'a> {
+    /// Start a frame, reusing the buffers of the previous one. See also
+    /// [`WgpuRenderer::begin_frame`].
     pub fn new(renderer: &'a mut WgpuRenderer) -> Self {
+        renderer.apply_scale();
         let text = renderer.text();
-        let geometry: VertexBuffers<GpuVertex, u32> = VertexBuffers::new();
+        let mut buffers = renderer
+            .frame_buffers
+            .take()
+            .unwrap_or_else(FrameBuffers::new);
+        buffers.geometry.vertices.clear();
+        buffers.geometry.indices.clear();
+        buffers.state_stack.clear();
+        buffers.clip_stack.clear();
+        buffers.primitives.clear();
+        buffers.backdrops.clear();
+        buffers.quads.clear();
 
         let mut context = Self {
             renderer,
-            fill_tess: FillTessellator::new(),
-            stroke_tess: StrokeTessellator::new(),
-            geometry,
+            fill_tess: buffers.fill_tess,
+            stroke_tess: buffers.stroke_tess,
+            geometry: buffers.geometry,
             inner_text: text,
             cur_transform: Affine::default(),
-            state_stack: Vec::new(),
-            clip_stack: Vec::new(),
-            primitives: Vec::new(),
+            state_stack: buffers.state_stack,
+            clip_stack: buffers.clip_stack,
+            primitives: buffers.primitives,
+            backdrops: buffers.backdrops,
+            quads: buffers.quads,
+            pixel_snapping: true,
+            recording_layer: false,
+            capture: None,
+            started: Instant::now(),
+            unbalanced_saves: 0,
+            svg_error: None,
         };
         context.add_primitive();
         context
     }
 
+    /// Create a context which renders at `scale`, for surfaces whose device
+    /// pixel ratio differs from the one the renderer was last used with.
+    pub fn new_with_scale(renderer: &'a mut WgpuRenderer, scale: f64) -> Self {
+        renderer.set_scale(scale);
+        Self::new(renderer)
+    }
+
+    /// Snap axis-aligned rect fills and rect/line strokes to the physical
+    /// pixel grid so hairlines stay crisp. Enabled by default. Nothing is
+    /// snapped under a transform that rotates or skews.
+    pub fn set_pixel_snapping(&mut self, pixel_snapping: bool) {
+        self.pixel_snapping = pixel_snapping;
+    }
+
+    /// Add an antialiasing fringe around the geometry from `vertex_start`
+    /// and `index_start` on, if the renderer is set to.
+    fn add_fringe(&mut self, vertex_start: usize, index_start: usize) {
+        if !self.renderer.fringe_aa {
+            return;
+        }
+        let scale = self.pixels_per_unit();
+        if scale > 0.0 {
+            fringe::add_fringe(
+                &mut self.geometry,
+                vertex_start,
+                index_start,
+                (1.0 / scale) as f32,
+            );
+        }
+    }
+
+    /// How many physical pixels a unit spans once drawn, on average over
+    /// both axes, under the transform the renderer applies.
+    fn pixels_per_unit(&self) -> f64 {
+        self.renderer.surface.scale * self.applied_transform().determinant().abs().sqrt()
+    }
+
+    /// The tessellation tolerance in logical units which keeps curves within
+    /// a quarter of a physical pixel as drawn.
+    fn tolerance(&self) -> f64 {
+        let scale = self.pixels_per_unit();
+        if scale > 0.0 {
+            0.25 / scale
+        } else {
+            0.25
+        }
+    }
+
+    /// The part of the current transform that is drawn with, see
+    /// [`applied_transform`].
+    fn applied_transform(&self) -> Affine {
+        applied_transform(self.cur_transform)
+    }
+
+    fn cur_translation(&self) -> Vec2 {
+        let affine = self.cur_transform.as_coeffs();
+        Vec2::new(affine[4], affine[5])
+    }
+
+    /// How user space maps to physical pixels under the transform that is
+    /// drawn with, or `None` when pixel snapping is off.
+    fn pixel_grid(&self) -> Option<PixelGrid> {
+        if !self.pixel_snapping {
+            return None;
+        }
+        PixelGrid::new(self.applied_transform(), self.renderer.surface.scale)
+    }
+
+    fn snap_fill_rect(&self, rect: Rect) -> Rect {
+        let grid = match self.pixel_grid() {
+            Some(grid) => grid,
+            None => return rect,
+        };
+        Rect::new(
+            snap_edge(rect.x0, grid.scale.x, grid.offset.x),
+            snap_edge(rect.y0, grid.scale.y, grid.offset.y),
+            snap_edge(rect.x1, grid.scale.x, grid.offset.x),
+            snap_edge(rect.y1, grid.scale.y, grid.offset.y),
+        )
+    }
+
+    fn snap_stroke_rect(&self, rect: Rect, width: f64) -> (Rect, f64) {
+        // A stroke is as wide along both axes, which can't be a whole
+        // number of pixels on both under a non-uniform scale.
+        let grid = match self.pixel_grid() {
+            Some(grid) if grid.scale.x.abs() == grid.scale.y.abs() => grid,
+            _ => return (rect, width),
+        };
+        let physical_width = snap_width(width, grid.scale.x);
+        let rect = Rect::new(
+            snap_center(rect.x0, grid.scale.x, grid.offset.x, physical_width),
+            snap_center(rect.y0, grid.scale.y, grid.offset.y, physical_width),
+            snap_center(rect.x1, grid.scale.x, grid.offset.x, physical_width),
+            snap_center(rect.y1, grid.scale.y, grid.offset.y, physical_width),
+        );
+        (rect, physical_width / grid.scale.x.abs())
+    }
+
+    fn snap_stroke_line(&self, line: Line, width: f64) -> (Line, f64) {
+        let vertical = line.p0.x == line.p1.x;
+        let horizontal = line.p0.y == line.p1.y;
+        let grid = match self.pixel_grid() {
+            Some(grid) if vertical || horizontal => grid,
+            _ => return (line, width),
+        };
+        let (mut p0, mut p1) = (line.p0, line.p1);
+        // The width of a line goes across it, along the other axis.
+        let (scale, physical_width) = if vertical {
+            let physical_width = snap_width(width, grid.scale.x);
+            p0.x = snap_center(p0.x, grid.scale.x, grid.offset.x, physical_width);
+            p1.x = p0.x;
+            (grid.scale.x, physical_width)
+        } else {
+            let physical_width = snap_width(width, grid.scale.y);
+            p0.y = snap_center(p0.y, grid.scale.y, grid.offset.y, physical_width);
+            p1.y = p0.y;
+            (grid.scale.y, physical_width)
+        };
+        (Line::new(p0, p1), physical_width / scale.abs())
+    }
+
+    /// Whether anything inside `bbox`, in user space, can end up in the
+    /// viewport and the current clip. Shapes that can't are skipped before
+    /// any geometry is built for them.
+    fn is_visible(&self, bbox: Rect) -> bool {
+        if self.recording_layer {
+            return true;
+        }
+        let size = self.renderer.surface.size / self.renderer.surface.scale;
+        let mut visible = if size.is_empty() {
+            Rect::new(f64::MIN, f64::MIN, f64::MAX, f64::MAX)
+        } else {
+            size.to_rect()
+        };
+        if let Some(clip) = self.current_clip() {
+            visible = visible.intersect(clip.rect());
+        }
+        drawn_within(bbox, self.cur_transform, visible)
+    }
+
+    /// Start recording the draw calls of the frame, until
+    /// [`WgpuRenderContext::take_capture`].
+    pub fn start_capture(&mut self) {
+        self.capture = Some(RenderCapture::default());
+    }
+
+    /// Stop recording draw calls and return those made since
+    /// [`WgpuRenderContext::start_capture`].
+    pub fn take_capture(&mut self) -> Option<RenderCapture> {
+        self.capture.take()
+    }
+
+    /// Record a draw of `kind` within `bbox`, in user space, which added
+    /// the indices from `index_start` on, if capturing.
+    fn capture_draw(&mut self, kind: DrawKind, bbox: Rect, index_start: usize) {
+        if let Some(capture) = self.capture.as_mut() {
+            let triangles = (self.geometry.indices.len() - index_start) / 3;
+            if triangles > 0 {
+                capture.draws.push(CapturedDraw {
+                    kind,
+                    bounds: self.cur_transform.transform_rect_bbox(bbox),
+                    triangles,
+                });
+            }
+        }
+    }
+
+    /// Drop the states that were saved but never restored, with their clips
+    /// and transforms, so they don't leak into what is drawn next. A
+    /// forgotten restore otherwise shows as content clipped away for no
+    /// apparent reason.
+    fn reset_unbalanced(&mut self) {
+        let depth = self.state_stack.len();
+        if depth == 0 {
+            return;
+        }
+        let clips: usize = self.state_stack.iter().map(|state| state.n_clip).sum();
+        log::warn!(
+            "save stack unbalanced at finish: depth {} with {} clips left",
+            depth,
+            clips
+        );
+        self.cur_transform = self.state_stack[0].transform;
+        self.state_stack.clear();
+        for _ in 0..clips {
+            self.pop_clip();
+        }
+        self.add_primitive();
+        self.unbalanced_saves = depth;
+    }
+
     fn pop_clip(&mut self) {
         self.clip_stack.pop();
     }
 
-    pub(crate) fn current_clip(&self) -> Option<&Rect> {
-        self.clip_stack.last()
+    pub(crate) fn current_clip(&self) -> Option<&RoundedRect> {
+        self.clip_stack.last().map(|(clip, _)| clip)
+    }
+
+    /// Clip to `shape` like [`RenderContext::clip`], but fade content out
+    /// over `feather` logical pixels inside its edge rather than cutting it
+    /// off, like at the ends of a scrolling list. Only rects and rounded
+    /// rects can clip.
+    pub fn clip_with_feather(&mut self, shape: impl Shape, feather: f64) {
+        let rounded_rect = if let Some(rect) = shape.as_rect() {
+            RoundedRect::from_rect(rect, 0.0)
+        } else if let Some(rounded_rect) = shape.as_rounded_rect() {
+            rounded_rect
+        } else {
+            return;
+        };
+        let affine = self.cur_transform.as_coeffs();
+        let rect = rounded_rect.rect() + Vec2::new(affine[4], affine[5]);
+        self.clip_stack.push((
+            RoundedRect::from_rect(rect, rounded_rect.radii()),
+            feather.max(0.0),
+        ));
+        if let Some(state) = self.state_stack.last_mut() {
+            state.n_clip += 1;
+        }
+        self.add_primitive();
     }
 
     fn add_primitive(&mut self) {
         let affine = self.cur_transform.as_coeffs();
         let translate = [affine[4] as f32, affine[5] as f32];
-        let (clip, clip_rect) = self
+        let clip_feather = self
+            .clip_stack
+            .last()
+            .map_or(0.0, |(_, feather)| *feather as f32);
+        let (clip, clip_rect, clip_radius) = self
             .current_clip()
-            .map(|r| (1.0, [r.x0 as f32, r.y0 as f32, r.x1 as f32, r.y1 as f32]))
-            .unwrap_or((0.0, [0.0, 0.0, 0.0, 0.0]));
+            .map(|r| {
+                let rect = r.rect();
+                let radii = r.radii();
+                (
+                    1.0,
+                    [rect.x0 as f32, rect.y0 as f32, rect.x1 as f32, rect.y1 as f32],
+                    [
+                        radii.top_left as f32,
+                        radii.top_right as f32,
+                        radii.bottom_right as f32,
+                        radii.bottom_left as f32,
+                    ],
+                )
+            })
+            .unwrap_or((0.0, [0.0, 0.0, 0.0, 0.0], [0.0, 0.0, 0.0, 0.0]));
         self.primitives.push(Primitive {
             translate,
             clip,
             clip_rect,
+            clip_radius,
+            clip_feather,
             ..Default::default()
         });
     }
 
+    /// Draw `image` into `dst_rect` like [`RenderContext::draw_image`],
+    /// multiplied by `tint`, which leaves it as it is when white, and faded
+    /// to `alpha`. The shader applies both, for hover and disabled states
+    /// without making new images.
+    pub fn draw_image_with(
+        &mut self,
+        image: &WgpuImage,
+        dst_rect: impl Into<Rect>,
+        interp: InterpolationMode,
+        tint: Option<&Color>,
+        alpha: f64,
+    ) {
+        let src = image.size().to_rect();
+        self.add_image(image, src, dst_rect.into(), interp, tint, alpha);
+    }
+
+    /// Draw `image` onto the convex quad `quad`, whose corners the top
+    /// left, top right, bottom right and bottom left of the image go to, in
+    /// perspective, e.g. for a page turn or a tilted preview. The quad is
+    /// drawn as a grid of triangles whose corners follow the perspective,
+    /// so the image doesn't bend along a diagonal.
+    pub fn draw_image_quad(&mut self, image: &WgpuImage, quad: [Point; 4]) {
+        let bbox = quad
+            .iter()
+            .skip(1)
+            .fold(Rect::from_points(quad[0], quad[0]), |bbox, p| {
+                bbox.union_pt(*p)
+            });
+        if !self.is_visible(bbox) {
+            return;
+        }
+        let project = match square_to_quad(quad) {
+            Some(project) => project,
+            None => return,
+        };
+        let start = self.geometry.indices.len();
+        let src = image.size().to_rect();
+        self.add_image_grid(image, src, IMAGE_TEX, [1.0; 4], IMAGE_QUAD_CELLS, project);
+        self.capture_draw(DrawKind::Image, bbox, start);
+    }
+
     pub fn draw_svg(&mut self, svg: &Svg, rect: Rect, override_color: Option<&Color>) {
-        let view_box = svg.tree.svg_node().view_box;
-        let view_rect = view_box.rect;
-        let scale =
-            (rect.width() / view_rect.width()).min(rect.height() / view_rect.height()) as f32;
-
-        let translate = [rect.x0 as f32, rect.y0 as f32];
-        let override_color = override_color.map(|c| format_color(c));
-        let svg_data = self.renderer.svg_store.get_svg_data(svg);
-        let transforms = svg_data.transforms.clone();
-        let offset = self.geometry.vertices.len() as u32;
+        self.draw_svg_batch(svg, &[(rect, override_color.cloned())]);
+    }
+
+    /// Draw `svg` into `rect` like [`draw_svg`], turned clockwise by `angle`
+    /// radians around its center, for spinners. The rotation is part of the
+    /// instance, so every angle shares the one tessellation of the svg.
+    ///
+    /// [`draw_svg`]: WgpuRenderContext::draw_svg
+    pub fn draw_svg_rotated(
+        &mut self,
+        svg: &Svg,
+        rect: Rect,
+        angle: f64,
+        override_color: Option<&Color>,
+    ) {
+        self.draw_svg_instances(svg, &[(rect, angle, override_color.cloned())]);
+    }
+
+    /// Draw `svg` into `rect` like [`draw_svg`], choosing how it is fitted
+    /// to the physical pixel grid.
+    ///
+    /// [`draw_svg`]: WgpuRenderContext::draw_svg
+    pub fn draw_svg_scaled(
+        &mut self,
+        svg: &Svg,
+        rect: Rect,
+        override_color: Option<&Color>,
+        scaling: SvgScaling,
+    ) {
+        let rect = match scaling {
+            SvgScaling::Smooth => rect,
+            SvgScaling::Crisp => self.crisp_svg_rect(svg, rect),
+        };
+        self.draw_svg(svg, rect, override_color);
+    }
 
-        let primitive_id = self.primitives.len() as u32;
-        for transform in transforms {
+    /// Move `rect` onto a physical pixel edge and shrink it to where `svg`
+    /// ends up covering whole physical pixels, at a whole number of pixels
+    /// per svg unit when it is close to one.
+    fn crisp_svg_rect(&self, svg: &Svg, rect: Rect) -> Rect {
+        let view_rect = svg.tree.svg_node().view_box.rect;
+        let grid = match PixelGrid::new(self.applied_transform(), self.renderer.surface.scale) {
+            Some(grid) if grid.scale.x.abs() == grid.scale.y.abs() => grid,
+            _ => return rect,
+        };
+        let scale = grid.scale.x.abs();
+        let mut pixels_per_unit =
+            (rect.width() / view_rect.width()).min(rect.height() / view_rect.height()) * scale;
+        if pixels_per_unit >= 1.0 && (pixels_per_unit - pixels_per_unit.round()).abs() < 0.125 {
+            pixels_per_unit = pixels_per_unit.round();
+        } else {
+            let width = (view_rect.width() * pixels_per_unit).round();
+            pixels_per_unit = width / view_rect.width();
+        }
+        let origin = Point::new(
+            snap_edge(rect.x0, grid.scale.x, grid.offset.x),
+            snap_edge(rect.y0, grid.scale.y, grid.offset.y),
+        );
+        Rect::from_origin_size(
+            origin,
+            (
+                view_rect.width() * pixels_per_unit / scale,
+                view_rect.height() * pixels_per_unit / scale,
+            ),
+        )
+    }
+
+    /// Draw `svg` into every rect of `instances`, optionally tinted. The
+    /// tessellated svg is looked up once, and instances of the same size
+    /// share their transform primitives.
+    pub fn draw_svg_batch(&mut self, svg: &Svg, instances: &[(Rect, Option<Color>)]) {
+        let instances: Vec<_> = instances
+            .iter()
+            .map(|(rect, color)| (*rect, 0.0, color.clone()))
+            .collect();
+        self.draw_svg_instances(svg, &instances);
+    }
+
+    /// Draw `svg` into every rect of `instances`, turned by its angle and
+    /// optionally tinted.
+    fn draw_svg_instances(&mut self, svg: &Svg, instances: &[(Rect, f64, Option<Color>)]) {
+        let view_rect = svg.tree.svg_node().view_box.rect;
+        let view_rect = Rect::new(
+            view_rect.x(),
+            view_rect.y(),
+            view_rect.right(),
+            view_rect.bottom(),
+        );
+        // The svg is scaled to fit the rect at its top left corner, and
+        // turned around the center of where it lands.
+        let placements: Vec<_> = instances
+            .iter()
+            .map(|(rect, angle, _)| {
+                let scale =
+                    (rect.width() / view_rect.width()).min(rect.height() / view_rect.height());
+                let center = view_rect.center().to_vec2() * scale;
+                let placement = Affine::translate(center)
+                    * Affine::rotate(*angle)
+                    * Affine::translate(-center)
+                    * Affine::scale(scale);
+                let bounds = (Affine::translate(rect.origin().to_vec2()) * placement)
+                    .transform_rect_bbox(view_rect);
+                (scale, *angle, placement, bounds)
+            })
+            .collect();
+        let instances: Vec<_> = instances
+            .iter()
+            .zip(placements)
+            .filter(|(_, (_, _, _, bounds))| self.is_visible(*bounds))
+            .collect();
+        if instances.is_empty() {
+            return;
+        }
+        let transforms = self
+            .renderer
+            .core
+            .state()
+            .svg_store
+            .get_svg_data(svg)
+            .map(|svg_data| svg_data.transforms.clone());
+        let transforms = match transforms {
+            Ok(transforms) => transforms,
+            Err(err) => {
+                self.svg_error = Some(err);
+                if self.renderer.svg_placeholders {
+                    let brush = Brush::Solid(Color::rgb8(255, 0, 255));
+                    for ((rect, _, _), _) in instances {
+                        self.fill(*rect, &brush);
+                    }
+                }
+                return;
+            }
+        };
+
+        let mut placed: Vec<(f64, f64, u32)> = Vec::new();
+        let mut primitive_ids = Vec::with_capacity(instances.len());
+        for (_, (scale, angle, placement, _)) in &instances {
+            let primitive_id = match placed.iter().find(|(s, a, _)| s == scale && a == angle) {
+                Some((_, _, primitive_id)) => *primitive_id,
+                None => {
+                    let primitive_id = self.primitives.len() as u32;
+                    for t in &transforms {
+                        let transform = *placement * Affine::new(t.map(f64::from));
+                        let c = transform.as_coeffs().map(|c| c as f32);
+                        self.add_primitive();
+                        let primitive = self.primitives.last_mut().unwrap();
+                        primitive.transform_1 = [c[0], c[1], c[2], c[3]];
+                        primitive.transform_2 = [c[4], c[5]];
+                    }
+                    placed.push((*scale, *angle, primitive_id));
+                    primitive_id
+                }
+            };
+            primitive_ids.push(primitive_id);
+        }
+        self.add_primitive();
+
+        let mut state = self.renderer.core.state();
+        let svg_data = match state.svg_store.get_svg_data(svg) {
+            Ok(svg_data) => svg_data,
+            Err(_) => return,
+        };
+        let geometry = &mut self.geometry;
+        geometry
+            .vertices
+            .reserve(svg_data.geometry.vertices.len() * instances.len());
+        geometry
+            .indices
+            .reserve(svg_data.geometry.indices.len() * instances.len());
+        for (((rect, _, color), _), primitive_id) in instances.iter().zip(primitive_ids) {
+            let translate = [rect.x0 as f32, rect.y0 as f32];
+            let color = color.as_ref().map(format_color);
+            let offset = geometry.vertices.len() as u32;
+            geometry
+                .vertices
+                .extend(svg_data.geometry.vertices.iter().map(|v| {
+                    let mut v = *v;
+                    v.translate = translate;
+                    v.primitive_id += primitive_id;
+                    if let Some(c) = color {
+                        v.color = c;
+                    }
+                    v
+                }));
+            geometry
+                .indices
+                .extend(svg_data.geometry.indices.iter().map(|i| *i + offset));
+        }
+        let transform = self.cur_transform;
+        if let Some(capture) = self.capture.as_mut() {
+            let triangles = svg_data.geometry.indices.len() / 3;
+            capture
+                .draws
+                .extend(instances.iter().map(|(_, (_, _, _, bounds))| CapturedDraw {
+                    kind: DrawKind::Triangles,
+                    bounds: transform.transform_rect_bbox(*bounds),
+                    triangles,
+                }));
+        }
+    }
+
+    /// Draw `layout` with the baseline of its first line at
+    /// `baseline_origin`, rather than its top left corner.
+    pub fn draw_text_at_baseline(&mut self, layout: &WgpuTextLayout, baseline_origin: Point) {
+        let baseline = layout.line_baseline(0).unwrap_or(0.0);
+        self.draw_text(layout, baseline_origin - Vec2::new(0.0, baseline));
+    }
+
+    /// Draw a grid of monospace cells, mapping each char straight to its
+    /// cached glyph instead of shaping the text into a layout. The line of
+    /// every glyph is centered vertically in its cell.
+    pub fn draw_text_grid(
+        &mut self,
+        cells: &[Cell],
+        font_family: &FontFamily,
+        font_size: f64,
+        cell_size: Size,
+        origin: Point,
+    ) {
+        let translate = [origin.x as f32, origin.y as f32];
+        let primitive_id = self.primitives.len() as u32 - 1;
+        for cell in cells {
+            if cell.ch.is_whitespace() {
+                continue;
+            }
+            let glyph_pos = match self.inner_text.get_glyph_pos(
+                cell.ch,
+                font_family.clone(),
+                font_size as f32,
+                cell.weight.into(),
+                None,
+            ) {
+                Ok(glyph_pos) => glyph_pos,
+                Err(_) => {
+                    if is_visible_char(cell.ch) {
+                        let cell_rect = Rect::from_origin_size(
+                            origin
+                                + Vec2::new(
+                                    cell.col as f64 * cell_size.width,
+                                    cell.row as f64 * cell_size.height,
+                                ),
+                            cell_size,
+                        );
+                        let inset = (cell_size.width * 0.1).round();
+                        let color = format_color(&cell.color);
+                        for side in missing_glyph_box(cell_rect.inset(-inset)) {
+                            self.add_quad(side, color, primitive_id);
+                        }
+                    }
+                    continue;
+                }
+            };
+            let cell_rect = Rect::from_origin_size(
+                (
+                    cell.col as f64 * cell_size.width,
+                    cell.row as f64 * cell_size.height,
+                ),
+                cell_size,
+            );
+            let rect = glyph_pos.rect.with_origin((
+                cell_rect.x0,
+                centered_line_top(cell_rect, &glyph_pos.metric),
+            ));
+            add_glyph_quad(
+                &mut self.geometry,
+                &rect,
+                &glyph_pos,
+                format_color(&cell.color),
+                translate,
+                primitive_id,
+            );
+        }
+    }
+
+    /// Draw `number` in `rect`, its line vertically centered and aligned
+    /// horizontally by `alignment`, e.g. for line numbers in a gutter. The
+    /// digits map straight to their cached glyphs, with every digit taking
+    /// the advance of `0` so that numbers line up in columns.
+    pub fn draw_aligned_number(
+        &mut self,
+        rect: Rect,
+        number: u64,
+        font_family: &FontFamily,
+        font_size: f64,
+        color: &Color,
+        alignment: TextAlignment,
+    ) {
+        let mut digits = [0u8; 20];
+        let mut len = 0;
+        let mut n = number;
+        loop {
+            digits[digits.len() - 1 - len] = b'0' + (n % 10) as u8;
+            len += 1;
+            n /= 10;
+            if n == 0 {
+                break;
+            }
+        }
+        let digits = &digits[digits.len() - len..];
+
+        let glyph = |text: &WgpuText, digit: u8| {
+            text.get_glyph_pos(
+                digit as char,
+                font_family.clone(),
+                font_size as f32,
+                FontWeight::REGULAR.into(),
+                None,
+            )
+        };
+        let zero = match glyph(&self.inner_text, b'0') {
+            Ok(zero) => zero,
+            Err(_) => return,
+        };
+        let advance = zero.width;
+        let width = advance * len as f64;
+        let x = match alignment {
+            TextAlignment::End => rect.x1 - width,
+            TextAlignment::Center => rect.x0 + (rect.width() - width) / 2.0,
+            _ => rect.x0,
+        };
+        let y = centered_line_top(rect, &zero.metric);
+
+        let color = format_color(color);
+        let primitive_id = self.primitives.len() as u32 - 1;
+        for (i, digit) in digits.iter().enumerate() {
+            let glyph_pos = match glyph(&self.inner_text, *digit) {
+                Ok(glyph_pos) => glyph_pos,
+                Err(_) => continue,
+            };
+            let offset = ((advance - glyph_pos.width) / 2.0).round();
+            let rect = glyph_pos
+                .rect
+                .with_origin((x + advance * i as f64 + offset, y));
+            add_glyph_quad(
+                &mut self.geometry,
+                &rect,
+                &glyph_pos,
+                color,
+                [0.0, 0.0],
+                primitive_id,
+            );
+        }
+    }
+
+    /// Draw the layer `name`, e.g. a tab bar or gutter that rarely
+    /// changes. The first time, and after
+    /// [`WgpuRenderer::invalidate_layer`], `draw` records it; otherwise the
+    /// geometry recorded then is drawn again without calling `draw`. The
+    /// recording keeps the transform and clip that were in effect.
+    pub fn layer(&mut self, name: &str, draw: impl FnOnce(&mut Self)) {
+        if let Some(layer) = self.renderer.layers.get(name) {
+            let primitive_base = self.primitives.len() as u32;
+            let vertex_base = self.geometry.vertices.len() as u32;
+            let index_base = self.geometry.indices.len() as u32;
+            self.primitives.extend_from_slice(&layer.primitives);
+            self.geometry
+                .vertices
+                .extend(layer.vertices.iter().map(|v| GpuVertex {
+                    primitive_id: v.primitive_id + primitive_base,
+                    ..*v
+                }));
+            self.geometry
+                .indices
+                .extend(layer.indices.iter().map(|i| i + vertex_base));
+            self.backdrops
+                .extend(layer.backdrops.iter().map(|backdrop| Backdrop {
+                    index: backdrop.index + index_base,
+                    ..backdrop.clone()
+                }));
+            self.quads.extend(layer.quads.iter().map(|quad| Quad {
+                indices: quad.indices.start + index_base..quad.indices.end + index_base,
+                ..quad.clone()
+            }));
             self.add_primitive();
-            let primitive = self.primitives.last_mut().unwrap();
-            primitive.transform_1[0] *= scale * transform[0];
-            primitive.transform_1[3] *= scale * transform[3];
-            primitive.transform_2[0] += scale * transform[4];
-            primitive.transform_2[1] += scale * transform[5];
+            return;
         }
+
+        // Start on a primitive of its own, so that the layer doesn't refer
+        // to primitives recorded before it.
         self.add_primitive();
+        let primitive_base = self.primitives.len() - 1;
+        let vertex_base = self.geometry.vertices.len();
+        let index_base = self.geometry.indices.len();
+        let backdrop_base = self.backdrops.len();
+        let quad_base = self.quads.len();
+        let recording_layer = std::mem::replace(&mut self.recording_layer, true);
+        draw(self);
+        self.recording_layer = recording_layer;
 
-        let svg_data = self.renderer.svg_store.get_svg_data(svg);
-        let mut vertices = svg_data
-            .geometry
+        let vertex_offset = vertex_base as u32;
+        let index_offset = index_base as u32;
+        let layer = RecordedLayer {
+            vertices: self.geometry.vertices[vertex_base..]
+                .iter()
+                .map(|v| GpuVertex {
+                    primitive_id: v.primitive_id - primitive_base as u32,
+                    ..*v
+                })
+                .collect(),
+            indices: self.geometry.indices[index_base..]
+                .iter()
+                .map(|i| i - vertex_offset)
+                .collect(),
+            primitives: self.primitives[primitive_base..].to_vec(),
+            backdrops: self.backdrops[backdrop_base..]
+                .iter()
+                .map(|backdrop| Backdrop {
+                    index: backdrop.index - index_offset,
+                    ..backdrop.clone()
+                })
+                .collect(),
+            quads: self.quads[quad_base..]
+                .iter()
+                .map(|quad| Quad {
+                    indices: quad.indices.start - index_offset..quad.indices.end - index_offset,
+                    ..quad.clone()
+                })
+                .collect(),
+        };
+        self.renderer.layers.insert(name.to_string(), layer);
+        self.add_primitive();
+    }
+
+    /// Blur everything drawn so far inside `rect` with a gaussian of
+    /// `radius`, e.g. to put a translucent panel on top of it.
+    pub fn backdrop_blur(&mut self, rect: Rect, radius: f64) {
+        let mut rect = rect + self.cur_translation();
+        if let Some(clip) = self.current_clip() {
+            rect = rect.intersect(clip.rect());
+        }
+        if radius <= 0.0 || rect.width() <= 0.0 || rect.height() <= 0.0 {
+            return;
+        }
+        self.backdrops.push(Backdrop {
+            index: self.geometry.indices.len() as u32,
+            rect,
+            radius,
+        });
+    }
+
+    /// Like `blurred_rect`, but the shadow follows a rect with rounded
+    /// corners of `radius`.
+    pub fn blurred_rounded_rect(
+        &mut self,
+        rect: Rect,
+        radius: f64,
+        blur_radius: f64,
+        brush: &impl IntoBrush<Self>,
+    ) {
+        self.add_blurred_rect(rect, radius, blur_radius, false, brush);
+    }
+
+    /// Draw a shadow on the inside of `rect`, fading from its edges towards
+    /// the center, e.g. for pressed states or scroll edge indicators.
+    pub fn blurred_rect_inset(
+        &mut self,
+        rect: Rect,
+        blur_radius: f64,
+        brush: &impl IntoBrush<Self>,
+    ) {
+        self.add_blurred_rect(rect, 0.0, blur_radius, true, brush);
+    }
+
+    fn add_blurred_rect(
+        &mut self,
+        rect: Rect,
+        radius: f64,
+        blur_radius: f64,
+        inset: bool,
+        brush: &impl IntoBrush<Self>,
+    ) {
+        // The blur is computed in the units of the rect, so it looks the
+        // same at every scale, except that one narrower than about half a
+        // physical pixel would leave a jagged edge at 1x while a 2x screen
+        // still shows it soft.
+        let scale = self.pixels_per_unit();
+        let blur_radius = if scale > 0.0 {
+            blur_radius.max(0.5 / scale)
+        } else {
+            blur_radius
+        };
+        let (rect, blur_rect) = if inset {
+            (rect, rect)
+        } else {
+            let rect = rect.inflate(3.0 * blur_radius, 3.0 * blur_radius);
+            (rect, rect.inflate(-3.0 * blur_radius, -3.0 * blur_radius))
+        };
+        if !self.is_visible(rect) {
+            return;
+        }
+        let index_start = self.geometry.indices.len();
+        let brush = brush.make_brush(self, || rect).into_owned();
+        let color = format_color(&brush.vertex_color());
+
+        self.add_primitive();
+        let primitive = self.primitives.last_mut().unwrap();
+        brush.apply(primitive);
+        primitive.blur_radius = blur_radius as f32;
+        primitive.blur_corner_radius = radius as f32;
+        primitive.blur_inset = if inset { 1.0 } else { 0.0 };
+        primitive.blur_rect = [
+            blur_rect.x0 as f32,
+            blur_rect.y0 as f32,
+            blur_rect.x1 as f32,
+            blur_rect.y1 as f32,
+        ];
+
+        let primitive_id = self.primitives.len() as u32 - 1;
+        let tolerance = self.tolerance() as f32;
+        let _ = self.fill_tess.tessellate_rectangle(
+            &lyon::geom::Rect::new(
+                lyon::geom::Point::new(rect.x0 as f32, rect.y0 as f32),
+                lyon::geom::Size::new(rect.width() as f32, rect.height() as f32),
+            ),
+            &FillOptions::tolerance(tolerance).with_fill_rule(tessellation::FillRule::NonZero),
+            &mut BuffersBuilder::new(&mut self.geometry, |vertex: FillVertex| GpuVertex {
+                pos: vertex.position().to_array(),
+                color,
+                primitive_id,
+                ..Default::default()
+            }),
+        );
+        self.add_primitive();
+        self.capture_draw(DrawKind::Triangles, rect, index_start);
+    }
+
+    /// Stroke a circular `arc` of `width`, with round caps. Circular arcs
+    /// are drawn analytically in the shader rather than tessellated.
+    pub fn stroke_arc(&mut self, arc: &Arc, brush: &impl IntoBrush<Self>, width: f64) {
+        if arc.radii.x != arc.radii.y {
+            let style = StrokeStyle::new().line_cap(LineCap::Round);
+            self.stroke_styled(*arc, brush, width, &style);
+            return;
+        }
+        let brush = brush.make_brush(self, || arc.bounding_box()).into_owned();
+        let (mut start, mut sweep) = (arc.start_angle + arc.x_rotation, arc.sweep_angle);
+        if sweep < 0.0 {
+            start += sweep;
+            sweep = -sweep;
+        }
+        let index_start = self.geometry.indices.len();
+        if let Some(primitive) = self.add_circle(
+            arc.center,
+            arc.radii.x,
+            width,
+            (start, sweep.min(std::f64::consts::TAU)),
+            &brush.vertex_color(),
+        ) {
+            brush.apply(primitive);
+        }
+        let bbox = arc.bounding_box().inflate(width / 2.0, width / 2.0);
+        self.capture_draw(DrawKind::Triangles, bbox, index_start);
+    }
+
+    /// Fill a ring of `width` along a circular `arc` with a conic gradient,
+    /// e.g. for a progress ring, or the whole circle when `width` is 0. The
+    /// `stops` go from the start of the arc at `0.0` to its end at `1.0`.
+    /// The gradient is computed per pixel in the shader, as a smooth sweep
+    /// would take far too many triangles to tessellate.
+    pub fn fill_arc_gradient(&mut self, arc: &Arc, width: f64, stops: &[GradientStop]) {
+        if stops.is_empty() {
+            return;
+        }
+        let bbox = arc.bounding_box().inflate(width / 2.0, width / 2.0);
+        if !self.is_visible(bbox) {
+            return;
+        }
+        let mut stops = stops.to_vec();
+        stops.sort_by(|a, b| a.pos.partial_cmp(&b.pos).unwrap_or(Ordering::Equal));
+        let index_start = self.geometry.indices.len();
+        if arc.radii.x != arc.radii.y {
+            self.stroke_along(*arc, width, |t| gradient_color_at(&stops, t));
+            self.capture_draw(DrawKind::Triangles, bbox, index_start);
+            return;
+        }
+        let (mut start, mut sweep) = (arc.start_angle + arc.x_rotation, arc.sweep_angle);
+        if sweep < 0.0 {
+            start += sweep;
+            sweep = -sweep;
+        }
+        let sweep = sweep.min(std::f64::consts::TAU);
+
+        // Every pair of neighbouring stops is drawn over the whole ring,
+        // with the shader discarding what lies outside of them. The first
+        // and the last extend over the caps.
+        let segments = (stops.len() - 1).max(1);
+        for i in 0..segments {
+            let from = &stops[i];
+            let to = stops.get(i + 1).unwrap_or(from);
+            let end_color = format_color(&to.color);
+            let window = [
+                if i == 0 { f32::MIN } else { from.pos },
+                if i + 1 == segments { f32::MAX } else { to.pos },
+            ];
+            if let Some(primitive) =
+                self.add_circle(arc.center, arc.radii.x, width, (start, sweep), &from.color)
+            {
+                primitive.conic = [window[0], window[1], from.pos, to.pos];
+                primitive.conic_color = end_color;
+            }
+        }
+        self.capture_draw(DrawKind::Triangles, bbox, index_start);
+    }
+
+    /// Stroke `shape` with a color that changes along it, e.g. for a
+    /// progress ring or graph edges. `color_at` maps the distance along the
+    /// path, from `0.0` at its start to `1.0` at its end, to a color, which
+    /// is interpolated between the vertices of the stroke.
+    pub fn stroke_along(&mut self, shape: impl Shape, width: f64, color_at: impl Fn(f64) -> Color) {
+        let tolerance = self.tolerance() as f32;
+        let primitive_id = self.primitives.len() as u32 - 1;
+        let path = lyon_path(shape.path_elements(tolerance as f64));
+        let mut stroke: VertexBuffers<([f32; 2], f32), u32> = VertexBuffers::new();
+        let _ = self.stroke_tess.tessellate_path(
+            &path,
+            &StrokeOptions::tolerance(tolerance)
+                .with_line_width(width as f32)
+                .with_line_cap(tessellation::LineCap::Round)
+                .with_line_join(tessellation::LineJoin::Round),
+            &mut BuffersBuilder::new(&mut stroke, |vertex: StrokeVertex| {
+                let mut pos = vertex.position_on_path().to_array();
+                let normal = vertex.normal().to_array();
+                pos[0] += normal[0] * width as f32 / 2.0;
+                pos[1] += normal[1] * width as f32 / 2.0;
+                (pos, vertex.advancement())
+            }),
+        );
+
+        let length = stroke
             .vertices
             .iter()
-            .map(|v| {
-                let mut v = v.clone();
-                v.translate = translate;
-                v.primitive_id = primitive_id + v.primitive_id;
-                if let Some(c) = override_color.clone() {
-                    v.color = c;
+            .fold(0.0f32, |length, (_, advancement)| length.max(*advancement));
+        let offset = self.geometry.vertices.len() as u32;
+        self.geometry
+            .vertices
+            .extend(stroke.vertices.iter().map(|(pos, advancement)| {
+                let t = if length > 0.0 {
+                    (advancement / length).clamp(0.0, 1.0)
+                } else {
+                    0.0
+                };
+                GpuVertex {
+                    pos: *pos,
+                    color: format_color(&color_at(t as f64)),
+                    primitive_id,
+                    ..Default::default()
                 }
-                v
-            })
-            .collect();
-        let mut indices = svg_data
-            .geometry
+            }));
+        let index_start = self.geometry.indices.len();
+        self.geometry
             .indices
-            .iter()
-            .map(|i| *i + offset)
-            .collect();
-        self.geometry.vertices.append(&mut vertices);
-        self.geometry.indices.append(&mut indices);
+            .extend(stroke.indices.iter().map(|i| *i + offset));
+        self.add_fringe(offset as usize, index_start);
     }
-}
 
-#[derive(Clone)]
-pub enum Brush {
-    Solid(Color),
-}
+    /// Draw lines through `points` as cheaply as possible, e.g. for debug
+    /// overlays or graphs with many thousands of edges. Each segment is a
+    /// single quad whose coverage the shader computes, like a hairline,
+    /// with no tessellation, pixel snapping, joins or caps. Meant for widths
+    /// up to a physical pixel: wider lines show notches at their corners.
+    pub fn draw_polyline_fast(&mut self, points: &[Point], color: &Color, width: f64) {
+        let bbox = match points.split_first() {
+            Some((first, rest)) => rest
+                .iter()
+                .fold(Rect::from_points(*first, *first), |bbox, p| {
+                    bbox.union_pt(*p)
+                })
+                .inflate(width, width),
+            None => return,
+        };
+        if !self.is_visible(bbox) {
+            return;
+        }
+        let index_start = self.geometry.indices.len();
+        let color = format_color(color);
+        for segment in points.windows(2) {
+            let line = Line::new(segment[0], segment[1]);
+            self.add_hairline(line, width, LineCap::Butt, color);
+        }
+        self.capture_draw(DrawKind::Triangles, bbox, index_start);
+    }
 
-pub struct WgpuImage {}
+    /// Outline `rounded_rect` with dashes, e.g. to show keyboard focus.
+    /// Animating `style.phase` moves the dashes along the outline without
+    /// any new geometry. All corners use the largest radius of the rect.
+    pub fn draw_focus_ring(
+        &mut self,
+        rounded_rect: RoundedRect,
+        style: &FocusRingStyle,
+        brush: &impl IntoBrush<Self>,
+    ) {
+        let rect = rounded_rect.rect();
+        let brush = brush.make_brush(self, || rect).into_owned();
+        let color = format_color(&brush.vertex_color());
+        if style.width <= 0.0 {
+            return;
+        }
+        let radii = rounded_rect.radii();
+        let radius = radii
+            .top_left
+            .max(radii.top_right)
+            .max(radii.bottom_right)
+            .max(radii.bottom_left);
+        let (dash, gap) = if style.dash > 0.0 {
+            (style.dash, style.gap.max(0.0))
+        } else {
+            (1.0, 0.0)
+        };
 
-impl<'a> RenderContext for WgpuRenderContext<'a> {
-    type Brush = Brush;
-    type Text = WgpuText;
-    type TextLayout = WgpuTextLayout;
-    type Image = WgpuImage;
+        self.add_primitive();
+        let primitive = self.primitives.last_mut().unwrap();
+        brush.apply(primitive);
+        primitive.focus_rect = [
+            rect.x0 as f32,
+            rect.y0 as f32,
+            rect.x1 as f32,
+            rect.y1 as f32,
+        ];
+        primitive.focus = [radius as f32, style.width as f32, dash as f32, gap as f32];
+        primitive.focus_phase = style.phase as f32;
+        let primitive_id = self.primitives.len() as u32 - 1;
 
-    fn status(&mut self) -> Result<(), piet::Error> {
-        todo!()
+        // One more pixel around the edge for the antialiasing ramp.
+        let extent = style.width / 2.0 + 1.0 / self.renderer.surface.scale;
+        self.add_quad(rect.inflate(extent, extent), color, primitive_id);
+        self.add_primitive();
     }
 
-    fn solid_brush(&mut self, color: Color) -> Self::Brush {
-        Brush::Solid(color)
+    /// Draw a text caret in the char cell `cell`, snapped to physical
+    /// pixels. `phase` is the position in the blink cycle from 0 to 1; the
+    /// caret shows during the first half.
+    pub fn draw_caret(&mut self, cell: Rect, shape: CaretShape, color: &Color, phase: f64) {
+        if phase.rem_euclid(1.0) >= 0.5 {
+            return;
+        }
+        let rect = match shape {
+            CaretShape::Bar(width) => Rect::new(cell.x0, cell.y0, cell.x0 + width, cell.y1),
+            CaretShape::Block => cell,
+            CaretShape::Underline(height) => Rect::new(cell.x0, cell.y1 - height, cell.x1, cell.y1),
+        };
+        let mut rect = self.snap_fill_rect(rect);
+        // Thin carets keep at least one physical pixel after snapping.
+        let pixel = 1.0 / self.renderer.surface.scale;
+        if rect.width() < pixel {
+            rect.x1 = rect.x0 + pixel;
+        }
+        if rect.height() < pixel {
+            rect.y0 = rect.y1 - pixel;
+        }
+        let primitive_id = self.primitives.len() as u32 - 1;
+        self.add_quad(rect, format_color(color), primitive_id);
     }
 
-    fn gradient(
+    /// Fill `rect` with a gradient from `start` to `end` along `axis`, like
+    /// the shadow at the edge of a scrolled view. Two stops along an axis
+    /// need nothing but the vertex colors of a quad, so this is much
+    /// cheaper than a gradient brush.
+    pub fn fill_rect_gradient(
+        &mut self,
+        rect: Rect,
+        start: &Color,
+        end: &Color,
+        axis: GradientAxis,
+    ) {
+        if !self.is_visible(rect) {
+            return;
+        }
+        let index_start = self.geometry.indices.len();
+        let rect = self.snap_fill_rect(rect);
+        let start = format_color(start);
+        let end = format_color(end);
+        let colors = match axis {
+            GradientAxis::Horizontal => [start, start, end, end],
+            GradientAxis::Vertical => [start, end, end, start],
+        };
+        let primitive_id = self.primitives.len() as u32 - 1;
+        self.add_quad_colors(rect, colors, primitive_id);
+        self.add_fill_quad(index_start as u32, rect, start[3] >= 1.0 && end[3] >= 1.0);
+        self.capture_draw(DrawKind::Quad, rect, index_start);
+    }
+
+    /// Make a gradient brush like [`RenderContext::gradient`], with its
+    /// stops blended in `space` rather than in sRGB. The blended colors are
+    /// baked into the image atlas once, here, and identical gradients share
+    /// them.
+    pub fn gradient_in(
         &mut self,
         gradient: impl Into<piet::FixedGradient>,
-    ) -> Result<Self::Brush, piet::Error> {
-        todo!()
+        space: GradientSpace,
+    ) -> Result<Brush, piet::Error> {
+        let gradient = gradient.into();
+        let stops = sorted_stops(&gradient).ok_or(piet::Error::InvalidInput)?;
+        let lut = bake_lut(&stops, space);
+        let core = &self.renderer.core;
+        core.ensure_encoder();
+        let mut encoder = core.encoder.borrow_mut();
+        let lut = core.state().images.insert(
+            &core.device,
+            encoder.as_mut().unwrap(),
+            GRADIENT_LUT_WIDTH,
+            1,
+            &lut,
+            piet::ImageFormat::RgbaSeparate,
+        )?;
+        Ok(Brush::Gradient(GradientBrush::new(
+            gradient, lut, stops, space,
+        )))
     }
 
-    fn clear(&mut self, region: impl Into<Option<Rect>>, color: Color) {}
+    /// Color what is drawn until [`Self::end_brush`] with `brush`'s
+    /// gradient, if it has one, through a primitive of its own.
+    fn begin_brush(&mut self, brush: &Brush) {
+        if let Brush::Gradient(gradient) = brush {
+            self.add_primitive();
+            gradient.apply(self.primitives.last_mut().unwrap());
+        }
+    }
 
-    fn stroke(&mut self, shape: impl Shape, brush: &impl piet::IntoBrush<Self>, width: f64) {
+    fn end_brush(&mut self, brush: &Brush) {
+        if let Brush::Gradient(_) = brush {
+            self.add_primitive();
+        }
+    }
+
+    /// Remember the quad filling `rect` with the indices from `start` on,
+    /// for culling it when an opaque quad covers it later.
+    fn add_fill_quad(&mut self, start: u32, rect: Rect, opaque: bool) {
+        let indices = start..self.geometry.indices.len() as u32;
+        let mut quad = Quad::new(indices, rect, self.applied_transform(), opaque);
+        if let Some(clip) = self.current_clip() {
+            quad.rect = quad.rect.intersect(clip.rect());
+            quad.opaque &= clip.radii().as_single_radius() == Some(0.0);
+        }
+        if let Some((_, feather)) = self.clip_stack.last() {
+            quad.opaque &= *feather == 0.0;
+        }
+        self.quads.push(quad);
+    }
+
+    fn add_quad(&mut self, rect: Rect, color: [f32; 4], primitive_id: u32) {
+        self.add_quad_colors(rect, [color; 4], primitive_id);
+    }
+
+    /// Add a quad with its own color at every corner, starting at the top
+    /// left and going counterclockwise.
+    fn add_quad_colors(&mut self, rect: Rect, colors: [[f32; 4]; 4], primitive_id: u32) {
+        let offset = self.geometry.vertices.len() as u32;
+        let corners = [
+            (rect.x0, rect.y0),
+            (rect.x0, rect.y1),
+            (rect.x1, rect.y1),
+            (rect.x1, rect.y0),
+        ];
+        for (&(x, y), &color) in corners.iter().zip(colors.iter()) {
+            self.geometry.vertices.push(GpuVertex {
+                pos: [x as f32, y as f32],
+                color,
+                primitive_id,
+                ..Default::default()
+            });
+        }
+        self.geometry
+            .indices
+            .extend([0, 1, 2, 0, 2, 3].iter().map(|i| i + offset));
+    }
+
+    /// Draw the `src` area of `image`, in its pixels, into `dst`, tinted
+    /// with `tint` and faded to `alpha`.
+    fn add_image(
+        &mut self,
+        image: &WgpuImage,
+        src: Rect,
+        dst: Rect,
+        interp: InterpolationMode,
+        tint: Option<&Color>,
+        alpha: f64,
+    ) {
+        let src = src.intersect(image.size().to_rect());
+        if src.area() == 0.0 || dst.area() == 0.0 || !self.is_visible(dst) {
+            return;
+        }
+        let mut color = tint.map(format_color).unwrap_or([1.0; 4]);
+        color[3] *= alpha.clamp(0.0, 1.0) as f32;
+        if color[3] == 0.0 {
+            return;
+        }
+        let tex = match interp {
+            InterpolationMode::Bilinear => IMAGE_TEX,
+            InterpolationMode::NearestNeighbor => IMAGE_NEAREST_TEX,
+        };
+        let start = self.geometry.indices.len();
+        self.add_image_grid(image, src, tex, color, 1, |u, v| {
+            Point::new(dst.x0 + u * dst.width(), dst.y0 + v * dst.height())
+        });
+        self.capture_draw(DrawKind::Image, dst, start);
+    }
+
+    /// Add the `src` area of `image` as a grid of `cells` by `cells` quads,
+    /// with the point of the image at `u`, `v`, from 0 to 1 across it,
+    /// going to `point(u, v)`.
+    fn add_image_grid(
+        &mut self,
+        image: &WgpuImage,
+        src: Rect,
+        tex: f32,
+        color: [f32; 4],
+        cells: u32,
+        point: impl Fn(f64, f64) -> Point,
+    ) {
+        // Images are placed in the atlas in texels.
+        let rect = image.rect();
+        let src = src + Vec2::new(rect.x as f64, rect.y as f64);
+        let tex = if image.gray {
+            tex + GRAY_IMAGE_TEX_OFFSET
+        } else {
+            tex
+        };
+        let primitive_id = self.primitives.len() as u32 - 1;
+        let offset = self.geometry.vertices.len() as u32;
+        for j in 0..=cells {
+            let v = j as f64 / cells as f64;
+            for i in 0..=cells {
+                let u = i as f64 / cells as f64;
+                let pos = point(u, v);
+                self.geometry.vertices.push(GpuVertex {
+                    pos: [pos.x as f32, pos.y as f32],
+                    color,
+                    tex,
+                    tex_pos: [
+                        (src.x0 + u * src.width()) as f32,
+                        (src.y0 + v * src.height()) as f32,
+                    ],
+                    primitive_id,
+                    ..Default::default()
+                });
+            }
+        }
+        let row = cells + 1;
+        for j in 0..cells {
+            for i in 0..cells {
+                let top_left = offset + j * row + i;
+                let bottom_left = top_left + row;
+                self.geometry.indices.extend_from_slice(&[
+                    top_left,
+                    bottom_left,
+                    bottom_left + 1,
+                    top_left,
+                    bottom_left + 1,
+                    top_left + 1,
+                ]);
+            }
+        }
+    }
+
+    /// Draw a quad covering `center` and `radius` with a primitive that has
+    /// the shader compute the coverage of the disc, or of the ring of
+    /// `stroke_width` when it isn't 0.
+    fn add_circle(
+        &mut self,
+        center: Point,
+        radius: f64,
+        stroke_width: f64,
+        arc: (f64, f64),
+        color: &Color,
+    ) -> Option<&mut Primitive> {
+        if radius <= 0.0 {
+            return None;
+        }
+        let color = format_color(color);
+        self.add_primitive();
+        let primitive = self.primitives.last_mut().unwrap();
+        primitive.circle = [
+            center.x as f32,
+            center.y as f32,
+            radius as f32,
+            stroke_width as f32,
+        ];
+        primitive.arc = [arc.0 as f32, arc.1 as f32];
+        let primitive_id = self.primitives.len() as u32 - 1;
+
+        // One more pixel around the edge for the antialiasing ramp.
+        let extent = radius + stroke_width / 2.0 + 1.0 / self.renderer.surface.scale;
+        let rect = Rect::from_center_size(center, Size::new(extent * 2.0, extent * 2.0));
+        self.add_quad(rect, color, primitive_id);
+        self.add_primitive();
+        let circle = self.primitives.len() - 2;
+        Some(&mut self.primitives[circle])
+    }
+
+    /// Draw a line no wider than a physical pixel as a quad a pixel wider
+    /// on both sides, with the shader computing how much of every pixel the
+    /// line covers across its width. Round caps are drawn square, there is
+    /// no telling them apart at this size.
+    fn add_hairline(&mut self, line: Line, width: f64, cap: LineCap, color: [f32; 4]) {
+        let length = line.length();
+        if length == 0.0 {
+            return;
+        }
+        let dir = (line.p1 - line.p0) / length;
+        let normal = Vec2::new(-dir.y, dir.x);
+        let half_width = width / 2.0;
+        let extent = half_width + 1.0 / self.renderer.surface.scale;
+        let cap = match cap {
+            LineCap::Butt => Vec2::ZERO,
+            LineCap::Round | LineCap::Square => dir * half_width,
+        };
+        let primitive_id = self.primitives.len() as u32 - 1;
+        let offset = self.geometry.vertices.len() as u32;
+        for (p, side) in [
+            (line.p0 - cap, -extent),
+            (line.p0 - cap, extent),
+            (line.p1 + cap, extent),
+            (line.p1 + cap, -extent),
+        ] {
+            let pos = p + normal * side;
+            self.geometry.vertices.push(GpuVertex {
+                pos: [pos.x as f32, pos.y as f32],
+                color,
+                tex: -1.0,
+                tex_pos: [side as f32, half_width as f32],
+                primitive_id,
+                ..Default::default()
+            });
+        }
+        self.geometry
+            .indices
+            .extend([0, 1, 2, 0, 2, 3].iter().map(|i| i + offset));
+    }
+
+    /// Stroke `shape` without culling it.
+    fn add_stroke(
+        &mut self,
+        shape: impl Shape,
+        brush: &impl IntoBrush<Self>,
+        width: f64,
+        style: &StrokeStyle,
+    ) {
         let brush = brush.make_brush(self, || shape.bounding_box()).into_owned();
-        let Brush::Solid(color) = brush;
-        let color = format_color(&color);
+        let solid = brush.vertex_color();
+        let color = format_color(&solid);
+        // The fast paths for circles and rects draw them solid.
+        let dashed = !style.dash_pattern.is_empty();
+        if let Some(circle) = shape.as_circle().filter(|_| !dashed) {
+            if let Some(primitive) =
+                self.add_circle(circle.center, circle.radius, width, (0.0, 0.0), &solid)
+            {
+                brush.apply(primitive);
+            }
+            return;
+        }
+
+        self.begin_brush(&brush);
         // let affine = self.cur_transform.as_coeffs();
         // let translate = [affine[4] as f32, affine[5] as f32];
         let primitive_id = self.primitives.len() as u32 - 1;
+        let tolerance = self.tolerance();
+        let vertex_start = self.geometry.vertices.len();
+        let index_start = self.geometry.indices.len();
 
-        if let Some(rect) = shape.as_rect() {
+        if let Some(rect) = shape.as_rect().filter(|_| !dashed) {
+            let (rect, width) = self.snap_stroke_rect(rect, width);
             self.stroke_tess.tessellate_rectangle(
                 &lyon::geom::Rect::new(
                     lyon::geom::Point::new(rect.x0 as f32, rect.y0 as f32),
                     lyon::geom::Size::new(rect.width() as f32, rect.height() as f32),
                 ),
-                &StrokeOptions::tolerance(0.02)
-                    .with_line_width(width as f32)
-                    .with_line_cap(tessellation::LineCap::Round)
-                    .with_line_join(tessellation::LineJoin::Round),
+                &stroke_options(tolerance as f32, width as f32, style),
                 &mut BuffersBuilder::new(&mut self.geometry, |vertex: StrokeVertex| {
                     let mut pos = vertex.position_on_path().to_array();
                     let normal = vertex.normal().to_array();
@@ -194,71 +1550,23 @@ impl<'a> RenderContext for WgpuRenderContext<'a> {
                 }),
             );
         } else if let Some(line) = shape.as_line() {
-            let mut builder = lyon::path::Path::builder();
-            builder.begin(lyon::geom::point(line.p0.x as f32, line.p0.y as f32));
-            builder.line_to(lyon::geom::point(line.p1.x as f32, line.p1.y as f32));
-            builder.close();
-            let path = builder.build();
-            self.stroke_tess.tessellate_path(
-                &path,
-                &StrokeOptions::tolerance(0.02)
-                    .with_line_width(width as f32)
-                    .with_line_cap(tessellation::LineCap::Round)
-                    .with_line_join(tessellation::LineJoin::Round),
-                &mut BuffersBuilder::new(&mut self.geometry, |vertex: StrokeVertex| {
-                    let mut pos = vertex.position_on_path().to_array();
-                    let normal = vertex.normal().to_array();
-                    pos[0] += normal[0] * width as f32 / 2.0;
-                    pos[1] += normal[1] * width as f32 / 2.0;
-                    GpuVertex {
-                        pos,
-                        color,
-                        primitive_id,
-                        ..Default::default()
-                    }
-                }),
-            );
-        } else {
-            let mut builder = lyon::path::Path::builder();
-            let mut in_subpath = false;
-            for el in shape.path_elements(0.01) {
-                match el {
-                    piet::kurbo::PathEl::MoveTo(p) => {
-                        builder.begin(lyon::geom::point(p.x as f32, p.y as f32));
-                        in_subpath = true;
-                    }
-                    piet::kurbo::PathEl::LineTo(p) => {
-                        builder.line_to(lyon::geom::point(p.x as f32, p.y as f32));
-                    }
-                    piet::kurbo::PathEl::QuadTo(ctrl, to) => {
-                        builder.quadratic_bezier_to(
-                            lyon::geom::point(ctrl.x as f32, ctrl.y as f32),
-                            lyon::geom::point(to.x as f32, to.y as f32),
-                        );
-                    }
-                    piet::kurbo::PathEl::CurveTo(c1, c2, p) => {
-                        builder.cubic_bezier_to(
-                            lyon::geom::point(c1.x as f32, c1.y as f32),
-                            lyon::geom::point(c2.x as f32, c2.y as f32),
-                            lyon::geom::point(p.x as f32, p.y as f32),
-                        );
-                    }
-                    piet::kurbo::PathEl::ClosePath => {
-                        in_subpath = false;
-                        builder.close();
-                    }
-                }
+            let (line, width) = self.snap_stroke_line(line, width);
+            // Hairlines are solid, so dashed ones are tessellated.
+            if !dashed && width * self.renderer.surface.scale <= 1.0 {
+                self.add_hairline(line, width, style.line_cap, color);
+                self.end_brush(&brush);
+                return;
             }
-            if in_subpath {
+            let mut builder = lyon::path::Path::builder();
+            for dash in dash_line(line, &style.dash_pattern, style.dash_offset) {
+                builder.begin(lyon::geom::point(dash.p0.x as f32, dash.p0.y as f32));
+                builder.line_to(lyon::geom::point(dash.p1.x as f32, dash.p1.y as f32));
                 builder.end(false);
             }
             let path = builder.build();
             self.stroke_tess.tessellate_path(
                 &path,
-                &StrokeOptions::tolerance(0.02)
-                    .with_line_width(width as f32)
-                    .with_line_cap(tessellation::LineCap::Round)
-                    .with_line_join(tessellation::LineJoin::Round),
+                &stroke_options(tolerance as f32, width as f32, style),
                 &mut BuffersBuilder::new(&mut self.geometry, |vertex: StrokeVertex| {
                     let mut pos = vertex.position_on_path().to_array();
                     let normal = vertex.normal().to_array();
@@ -272,7 +1580,179 @@ impl<'a> RenderContext for WgpuRenderContext<'a> {
                     }
                 }),
             );
+        } else {
+            let mut elements: Vec<PathEl> = shape.path_elements(tolerance).collect();
+            if dashed {
+                elements = dash_path(&elements, &style.dash_pattern, style.dash_offset, tolerance);
+            }
+            let tolerance = tolerance as f32;
+            let key = PathCacheKey::stroke(&elements, tolerance, width as f32, style);
+            let stroke_tess = &mut self.stroke_tess;
+            let mut state = self.renderer.core.state();
+            let path = state.path_cache.get_or_insert_with(key, || {
+                let path = lyon_path(elements);
+                let mut geometry = PathGeometry::new();
+                let _ = stroke_tess.tessellate_path(
+                    &path,
+                    &stroke_options(tolerance, width as f32, style),
+                    &mut BuffersBuilder::new(&mut geometry, |vertex: StrokeVertex| {
+                        let mut pos = vertex.position_on_path().to_array();
+                        let normal = vertex.normal().to_array();
+                        pos[0] += normal[0] * width as f32 / 2.0;
+                        pos[1] += normal[1] * width as f32 / 2.0;
+                        pos
+                    }),
+                );
+                geometry
+            });
+            append_path(&mut self.geometry, path, color, primitive_id);
+        }
+        self.add_fringe(vertex_start, index_start);
+        self.end_brush(&brush);
+    }
+}
+
+impl<'a> Drop for WgpuRenderContext<'a> {
+    fn drop(&mut self) {
+        self.renderer.frame_buffers = Some(FrameBuffers {
+            fill_tess: std::mem::replace(&mut self.fill_tess, FillTessellator::new()),
+            stroke_tess: std::mem::replace(&mut self.stroke_tess, StrokeTessellator::new()),
+            geometry: std::mem::replace(&mut self.geometry, VertexBuffers::new()),
+            state_stack: std::mem::take(&mut self.state_stack),
+            clip_stack: std::mem::take(&mut self.clip_stack),
+            primitives: std::mem::take(&mut self.primitives),
+            backdrops: std::mem::take(&mut self.backdrops),
+            quads: std::mem::take(&mut self.quads),
+        });
+    }
+}
+
+/// How [`WgpuRenderContext::draw_focus_ring`] dashes its outline, in
+/// logical pixels.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FocusRingStyle {
+    pub width: f64,
+    /// The length of each dash; 0 draws a solid outline.
+    pub dash: f64,
+    pub gap: f64,
+    /// How far the dashes are shifted along the outline.
+    pub phase: f64,
+}
+
+impl Default for FocusRingStyle {
+    fn default() -> Self {
+        Self {
+            width: 2.0,
+            dash: 4.0,
+            gap: 2.0,
+            phase: 0.0,
+        }
+    }
+}
+
+/// How [`WgpuRenderContext::draw_svg_scaled`] fits an svg to the physical
+/// pixel grid.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SvgScaling {
+    /// Exactly into the given rect, wherever its edges fall.
+    Smooth,
+    /// Onto whole physical pixels, for icons drawn at 100% or 200% to keep
+    /// their straight edges sharp instead of smeared over two pixels.
+    Crisp,
+}
+
+/// The direction of a [`WgpuRenderContext::fill_rect_gradient`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GradientAxis {
+    /// From the left edge to the right.
+    Horizontal,
+    /// From the top edge to the bottom.
+    Vertical,
+}
+
+/// The shape of a caret drawn with [`WgpuRenderContext::draw_caret`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CaretShape {
+    /// A vertical bar of the given width at the start of the cell.
+    Bar(f64),
+    /// The whole cell.
+    Block,
+    /// A horizontal bar of the given height at the bottom of the cell.
+    Underline(f64),
+}
+
+#[derive(Clone)]
+pub enum Brush {
+    Solid(Color),
+    Gradient(GradientBrush),
+}
+
+impl Brush {
+    /// The color vertices are drawn with: a solid color, or white for a
+    /// gradient, which the shader multiplies by the gradient's colors.
+    pub(crate) fn vertex_color(&self) -> Color {
+        match self {
+            Brush::Solid(color) => color.clone(),
+            Brush::Gradient(_) => Color::WHITE,
+        }
+    }
+
+    /// A solid color standing in for the brush where gradients can't be
+    /// drawn.
+    #[cfg(feature = "svg-export")]
+    pub(crate) fn solid_color(&self) -> Color {
+        match self {
+            Brush::Solid(color) => color.clone(),
+            Brush::Gradient(gradient) => gradient.color_at(0.5),
+        }
+    }
+
+    pub(crate) fn is_opaque(&self) -> bool {
+        match self {
+            Brush::Solid(color) => color.as_rgba().3 >= 1.0,
+            Brush::Gradient(gradient) => gradient.is_opaque(),
+        }
+    }
+
+    /// Have `primitive` draw with this brush's gradient, if it has one.
+    pub(crate) fn apply(&self, primitive: &mut Primitive) {
+        if let Brush::Gradient(gradient) = self {
+            gradient.apply(primitive);
+        }
+    }
+}
+
+impl<'a> RenderContext for WgpuRenderContext<'a> {
+    type Brush = Brush;
+    type Text = WgpuText;
+    type TextLayout = WgpuTextLayout;
+    type Image = WgpuImage;
+
+    fn status(&mut self) -> Result<(), piet::Error> {
+        if std::mem::take(&mut self.unbalanced_saves) > 0 {
+            return Err(piet::Error::StackUnbalance);
         }
+        if let Some(err) = self.svg_error.take() {
+            return Err(piet::Error::BackendError(Box::new(err)));
+        }
+        Ok(())
+    }
+
+    fn solid_brush(&mut self, color: Color) -> Self::Brush {
+        Brush::Solid(color)
+    }
+
+    fn gradient(
+        &mut self,
+        gradient: impl Into<piet::FixedGradient>,
+    ) -> Result<Self::Brush, piet::Error> {
+        self.gradient_in(gradient, GradientSpace::default())
+    }
+
+    fn clear(&mut self, region: impl Into<Option<Rect>>, color: Color) {}
+
+    fn stroke(&mut self, shape: impl Shape, brush: &impl piet::IntoBrush<Self>, width: f64) {
+        self.stroke_styled(shape, brush, width, &StrokeStyle::new());
     }
 
     fn stroke_styled(
@@ -282,20 +1762,49 @@ impl<'a> RenderContext for WgpuRenderContext<'a> {
         width: f64,
         style: &piet::StrokeStyle,
     ) {
+        let join_extent = match style.line_join {
+            LineJoin::Miter { limit } => limit.max(1.0),
+            LineJoin::Round | LineJoin::Bevel => 1.0,
+        };
+        let extent = width / 2.0 * join_extent;
+        let bbox = shape.bounding_box().inflate(extent, extent);
+        if !self.is_visible(bbox) {
+            return;
+        }
+        let start = self.geometry.indices.len();
+        self.add_stroke(shape, brush, width, style);
+        self.capture_draw(DrawKind::Triangles, bbox, start);
     }
 
     fn fill(&mut self, shape: impl piet::kurbo::Shape, brush: &impl piet::IntoBrush<Self>) {
-        if let Some(rect) = shape.as_rect() {
+        let bbox = shape.bounding_box();
+        if !self.is_visible(bbox) {
+            return;
+        }
+        let index_start = self.geometry.indices.len();
+        if let Some(circle) = shape.as_circle() {
             let brush = brush.make_brush(self, || shape.bounding_box()).into_owned();
-            let Brush::Solid(color) = brush;
-            let color = format_color(&color);
+            let color = brush.vertex_color();
+            if let Some(primitive) =
+                self.add_circle(circle.center, circle.radius, 0.0, (0.0, 0.0), &color)
+            {
+                brush.apply(primitive);
+            }
+        } else if let Some(rect) = shape.as_rect() {
+            let rect = self.snap_fill_rect(rect);
+            let brush = brush.make_brush(self, || shape.bounding_box()).into_owned();
+            let color = format_color(&brush.vertex_color());
+            self.begin_brush(&brush);
             let primitive_id = self.primitives.len() as u32 - 1;
-            self.fill_tess.tessellate_rectangle(
+            let tolerance = self.tolerance() as f32;
+            let vertex_start = self.geometry.vertices.len();
+            let start = self.geometry.indices.len() as u32;
+            let _ = self.fill_tess.tessellate_rectangle(
                 &lyon::geom::Rect::new(
                     lyon::geom::Point::new(rect.x0 as f32, rect.y0 as f32),
                     lyon::geom::Size::new(rect.width() as f32, rect.height() as f32),
                 ),
-                &FillOptions::tolerance(0.02).with_fill_rule(tessellation::FillRule::NonZero),
+                &FillOptions::tolerance(tolerance).with_fill_rule(tessellation::FillRule::NonZero),
                 &mut BuffersBuilder::new(&mut self.geometry, |vertex: FillVertex| GpuVertex {
                     pos: vertex.position().to_array(),
                     color,
@@ -303,7 +1812,22 @@ impl<'a> RenderContext for WgpuRenderContext<'a> {
                     ..Default::default()
                 }),
             );
+            // Rects on the pixel grid are sharp as they are, rotated or
+            // skewed ones need antialiasing.
+            let coeffs = self.cur_transform.as_coeffs();
+            let axis_aligned = coeffs[1] == 0.0 && coeffs[2] == 0.0;
+            if !axis_aligned {
+                self.add_fringe(vertex_start, start as usize);
+            }
+            self.add_fill_quad(start, rect, brush.is_opaque());
+            self.end_brush(&brush);
         }
+        let kind = if shape.as_rect().is_some() {
+            DrawKind::Quad
+        } else {
+            DrawKind::Triangles
+        };
+        self.capture_draw(kind, bbox, index_start);
     }
 
     fn fill_even_odd(
@@ -314,15 +1838,7 @@ impl<'a> RenderContext for WgpuRenderContext<'a> {
     }
 
     fn clip(&mut self, shape: impl Shape) {
-        if let Some(rect) = shape.as_rect() {
-            let affine = self.cur_transform.as_coeffs();
-            let rect = rect + Vec2::new(affine[4], affine[5]);
-            self.clip_stack.push(rect);
-            if let Some(state) = self.state_stack.last_mut() {
-                state.n_clip += 1;
-            }
-            self.add_primitive();
-        }
+        self.clip_with_feather(shape, 0.0);
     }
 
     fn text(&mut self) -> &mut Self::Text {
@@ -332,7 +1848,12 @@ impl<'a> RenderContext for WgpuRenderContext<'a> {
     fn draw_text(&mut self, layout: &Self::TextLayout, pos: impl Into<piet::kurbo::Point>) {
         let point: Point = pos.into();
         let translate = [point.x as f32, point.y as f32];
+        // Appended to the frame's geometry like any shape, so that it keeps
+        // its place in the drawing order.
+        let start = self.geometry.indices.len();
         layout.draw_text(self, translate);
+        let bbox = Rect::from_origin_size(point, piet::TextLayout::size(layout));
+        self.capture_draw(DrawKind::Text, bbox, start);
     }
 
     fn save(&mut self) -> Result<(), piet::Error> {
@@ -358,44 +1879,73 @@ impl<'a> RenderContext for WgpuRenderContext<'a> {
     }
 
     fn finish(&mut self) -> Result<(), piet::Error> {
-        self.renderer.ensure_encoder();
-        let mut encoder = self.renderer.take_encoder();
-
-        self.renderer.pipeline.upload_data(
-            &self.renderer.device,
-            &mut self.renderer.staging_belt.borrow_mut(),
-            &mut encoder,
-            &self.geometry,
-            &self.primitives,
-        );
+        self.reset_unbalanced();
+        if self.renderer.is_lost() {
+            self.renderer.mark_lost();
+            return Err(piet::Error::NotSupported);
+        }
+        if !self.renderer.has_area() {
+            return Ok(());
+        }
 
-        let texture = self
-            .renderer
-            .surface
-            .get_current_texture()
-            .map_err(|e| piet::Error::NotSupported)?;
-        let view = texture
-            .texture
-            .create_view(&wgpu::TextureViewDescriptor::default());
-
-        self.renderer.pipeline.draw(
-            &self.renderer.device,
-            &mut encoder,
-            &view,
-            &self.renderer.msaa,
-            &self.geometry,
-        );
+        let (frame, view) = self.renderer.acquire_frame()?;
+
+        let core = self.renderer.core.clone();
+        core.ensure_encoder();
+        let mut encoder = core.take_encoder();
+
+        cull_occluded(&mut self.geometry.indices, &self.quads, &mut self.backdrops);
+
+        {
+            let mut state = core.state();
+            let state = &mut *state;
+            core.text.cache.borrow_mut().flush(
+                &core.device,
+                &mut core.staging_belt.borrow_mut(),
+                &mut encoder,
+            );
+            state.images.flush(
+                &core.device,
+                &mut core.staging_belt.borrow_mut(),
+                &mut encoder,
+            );
+            state.pipeline.update_bindings(
+                &core.device,
+                &core.text.cache.borrow(),
+                &state.images,
+                self.primitives.len(),
+            );
+            let viewport = self.renderer.surface.viewport();
+            state.pipeline.upload_data(
+                &core.device,
+                &mut core.staging_belt.borrow_mut(),
+                &mut encoder,
+                &viewport,
+                &self.geometry,
+                &self.primitives,
+                &self.backdrops,
+            );
 
-        self.renderer.staging_belt.borrow_mut().finish();
-        self.renderer.queue.submit(Some(encoder.finish()));
-        texture.present();
+            state.pipeline.draw(
+                &core.device,
+                &mut encoder,
+                &view,
+                &self.renderer.surface.msaa,
+                &viewport,
+                &self.geometry,
+                &self.primitives,
+                &self.backdrops,
+            );
+        }
+        self.renderer.encode_frame(&mut encoder, &view);
 
-        self.renderer
-            .local_pool
-            .spawner()
-            .spawn(self.renderer.staging_belt.borrow_mut().recall())
-            .expect("Recall staging belt");
-        self.renderer.local_pool.run_until_stalled();
+        core.staging_belt.borrow_mut().finish();
+        core.queue.submit(Some(encoder.finish()));
+        if let Some(frame) = frame {
+            frame.present();
+        }
+        self.renderer.track_frame(self.started);
+        self.renderer.finish_frame();
 
         Ok(())
     }
@@ -415,7 +1965,17 @@ impl<'a> RenderContext for WgpuRenderContext<'a> {
         buf: &[u8],
         format: piet::ImageFormat,
     ) -> Result<Self::Image, piet::Error> {
-        todo!()
+        let core = &self.renderer.core;
+        core.ensure_encoder();
+        let mut encoder = core.encoder.borrow_mut();
+        core.state().images.insert(
+            &core.device,
+            encoder.as_mut().unwrap(),
+            width,
+            height,
+            buf,
+            format,
+        )
     }
 
     fn draw_image(
@@ -424,7 +1984,7 @@ impl<'a> RenderContext for WgpuRenderContext<'a> {
         dst_rect: impl Into<piet::kurbo::Rect>,
         interp: piet::InterpolationMode,
     ) {
-        todo!()
+        self.draw_image_with(image, dst_rect, interp, None, 1.0);
     }
 
     fn draw_image_area(
@@ -434,7 +1994,7 @@ impl<'a> RenderContext for WgpuRenderContext<'a> {
         dst_rect: impl Into<piet::kurbo::Rect>,
         interp: piet::InterpolationMode,
     ) {
-        todo!()
+        self.add_image(image, src_rect.into(), dst_rect.into(), interp, None, 1.0);
     }
 
     fn capture_image_area(
@@ -450,37 +2010,7 @@ impl<'a> RenderContext for WgpuRenderContext<'a> {
         blur_radius: f64,
         brush: &impl piet::IntoBrush<Self>,
     ) {
-        let rect = rect.inflate(3.0 * blur_radius, 3.0 * blur_radius);
-        let blur_rect = rect.inflate(-3.0 * blur_radius, -3.0 * blur_radius);
-        let brush = brush.make_brush(self, || rect).into_owned();
-        let Brush::Solid(color) = brush;
-        let color = format_color(&color);
-
-        self.add_primitive();
-        let primitive = self.primitives.last_mut().unwrap();
-        primitive.blur_radius = blur_radius as f32;
-        primitive.blur_rect = [
-            blur_rect.x0 as f32,
-            blur_rect.y0 as f32,
-            blur_rect.x1 as f32,
-            blur_rect.y1 as f32,
-        ];
-
-        let primitive_id = self.primitives.len() as u32 - 1;
-        self.fill_tess.tessellate_rectangle(
-            &lyon::geom::Rect::new(
-                lyon::geom::Point::new(rect.x0 as f32, rect.y0 as f32),
-                lyon::geom::Size::new(rect.width() as f32, rect.height() as f32),
-            ),
-            &FillOptions::tolerance(0.02).with_fill_rule(tessellation::FillRule::NonZero),
-            &mut BuffersBuilder::new(&mut self.geometry, |vertex: FillVertex| GpuVertex {
-                pos: vertex.position().to_array(),
-                color,
-                primitive_id,
-                ..Default::default()
-            }),
-        );
-        self.add_primitive();
+        self.blurred_rounded_rect(rect, 0.0, blur_radius, brush);
     }
 
     fn current_transform(&self) -> piet::kurbo::Affine {
@@ -507,10 +2037,277 @@ impl<'a> IntoBrush<WgpuRenderContext<'a>> for Brush {
     }
 }
 
-impl Image for WgpuImage {
-    fn size(&self) -> piet::kurbo::Size {
-        todo!()
+/// The part of `transform` that geometry is drawn with. Only its
+/// translation reaches the GPU, so whatever has to match what is drawn,
+/// like occlusion, goes by this rather than by the whole transform.
+pub(crate) fn applied_transform(transform: Affine) -> Affine {
+    let [_, _, _, _, x, y] = transform.as_coeffs();
+    Affine::translate((x, y))
+}
+
+/// The top of the glyph quads whose line, from the ascent down to the
+/// descent in `metric`, is centered vertically in `rect`.
+fn centered_line_top(rect: Rect, metric: &GlyphMetricInfo) -> f64 {
+    rect.y0 + (rect.height() - (metric.ascent + metric.descent.abs())) / 2.0
+}
+
+/// Whether `bbox`, in user space, drawn under `transform` overlaps
+/// `visible`, in logical pixels.
+fn drawn_within(bbox: Rect, transform: Affine, visible: Rect) -> bool {
+    let bbox = applied_transform(transform).transform_rect_bbox(bbox);
+    bbox.x0 < visible.x1 && bbox.x1 > visible.x0 && bbox.y0 < visible.y1 && bbox.y1 > visible.y0
+}
+
+/// The scale and offset from user space to physical pixels along each
+/// axis of a transform that neither rotates nor skews.
+#[derive(Clone, Copy, Debug)]
+struct PixelGrid {
+    scale: Vec2,
+    offset: Vec2,
+}
+
+impl PixelGrid {
+    fn new(transform: Affine, scale: f64) -> Option<Self> {
+        let [a, b, c, d, e, f] = transform.as_coeffs();
+        if b != 0.0 || c != 0.0 || a == 0.0 || d == 0.0 {
+            return None;
+        }
+        Some(Self {
+            scale: Vec2::new(a * scale, d * scale),
+            offset: Vec2::new(e * scale, f * scale),
+        })
+    }
+}
+
+/// Round the user space coordinate `v` to where it lands on a physical
+/// pixel edge, with `scale` and `offset` from user space to pixels.
+fn snap_edge(v: f64, scale: f64, offset: f64) -> f64 {
+    ((v * scale + offset).round() - offset) / scale
+}
+
+/// Round the center of a stroke of `width` physical pixels so both of its
+/// edges land on physical pixel edges.
+fn snap_center(v: f64, scale: f64, offset: f64, width: f64) -> f64 {
+    ((v * scale + offset - width / 2.0).round() + width / 2.0 - offset) / scale
+}
+
+/// The whole number of physical pixels, at least one, a stroke of `width`
+/// user space units snaps to.
+fn snap_width(width: f64, scale: f64) -> f64 {
+    (width * scale.abs()).round().max(1.0)
+}
+
+/// The color of the sorted gradient `stops` at `t`.
+fn gradient_color_at(stops: &[GradientStop], t: f64) -> Color {
+    let t = t as f32;
+    let next = stops
+        .iter()
+        .position(|stop| stop.pos > t)
+        .unwrap_or(stops.len());
+    let from = &stops[next.saturating_sub(1)];
+    let to = stops.get(next).unwrap_or(from);
+    let f = if to.pos > from.pos {
+        ((t - from.pos) / (to.pos - from.pos)) as f64
+    } else {
+        0.0
+    };
+    let (r0, g0, b0, a0) = from.color.as_rgba();
+    let (r1, g1, b1, a1) = to.color.as_rgba();
+    Color::rgba(
+        r0 + (r1 - r0) * f,
+        g0 + (g1 - g0) * f,
+        b0 + (b1 - b0) * f,
+        a0 + (a1 - a0) * f,
+    )
+}
+
+/// The perspective transform taking the unit square onto `quad`, with the
+/// corners in clockwise order from the origin, or `None` if `quad` is
+/// degenerate. See Heckbert, "Fundamentals of Texture Mapping and Image
+/// Warping", section 2.2.3.
+fn square_to_quad(quad: [Point; 4]) -> Option<impl Fn(f64, f64) -> Point> {
+    let [p0, p1, p2, p3] = quad;
+    let d1 = p1 - p2;
+    let d2 = p3 - p2;
+    let d3 = p0 - p1 + (p2 - p3);
+    let det = d1.cross(d2);
+    if det.abs() < 1e-9 {
+        return None;
+    }
+    let g = d3.cross(d2) / det;
+    let h = d1.cross(d3) / det;
+    let a = p1.to_vec2() - p0.to_vec2() + p1.to_vec2() * g;
+    let b = p3.to_vec2() - p0.to_vec2() + p3.to_vec2() * h;
+    Some(move |u: f64, v: f64| {
+        let w = g * u + h * v + 1.0;
+        ((a * u + b * v + p0.to_vec2()) / w).to_point()
+    })
+}
+
+/// The dashes of `line` for a `pattern` of alternating dash and gap
+/// lengths, started `offset` into it, or the whole line without a pattern.
+fn dash_line(line: Line, pattern: &[f64], offset: f64) -> Vec<Line> {
+    let length = line.length();
+    // An odd pattern alternates by going through it twice.
+    let pattern: Vec<f64> = match pattern.len() % 2 {
+        0 => pattern.to_vec(),
+        _ => pattern.iter().chain(pattern).copied().collect(),
+    };
+    let period: f64 = pattern.iter().sum();
+    if !(period > 0.0 && length > 0.0) {
+        return vec![line];
+    }
+    let mut dashes = Vec::new();
+    let mut pos = -offset.rem_euclid(period);
+    for (i, len) in pattern.iter().enumerate().cycle() {
+        if pos >= length {
+            break;
+        }
+        let end = pos + len;
+        if i % 2 == 0 && end >= 0.0 {
+            dashes.push(Line::new(
+                line.eval(pos.max(0.0) / length),
+                line.eval(end.min(length) / length),
+            ));
+        }
+        pos = end;
+    }
+    dashes
+}
+
+/// The dashes of the path `elements` in `pattern` from `offset`, flattened
+/// to polylines within `tolerance`. Like in cairo, every subpath starts the
+/// pattern over.
+fn dash_path(elements: &[PathEl], pattern: &[f64], offset: f64, tolerance: f64) -> Vec<PathEl> {
+    let pattern: Vec<f64> = match pattern.len() % 2 {
+        0 => pattern.to_vec(),
+        _ => pattern.iter().chain(pattern).copied().collect(),
+    };
+    let period: f64 = pattern.iter().sum();
+    if period.is_nan() || period <= 0.0 {
+        return elements.to_vec();
+    }
+
+    let mut polylines: Vec<Vec<Point>> = Vec::new();
+    piet::kurbo::flatten(elements.iter().copied(), tolerance, |el| match el {
+        PathEl::MoveTo(p) => polylines.push(vec![p]),
+        PathEl::LineTo(p) => match polylines.last_mut() {
+            Some(polyline) => polyline.push(p),
+            None => polylines.push(vec![p]),
+        },
+        PathEl::ClosePath => {
+            if let Some(polyline) = polylines.last_mut() {
+                let start = polyline[0];
+                polyline.push(start);
+            }
+        }
+        _ => {}
+    });
+
+    let mut dashes = Vec::new();
+    for polyline in polylines {
+        // Skip the part of the pattern before `offset`.
+        let mut i = 0;
+        let mut pos = offset.rem_euclid(period);
+        while pos >= pattern[i] {
+            pos -= pattern[i];
+            i = (i + 1) % pattern.len();
+        }
+        let mut left = pattern[i] - pos;
+        // Dashes start lazily, so that no gap ends in an empty subpath.
+        let mut dash_start = (i % 2 == 0).then(|| polyline[0]);
+        for segment in polyline.windows(2) {
+            let (mut p, end) = (segment[0], segment[1]);
+            let mut rest = (end - p).hypot();
+            let dir = if rest > 0.0 {
+                (end - p) / rest
+            } else {
+                Vec2::ZERO
+            };
+            while rest > left {
+                p += dir * left;
+                rest -= left;
+                if i % 2 == 0 {
+                    if let Some(start) = dash_start.take() {
+                        dashes.push(PathEl::MoveTo(start));
+                    }
+                    dashes.push(PathEl::LineTo(p));
+                } else {
+                    dash_start = Some(p);
+                }
+                i = (i + 1) % pattern.len();
+                left = pattern[i];
+            }
+            left -= rest;
+            if i % 2 == 0 {
+                if let Some(start) = dash_start.take() {
+                    dashes.push(PathEl::MoveTo(start));
+                }
+                dashes.push(PathEl::LineTo(end));
+            }
+        }
+    }
+    dashes
+}
+
+/// Tessellation options for a stroke of `width` in `style`.
+fn stroke_options(tolerance: f32, width: f32, style: &StrokeStyle) -> StrokeOptions {
+    let cap = match style.line_cap {
+        LineCap::Butt => tessellation::LineCap::Butt,
+        LineCap::Round => tessellation::LineCap::Round,
+        LineCap::Square => tessellation::LineCap::Square,
+    };
+    let options = StrokeOptions::tolerance(tolerance)
+        .with_line_width(width)
+        .with_line_cap(cap);
+    match style.line_join {
+        LineJoin::Miter { limit } => options
+            .with_line_join(tessellation::LineJoin::Miter)
+            .with_miter_limit((limit as f32).max(StrokeOptions::MINIMUM_MITER_LIMIT)),
+        LineJoin::Round => options.with_line_join(tessellation::LineJoin::Round),
+        LineJoin::Bevel => options.with_line_join(tessellation::LineJoin::Bevel),
+    }
+}
+
+/// Build a lyon path from kurbo path elements.
+fn lyon_path(elements: impl IntoIterator<Item = PathEl>) -> lyon::path::Path {
+    let mut builder = lyon::path::Path::builder();
+    let mut in_subpath = false;
+    for el in elements {
+        match el {
+            PathEl::MoveTo(p) => {
+                if in_subpath {
+                    builder.end(false);
+                }
+                builder.begin(lyon::geom::point(p.x as f32, p.y as f32));
+                in_subpath = true;
+            }
+            PathEl::LineTo(p) => {
+                builder.line_to(lyon::geom::point(p.x as f32, p.y as f32));
+            }
+            PathEl::QuadTo(ctrl, to) => {
+                builder.quadratic_bezier_to(
+                    lyon::geom::point(ctrl.x as f32, ctrl.y as f32),
+                    lyon::geom::point(to.x as f32, to.y as f32),
+                );
+            }
+            PathEl::CurveTo(c1, c2, p) => {
+                builder.cubic_bezier_to(
+                    lyon::geom::point(c1.x as f32, c1.y as f32),
+                    lyon::geom::point(c2.x as f32, c2.y as f32),
+                    lyon::geom::point(p.x as f32, p.y as f32),
+                );
+            }
+            PathEl::ClosePath => {
+                in_subpath = false;
+                builder.close();
+            }
+        }
+    }
+    if in_subpath {
+        builder.end(false);
     }
+    builder.build()
 }
 
 pub fn from_linear(x: f32) -> f32 {
@@ -530,3 +2327,238 @@ pub fn format_color(color: &Color) -> [f32; 4] {
         color.3 as f32,
     ]
 }
+
+#[cfg(test)]
+mod tests {
+    use piet::kurbo::{Affine, Circle, Line, PathEl, Point, Rect, Shape, Vec2};
+
+    use super::{
+        applied_transform, centered_line_top, dash_line, dash_path, drawn_within, snap_center,
+        snap_edge, snap_width, PixelGrid,
+    };
+    use crate::pipeline::GlyphMetricInfo;
+
+    fn is_whole(v: f64) -> bool {
+        (v - v.round()).abs() < 1e-9
+    }
+
+    #[test]
+    fn snaps_to_physical_pixels_under_scale() {
+        let transform = applied_transform(Affine::translate((3.3, 0.0)) * Affine::scale(1.5));
+        let grid = PixelGrid::new(transform, 2.0).unwrap();
+        // Only the translation is drawn, so the grid follows the surface scale.
+        assert_eq!(grid.scale, Vec2::new(2.0, 2.0));
+        for &v in &[0.0, 0.1, 1.0, 2.7, 10.45] {
+            let x = snap_edge(v, grid.scale.x, grid.offset.x);
+            let physical = transform * Point::new(x, 0.0);
+            assert!(is_whole(physical.x * 2.0), "{} -> {}", v, physical.x);
+            // Never further than half a physical pixel.
+            assert!((x - v).abs() * grid.scale.x <= 0.5 + 1e-9);
+        }
+    }
+
+    #[test]
+    fn stroke_edges_land_on_pixels_under_scale() {
+        let transform = applied_transform(Affine::translate((0.3, 0.0)) * Affine::scale(1.25));
+        let grid = PixelGrid::new(transform, 1.5).unwrap();
+        let width = snap_width(1.0, grid.scale.x);
+        assert_eq!(width, 2.0);
+        let center = snap_center(4.3, grid.scale.x, grid.offset.x, width);
+        let physical = (transform * Point::new(center, 0.0)).x * 1.5;
+        assert!(is_whole(physical - width / 2.0));
+        assert!(is_whole(physical + width / 2.0));
+    }
+
+    #[test]
+    fn no_grid_when_rotated_or_skewed() {
+        assert!(PixelGrid::new(Affine::rotate(0.1), 1.0).is_none());
+        assert!(PixelGrid::new(Affine::new([1.0, 0.0, 0.5, 1.0, 0.0, 0.0]), 1.0).is_none());
+        assert!(PixelGrid::new(Affine::scale(0.0), 1.0).is_none());
+    }
+
+    #[test]
+    fn dashes_follow_the_pattern_and_offset() {
+        let line = Line::new((0.0, 0.0), (6.0, 0.0));
+        let spans = |pattern: &[f64], offset: f64| -> Vec<(f64, f64)> {
+            dash_line(line, pattern, offset)
+                .iter()
+                .map(|dash| (dash.p0.x, dash.p1.x))
+                .collect()
+        };
+        assert_eq!(
+            spans(&[2.0, 1.0], 0.5),
+            [(0.0, 1.5), (2.5, 4.5), (5.5, 6.0)]
+        );
+        // An odd pattern is its own gap.
+        assert_eq!(spans(&[1.0], 0.0), [(0.0, 1.0), (2.0, 3.0), (4.0, 5.0)]);
+        assert_eq!(spans(&[], 0.0), [(0.0, 6.0)]);
+        assert_eq!(spans(&[0.0, 0.0], 0.0), [(0.0, 6.0)]);
+    }
+
+    #[test]
+    fn dashed_circles_are_dashed() {
+        let circle = Circle::new((0.0, 0.0), 10.0);
+        let elements: Vec<PathEl> = circle.path_elements(0.01).collect();
+        let dashes = dash_path(&elements, &[5.0, 5.0], 0.0, 0.01);
+        let mut lengths = Vec::new();
+        let mut last = Point::ZERO;
+        for el in dashes {
+            match el {
+                PathEl::MoveTo(p) => {
+                    lengths.push(0.0);
+                    last = p;
+                }
+                PathEl::LineTo(p) => {
+                    *lengths.last_mut().unwrap() += (p - last).hypot();
+                    last = p;
+                }
+                _ => panic!("{:?} in dashes", el),
+            }
+        }
+        // The circumference is 20π, so six whole dashes and a bit of one.
+        assert_eq!(lengths.len(), 7);
+        for length in &lengths[..6] {
+            assert!((length - 5.0).abs() < 1e-6, "{:?}", lengths);
+        }
+        // Flattened within the tolerance, the circle is a little shorter.
+        let circumference = 20.0 * std::f64::consts::PI;
+        assert!(
+            (lengths[6] - (circumference - 60.0)).abs() < 0.05,
+            "{:?}",
+            lengths
+        );
+
+        // The offset shifts the dashes along the circle.
+        let shifted = dash_path(&elements, &[5.0, 5.0], 5.0, 0.01);
+        assert!(matches!(shifted[0], PathEl::MoveTo(p) if p.y.abs() > 1.0));
+    }
+
+    #[test]
+    fn numbers_are_centered_on_their_line() {
+        let metric = GlyphMetricInfo {
+            ascent: 10.0,
+            descent: -4.0,
+            line_gap: 3.0,
+            mono: true,
+            ink: Rect::ZERO,
+        };
+        let rect = Rect::new(0.0, 20.0, 30.0, 40.0);
+        let top = centered_line_top(rect, &metric);
+        // As much space above the ascent as below the descent, whatever
+        // the line gap.
+        assert_eq!(top, 23.0);
+        assert_eq!(
+            top - rect.y0,
+            rect.y1 - (top + metric.ascent - metric.descent)
+        );
+    }
+
+    #[test]
+    fn culls_by_what_is_drawn_under_a_linear_transform() {
+        let viewport = Rect::new(0.0, 0.0, 500.0, 500.0);
+        let shape = Rect::new(300.0, 300.0, 400.0, 400.0);
+        // Scales and rotations aren't drawn, so the shape stays on screen.
+        assert!(drawn_within(shape, Affine::scale(2.0), viewport));
+        assert!(drawn_within(shape, Affine::rotate(3.0), viewport));
+        // The translation is.
+        let transform = Affine::translate((300.0, 0.0)) * Affine::scale(0.5);
+        assert!(!drawn_within(shape, transform, viewport));
+    }
+
+    /// Draw a frame with `paint` on a headless `renderer` and read it back.
+    /// The tests that draw need a GPU adapter, so they are ignored unless
+    /// run with `cargo test --features headless -- --ignored`.
+    #[cfg(feature = "headless")]
+    fn draw(
+        renderer: &mut crate::WgpuRenderer,
+        paint: impl FnOnce(&mut super::WgpuRenderContext<'_>),
+    ) -> Vec<u8> {
+        use piet::RenderContext;
+
+        let mut ctx = renderer.begin_frame();
+        paint(&mut ctx);
+        ctx.finish().unwrap();
+        drop(ctx);
+        renderer.read_pixels().unwrap()
+    }
+
+    /// The RGBA pixel at `x`, `y` of tightly packed rows `width` wide.
+    #[cfg(feature = "headless")]
+    fn pixel(pixels: &[u8], width: usize, x: usize, y: usize) -> [u8; 4] {
+        let i = (y * width + x) * 4;
+        [pixels[i], pixels[i + 1], pixels[i + 2], pixels[i + 3]]
+    }
+
+    #[cfg(feature = "headless")]
+    #[test]
+    #[ignore = "needs a GPU adapter"]
+    fn frames_start_from_the_clear_color() {
+        use piet::{Color, RenderContext};
+
+        let mut renderer = crate::WgpuRenderer::new_headless(8, 8).unwrap();
+        draw(&mut renderer, |ctx| {
+            ctx.fill(Rect::new(0.0, 0.0, 8.0, 8.0), &Color::rgb8(255, 0, 0))
+        });
+        let pixels = draw(&mut renderer, |ctx| {
+            ctx.fill(Rect::new(0.0, 0.0, 4.0, 8.0), &Color::rgb8(0, 0, 255))
+        });
+        assert_eq!(pixel(&pixels, 8, 1, 4), [0, 0, 255, 255]);
+        // Nothing is drawn there this frame, so the last frame's red is gone.
+        assert_eq!(pixel(&pixels, 8, 6, 4), [255, 255, 255, 255]);
+    }
+
+    #[cfg(feature = "headless")]
+    #[test]
+    #[ignore = "needs a GPU adapter"]
+    fn later_draws_cover_earlier_ones() {
+        use piet::{
+            Color, FontFamily, ImageFormat, InterpolationMode, RenderContext, Text,
+            TextLayoutBuilder,
+        };
+
+        use super::WgpuRenderContext;
+
+        type Paint = fn(&mut WgpuRenderContext<'_>);
+        const WHOLE: Rect = Rect::new(0.0, 0.0, 40.0, 40.0);
+        fn image(ctx: &mut WgpuRenderContext<'_>) {
+            let image = ctx
+                .make_image(1, 1, &[0, 255, 0, 255], ImageFormat::RgbaSeparate)
+                .unwrap();
+            ctx.draw_image(&image, WHOLE, InterpolationMode::NearestNeighbor);
+        }
+        fn blurred_rect(ctx: &mut WgpuRenderContext<'_>) {
+            ctx.blurred_rect(WHOLE.inflate(20.0, 20.0), 2.0, &Color::rgb8(0, 0, 255));
+        }
+        fn text(ctx: &mut WgpuRenderContext<'_>) {
+            let layout = ctx
+                .text()
+                .new_text_layout("MMM")
+                .font(FontFamily::SANS_SERIF, 30.0)
+                .text_color(Color::BLACK)
+                .build()
+                .unwrap();
+            ctx.draw_text(&layout, (0.0, 0.0));
+        }
+
+        let red = Color::rgb8(255, 0, 0);
+        let all_red = |pixels: &[u8]| pixels.chunks(4).all(|p| p == [255, 0, 0, 255]);
+        let mut renderer = crate::WgpuRenderer::new_headless(40, 40).unwrap();
+        let kinds: &[(&str, Paint)] = &[
+            ("image", image),
+            ("blurred rect", blurred_rect),
+            ("text", text),
+        ];
+        for &(kind, paint) in kinds {
+            let pixels = draw(&mut renderer, |ctx| {
+                ctx.fill(WHOLE, &red);
+                paint(ctx);
+            });
+            assert!(!all_red(&pixels), "{} under a fill", kind);
+            let pixels = draw(&mut renderer, |ctx| {
+                paint(ctx);
+                ctx.fill(WHOLE, &red);
+            });
+            assert!(all_red(&pixels), "{} over a fill", kind);
+        }
+    }
+}
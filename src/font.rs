@@ -1,11 +1,264 @@
+use font_kit::{
+    properties::{Properties, Stretch, Style, Weight},
+    source::SystemSource,
+};
+use piet::{FontStyle, FontWeight};
+
+/// A font loaded into the renderer's font cache, as returned by
+/// [`WgpuText::resolve`](crate::PietText::resolve).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct FontId(pub(crate) usize);
+
+/// An installed font family and the faces it provides.
+#[derive(Clone, Debug)]
+pub struct FamilyInfo {
+    pub name: String,
+    pub faces: Vec<FaceInfo>,
+}
+
+/// A single face of a [`FamilyInfo`].
+#[derive(Clone, Debug)]
+pub struct FaceInfo {
+    pub postscript_name: Option<String>,
+    pub weight: FontWeight,
+    pub style: FontStyle,
+    pub stretch: FontStretch,
+}
+
+/// How condensed or expanded a face is, as a percentage of the normal
+/// width like CSS `font-stretch`, in tenths of a percent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FontStretch(u16);
+
+impl FontStretch {
+    pub const ULTRA_CONDENSED: FontStretch = FontStretch(500);
+    pub const EXTRA_CONDENSED: FontStretch = FontStretch(625);
+    pub const CONDENSED: FontStretch = FontStretch(750);
+    pub const SEMI_CONDENSED: FontStretch = FontStretch(875);
+    pub const NORMAL: FontStretch = FontStretch(1000);
+    pub const SEMI_EXPANDED: FontStretch = FontStretch(1125);
+    pub const EXPANDED: FontStretch = FontStretch(1250);
+    pub const EXTRA_EXPANDED: FontStretch = FontStretch(1500);
+    pub const ULTRA_EXPANDED: FontStretch = FontStretch(2000);
+
+    /// The stretch of `percent` of the normal width, clamped to the range
+    /// CSS allows, 50% to 200%.
+    pub fn from_percent(percent: f64) -> Self {
+        FontStretch((percent.clamp(50.0, 200.0) * 10.0).round() as u16)
+    }
+
+    pub fn to_percent(self) -> f64 {
+        self.0 as f64 / 10.0
+    }
+}
+
+impl Default for FontStretch {
+    fn default() -> Self {
+        FontStretch::NORMAL
+    }
+}
+
+/// The weight, style and stretch to pick a face of a family by.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct FaceQuery {
+    pub(crate) weight: FontWeight,
+    pub(crate) style: FontStyle,
+    pub(crate) stretch: FontStretch,
+}
+
+impl FaceQuery {
+    pub(crate) fn new(weight: FontWeight, style: FontStyle, stretch: FontStretch) -> Self {
+        Self {
+            weight,
+            style,
+            stretch,
+        }
+    }
+
+    /// The query that `properties` answer exactly.
+    pub(crate) fn of(properties: &Properties) -> Self {
+        Self {
+            weight: FontWeight::new(properties.weight.0 as u16),
+            style: match properties.style {
+                Style::Normal => FontStyle::Regular,
+                Style::Italic | Style::Oblique => FontStyle::Italic,
+            },
+            stretch: FontStretch::from_percent(properties.stretch.0 as f64 * 100.0),
+        }
+    }
+
+    /// The properties to match installed faces against.
+    pub(crate) fn properties(&self) -> Properties {
+        *Properties::new()
+            .weight(Weight(self.weight.to_raw() as f32))
+            .style(match self.style {
+                FontStyle::Regular => Style::Normal,
+                FontStyle::Italic => Style::Italic,
+            })
+            .stretch(Stretch(self.stretch.to_percent() as f32 / 100.0))
+    }
+
+    /// How far `face` is from the query, for picking the closest of a set
+    /// of faces. Like CSS font matching, stretch matters most, then style,
+    /// then weight; of two faces equally far off, the narrower and the
+    /// lighter one wins.
+    pub(crate) fn distance(&self, face: &FaceQuery) -> impl Ord {
+        let stretch = (face.stretch.0 as i32 - self.stretch.0 as i32).abs();
+        let weight = (face.weight.to_raw() as i32 - self.weight.to_raw() as i32).abs();
+        (
+            stretch,
+            face.stretch,
+            face.style != self.style,
+            weight,
+            face.weight.to_raw(),
+        )
+    }
+}
+
+impl From<FontWeight> for FaceQuery {
+    fn from(weight: FontWeight) -> Self {
+        Self::new(weight, FontStyle::Regular, FontStretch::NORMAL)
+    }
+}
+
 pub struct FontSource {
-    source: font_kit::source::SystemSource,
+    source: SystemSource,
 }
 
 impl FontSource {
     pub fn new() -> Self {
         Self {
-            source: font_kit::source::SystemSource::new(),
+            source: SystemSource::new(),
+        }
+    }
+
+    pub fn has_family(&self, name: &str) -> bool {
+        self.source.select_family_by_name(name).is_ok()
+    }
+
+    /// All installed families, sorted by name. Every face is loaded to read
+    /// its properties, so this is slow on systems with many fonts.
+    pub fn families(&self) -> Vec<FamilyInfo> {
+        let mut names = self.source.all_families().unwrap_or_default();
+        names.sort();
+        names.dedup();
+        names
+            .into_iter()
+            .filter_map(|name| {
+                let family = self.source.select_family_by_name(&name).ok()?;
+                let faces = family
+                    .fonts()
+                    .iter()
+                    .filter_map(|handle| handle.load().ok())
+                    .map(|font| {
+                        let face = FaceQuery::of(&font.properties());
+                        FaceInfo {
+                            postscript_name: font.postscript_name(),
+                            weight: face.weight,
+                            style: face.style,
+                            stretch: face.stretch,
+                        }
+                    })
+                    .collect();
+                Some(FamilyInfo { name, faces })
+            })
+            .collect()
+    }
+}
+
+/// The regional glyph forms of Han ideographs and the other CJK chars,
+/// picked by the locale of a text layout.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) enum HanRegion {
+    Japanese,
+    SimplifiedChinese,
+    TraditionalChinese,
+    HongKong,
+    Korean,
+}
+
+impl HanRegion {
+    /// The region for a BCP 47 locale such as `ja-JP` or `zh-Hant`, or
+    /// `None` if the locale doesn't call for particular CJK glyphs.
+    pub(crate) fn from_locale(locale: &str) -> Option<Self> {
+        let locale = locale.to_ascii_lowercase().replace('_', "-");
+        let mut subtags = locale.split('-');
+        match subtags.next()? {
+            "ja" => Some(HanRegion::Japanese),
+            "ko" => Some(HanRegion::Korean),
+            "zh" | "yue" => {
+                let subtags: Vec<&str> = subtags.collect();
+                if subtags.contains(&"hk") || subtags.contains(&"mo") {
+                    Some(HanRegion::HongKong)
+                } else if subtags.contains(&"hant") || subtags.contains(&"tw") {
+                    Some(HanRegion::TraditionalChinese)
+                } else {
+                    Some(HanRegion::SimplifiedChinese)
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// Families with the glyph forms of the region, most common first.
+    pub(crate) fn families(self) -> &'static [&'static str] {
+        match self {
+            HanRegion::Japanese => &[
+                "Noto Sans CJK JP",
+                "Noto Sans JP",
+                "Source Han Sans JP",
+                "Hiragino Sans",
+                "Yu Gothic",
+                "Meiryo",
+                "MS Gothic",
+            ],
+            HanRegion::SimplifiedChinese => &[
+                "Noto Sans CJK SC",
+                "Noto Sans SC",
+                "Source Han Sans SC",
+                "PingFang SC",
+                "Microsoft YaHei",
+            ],
+            HanRegion::TraditionalChinese => &[
+                "Noto Sans CJK TC",
+                "Noto Sans TC",
+                "Source Han Sans TC",
+                "PingFang TC",
+                "Microsoft JhengHei",
+            ],
+            HanRegion::HongKong => &[
+                "Noto Sans CJK HK",
+                "Noto Sans HK",
+                "Source Han Sans HC",
+                "PingFang HK",
+                "Microsoft JhengHei",
+            ],
+            HanRegion::Korean => &[
+                "Noto Sans CJK KR",
+                "Noto Sans KR",
+                "Source Han Sans KR",
+                "Apple SD Gothic Neo",
+                "Malgun Gothic",
+            ],
         }
     }
+
+    /// Whether the forms of `c` differ between regions: Han ideographs,
+    /// kana, hangul and CJK punctuation.
+    pub(crate) fn applies_to(c: char) -> bool {
+        matches!(
+            c as u32,
+            0x2E80..=0x2FDF
+                | 0x3000..=0x30FF
+                | 0x3100..=0x31FF
+                | 0x3400..=0x4DBF
+                | 0x4E00..=0x9FFF
+                | 0xAC00..=0xD7AF
+                | 0xF900..=0xFAFF
+                | 0xFF00..=0xFFEF
+                | 0x20000..=0x3FFFF
+        )
+    }
 }
@@ -0,0 +1,129 @@
+use std::collections::HashMap;
+
+use lyon::lyon_tessellation::VertexBuffers;
+
+use crate::pipeline::GpuVertex;
+
+/// How far out a fringe vertex may be pushed at a sharp corner, in
+/// fringe widths, like a miter limit.
+const MAX_MITER: f32 = 2.0;
+
+/// Antialias the triangles from `vertex_start` and `index_start` on,
+/// without relying on multisampling: their outline is moved in by half
+/// of `width` and a strip fading to transparent is added around it, out to
+/// half of `width` past the original outline. With `width` a physical
+/// pixel, edges on the pixel grid stay sharp.
+pub(crate) fn add_fringe(
+    geometry: &mut VertexBuffers<GpuVertex, u32>,
+    vertex_start: usize,
+    index_start: usize,
+    width: f32,
+) {
+    // Tessellators emit the same point more than once, e.g. where a
+    // stroke changes direction, so the mesh is welded by position to tell
+    // its outline from edges inside it.
+    let mut welded: HashMap<[u32; 2], u32> = HashMap::new();
+    let weld: Vec<u32> = geometry.vertices[vertex_start..]
+        .iter()
+        .enumerate()
+        .map(|(i, v)| {
+            let key = [v.pos[0].to_bits(), v.pos[1].to_bits()];
+            *welded.entry(key).or_insert((vertex_start + i) as u32)
+        })
+        .collect();
+    let welded_index = |i: u32| weld[i as usize - vertex_start];
+
+    // Every edge with the third corner of its triangle and how many
+    // triangles share it; an edge of a single triangle is on the outline.
+    let mut edges: Vec<(u32, u32, u32, usize)> = Vec::new();
+    let mut edge_ids: HashMap<(u32, u32), usize> = HashMap::new();
+    for triangle in geometry.indices[index_start..].chunks(3) {
+        let [a, b, c] = [
+            welded_index(triangle[0]),
+            welded_index(triangle[1]),
+            welded_index(triangle[2]),
+        ];
+        if a == b || b == c || c == a {
+            continue;
+        }
+        for &(p, q, opposite) in [(a, b, c), (b, c, a), (c, a, b)].iter() {
+            let id = *edge_ids.entry((p.min(q), p.max(q))).or_insert_with(|| {
+                edges.push((p, q, opposite, 0));
+                edges.len() - 1
+            });
+            edges[id].3 += 1;
+        }
+    }
+
+    let pos = |i: u32| geometry.vertices[i as usize].pos;
+    let mut outline: Vec<(u32, u32)> = Vec::new();
+    let mut normals: HashMap<u32, [f32; 2]> = HashMap::new();
+    for &(p, q, opposite, count) in edges.iter() {
+        if count != 1 {
+            continue;
+        }
+        let (a, b, c) = (pos(p), pos(q), pos(opposite));
+        let (dx, dy) = (b[0] - a[0], b[1] - a[1]);
+        let length = (dx * dx + dy * dy).sqrt();
+        if length <= 0.0 {
+            continue;
+        }
+        let mut normal = [dy / length, -dx / length];
+        if normal[0] * (c[0] - a[0]) + normal[1] * (c[1] - a[1]) > 0.0 {
+            normal = [-normal[0], -normal[1]];
+        }
+        for i in [p, q].iter() {
+            let sum = normals.entry(*i).or_insert([0.0, 0.0]);
+            sum[0] += normal[0];
+            sum[1] += normal[1];
+        }
+        outline.push((p, q));
+    }
+    if outline.is_empty() {
+        return;
+    }
+
+    // Each outline vertex gets a transparent copy out along the miter of
+    // the edges meeting there, and is itself moved in as far.
+    let half_width = width / 2.0;
+    let mut offsets: HashMap<u32, [f32; 2]> = HashMap::new();
+    let mut outer: HashMap<u32, u32> = HashMap::new();
+    for &(p, q) in outline.iter() {
+        for &i in [p, q].iter() {
+            if outer.contains_key(&i) {
+                continue;
+            }
+            let sum = normals[&i];
+            let length = (sum[0] * sum[0] + sum[1] * sum[1]).sqrt();
+            if length <= f32::EPSILON {
+                continue;
+            }
+            // Two unit normals sum to twice the cosine of half the angle
+            // between them, which the miter is longer by.
+            let miter = (2.0 / length).min(MAX_MITER) * half_width / length;
+            let offset = [sum[0] * miter, sum[1] * miter];
+            let mut fringe = geometry.vertices[i as usize];
+            fringe.pos = [fringe.pos[0] + offset[0], fringe.pos[1] + offset[1]];
+            fringe.color[3] = 0.0;
+            offsets.insert(i, offset);
+            outer.insert(i, geometry.vertices.len() as u32);
+            geometry.vertices.push(fringe);
+        }
+    }
+    for (v, i) in geometry.vertices[vertex_start..]
+        .iter_mut()
+        .zip(weld.iter())
+    {
+        if let Some(offset) = offsets.get(i) {
+            v.pos = [v.pos[0] - offset[0], v.pos[1] - offset[1]];
+        }
+    }
+
+    for (p, q) in outline {
+        if let (Some(&outer_p), Some(&outer_q)) = (outer.get(&p), outer.get(&q)) {
+            geometry
+                .indices
+                .extend_from_slice(&[p, q, outer_q, p, outer_q, outer_p]);
+        }
+    }
+}
@@ -0,0 +1,209 @@
+use std::cmp::Ordering;
+
+use piet::{kurbo::Point, Color, FixedGradient, GradientStop};
+
+use crate::context::from_linear;
+use crate::image::WgpuImage;
+use crate::pipeline::Primitive;
+
+/// How many colors of a gradient are baked into the image atlas.
+pub(crate) const GRADIENT_LUT_WIDTH: usize = 256;
+
+/// The color space the stops of a gradient are blended in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum GradientSpace {
+    /// The sRGB encoded components, like other piet backends and CSS.
+    #[default]
+    Srgb,
+    /// Linear light, which keeps blends of saturated colors from going
+    /// dark in the middle, but moves the middle towards the lighter stop.
+    LinearRgb,
+    /// The perceptual OKLab space, in which lightness and hue change
+    /// evenly. Looks the smoothest for UI gradients.
+    Oklab,
+}
+
+/// A gradient brush made with
+/// [`WgpuRenderContext::gradient_in`](crate::Piet::gradient_in). Its
+/// colors are looked up in a strip of the image atlas, baked when the
+/// brush is made, so drawing with it costs no more than with a solid color.
+#[derive(Clone, Debug)]
+pub struct GradientBrush {
+    /// Start and end of a linear gradient, or the center of a radial one
+    /// and the focus it radiates from.
+    pub(crate) points: [Point; 2],
+    /// The radius of a radial gradient, 0 for a linear one.
+    pub(crate) radius: f64,
+    pub(crate) lut: WgpuImage,
+    /// The stops, sorted by position.
+    stops: Vec<GradientStop>,
+    space: GradientSpace,
+}
+
+impl GradientBrush {
+    pub(crate) fn new(
+        gradient: FixedGradient,
+        lut: WgpuImage,
+        stops: Vec<GradientStop>,
+        space: GradientSpace,
+    ) -> Self {
+        let (points, radius) = match gradient {
+            FixedGradient::Linear(linear) => ([linear.start, linear.end], 0.0),
+            FixedGradient::Radial(radial) => {
+                // A focus on or outside the circle has no well defined
+                // gradient, so it's kept just inside.
+                let radius = radial.radius.max(0.0);
+                let mut offset = radial.origin_offset;
+                if offset.hypot() > radius * 0.99 {
+                    offset = offset.normalize() * radius * 0.99;
+                }
+                ([radial.center, radial.center + offset], radius)
+            }
+        };
+        Self {
+            points,
+            radius,
+            lut,
+            stops,
+            space,
+        }
+    }
+
+    /// The color at `t` along the gradient, from `0.0` at its start to
+    /// `1.0` at its end.
+    pub fn color_at(&self, t: f64) -> Color {
+        color_at(&self.stops, t.clamp(0.0, 1.0), self.space)
+    }
+
+    pub(crate) fn is_opaque(&self) -> bool {
+        self.stops.iter().all(|stop| stop.color.as_rgba().3 >= 1.0)
+    }
+
+    /// Have the shader color what `primitive` draws with this gradient.
+    pub(crate) fn apply(&self, primitive: &mut Primitive) {
+        let [a, b] = self.points;
+        primitive.gradient = [a.x as f32, a.y as f32, b.x as f32, b.y as f32];
+        let lut = self.lut.rect();
+        primitive.gradient_lut = [
+            lut.x as f32,
+            lut.y as f32,
+            lut.width as f32,
+            self.radius as f32,
+        ];
+    }
+}
+
+/// The stops of `gradient`, sorted by position, or `None` without any.
+pub(crate) fn sorted_stops(gradient: &FixedGradient) -> Option<Vec<GradientStop>> {
+    let mut stops = match gradient {
+        FixedGradient::Linear(linear) => linear.stops.clone(),
+        FixedGradient::Radial(radial) => radial.stops.clone(),
+    };
+    if stops.is_empty() {
+        return None;
+    }
+    stops.sort_by(|a, b| a.pos.partial_cmp(&b.pos).unwrap_or(Ordering::Equal));
+    Some(stops)
+}
+
+/// [`GRADIENT_LUT_WIDTH`] straight alpha sRGB pixels from the start of the
+/// sorted `stops` to their end, blended in `space`.
+pub(crate) fn bake_lut(stops: &[GradientStop], space: GradientSpace) -> Vec<u8> {
+    (0..GRADIENT_LUT_WIDTH)
+        .flat_map(|i| {
+            let t = i as f64 / (GRADIENT_LUT_WIDTH - 1) as f64;
+            let (r, g, b, a) = color_at(stops, t, space).as_rgba8();
+            [r, g, b, a]
+        })
+        .collect()
+}
+
+/// The color of the sorted `stops` at `t`, blended in `space`. Colors are
+/// blended premultiplied, so a transparent stop doesn't darken its
+/// neighbours.
+fn color_at(stops: &[GradientStop], t: f64, space: GradientSpace) -> Color {
+    let t = t as f32;
+    let next = stops
+        .iter()
+        .position(|stop| stop.pos > t)
+        .unwrap_or(stops.len());
+    let from = &stops[next.saturating_sub(1)];
+    let to = stops.get(next).unwrap_or(from);
+    let f = if to.pos > from.pos {
+        ((t - from.pos) / (to.pos - from.pos)) as f64
+    } else {
+        0.0
+    };
+
+    let (c0, a0) = to_space(&from.color, space);
+    let (c1, a1) = to_space(&to.color, space);
+    let alpha = a0 + (a1 - a0) * f;
+    if alpha <= 0.0 {
+        return Color::rgba(0.0, 0.0, 0.0, 0.0);
+    }
+    let mut c = [0.0; 3];
+    for i in 0..3 {
+        c[i] = (c0[i] * a0 + (c1[i] * a1 - c0[i] * a0) * f) / alpha;
+    }
+    from_space(c, alpha, space)
+}
+
+fn to_space(color: &Color, space: GradientSpace) -> ([f64; 3], f64) {
+    let (r, g, b, a) = color.as_rgba();
+    let c = match space {
+        GradientSpace::Srgb => [r, g, b],
+        GradientSpace::LinearRgb => [r, g, b].map(srgb_to_linear),
+        GradientSpace::Oklab => linear_to_oklab([r, g, b].map(srgb_to_linear)),
+    };
+    (c, a)
+}
+
+fn from_space(c: [f64; 3], alpha: f64, space: GradientSpace) -> Color {
+    let [r, g, b] = match space {
+        GradientSpace::Srgb => c,
+        GradientSpace::LinearRgb => c.map(linear_to_srgb),
+        GradientSpace::Oklab => oklab_to_linear(c).map(linear_to_srgb),
+    };
+    Color::rgba(
+        r.clamp(0.0, 1.0),
+        g.clamp(0.0, 1.0),
+        b.clamp(0.0, 1.0),
+        alpha.clamp(0.0, 1.0),
+    )
+}
+
+fn srgb_to_linear(x: f64) -> f64 {
+    from_linear(x as f32) as f64
+}
+
+fn linear_to_srgb(x: f64) -> f64 {
+    if x <= 0.0031308 {
+        x * 12.92
+    } else {
+        1.055 * x.max(0.0).powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// See Björn Ottosson, "A perceptual color space for image processing".
+fn linear_to_oklab([r, g, b]: [f64; 3]) -> [f64; 3] {
+    let l = (0.4122214708 * r + 0.5363325363 * g + 0.0514459929 * b).cbrt();
+    let m = (0.2119034982 * r + 0.6806995451 * g + 0.1073969566 * b).cbrt();
+    let s = (0.0883024619 * r + 0.2817188376 * g + 0.6299787005 * b).cbrt();
+    [
+        0.2104542553 * l + 0.7936177850 * m - 0.0040720468 * s,
+        1.9779984951 * l - 2.4285922050 * m + 0.4505937099 * s,
+        0.0259040371 * l + 0.7827717662 * m - 0.8086757660 * s,
+    ]
+}
+
+fn oklab_to_linear([l, a, b]: [f64; 3]) -> [f64; 3] {
+    let l_ = l + 0.3963377774 * a + 0.2158037573 * b;
+    let m_ = l - 0.1055613458 * a - 0.0638541728 * b;
+    let s_ = l - 0.0894841775 * a - 1.2914855480 * b;
+    let (l, m, s) = (l_ * l_ * l_, m_ * m_ * m_, s_ * s_ * s_);
+    [
+        4.0767416621 * l - 3.3077115913 * m + 0.2309699292 * s,
+        -1.2684380046 * l + 2.6097574011 * m - 0.3413193965 * s,
+        -0.0041960863 * l - 0.7034186147 * m + 1.7076147010 * s,
+    ]
+}
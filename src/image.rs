@@ -0,0 +1,353 @@
+use std::cell::Cell;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::rc::{Rc, Weak};
+
+use hashbrown::HashMap;
+use piet::{kurbo::Size, ImageFormat};
+
+use crate::atlas::{bytes_per_pixel, AtlasRect, GpuAtlas};
+
+/// The width of the image atlas, which is the widest an image can be.
+const IMAGE_ATLAS_WIDTH: u32 = 4096;
+
+/// Texels around each image repeating its edge, so that filtering at the
+/// edge doesn't pick up its neighbours in the atlas.
+const IMAGE_PADDING: u32 = 1;
+
+/// An image made with [`piet::RenderContext::make_image`], which lives in
+/// the image atlas of the renderer that made it until its last clone is
+/// dropped.
+#[derive(Clone, Debug)]
+pub struct WgpuImage {
+    slot: Rc<ImageSlot>,
+    /// Whether the image is in the single channel grayscale atlas.
+    pub(crate) gray: bool,
+}
+
+impl WgpuImage {
+    /// Where the image is in the atlas, in texels, without its padding.
+    pub(crate) fn rect(&self) -> AtlasRect {
+        self.slot.rect.get()
+    }
+}
+
+impl piet::Image for WgpuImage {
+    fn size(&self) -> Size {
+        let rect = self.rect();
+        Size::new(rect.width as f64, rect.height as f64)
+    }
+}
+
+/// The place of an image in the atlas, shared by the clones of its
+/// [`WgpuImage`]. It changes when the atlas is compacted.
+#[derive(Debug)]
+struct ImageSlot {
+    rect: Cell<AtlasRect>,
+}
+
+/// Identifies the pixels of an image, so that making the same image again
+/// reuses its place in the atlas.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct ImageKey {
+    hash: u64,
+    width: u32,
+    height: u32,
+    gray: bool,
+}
+
+/// An image in the atlas, by the id of its slot.
+type SlotId = u64;
+
+/// The images of a renderer, packed into an sRGB RGBA atlas that the
+/// geometry pipeline samples. Grayscale images go into an R8 atlas of their
+/// own, at a quarter of the memory and without expanding them on the CPU.
+///
+/// An image keeps its place while any clone of its [`WgpuImage`] is
+/// alive. The place of a dropped image is freed at the flush after the one
+/// that notices it, since frames already drawing it may still upload it.
+pub(crate) struct ImageStore {
+    atlas: GpuAtlas<SlotId>,
+    gray_atlas: GpuAtlas<SlotId>,
+    images: HashMap<ImageKey, (SlotId, Weak<ImageSlot>)>,
+    /// Slots of dropped images, freed at the next flush.
+    released: Vec<(SlotId, bool)>,
+    next_slot: SlotId,
+}
+
+impl ImageStore {
+    pub(crate) fn new(device: &wgpu::Device) -> Self {
+        Self {
+            atlas: GpuAtlas::new(
+                device,
+                "piet_wgpu::ImageStore",
+                wgpu::TextureFormat::Rgba8UnormSrgb,
+                IMAGE_ATLAS_WIDTH,
+                64,
+            ),
+            // There's no sRGB R8 format, so the shader decodes these.
+            gray_atlas: GpuAtlas::new(
+                device,
+                "piet_wgpu::ImageStore::gray",
+                wgpu::TextureFormat::R8Unorm,
+                IMAGE_ATLAS_WIDTH,
+                64,
+            ),
+            images: HashMap::new(),
+            released: Vec::new(),
+            next_slot: 0,
+        }
+    }
+
+    fn atlas_mut(&mut self, gray: bool) -> &mut GpuAtlas<SlotId> {
+        if gray {
+            &mut self.gray_atlas
+        } else {
+            &mut self.atlas
+        }
+    }
+
+    /// Add an image of `width` by `height` pixels in `format`, unless the
+    /// same image was added before.
+    pub(crate) fn insert(
+        &mut self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        width: usize,
+        height: usize,
+        buf: &[u8],
+        format: ImageFormat,
+    ) -> Result<WgpuImage, piet::Error> {
+        if buf.len() < width * height * format.bytes_per_pixel() {
+            return Err(piet::Error::InvalidInput);
+        }
+        let padded_width = width as u32 + 2 * IMAGE_PADDING;
+        if padded_width > IMAGE_ATLAS_WIDTH {
+            return Err(piet::Error::InvalidInput);
+        }
+        let buf = &buf[..width * height * format.bytes_per_pixel()];
+        let gray = format == ImageFormat::Grayscale;
+        let pixels = if gray {
+            buf.to_vec()
+        } else {
+            to_rgba(buf, format)?
+        };
+
+        let mut hasher = DefaultHasher::new();
+        pixels.hash(&mut hasher);
+        let key = ImageKey {
+            hash: hasher.finish(),
+            width: width as u32,
+            height: height as u32,
+            gray,
+        };
+        if let Some(slot) = self.images.get(&key).and_then(|(_, slot)| slot.upgrade()) {
+            return Ok(WgpuImage { slot, gray });
+        }
+
+        let id = self.next_slot;
+        self.next_slot += 1;
+        let padded_height = height as u32 + 2 * IMAGE_PADDING;
+        let atlas = self.atlas_mut(gray);
+        let padded = pad(&pixels, width, height, bytes_per_pixel(atlas.format()));
+        let rect = atlas
+            .insert(device, encoder, id, [padded_width, padded_height], &padded)
+            .ok_or(piet::Error::NotSupported)?;
+        let slot = Rc::new(ImageSlot {
+            rect: Cell::new(unpadded(rect)),
+        });
+        // A dropped image of the same pixels may still be waiting to be
+        // released under the key, with its own slot.
+        if let Some((old, _)) = self.images.insert(key, (id, Rc::downgrade(&slot))) {
+            self.released.push((old, gray));
+        }
+        Ok(WgpuImage { slot, gray })
+    }
+
+    /// Free the slots of the images dropped before the last flush, and
+    /// find the ones dropped since, to free at the next.
+    fn release_dropped(&mut self) {
+        for (id, gray) in std::mem::take(&mut self.released) {
+            self.atlas_mut(gray).remove(&id);
+        }
+        let released = &mut self.released;
+        self.images.retain(|key, (id, slot)| {
+            let alive = slot.strong_count() > 0;
+            if !alive {
+                released.push((*id, key.gray));
+            }
+            alive
+        });
+    }
+
+    /// Pack the images of both atlases again, to give back the space of
+    /// the dropped ones. The packed images go into new textures, so frames
+    /// in flight keep the old ones and the dropped images are freed at once.
+    pub(crate) fn compact(&mut self, device: &wgpu::Device, encoder: &mut wgpu::CommandEncoder) {
+        self.release_dropped();
+        self.release_dropped();
+        // Slot ids are unique across the atlases.
+        let mut moved = self.atlas.compact(device, encoder);
+        moved.extend(self.gray_atlas.compact(device, encoder));
+        self.move_slots(&moved);
+    }
+
+    /// Point the slots of the images at the places they moved to.
+    fn move_slots(&self, moved: &[(SlotId, AtlasRect)]) {
+        if moved.is_empty() {
+            return;
+        }
+        let moved: HashMap<SlotId, AtlasRect> = moved.iter().copied().collect();
+        for (id, slot) in self.images.values() {
+            if let (Some(rect), Some(slot)) = (moved.get(id), slot.upgrade()) {
+                slot.rect.set(unpadded(*rect));
+            }
+        }
+    }
+
+    pub(crate) fn view(&self) -> &wgpu::TextureView {
+        self.atlas.view()
+    }
+
+    pub(crate) fn gray_view(&self) -> &wgpu::TextureView {
+        self.gray_atlas.view()
+    }
+
+    /// Changes whenever either atlas is reallocated.
+    pub(crate) fn generation(&self) -> u64 {
+        self.atlas.generation() + self.gray_atlas.generation()
+    }
+
+    pub(crate) fn memory_bytes(&self) -> u64 {
+        self.atlas.memory_bytes() + self.gray_atlas.memory_bytes()
+    }
+
+    /// Upload the images made since the last frame.
+    pub(crate) fn flush(
+        &mut self,
+        device: &wgpu::Device,
+        staging_belt: &mut wgpu::util::StagingBelt,
+        encoder: &mut wgpu::CommandEncoder,
+    ) {
+        self.atlas.flush(device, staging_belt, encoder);
+        self.gray_atlas.flush(device, staging_belt, encoder);
+        self.release_dropped();
+    }
+}
+
+/// The place of an image inside `rect`, its place with padding.
+fn unpadded(rect: AtlasRect) -> AtlasRect {
+    AtlasRect {
+        x: rect.x + IMAGE_PADDING,
+        y: rect.y + IMAGE_PADDING,
+        width: rect.width - 2 * IMAGE_PADDING,
+        height: rect.height - 2 * IMAGE_PADDING,
+    }
+}
+
+/// Straight alpha RGBA pixels from `buf` in `format`.
+pub(crate) fn to_rgba(buf: &[u8], format: ImageFormat) -> Result<Vec<u8>, piet::Error> {
+    Ok(match format {
+        ImageFormat::Grayscale => buf.iter().flat_map(|&v| [v, v, v, 255]).collect(),
+        ImageFormat::Rgb => buf
+            .chunks(3)
+            .flat_map(|p| [p[0], p[1], p[2], 255])
+            .collect(),
+        ImageFormat::RgbaSeparate => buf.to_vec(),
+        ImageFormat::RgbaPremul => buf
+            .chunks(4)
+            .flat_map(|p| {
+                let unpremultiply = |c: u8| match p[3] {
+                    0 => 0,
+                    a => (c as u32 * 255 / a as u32).min(255) as u8,
+                };
+                [
+                    unpremultiply(p[0]),
+                    unpremultiply(p[1]),
+                    unpremultiply(p[2]),
+                    p[3],
+                ]
+            })
+            .collect(),
+        _ => return Err(piet::Error::NotSupported),
+    })
+}
+
+/// Encode straight alpha RGBA rows, top row first, as a PNG.
+pub(crate) fn encode_png(width: u32, height: u32, pixels: &[u8]) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    let mut encoder = png::Encoder::new(&mut data, width, height);
+    encoder.set_color(png::ColorType::RGBA);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header()?.write_image_data(pixels)?;
+    Ok(data)
+}
+
+/// `pixels` of `bpp` bytes with [`IMAGE_PADDING`] texels around them
+/// repeating the edge.
+fn pad(pixels: &[u8], width: usize, height: usize, bpp: usize) -> Vec<u8> {
+    let padding = IMAGE_PADDING as usize;
+    let padded_width = width + 2 * padding;
+    let padded_height = height + 2 * padding;
+    let mut padded = Vec::with_capacity(padded_width * padded_height * bpp);
+    for y in 0..padded_height {
+        let y = y.saturating_sub(padding).min(height.saturating_sub(1));
+        for x in 0..padded_width {
+            let x = x.saturating_sub(padding).min(width.saturating_sub(1));
+            let i = (y * width + x) * bpp;
+            padded.extend_from_slice(pixels.get(i..i + bpp).unwrap_or(&[0; 4][..bpp]));
+        }
+    }
+    padded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::atlas::tests::device;
+
+    #[test]
+    #[ignore = "needs a GPU adapter"]
+    fn dropped_gray_images_free_their_place() {
+        let (device, queue) = device().expect("no GPU adapter");
+        let mut store = ImageStore::new(&device);
+        let mut staging_belt = wgpu::util::StagingBelt::new(1 << 16);
+        // Each image takes a whole shelf, so without releasing them the
+        // atlas would grow by one image per iteration.
+        let width = (IMAGE_ATLAS_WIDTH - 2 * IMAGE_PADDING) as usize;
+        let height = 30;
+        let mut heights = Vec::new();
+        for i in 0..64 {
+            let mut encoder = device.create_command_encoder(&Default::default());
+            let pixels = vec![i as u8; width * height];
+            let image = store
+                .insert(
+                    &device,
+                    &mut encoder,
+                    width,
+                    height,
+                    &pixels,
+                    ImageFormat::Grayscale,
+                )
+                .unwrap();
+            assert!(image.gray);
+            drop(image);
+            store.flush(&device, &mut staging_belt, &mut encoder);
+            staging_belt.finish();
+            queue.submit(Some(encoder.finish()));
+            let recall = staging_belt.recall();
+            device.poll(wgpu::Maintain::Wait);
+            futures::executor::block_on(recall);
+            heights.push(store.gray_atlas.allocator().height());
+        }
+        assert_eq!(heights.first(), heights.last());
+        assert!(store.gray_atlas.allocator().len() <= 2);
+
+        let mut encoder = device.create_command_encoder(&Default::default());
+        store.compact(&device, &mut encoder);
+        queue.submit(Some(encoder.finish()));
+        assert_eq!(store.gray_atlas.allocator().len(), 0);
+        assert_eq!(store.gray_atlas.allocator().used_height(), 0);
+    }
+}
@@ -1 +1,119 @@
+use std::ops::Range;
 
+use lyon::lyon_tessellation::VertexBuffers;
+use piet::kurbo::Size;
+
+use crate::backdrop::Backdrop;
+use crate::occlusion::Quad;
+use crate::pipeline::{GpuVertex, Primitive};
+
+/// Beyond this many scissored draws in a range, the extra draw calls cost
+/// more than the fragments they save, and the range is drawn at once.
+const MAX_SCISSOR_RUNS: usize = 256;
+
+/// One step of drawing a frame. Each command is recorded as its own
+/// render pass, so fewer commands means fewer passes and state changes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum DrawCommand {
+    /// Draw these indices of the geometry with the main pipeline.
+    Geometry(Range<u32>),
+    /// Blur the backdrop with this index.
+    Backdrop(usize),
+}
+
+/// What a named layer drew the last time it was recorded, replayed in
+/// later frames until the layer is invalidated. Vertices refer to the
+/// primitives from 0, indices to the vertices from 0, and backdrops and
+/// quads to the indices from 0.
+pub(crate) struct RecordedLayer {
+    pub(crate) vertices: Vec<GpuVertex>,
+    pub(crate) indices: Vec<u32>,
+    pub(crate) primitives: Vec<Primitive>,
+    pub(crate) backdrops: Vec<Backdrop>,
+    pub(crate) quads: Vec<Quad>,
+}
+
+impl RecordedLayer {
+    pub(crate) fn memory_bytes(&self) -> u64 {
+        (self.vertices.len() * std::mem::size_of::<GpuVertex>()
+            + self.indices.len() * std::mem::size_of::<u32>()
+            + self.primitives.len() * std::mem::size_of::<Primitive>()
+            + self.backdrops.len() * std::mem::size_of::<Backdrop>()
+            + self.quads.len() * std::mem::size_of::<Quad>()) as u64
+    }
+}
+
+/// Order the geometry and the backdrop blurs of a frame. Geometry has to
+/// stay in submission order for transparency, so only backdrops which
+/// don't draw anything are dropped, merging the geometry around them.
+pub(crate) fn command_list(
+    backdrops: &[Backdrop],
+    index_count: u32,
+    is_visible: impl Fn(usize) -> bool,
+) -> Vec<DrawCommand> {
+    let mut commands = Vec::with_capacity(backdrops.len() * 2 + 1);
+    let mut start = 0;
+    for (i, backdrop) in backdrops.iter().enumerate() {
+        if !is_visible(i) {
+            continue;
+        }
+        if start < backdrop.index {
+            commands.push(DrawCommand::Geometry(start..backdrop.index));
+        }
+        commands.push(DrawCommand::Backdrop(i));
+        start = backdrop.index;
+    }
+    if start < index_count {
+        commands.push(DrawCommand::Geometry(start..index_count));
+    }
+    commands
+}
+
+/// Split `range` of the geometry into runs of consecutive triangles whose
+/// primitives clip to the same rect, each with a scissor rect in physical
+/// pixels around that clip, so that fragments outside of it aren't shaded
+/// at all; the shader still does the exact test for rounded and
+/// antialiased edges. Runs are `None` when unclipped, and are dropped when
+/// clipped away entirely. Geometry stays in submission order for
+/// transparency, so only neighbouring triangles are merged, and the whole
+/// range comes back unscissored if that takes too many draws.
+pub(crate) fn scissor_runs(
+    range: Range<u32>,
+    geometry: &VertexBuffers<GpuVertex, u32>,
+    primitives: &[Primitive],
+    size: Size,
+    scale: f64,
+) -> Vec<(Range<u32>, Option<[u32; 4]>)> {
+    let scissor = |primitive: Option<&Primitive>| {
+        let primitive = primitive.filter(|p| p.clip > 0.0)?;
+        let [x0, y0, x1, y1] = primitive.clip_rect;
+        // Half a pixel more for the antialiased edge.
+        let x0 = (x0 as f64 * scale - 0.5).floor().clamp(0.0, size.width);
+        let y0 = (y0 as f64 * scale - 0.5).floor().clamp(0.0, size.height);
+        let x1 = (x1 as f64 * scale + 0.5).ceil().clamp(x0, size.width);
+        let y1 = (y1 as f64 * scale + 0.5).ceil().clamp(y0, size.height);
+        Some([x0 as u32, y0 as u32, (x1 - x0) as u32, (y1 - y0) as u32])
+    };
+
+    let mut runs: Vec<(Range<u32>, Option<[u32; 4]>)> = Vec::new();
+    let mut last_primitive = None;
+    let mut last_scissor = None;
+    for start in range.clone().step_by(3) {
+        let vertex = geometry.indices[start as usize] as usize;
+        let primitive_id = geometry.vertices[vertex].primitive_id;
+        if last_primitive != Some(primitive_id) {
+            last_primitive = Some(primitive_id);
+            last_scissor = scissor(primitives.get(primitive_id as usize));
+        }
+        let end = (start + 3).min(range.end);
+        match runs.last_mut() {
+            Some((run, run_scissor)) if *run_scissor == last_scissor => run.end = end,
+            _ => runs.push((start..end, last_scissor)),
+        }
+    }
+    runs.retain(|(_, scissor)| !matches!(scissor, Some([_, _, w, h]) if *w == 0 || *h == 0));
+    if runs.len() > MAX_SCISSOR_RUNS {
+        return vec![(range, None)];
+    }
+    runs
+}
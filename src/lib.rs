@@ -1,21 +1,64 @@
+mod atlas;
+mod backdrop;
+mod capture;
 mod context;
 mod font;
+mod fringe;
+mod gradient;
+mod image;
 mod layer;
+mod occlusion;
+mod path_cache;
 mod pipeline;
+#[cfg(feature = "svg-export")]
+mod recording;
 mod svg;
+#[cfg(feature = "testing")]
+pub mod testing;
 mod text;
 mod transformation;
 
+pub use font::{FaceInfo, FamilyInfo, FontId, FontStretch};
+use futures::task::{LocalSpawnExt, SpawnExt};
 use log::info;
 pub use piet::kurbo;
-use piet::kurbo::Size;
+use piet::kurbo::{Rect, Size};
 pub use piet::*;
-pub use svg::Svg;
+pub use atlas::AtlasStats;
+pub use capture::{CapturedDraw, DrawKind, RenderCapture};
+pub use context::{CaretShape, FocusRingStyle, GradientAxis, SvgScaling};
+pub use gradient::{GradientBrush, GradientSpace};
+pub use pipeline::{EmojiSource, TextCacheHandle};
+#[cfg(feature = "svg-export")]
+pub use recording::{RecordingContext, RecordingImage, TextMode};
+pub use svg::{Svg, SvgError};
+pub use usvg;
+use layer::RecordedLayer;
+use path_cache::PathCache;
 use svg::SvgStore;
 
-use std::{cell::RefCell, marker::PhantomData, rc::Rc};
+use std::{
+    cell::{RefCell, RefMut},
+    collections::HashMap,
+    marker::PhantomData,
+    path::Path,
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
-use context::{WgpuImage, WgpuRenderContext};
+use context::{FrameBuffers, WgpuRenderContext};
+use image::{ImageStore, WgpuImage};
+use pipeline::Viewport;
+pub use text::{
+    Affinity, Cell, EllipsisPosition, GlyphBitmap, GlyphContent, GlyphOutline, InlineBoxId, RegionId,
+    TextDirection, TextOverflow, WrapMode,
+};
+#[cfg(feature = "serde")]
+pub use text::LayoutData;
 use text::{WgpuText, WgpuTextLayout, WgpuTextLayoutBuilder};
 
 pub type Piet<'a> = WgpuRenderContext<'a>;
@@ -30,51 +73,438 @@ pub type PietTextLayoutBuilder = WgpuTextLayoutBuilder;
 
 pub type PietImage = WgpuImage;
 
-pub struct WgpuRenderer {
-    instance: wgpu::Instance,
+/// Records an embedder's passes into a frame, see
+/// [`WgpuRenderer::on_encode_frame`].
+type EncodeFrameCallback = dyn FnMut(&wgpu::Device, &mut wgpu::CommandEncoder, &wgpu::TextureView);
+
+/// What the GPU behind a [`WgpuRenderer`] supports, to pick atlas sizes
+/// and effects accordingly.
+#[derive(Clone, Debug)]
+pub struct RendererCaps {
+    pub adapter_name: String,
+    pub backend: wgpu::Backend,
+    /// The largest width or height of a texture, which also bounds the
+    /// glyph atlas.
+    pub max_texture_size: u32,
+    /// The multisample counts render targets can be created with.
+    pub sample_counts: Vec<u32>,
+    /// The multisample count the renderer draws with.
+    pub msaa_samples: u32,
+    /// Whether blending can use a second fragment output, e.g. for
+    /// subpixel text. Not available through wgpu yet, so always `false`.
+    pub dual_source_blending: bool,
+}
+
+/// How long a frame took, reported through [`WgpuRenderer::on_frame`].
+#[derive(Clone, Copy, Debug)]
+pub struct FrameTiming {
+    /// From creating the render context to submitting the frame.
+    pub cpu: Duration,
+    /// From submitting the frame to the GPU finishing it.
+    pub gpu: Duration,
+}
+
+/// The memory a [`WgpuRenderer`] holds on to between frames, in bytes,
+/// from [`WgpuRenderer::memory_usage`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MemoryReport {
+    /// The glyph atlas texture and its upload buffer.
+    pub atlas: u64,
+    /// The image atlas texture and its upload buffer.
+    pub images: u64,
+    /// Rasterized glyphs, kept to fill the atlases of every renderer
+    /// sharing the [`TextCacheHandle`].
+    pub glyph_bitmaps: u64,
+    /// The buffers the geometry of a frame is built in and uploaded to.
+    pub buffers: u64,
+    /// Tessellated paths and svgs, and recorded layers.
+    pub tessellation_cache: u64,
+    /// Shaped text runs and glyph advances.
+    pub layout_cache: u64,
+}
+
+impl MemoryReport {
+    pub fn total(&self) -> u64 {
+        self.atlas
+            + self.images
+            + self.glyph_bitmaps
+            + self.buffers
+            + self.tessellation_cache
+            + self.layout_cache
+    }
+}
+
+/// How much [`WgpuRenderer::trim_memory`] drops.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TrimLevel {
+    /// The tessellated paths and shaped text runs, which are quick to
+    /// build again.
+    Moderate,
+    /// Everything that can be rebuilt: also svgs, layers and rasterized
+    /// glyphs, and the geometry buffers shrink to what the next frame
+    /// needs.
+    Critical,
+}
+
+/// How [`WgpuRenderer::new_from_window`] sets a renderer up.
+#[derive(Clone)]
+pub struct RendererConfig {
+    /// The size of the window in physical pixels.
+    pub size: Size,
+    /// The scale factor of the window.
+    pub scale: f64,
+    /// The swap interval, see [`WgpuRenderer::set_present_mode`].
+    pub present_mode: wgpu::PresentMode,
+    /// The graphics APIs to pick a GPU from, by default those named by
+    /// the `WGPU_BACKEND` environment variable, or else all of them.
+    pub backends: wgpu::Backends,
+    /// Whether to prefer an integrated or discrete GPU.
+    pub power_preference: wgpu::PowerPreference,
+    /// See [`WgpuRenderer::set_fringe_aa`].
+    pub fringe_aa: bool,
+    /// See [`WgpuRenderer::set_premultiplied_alpha`].
+    pub premultiplied_alpha: bool,
+    /// The font and glyph cache to share with other renderers, or `None`
+    /// for a new one.
+    pub text_cache: Option<TextCacheHandle>,
+}
+
+impl Default for RendererConfig {
+    fn default() -> Self {
+        Self {
+            size: Size::ZERO,
+            scale: 1.0,
+            present_mode: wgpu::PresentMode::Fifo,
+            backends: wgpu::util::backend_bits_from_env().unwrap_or_else(wgpu::Backends::all),
+            power_preference: wgpu::PowerPreference::HighPerformance,
+            fringe_aa: false,
+            premultiplied_alpha: false,
+            text_cache: None,
+        }
+    }
+}
+
+/// The part of a renderer that windows drawing with the same GPU can
+/// share: the device and queue, the pipelines, and the glyph, image, svg
+/// and path caches. Text, images and svgs made for one window can be drawn
+/// in all of them, and nothing is loaded or compiled twice. Each
+/// [`WgpuRenderer`] sharing a core keeps its own surface, size and scale.
+///
+/// Make the first renderer as usual, and the others with
+/// [`WgpuRenderer::new_with_core`] and its [`WgpuRenderer::core`].
+///
+/// # Synchronization
+///
+/// - The renderers sharing a core live on one thread; the core isn't
+///   `Send`.
+/// - Their frames may be drawn at the same time, but go to the GPU one at
+///   a time, in the order they are finished. Glyphs and images added while
+///   drawing any of them are uploaded by the next frame to finish, before
+///   it draws anything.
+/// - Text is laid out at one scale per core. A frame at another scale than
+///   the one before throws away the shaped text of the core's
+///   [`WgpuText`], so windows on screens of different scales are better
+///   off with cores of their own, sharing only fonts and glyphs through a
+///   [`TextCacheHandle`].
+/// - Every window takes the texture format of the core;
+///   [`WgpuRenderer::new_with_core`] fails for one that can't.
+/// - A lost device is lost for every renderer sharing it. Rebuild one with
+///   [`WgpuRenderer::recreate`] and the others on its new core with
+///   [`WgpuRenderer::recreate_with_core`].
+#[derive(Clone)]
+pub struct RendererCore {
+    instance: Rc<wgpu::Instance>,
+    adapter: Rc<wgpu::Adapter>,
     device: Rc<wgpu::Device>,
-    surface: wgpu::Surface,
-    queue: wgpu::Queue,
+    queue: Rc<wgpu::Queue>,
     format: wgpu::TextureFormat,
+    capabilities: Rc<RendererCaps>,
+    /// Set when the device is gone and the renderers must be rebuilt with
+    /// [`WgpuRenderer::recreate`].
+    lost: Arc<AtomicBool>,
     staging_belt: Rc<RefCell<wgpu::util::StagingBelt>>,
+    /// Uploads recorded since the last frame, which submits them.
+    encoder: Rc<RefCell<Option<wgpu::CommandEncoder>>>,
+    text: WgpuText,
+    state: Rc<RefCell<CoreState>>,
+}
+
+/// The part of a [`RendererCore`] its renderers take turns changing.
+pub(crate) struct CoreState {
+    pub(crate) pipeline: pipeline::Pipeline,
+    pub(crate) images: ImageStore,
+    pub(crate) svg_store: SvgStore,
+    pub(crate) path_cache: PathCache,
     local_pool: futures::executor::LocalPool,
+}
+
+impl RendererCore {
+    fn new(
+        instance: wgpu::Instance,
+        adapter: wgpu::Adapter,
+        format: wgpu::TextureFormat,
+        text_cache: TextCacheHandle,
+    ) -> Result<Self, piet::Error> {
+        info!("{:?}", adapter.get_info());
+
+        let (device, queue) = futures::executor::block_on(
+            adapter.request_device(&wgpu::DeviceDescriptor::default(), None),
+        )
+        .map_err(|e| piet::Error::BackendError(Box::new(e)))?;
+
+        let adapter_info = adapter.get_info();
+        let capabilities = RendererCaps {
+            adapter_name: adapter_info.name,
+            backend: adapter_info.backend,
+            max_texture_size: device.limits().max_texture_dimension_2d,
+            // WebGPU guarantees these for every renderable format, and
+            // wgpu can't be asked for more yet.
+            sample_counts: vec![1, 4],
+            msaa_samples: 4,
+            dual_source_blending: false,
+        };
+
+        let lost = Arc::new(AtomicBool::new(false));
+        {
+            let lost = lost.clone();
+            device.on_uncaptured_error(move |e| {
+                log::error!("wgpu error: {}", e);
+                if is_device_lost(&e) {
+                    lost.store(true, Ordering::SeqCst);
+                } else {
+                    // Anything else is a bug in the renderer.
+                    debug_assert!(false, "wgpu validation error: {}", e);
+                }
+            });
+        }
+
+        let staging_belt = Rc::new(RefCell::new(wgpu::util::StagingBelt::new(1024)));
+        let encoder = Rc::new(RefCell::new(None));
+        let device = Rc::new(device);
+        let text = WgpuText::new(device.clone(), encoder.clone(), text_cache);
+        let images = ImageStore::new(&device);
+        let pipeline = pipeline::Pipeline::new(&device, format, &text.cache.borrow(), &images);
+
+        Ok(Self {
+            instance: Rc::new(instance),
+            adapter: Rc::new(adapter),
+            device,
+            queue: Rc::new(queue),
+            format,
+            capabilities: Rc::new(capabilities),
+            lost,
+            staging_belt,
+            encoder,
+            text,
+            state: Rc::new(RefCell::new(CoreState {
+                pipeline,
+                images,
+                svg_store: SvgStore::new(),
+                path_cache: PathCache::new(),
+                local_pool: futures::executor::LocalPool::new(),
+            })),
+        })
+    }
+
+    /// Borrow the pipelines and caches. Not to be held across calls which
+    /// might borrow them again.
+    pub(crate) fn state(&self) -> RefMut<'_, CoreState> {
+        self.state.borrow_mut()
+    }
+
+    /// Lay text out at `scale` from now on.
+    fn set_text_scale(&self, scale: f64) {
+        let mut cache = self.text.cache.borrow_mut();
+        if cache.scale != scale {
+            cache.scale = scale;
+            drop(cache);
+            // Shaped runs hold glyphs rasterized for the old scale.
+            self.text.clear_shape_cache();
+        }
+    }
+
+    pub(crate) fn ensure_encoder(&self) {
+        let mut encoder = self.encoder.borrow_mut();
+        if encoder.is_none() {
+            *encoder = Some(
+                self.device
+                    .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                        label: Some("render"),
+                    }),
+            );
+        }
+    }
+
+    pub(crate) fn take_encoder(&self) -> wgpu::CommandEncoder {
+        self.encoder.take().unwrap()
+    }
+}
+
+/// Whether `error` leaves the device unusable, which a renderer recovers
+/// from by being rebuilt, rather than being a bug: the device ran out of
+/// memory or was lost. wgpu reports a lost device as a validation error
+/// caused by it, whose type it doesn't export.
+fn is_device_lost(error: &wgpu::Error) -> bool {
+    match error {
+        wgpu::Error::OutOfMemory { .. } => true,
+        wgpu::Error::Validation { source, .. } => {
+            let mut cause: Option<&(dyn std::error::Error + 'static)> = Some(&**source);
+            while let Some(e) = cause {
+                if e.to_string() == "parent device is lost" {
+                    return true;
+                }
+                cause = e.source();
+            }
+            false
+        }
+    }
+}
+
+/// What a [`WgpuRenderer`] draws into: a window surface, or a texture for
+/// headless renderers, along with the multisampled frame resolved into it.
+pub(crate) struct Surface {
+    /// The window surface, or `None` for headless renderers, which draw
+    /// into `offscreen` instead.
+    window: Option<wgpu::Surface>,
+    offscreen: Option<wgpu::Texture>,
     msaa: wgpu::TextureView,
-    size: Size,
-    svg_store: SvgStore,
+    /// The size in physical pixels.
+    pub(crate) size: Size,
+    pub(crate) scale: f64,
+    /// Whether frames hold premultiplied alpha, for external compositors.
+    premultiplied: bool,
+    present_mode: wgpu::PresentMode,
+}
 
-    text: WgpuText,
+impl Surface {
+    pub(crate) fn viewport(&self) -> Viewport {
+        Viewport {
+            size: self.size,
+            scale: self.scale,
+            premultiplied: self.premultiplied,
+        }
+    }
+}
+
+pub struct WgpuRenderer {
+    core: RendererCore,
+    pub(crate) surface: Surface,
+    /// Named layers recorded in earlier frames, see
+    /// [`WgpuRenderContext::layer`].
+    layers: HashMap<String, RecordedLayer>,
 
-    pipeline: pipeline::Pipeline,
-    pub(crate) encoder: Rc<RefCell<Option<wgpu::CommandEncoder>>>,
+    lost_notified: bool,
+    on_lost: Option<Box<dyn FnMut()>>,
+
+    /// Whether tessellated shapes get an antialiasing fringe.
+    pub(crate) fringe_aa: bool,
+    /// Whether svgs that can't be drawn leave a magenta box instead.
+    pub(crate) svg_placeholders: bool,
+    on_frame: Option<Box<dyn FnMut(FrameTiming)>>,
+    on_encode_frame: Option<Box<EncodeFrameCallback>>,
+    /// The buffers of the last frame, for the next one to reuse.
+    frame_buffers: Option<FrameBuffers>,
+    /// Timings of frames the GPU finished, waiting to be reported.
+    frame_timings: Rc<RefCell<Vec<FrameTiming>>>,
 }
 
 impl WgpuRenderer {
     pub fn new<W: raw_window_handle::HasRawWindowHandle>(window: &W) -> Result<Self, piet::Error> {
-        let backend = wgpu::util::backend_bits_from_env().unwrap_or_else(wgpu::Backends::all);
-        let instance = wgpu::Instance::new(backend);
+        Self::new_with_text_cache(window, TextCacheHandle::new())
+    }
+
+    /// Create a renderer which loads fonts and rasterizes glyphs through
+    /// `text_cache`, shared with the other renderers created from it.
+    pub fn new_with_text_cache<W: raw_window_handle::HasRawWindowHandle>(
+        window: &W,
+        text_cache: TextCacheHandle,
+    ) -> Result<Self, piet::Error> {
+        Self::new_from_window(
+            window,
+            RendererConfig {
+                text_cache: Some(text_cache),
+                ..RendererConfig::default()
+            },
+        )
+    }
+
+    /// Create a renderer for `window`, set up by `config` and ready to
+    /// draw a first frame at its size. After that, pass size and scale
+    /// changes of the window to [`WgpuRenderer::resize`].
+    pub fn new_from_window<W: raw_window_handle::HasRawWindowHandle>(
+        window: &W,
+        config: RendererConfig,
+    ) -> Result<Self, piet::Error> {
+        let instance = wgpu::Instance::new(config.backends);
         let surface = unsafe { instance.create_surface(window) };
         let adapter =
             futures::executor::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
-                power_preference: wgpu::PowerPreference::HighPerformance,
+                power_preference: config.power_preference,
                 compatible_surface: Some(&surface),
                 force_fallback_adapter: false,
             }))
             .ok_or(piet::Error::NotSupported)?;
-        info!("{:?}", adapter.get_info());
-
-        let (device, queue) = futures::executor::block_on(
-            adapter.request_device(&wgpu::DeviceDescriptor::default(), None),
-        )
-        .map_err(|e| piet::Error::BackendError(Box::new(e)))?;
-
         let format = surface
             .get_preferred_format(&adapter)
             .ok_or(piet::Error::MissingFeature("no supported texture format"))?;
 
-        let staging_belt = wgpu::util::StagingBelt::new(1024);
-        let local_pool = futures::executor::LocalPool::new();
+        let text_cache = config.text_cache.unwrap_or_default();
+        let core = RendererCore::new(instance, adapter, format, text_cache)?;
+        let mut renderer = Self::from_core(core, Some(surface));
+        renderer.surface.present_mode = config.present_mode;
+        renderer.surface.premultiplied = config.premultiplied_alpha;
+        renderer.fringe_aa = config.fringe_aa;
+        renderer.resize(config.size, config.scale);
+        Ok(renderer)
+    }
+
+    /// Create a renderer for another window which shares the device,
+    /// pipelines and caches of `core`, from [`WgpuRenderer::core`]. See
+    /// [`RendererCore`] for what the renderers sharing it must keep to.
+    pub fn new_with_core<W: raw_window_handle::HasRawWindowHandle>(
+        window: &W,
+        core: &RendererCore,
+    ) -> Result<Self, piet::Error> {
+        let surface = unsafe { core.instance.create_surface(window) };
+        if !core.adapter.is_surface_supported(&surface) {
+            return Err(piet::Error::NotSupported);
+        }
+        if surface.get_preferred_format(&core.adapter) != Some(core.format) {
+            return Err(piet::Error::MissingFeature(
+                "the texture format of the shared renderer",
+            ));
+        }
+        Ok(Self::from_core(core.clone(), Some(surface)))
+    }
+
+    /// Create a renderer without a window, which draws into a texture of
+    /// `width` by `height` physical pixels that [`WgpuRenderer::read_pixels`]
+    /// reads back, e.g. for image based tests in CI.
+    #[cfg(feature = "headless")]
+    pub fn new_headless(width: u32, height: u32) -> Result<Self, piet::Error> {
+        let backend = wgpu::util::backend_bits_from_env().unwrap_or_else(wgpu::Backends::all);
+        let instance = wgpu::Instance::new(backend);
+        let adapter =
+            futures::executor::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::default(),
+                compatible_surface: None,
+                force_fallback_adapter: false,
+            }))
+            .ok_or(piet::Error::NotSupported)?;
+
+        let core = RendererCore::new(
+            instance,
+            adapter,
+            wgpu::TextureFormat::Rgba8UnormSrgb,
+            TextCacheHandle::new(),
+        )?;
+        let mut renderer = Self::from_core(core, None);
+        renderer.set_size(Size::new(width as f64, height as f64));
+        Ok(renderer)
+    }
 
-        let msaa_texture = device.create_texture(&wgpu::TextureDescriptor {
+    fn from_core(core: RendererCore, window: Option<wgpu::Surface>) -> Self {
+        let msaa_texture = core.device.create_texture(&wgpu::TextureDescriptor {
             label: Some("Multisampled frame descriptor"),
             size: wgpu::Extent3d {
                 width: 1,
@@ -84,45 +514,550 @@ impl WgpuRenderer {
             mip_level_count: 1,
             sample_count: 4,
             dimension: wgpu::TextureDimension::D2,
-            format,
+            format: core.format,
             usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
         });
         let msaa = msaa_texture.create_view(&wgpu::TextureViewDescriptor::default());
 
-        let staging_belt = Rc::new(RefCell::new(staging_belt));
-        let encoder = Rc::new(RefCell::new(None));
-        let device = Rc::new(device);
-        let text = WgpuText::new(device.clone(), staging_belt.clone(), encoder.clone());
-        let pipeline = pipeline::Pipeline::new(&device, format, &text.cache.borrow());
+        Self {
+            core,
+            surface: Surface {
+                window,
+                offscreen: None,
+                msaa,
+                size: Size::ZERO,
+                scale: 1.0,
+                premultiplied: false,
+                present_mode: wgpu::PresentMode::Fifo,
+            },
+            layers: HashMap::new(),
+            lost_notified: false,
+            on_lost: None,
+            fringe_aa: false,
+            svg_placeholders: cfg!(debug_assertions),
+            on_frame: None,
+            on_encode_frame: None,
+            frame_buffers: None,
+            frame_timings: Rc::new(RefCell::new(Vec::new())),
+        }
+    }
 
-        Ok(Self {
-            instance,
-            device,
-            queue,
-            surface,
-            text,
-            size: Size::ZERO,
-            format,
-            staging_belt,
-            local_pool,
-            msaa,
-            pipeline,
-            svg_store: SvgStore::new(),
-            encoder,
-        })
+    /// The device, pipelines and caches of this renderer, to pass to
+    /// [`WgpuRenderer::new_with_core`] for other windows.
+    pub fn core(&self) -> RendererCore {
+        self.core.clone()
     }
 
-    pub fn set_size(&mut self, size: Size) {
-        self.size = size;
+    /// Rebuild the device, surface, pipelines and caches after the GPU
+    /// context has been lost, keeping the current size and scale.
+    ///
+    /// Text layouts and the `WgpuText` handed out by [`WgpuRenderer::text`]
+    /// before the call refer to the old glyph cache and must be rebuilt.
+    pub fn recreate<W: raw_window_handle::HasRawWindowHandle>(
+        &mut self,
+        window: &W,
+    ) -> Result<(), piet::Error> {
+        let text_cache = self.text_cache();
+        let renderer = Self::new_with_text_cache(window, text_cache)?;
+        self.replace(renderer);
+        Ok(())
+    }
+
+    /// Rebuild the surface on `core` after the GPU context has been lost,
+    /// once another renderer that shared the lost one has been rebuilt
+    /// with [`WgpuRenderer::recreate`]. Like it, keeps the current size and
+    /// scale.
+    pub fn recreate_with_core<W: raw_window_handle::HasRawWindowHandle>(
+        &mut self,
+        window: &W,
+        core: &RendererCore,
+    ) -> Result<(), piet::Error> {
+        let renderer = Self::new_with_core(window, core)?;
+        self.replace(renderer);
+        Ok(())
+    }
+
+    /// Become `renderer`, keeping the settings and callbacks of this one.
+    fn replace(&mut self, renderer: Self) {
+        let size = self.surface.size;
+        let scale = self.surface.scale;
+        let on_lost = self.on_lost.take();
+        let on_frame = self.on_frame.take();
+        let on_encode_frame = self.on_encode_frame.take();
+        let present_mode = self.surface.present_mode;
+        let fringe_aa = self.fringe_aa;
+        let svg_placeholders = self.svg_placeholders;
+        let premultiplied = self.surface.premultiplied;
+
+        *self = renderer;
+        self.on_lost = on_lost;
+        self.on_frame = on_frame;
+        self.on_encode_frame = on_encode_frame;
+        self.surface.present_mode = present_mode;
+        self.fringe_aa = fringe_aa;
+        self.svg_placeholders = svg_placeholders;
+        self.surface.premultiplied = premultiplied;
+        self.set_size(size);
+        self.set_scale(scale);
+    }
+
+    /// The limits and features of the GPU this renderer runs on.
+    pub fn capabilities(&self) -> &RendererCaps {
+        &self.core.capabilities
+    }
+
+    /// Whether the GPU context has been lost, or has run out of memory.
+    /// Rendering is skipped until [`WgpuRenderer::recreate`] is called.
+    /// Other wgpu errors are bugs, which panic in debug builds.
+    pub fn is_lost(&self) -> bool {
+        self.core.lost.load(Ordering::SeqCst)
+    }
+
+    /// Register a callback which is invoked once when a context loss is
+    /// detected, so the embedder can schedule a [`WgpuRenderer::recreate`].
+    pub fn on_lost(&mut self, f: impl FnMut() + 'static) {
+        self.on_lost = Some(Box::new(f));
+    }
+
+    pub(crate) fn mark_lost(&mut self) {
+        self.core.lost.store(true, Ordering::SeqCst);
+        if !self.lost_notified {
+            self.lost_notified = true;
+            log::warn!("wgpu context lost");
+            if let Some(on_lost) = self.on_lost.as_mut() {
+                on_lost();
+            }
+        }
+    }
+
+    pub(crate) fn configure_surface(&self) {
         let sc_desc = wgpu::SurfaceConfiguration {
             usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
-            format: self.format,
-            width: size.width as u32,
-            height: size.height as u32,
-            present_mode: wgpu::PresentMode::Fifo,
+            format: self.core.format,
+            width: self.surface.size.width as u32,
+            height: self.surface.size.height as u32,
+            present_mode: self.surface.present_mode,
+        };
+        if let Some(surface) = self.surface.window.as_ref() {
+            surface.configure(&self.core.device, &sc_desc);
+        }
+    }
+
+    /// How frames are synchronized with the display: `Fifo`, the default,
+    /// waits for vertical blanks; `Mailbox` and `Immediate` lower the
+    /// latency, the latter at the cost of tearing. Backends without the mode
+    /// fall back to `Fifo`.
+    pub fn set_present_mode(&mut self, present_mode: wgpu::PresentMode) {
+        self.surface.present_mode = present_mode;
+        if self.has_area() {
+            self.configure_surface();
+        }
+    }
+
+    pub fn present_mode(&self) -> wgpu::PresentMode {
+        self.surface.present_mode
+    }
+
+    /// Antialias the edges of tessellated strokes and transformed rects
+    /// with a strip a physical pixel wide fading out around them, like
+    /// NanoVG does, for platforms where multisampling is missing or slow.
+    /// Circles, glyphs and hairlines are antialiased in the shader anyway.
+    /// Off by default.
+    pub fn set_fringe_aa(&mut self, fringe_aa: bool) {
+        self.fringe_aa = fringe_aa;
+    }
+
+    pub fn fringe_aa(&self) -> bool {
+        self.fringe_aa
+    }
+
+    /// Fill the rect of an svg that can't be drawn with magenta, so
+    /// missing icons stand out. The failure is reported by
+    /// [`status`](piet::RenderContext::status) either way. On in debug
+    /// builds.
+    pub fn set_svg_placeholders(&mut self, svg_placeholders: bool) {
+        self.svg_placeholders = svg_placeholders;
+    }
+
+    pub fn svg_placeholders(&self) -> bool {
+        self.svg_placeholders
+    }
+
+    /// Render frames with premultiplied alpha, starting out transparent
+    /// rather than white, for an external compositor such as a layer-shell
+    /// surface or a game engine to blend over what is behind them. The
+    /// surface itself has to be set up for it by the platform, wgpu can't
+    /// pick its alpha mode yet. Off by default.
+    pub fn set_premultiplied_alpha(&mut self, premultiplied: bool) {
+        self.surface.premultiplied = premultiplied;
+    }
+
+    pub fn premultiplied_alpha(&self) -> bool {
+        self.surface.premultiplied
+    }
+
+    /// The device the renderer draws with, for embedders creating their own
+    /// pipelines and resources to draw with in
+    /// [`WgpuRenderer::on_encode_frame`].
+    pub fn device(&self) -> &wgpu::Device {
+        &self.core.device
+    }
+
+    pub fn queue(&self) -> &wgpu::Queue {
+        &self.core.queue
+    }
+
+    /// Register a callback which records the embedder's own passes into
+    /// every frame, after everything the renderer drew and before the
+    /// frame is presented, e.g. for a 3D viewport or another engine's
+    /// output on top of the UI. The target has the renderer's texture
+    /// format and is a single sample.
+    ///
+    /// The renderer doesn't rely on any state carried between passes:
+    /// each of its passes loads the target and sets its own pipeline, bind
+    /// groups, buffers and scissor. So the callback needn't restore
+    /// anything, as long as it leaves the renderer's buffers and textures
+    /// alone and only submits through the encoder it is given.
+    pub fn on_encode_frame(
+        &mut self,
+        f: impl FnMut(&wgpu::Device, &mut wgpu::CommandEncoder, &wgpu::TextureView) + 'static,
+    ) {
+        self.on_encode_frame = Some(Box::new(f));
+    }
+
+    /// Record the passes of [`WgpuRenderer::on_encode_frame`] into a frame.
+    pub(crate) fn encode_frame(
+        &mut self,
+        encoder: &mut wgpu::CommandEncoder,
+        view: &wgpu::TextureView,
+    ) {
+        if let Some(on_encode_frame) = self.on_encode_frame.as_mut() {
+            on_encode_frame(&self.core.device, encoder, view);
+        }
+    }
+
+    /// Register a callback which receives the timing of every frame, for
+    /// measuring frame times and latency. A frame is reported during one of
+    /// the next frames, once the GPU has finished it.
+    pub fn on_frame(&mut self, f: impl FnMut(FrameTiming) + 'static) {
+        self.on_frame = Some(Box::new(f));
+    }
+
+    /// Start timing the frame that was just submitted, recorded since
+    /// `started`, if anyone is listening.
+    pub(crate) fn track_frame(&mut self, started: Instant) {
+        if self.on_frame.is_none() {
+            return;
+        }
+        let cpu = started.elapsed();
+        let submitted = Instant::now();
+        let done = self.core.queue.on_submitted_work_done();
+        let timings = self.frame_timings.clone();
+        self.core
+            .state()
+            .local_pool
+            .spawner()
+            .spawn_local(async move {
+                done.await;
+                timings.borrow_mut().push(FrameTiming {
+                    cpu,
+                    gpu: submitted.elapsed(),
+                });
+            })
+            .expect("Track frame");
+    }
+
+    /// Recall the staging belt of the frame that was just submitted, and
+    /// hand the timings of the frames the GPU finished to the callback.
+    pub(crate) fn finish_frame(&mut self) {
+        {
+            let mut state = self.core.state();
+            state
+                .local_pool
+                .spawner()
+                .spawn(self.core.staging_belt.borrow_mut().recall())
+                .expect("Recall staging belt");
+            state.local_pool.run_until_stalled();
+        }
+        self.report_frames();
+    }
+
+    /// Hand the timings of the frames the GPU finished to the callback.
+    fn report_frames(&mut self) {
+        if self.on_frame.is_none() {
+            return;
+        }
+        self.core.device.poll(wgpu::Maintain::Poll);
+        self.core.state().local_pool.run_until_stalled();
+        let timings = std::mem::take(&mut *self.frame_timings.borrow_mut());
+        if let Some(on_frame) = self.on_frame.as_mut() {
+            for timing in timings {
+                on_frame(timing);
+            }
+        }
+    }
+
+    /// The texture to draw the next frame into, along with the surface
+    /// frame to present afterwards when there is a window.
+    pub(crate) fn acquire_frame(
+        &mut self,
+    ) -> Result<(Option<wgpu::SurfaceTexture>, wgpu::TextureView), piet::Error> {
+        let surface = match self.surface.window.as_ref() {
+            Some(surface) => surface,
+            None => {
+                let texture = self
+                    .surface
+                    .offscreen
+                    .as_ref()
+                    .ok_or(piet::Error::InvalidInput)?;
+                let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+                return Ok((None, view));
+            }
+        };
+        let texture = match surface.get_current_texture() {
+            Ok(texture) => texture,
+            Err(wgpu::SurfaceError::Outdated) => {
+                self.configure_surface();
+                self.surface
+                    .window
+                    .as_ref()
+                    .unwrap()
+                    .get_current_texture()
+                    .map_err(|e| piet::Error::BackendError(Box::new(e)))?
+            }
+            Err(e @ wgpu::SurfaceError::Lost) | Err(e @ wgpu::SurfaceError::OutOfMemory) => {
+                self.mark_lost();
+                return Err(piet::Error::BackendError(Box::new(e)));
+            }
+            Err(e) => return Err(piet::Error::BackendError(Box::new(e))),
+        };
+        let view = texture
+            .texture
+            .create_view(&wgpu::TextureViewDescriptor::default());
+        Ok((Some(texture), view))
+    }
+
+    /// Read back the last frame of a headless renderer as tightly packed
+    /// RGBA rows, top row first.
+    #[cfg(feature = "headless")]
+    pub fn read_pixels(&self) -> Result<Vec<u8>, piet::Error> {
+        let texture = self
+            .surface
+            .offscreen
+            .as_ref()
+            .ok_or(piet::Error::NotSupported)?;
+        self.read_texture(texture)
+    }
+
+    /// Read back the last frame, e.g. for a "copy as image" feature. On a
+    /// window the frame is taken from the multisampled frame it was
+    /// resolved from, since presented surface textures can't be read.
+    pub fn capture_frame(&self) -> Result<ImageBuf, piet::Error> {
+        if !self.has_area() {
+            return Err(piet::Error::InvalidInput);
+        }
+        let pixels = match self.surface.offscreen.as_ref() {
+            Some(texture) => self.read_texture(texture)?,
+            None => {
+                let texture = self.core.device.create_texture(&wgpu::TextureDescriptor {
+                    label: Some("captured frame"),
+                    size: wgpu::Extent3d {
+                        width: self.surface.size.width as u32,
+                        height: self.surface.size.height as u32,
+                        depth_or_array_layers: 1,
+                    },
+                    mip_level_count: 1,
+                    sample_count: 1,
+                    dimension: wgpu::TextureDimension::D2,
+                    format: self.core.format,
+                    usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+                });
+                let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+                let mut encoder =
+                    self.core
+                        .device
+                        .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                            label: Some("capture frame"),
+                        });
+                // A pass without draws still resolves the multisampled frame.
+                let _ = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                    label: Some("capture frame"),
+                    color_attachments: &[wgpu::RenderPassColorAttachment {
+                        view: &self.surface.msaa,
+                        resolve_target: Some(&view),
+                        ops: wgpu::Operations {
+                            load: wgpu::LoadOp::Load,
+                            store: true,
+                        },
+                    }],
+                    depth_stencil_attachment: None,
+                });
+                self.core.queue.submit(Some(encoder.finish()));
+                self.read_texture(&texture)?
+            }
+        };
+        let format = if self.surface.premultiplied {
+            ImageFormat::RgbaPremul
+        } else {
+            ImageFormat::RgbaSeparate
         };
-        self.surface.configure(&self.device, &sc_desc);
-        let msaa_texture = self.device.create_texture(&wgpu::TextureDescriptor {
+        Ok(ImageBuf::from_raw(
+            pixels,
+            format,
+            self.surface.size.width as usize,
+            self.surface.size.height as usize,
+        ))
+    }
+
+    /// Write the last frame to a PNG file at `path`, see
+    /// [`WgpuRenderer::capture_frame`].
+    pub fn capture_frame_to_png(&self, path: impl AsRef<Path>) -> Result<(), piet::Error> {
+        let frame = self.capture_frame()?;
+        // PNGs hold straight alpha.
+        let pixels = image::to_rgba(frame.raw_pixels(), frame.format())?;
+        image::encode_png(frame.width() as u32, frame.height() as u32, &pixels)
+            .and_then(|png| std::fs::write(path, png))
+            .map_err(|e| piet::Error::BackendError(Box::new(e)))
+    }
+
+    /// Copy `texture`, the size of the frame, into tightly packed RGBA
+    /// rows, top row first.
+    fn read_texture(&self, texture: &wgpu::Texture) -> Result<Vec<u8>, piet::Error> {
+        let width = self.surface.size.width as u32;
+        let height = self.surface.size.height as u32;
+        let mut pixels = self.read_texture_texels(texture, width, height, 4)?;
+        if matches!(
+            self.core.format,
+            wgpu::TextureFormat::Bgra8Unorm | wgpu::TextureFormat::Bgra8UnormSrgb
+        ) {
+            for pixel in pixels.chunks_mut(4) {
+                pixel.swap(0, 2);
+            }
+        }
+        Ok(pixels)
+    }
+
+    /// Copy the top left `width` by `height` texels of `texture`, of
+    /// `bytes_per_pixel` each, into tightly packed rows, top row first.
+    fn read_texture_texels(
+        &self,
+        texture: &wgpu::Texture,
+        width: u32,
+        height: u32,
+        bytes_per_pixel: usize,
+    ) -> Result<Vec<u8>, piet::Error> {
+        let row_bytes = width as usize * bytes_per_pixel;
+        let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT as usize;
+        let padded_row_bytes = row_bytes + (align - row_bytes % align) % align;
+
+        let buffer = self.core.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("read pixels"),
+            size: (padded_row_bytes * height as usize) as u64,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+        let mut encoder =
+            self.core
+                .device
+                .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                    label: Some("read pixels"),
+                });
+        encoder.copy_texture_to_buffer(
+            texture.as_image_copy(),
+            wgpu::ImageCopyBuffer {
+                buffer: &buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: std::num::NonZeroU32::new(padded_row_bytes as u32),
+                    rows_per_image: std::num::NonZeroU32::new(height),
+                },
+            },
+            wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+        );
+        self.core.queue.submit(Some(encoder.finish()));
+
+        let slice = buffer.slice(..);
+        let mapped = slice.map_async(wgpu::MapMode::Read);
+        self.core.device.poll(wgpu::Maintain::Wait);
+        futures::executor::block_on(mapped).map_err(|e| piet::Error::BackendError(Box::new(e)))?;
+
+        let data = slice.get_mapped_range();
+        let mut pixels = Vec::with_capacity(row_bytes * height as usize);
+        for row in data.chunks(padded_row_bytes) {
+            pixels.extend_from_slice(&row[..row_bytes]);
+        }
+        drop(data);
+        buffer.unmap();
+        Ok(pixels)
+    }
+
+    /// Write the glyph atlas to a PNG file at `path`, for debugging glyph
+    /// placement: coverage in white, with a grid every 64 texels, the top
+    /// of every shelf in red and every glyph outlined in green. Glyphs
+    /// rasterized since the last frame aren't uploaded yet and show as
+    /// empty outlines.
+    pub fn dump_atlas(&self, path: impl AsRef<Path>) -> Result<(), piet::Error> {
+        let cache = self.core.text.cache.borrow();
+        let atlas = cache.atlas();
+        let allocator = atlas.allocator();
+        let (width, height) = (allocator.width(), allocator.height());
+        let texels = self.read_texture_texels(
+            atlas.texture(),
+            width,
+            height,
+            atlas::bytes_per_pixel(atlas.format()),
+        )?;
+        let pixels = allocator.annotate(&texels);
+        image::encode_png(width, height, &pixels)
+            .and_then(|png| std::fs::write(path, png))
+            .map_err(|e| piet::Error::BackendError(Box::new(e)))
+    }
+
+    /// Update the size in physical pixels and the scale together, e.g. on a
+    /// window resize, so that no frame is drawn with one but not the other.
+    /// Surface and textures are only recreated when the size changed.
+    pub fn resize(&mut self, size: Size, scale: f64) {
+        self.set_scale(scale);
+        if size != self.surface.size {
+            self.set_size(size);
+        }
+    }
+
+    /// Whether there are pixels to draw into. Minimized windows are 0x0,
+    /// and frames are skipped until they have a size again.
+    pub fn has_area(&self) -> bool {
+        self.surface.size.width >= 1.0 && self.surface.size.height >= 1.0
+    }
+
+    pub fn set_size(&mut self, size: Size) {
+        self.surface.size = size;
+        // Surfaces and textures can't be empty; keep the old ones around
+        // until there is an area again.
+        if !self.has_area() {
+            return;
+        }
+        self.configure_surface();
+        if self.surface.window.is_none() {
+            self.surface.offscreen =
+                Some(self.core.device.create_texture(&wgpu::TextureDescriptor {
+                    label: Some("offscreen frame"),
+                    size: wgpu::Extent3d {
+                        width: size.width as u32,
+                        height: size.height as u32,
+                        depth_or_array_layers: 1,
+                    },
+                    mip_level_count: 1,
+                    sample_count: 1,
+                    dimension: wgpu::TextureDimension::D2,
+                    format: self.core.format,
+                    usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+                }));
+        }
+        let msaa_texture = self.core.device.create_texture(&wgpu::TextureDescriptor {
             label: Some("Multisampled frame descriptor"),
             size: wgpu::Extent3d {
                 width: size.width as u32,
@@ -132,36 +1067,149 @@ impl WgpuRenderer {
             mip_level_count: 1,
             sample_count: 4,
             dimension: wgpu::TextureDimension::D2,
-            format: self.format,
+            format: self.core.format,
             usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
         });
-        self.msaa = msaa_texture.create_view(&wgpu::TextureViewDescriptor::default());
-        self.pipeline.size = size;
+        self.surface.msaa = msaa_texture.create_view(&wgpu::TextureViewDescriptor::default());
     }
 
     pub fn set_scale(&mut self, scale: f64) {
-        self.pipeline.scale = scale;
-        self.text.cache.borrow_mut().scale = scale;
+        if scale != self.surface.scale {
+            // Layers hold glyphs rasterized for the old scale.
+            self.layers.clear();
+        }
+        self.surface.scale = scale;
+        self.core.set_text_scale(scale);
+    }
+
+    /// Lay text out at the scale of this renderer, which another one
+    /// sharing its core may have changed.
+    pub(crate) fn apply_scale(&self) {
+        self.core.set_text_scale(self.surface.scale);
+    }
+
+    /// Drop the tessellated paths kept between frames, e.g. after a theme
+    /// change made the previously drawn shapes obsolete.
+    pub fn clear_path_cache(&mut self) {
+        self.core.state().path_cache.clear();
+    }
+
+    /// Keep at most `capacity` tessellated paths between frames, dropping
+    /// the oldest first.
+    pub fn set_path_cache_capacity(&mut self, capacity: usize) {
+        self.core.state().path_cache.set_capacity(capacity);
+    }
+
+    /// Have the layer `name` drawn again the next time
+    /// [`WgpuRenderContext::layer`] is called for it.
+    pub fn invalidate_layer(&mut self, name: &str) {
+        self.layers.remove(name);
+    }
+
+    /// Have every layer drawn again, e.g. after a theme change.
+    pub fn invalidate_layers(&mut self) {
+        self.layers.clear();
+    }
+
+    /// What the caches and buffers kept between frames take up, to decide
+    /// when to [`WgpuRenderer::trim_memory`].
+    pub fn memory_usage(&self) -> MemoryReport {
+        let cache = self.core.text.cache.borrow();
+        let state = self.core.state();
+        let layers: u64 = self.layers.values().map(|layer| layer.memory_bytes()).sum();
+        MemoryReport {
+            atlas: cache.atlas_bytes(),
+            images: state.images.memory_bytes(),
+            glyph_bitmaps: cache.fonts().glyph_bitmap_bytes(),
+            buffers: state.pipeline.buffer_bytes()
+                + self
+                    .frame_buffers
+                    .as_ref()
+                    .map_or(0, |buffers| buffers.memory_bytes()),
+            tessellation_cache: state.path_cache.memory_bytes()
+                + state.svg_store.memory_bytes()
+                + layers,
+            layout_cache: self.core.text.layout_cache_bytes(),
+        }
+    }
+
+    /// Drop caches under memory pressure; they fill up again as things are
+    /// drawn. The glyph atlas is kept, since text layouts refer to their
+    /// glyphs' places in it, and the image atlas is packed again without
+    /// the images that were dropped.
+    pub fn trim_memory(&mut self, level: TrimLevel) {
+        let mut state = self.core.state();
+        state.path_cache.clear();
+        self.core.text.clear_layout_caches();
+        self.core.ensure_encoder();
+        state.images.compact(
+            &self.core.device,
+            self.core.encoder.borrow_mut().as_mut().unwrap(),
+        );
+        if level == TrimLevel::Critical {
+            state.svg_store.clear();
+            self.layers.clear();
+            self.core.text.cache.borrow().fonts().clear_glyph_bitmaps();
+            state.pipeline.shrink_buffers(&self.core.device);
+            self.frame_buffers = None;
+        }
     }
 
     pub fn text(&self) -> WgpuText {
-        self.text.clone()
+        self.core.text.clone()
     }
 
-    pub(crate) fn ensure_encoder(&mut self) {
-        let mut encoder = self.encoder.borrow_mut();
-        if encoder.is_none() {
-            *encoder = Some(
-                self.device
-                    .create_command_encoder(&wgpu::CommandEncoderDescriptor {
-                        label: Some("render"),
-                    }),
-            );
+    /// Start drawing a frame. The context reuses the vertex, index and
+    /// state buffers of the previous frame, so that a steady UI doesn't
+    /// allocate for them every frame.
+    pub fn begin_frame(&mut self) -> WgpuRenderContext<'_> {
+        WgpuRenderContext::new(self)
+    }
+
+    /// Draw a frame from a windowing toolkit's paint callback, such as that
+    /// of a druid-shell `WinHandler`: `scale` is the scale factor of the
+    /// window, `Scale::x()` there, and `invalid` the rects in logical pixels
+    /// the toolkit asks to repaint, `Region::rects()` there.
+    ///
+    /// Surface frames are presented whole, so a frame is only drawn when
+    /// some rect is invalid, and `paint` then has to draw the whole window,
+    /// whose logical rect it is passed, not just the invalid rects.
+    pub fn render(
+        &mut self,
+        scale: f64,
+        invalid: &[Rect],
+        paint: impl FnOnce(&mut WgpuRenderContext<'_>, Rect),
+    ) -> Result<(), piet::Error> {
+        self.set_scale(scale);
+        if !invalid
+            .iter()
+            .any(|rect| rect.width() > 0.0 && rect.height() > 0.0)
+        {
+            return Ok(());
         }
+        let window = Size::new(
+            self.surface.size.width / scale,
+            self.surface.size.height / scale,
+        )
+        .to_rect();
+        let mut ctx = self.begin_frame();
+        paint(&mut ctx, window);
+        ctx.finish()?;
+        ctx.status()
     }
 
-    pub(crate) fn take_encoder(&mut self) -> wgpu::CommandEncoder {
-        self.encoder.take().unwrap()
+    /// The font and glyph cache of this renderer, to pass to
+    /// [`WgpuRenderer::new_with_text_cache`] for other windows.
+    pub fn text_cache(&self) -> TextCacheHandle {
+        self.core.text.cache.borrow().fonts()
+    }
+}
+
+impl Drop for WgpuRenderer {
+    fn drop(&mut self) {
+        // Let in-flight frames finish before the pipeline and caches
+        // destroy the buffers and textures they use.
+        self.core.device.poll(wgpu::Maintain::Wait);
     }
 }
 
@@ -0,0 +1,178 @@
+use std::ops::Range;
+
+use piet::kurbo::{Affine, Rect};
+
+use crate::backdrop::Backdrop;
+
+/// How many occluders are kept while culling; the largest ones win.
+const MAX_OCCLUDERS: usize = 64;
+
+/// A rect filled with one quad, as drawn by `fill`.
+#[derive(Clone)]
+pub(crate) struct Quad {
+    /// Its six indices in the geometry.
+    pub(crate) indices: Range<u32>,
+    /// The area it can draw to, in logical coordinates with the
+    /// transform and clip of the render context applied.
+    pub(crate) rect: Rect,
+    /// Whether it hides everything below it, which requires an opaque
+    /// color, a transform that keeps it an axis-aligned rect and no
+    /// rounded clip.
+    pub(crate) opaque: bool,
+}
+
+impl Quad {
+    /// The quad filling `rect` in user space, drawn with `transform`, the
+    /// transform the renderer applies to it. Its area is the bounding box
+    /// of the transformed rect, which is all it covers only when
+    /// `transform` doesn't rotate or skew it.
+    pub(crate) fn new(indices: Range<u32>, rect: Rect, transform: Affine, opaque: bool) -> Self {
+        let coeffs = transform.as_coeffs();
+        let axis_aligned = coeffs[1] == 0.0 && coeffs[2] == 0.0;
+        Self {
+            indices,
+            rect: transform.transform_rect_bbox(rect),
+            opaque: opaque && axis_aligned,
+        }
+    }
+}
+
+/// Drop the indices of quads entirely covered by an opaque quad drawn
+/// after them, e.g. backgrounds under panels, so they aren't uploaded or
+/// drawn. Backdrop blurs read what was drawn before them, so quads are
+/// never culled by occluders on the other side of a blur, and the blurs
+/// are moved along with the indices.
+pub(crate) fn cull_occluded(indices: &mut Vec<u32>, quads: &[Quad], backdrops: &mut [Backdrop]) {
+    let mut culled: Vec<Range<u32>> = Vec::new();
+    let mut occluders: Vec<Rect> = Vec::new();
+    let mut next_backdrop = backdrops.len();
+    for quad in quads.iter().rev() {
+        while next_backdrop > 0 && backdrops[next_backdrop - 1].index >= quad.indices.end {
+            next_backdrop -= 1;
+            occluders.clear();
+        }
+        if occluders.iter().any(|o| contains(o, &quad.rect)) {
+            culled.push(quad.indices.clone());
+            continue;
+        }
+        if !quad.opaque || quad.rect.area() <= 0.0 {
+            continue;
+        }
+        if occluders.len() < MAX_OCCLUDERS {
+            occluders.push(quad.rect);
+        } else if let Some(smallest) = occluders
+            .iter_mut()
+            .min_by(|a, b| a.area().partial_cmp(&b.area()).unwrap())
+        {
+            if smallest.area() < quad.rect.area() {
+                *smallest = quad.rect;
+            }
+        }
+    }
+    if culled.is_empty() {
+        return;
+    }
+
+    culled.reverse();
+    let mut kept = Vec::with_capacity(indices.len());
+    let mut start = 0;
+    for range in &culled {
+        kept.extend_from_slice(&indices[start as usize..range.start as usize]);
+        start = range.end;
+    }
+    kept.extend_from_slice(&indices[start as usize..]);
+    *indices = kept;
+
+    for backdrop in backdrops.iter_mut() {
+        let removed: u32 = culled
+            .iter()
+            .filter(|range| range.end <= backdrop.index)
+            .map(|range| range.end - range.start)
+            .sum();
+        backdrop.index -= removed;
+    }
+}
+
+fn contains(outer: &Rect, inner: &Rect) -> bool {
+    outer.x0 <= inner.x0 && outer.y0 <= inner.y0 && outer.x1 >= inner.x1 && outer.y1 >= inner.y1
+}
+
+#[cfg(test)]
+mod tests {
+    use piet::kurbo::{Affine, Rect};
+
+    use super::{cull_occluded, Quad};
+    use crate::context::applied_transform;
+
+    /// The indices of `quads`, six each, in order.
+    fn indices(quads: usize) -> Vec<u32> {
+        (0..quads as u32 * 6).collect()
+    }
+
+    /// The quad of `rect` filled under `transform`, the way the render
+    /// context records it.
+    fn fill(indices: std::ops::Range<u32>, rect: Rect, transform: Affine) -> Quad {
+        Quad::new(indices, rect, applied_transform(transform), true)
+    }
+
+    #[test]
+    fn scaled_occluder_covers_what_is_drawn() {
+        // Only the translation is drawn, so under a scale of 2 an opaque
+        // 0..100 rect covers 0..100, and what is drawn at 100..200 stays.
+        let scale = Affine::scale(2.0);
+        let quads = [
+            fill(0..6, Rect::new(100.0, 100.0, 200.0, 200.0), scale),
+            fill(6..12, Rect::new(10.0, 10.0, 90.0, 90.0), scale),
+            fill(12..18, Rect::new(0.0, 0.0, 100.0, 100.0), scale),
+        ];
+        assert_eq!(quads[2].rect, Rect::new(0.0, 0.0, 100.0, 100.0));
+        let mut indices = indices(3);
+        cull_occluded(&mut indices, &quads, &mut []);
+        let expected: Vec<u32> = (0..6).chain(12..18).collect();
+        assert_eq!(indices, expected);
+    }
+
+    #[test]
+    fn occluders_move_with_the_translation() {
+        let transform = Affine::translate((10.0, 0.0)) * Affine::scale(2.0);
+        let quads = [
+            fill(0..6, Rect::new(60.0, 10.0, 80.0, 40.0), Affine::IDENTITY),
+            fill(6..12, Rect::new(0.0, 0.0, 50.0, 50.0), transform),
+        ];
+        assert_eq!(quads[1].rect, Rect::new(10.0, 0.0, 60.0, 50.0));
+        // Past the drawn occluder, though inside the scaled one.
+        let mut kept = indices(2);
+        cull_occluded(&mut kept, &quads, &mut []);
+        assert_eq!(kept, (0..12).collect::<Vec<u32>>());
+
+        let quads = [
+            fill(0..6, Rect::new(20.0, 10.0, 40.0, 40.0), Affine::IDENTITY),
+            quads[1].clone(),
+        ];
+        let mut kept = indices(2);
+        cull_occluded(&mut kept, &quads, &mut []);
+        assert_eq!(kept, (6..12).collect::<Vec<u32>>());
+    }
+
+    #[test]
+    fn rotated_quad_is_no_occluder() {
+        let quads = [
+            Quad::new(
+                0..6,
+                Rect::new(40.0, 40.0, 60.0, 60.0),
+                Affine::IDENTITY,
+                true,
+            ),
+            Quad::new(
+                6..12,
+                Rect::new(-50.0, -50.0, 50.0, 50.0),
+                Affine::translate((50.0, 50.0)) * Affine::rotate(0.5),
+                true,
+            ),
+        ];
+        assert!(!quads[1].opaque);
+        let mut indices = indices(2);
+        cull_occluded(&mut indices, &quads, &mut []);
+        assert_eq!(indices, (0..12).collect::<Vec<u32>>());
+    }
+}
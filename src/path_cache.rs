@@ -0,0 +1,167 @@
+use std::collections::{hash_map::DefaultHasher, HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+
+use lyon::lyon_tessellation::VertexBuffers;
+use piet::{kurbo::PathEl, LineCap, LineJoin, StrokeStyle};
+
+use crate::pipeline::GpuVertex;
+
+/// Identifies a tessellated path: the hash of its elements together with
+/// the options it was tessellated with.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub(crate) struct PathCacheKey {
+    shape: u64,
+    tolerance: u32,
+    line_width: u32,
+}
+
+impl PathCacheKey {
+    pub(crate) fn stroke(
+        elements: &[PathEl],
+        tolerance: f32,
+        line_width: f32,
+        style: &StrokeStyle,
+    ) -> Self {
+        let mut hasher = DefaultHasher::new();
+        match style.line_cap {
+            LineCap::Butt => 0u8,
+            LineCap::Round => 1,
+            LineCap::Square => 2,
+        }
+        .hash(&mut hasher);
+        match style.line_join {
+            LineJoin::Miter { limit } => {
+                0u8.hash(&mut hasher);
+                limit.to_bits().hash(&mut hasher);
+            }
+            LineJoin::Round => 1u8.hash(&mut hasher),
+            LineJoin::Bevel => 2u8.hash(&mut hasher),
+        }
+        for el in elements {
+            let points: &[_] = match el {
+                PathEl::MoveTo(p) => {
+                    0u8.hash(&mut hasher);
+                    &[*p]
+                }
+                PathEl::LineTo(p) => {
+                    1u8.hash(&mut hasher);
+                    &[*p]
+                }
+                PathEl::QuadTo(p1, p2) => {
+                    2u8.hash(&mut hasher);
+                    &[*p1, *p2]
+                }
+                PathEl::CurveTo(p1, p2, p3) => {
+                    3u8.hash(&mut hasher);
+                    &[*p1, *p2, *p3]
+                }
+                PathEl::ClosePath => {
+                    4u8.hash(&mut hasher);
+                    &[]
+                }
+            };
+            for p in points {
+                p.x.to_bits().hash(&mut hasher);
+                p.y.to_bits().hash(&mut hasher);
+            }
+        }
+        Self {
+            shape: hasher.finish(),
+            tolerance: tolerance.to_bits(),
+            line_width: line_width.to_bits(),
+        }
+    }
+}
+
+/// Tessellated vertex positions and indices of a path.
+pub(crate) type PathGeometry = VertexBuffers<[f32; 2], u32>;
+
+/// Tessellation results of the paths drawn in recent frames, so that the
+/// same shapes aren't tessellated again every frame. The oldest paths are
+/// dropped once `capacity` is reached.
+pub(crate) struct PathCache {
+    paths: HashMap<PathCacheKey, PathGeometry>,
+    order: VecDeque<PathCacheKey>,
+    capacity: usize,
+}
+
+impl PathCache {
+    const DEFAULT_CAPACITY: usize = 512;
+
+    pub(crate) fn new() -> Self {
+        Self {
+            paths: HashMap::new(),
+            order: VecDeque::new(),
+            capacity: Self::DEFAULT_CAPACITY,
+        }
+    }
+
+    pub(crate) fn get_or_insert_with(
+        &mut self,
+        key: PathCacheKey,
+        f: impl FnOnce() -> PathGeometry,
+    ) -> &PathGeometry {
+        if !self.paths.contains_key(&key) {
+            while self.paths.len() >= self.capacity.max(1) {
+                match self.order.pop_front() {
+                    Some(oldest) => {
+                        self.paths.remove(&oldest);
+                    }
+                    None => break,
+                }
+            }
+            self.paths.insert(key, f());
+            self.order.push_back(key);
+        }
+        self.paths.get(&key).unwrap()
+    }
+
+    pub(crate) fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.paths.len() > capacity {
+            match self.order.pop_front() {
+                Some(oldest) => {
+                    self.paths.remove(&oldest);
+                }
+                None => break,
+            }
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.paths.clear();
+        self.order.clear();
+    }
+
+    /// The bytes of the cached vertices and indices.
+    pub(crate) fn memory_bytes(&self) -> u64 {
+        self.paths
+            .values()
+            .map(|path| {
+                (path.vertices.len() * std::mem::size_of::<[f32; 2]>()
+                    + path.indices.len() * std::mem::size_of::<u32>()) as u64
+            })
+            .sum()
+    }
+}
+
+/// Append a cached path to `geometry` with the given color and primitive.
+pub(crate) fn append_path(
+    geometry: &mut VertexBuffers<GpuVertex, u32>,
+    path: &PathGeometry,
+    color: [f32; 4],
+    primitive_id: u32,
+) {
+    let offset = geometry.vertices.len() as u32;
+    geometry
+        .vertices
+        .extend(path.vertices.iter().map(|pos| GpuVertex {
+            pos: *pos,
+            color,
+            primitive_id,
+            ..Default::default()
+        }));
+    geometry
+        .indices
+        .extend(path.indices.iter().map(|i| *i + offset));
+}
@@ -1,27 +1,34 @@
-use std::hash::BuildHasherDefault;
-use std::num::{NonZeroU32, NonZeroU64};
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::num::NonZeroU64;
+use std::path::Path;
+use std::rc::Rc;
 use std::sync::Arc;
 
 use font_kit::canvas::{Canvas, Format, RasterizationOptions};
 use font_kit::family_name::FamilyName;
+use font_kit::file_type::FileType;
 use font_kit::font::Font;
+use font_kit::handle::Handle;
 use font_kit::hinting::HintingOptions;
-use font_kit::loader::Loader;
+use font_kit::outline::OutlineSink;
 use font_kit::source::SystemSource;
-use hashbrown::HashMap;
+use hashbrown::{HashMap, HashSet};
 use include_dir::include_dir;
 use include_dir::Dir;
-use linked_hash_map::LinkedHashMap;
-use lyon::lyon_tessellation::{
-    BuffersBuilder, FillOptions, FillTessellator, FillVertex, FillVertexConstructor,
-    StrokeVertexConstructor, VertexBuffers,
-};
-use lyon::tessellation;
+use lyon::lyon_tessellation::VertexBuffers;
+use pathfinder_geometry::line_segment::LineSegment2F;
 use pathfinder_geometry::transform2d::Transform2F;
 use pathfinder_geometry::vector::{Vector2F, Vector2I};
-use piet::kurbo::{Affine, Point, Rect, Size};
-use piet::{Color, FontFamily, FontWeight};
-use wgpu::util::DeviceExt;
+use piet::kurbo::{BezPath, Point, Rect, Size};
+use piet::{FontFamily, FontStyle, FontWeight};
+use sha2::{Digest, Sha256};
+
+use crate::atlas::{AtlasStats, GpuAtlas};
+use crate::backdrop::{Backdrop, BackdropPipeline};
+use crate::font::{FaceQuery, FontId, FontStretch, HanRegion};
+use crate::image::ImageStore;
+use crate::layer::{command_list, scissor_runs, DrawCommand};
 
 const FONTS_DIR: Dir = include_dir!("./fonts");
 const DEFAULT_FONT: &[u8] = include_bytes!("../fonts/CascadiaCode-Regular.otf");
@@ -31,7 +38,8 @@ const DEFAULT_FONT: &[u8] = include_bytes!("../fonts/CascadiaCode-Regular.otf");
 struct Globals {
     resolution: [f32; 2],
     scale: f32,
-    _pad: f32,
+    /// 1 to output premultiplied alpha, 0 for straight alpha.
+    premultiplied: f32,
 }
 
 unsafe impl bytemuck::Pod for Globals {}
@@ -41,13 +49,46 @@ unsafe impl bytemuck::Zeroable for Globals {}
 #[derive(Copy, Clone)]
 pub struct Primitive {
     pub(crate) clip_rect: [f32; 4],
+    /// Corner radii of the clip rect: top left, top right, bottom right and
+    /// bottom left.
+    pub(crate) clip_radius: [f32; 4],
     pub(crate) transform_1: [f32; 4],
     pub(crate) blur_rect: [f32; 4],
+    /// Center, radius and stroke width of a circle drawn analytically. A
+    /// radius of 0 means this isn't a circle, a stroke width of 0 fills it.
+    pub(crate) circle: [f32; 4],
+    /// The outline of a dashed focus ring, with its corner radius, width,
+    /// dash and gap lengths in `focus`. A width of 0 means this isn't one.
+    pub(crate) focus_rect: [f32; 4],
+    pub(crate) focus: [f32; 4],
+    /// A conic gradient over a circle, running from the start of its arc at
+    /// 0 to the end at 1: the window of positions this primitive draws, and
+    /// the positions of the vertex color and of `conic_color` within it.
+    /// An empty window means there is no gradient.
+    pub(crate) conic: [f32; 4],
+    pub(crate) conic_color: [f32; 4],
+    /// A gradient colors what the primitive draws: the start and end of a
+    /// linear one, or the center and focus of a radial one, and in
+    /// `gradient_lut` where its colors are in the image atlas, how many
+    /// there are and the radius, which is 0 for a linear gradient. No
+    /// colors means there is no gradient.
+    pub(crate) gradient: [f32; 4],
+    pub(crate) gradient_lut: [f32; 4],
     pub(crate) transform_2: [f32; 2],
     pub(crate) translate: [f32; 2],
     pub(crate) scale: [f32; 2],
     pub(crate) clip: f32,
     pub(crate) blur_radius: f32,
+    pub(crate) blur_corner_radius: f32,
+    pub(crate) blur_inset: f32,
+    /// Start and sweep angle of a stroked circle; a sweep of 0 draws the
+    /// whole circle.
+    pub(crate) arc: [f32; 2],
+    /// How far the focus ring dashes are shifted along the outline.
+    pub(crate) focus_phase: f32,
+    /// How far in from the clip edge content fades out, in logical pixels.
+    pub(crate) clip_feather: f32,
+    pub(crate) _pad: [f32; 2],
 }
 
 unsafe impl bytemuck::Pod for Primitive {}
@@ -60,10 +101,24 @@ impl Default for Primitive {
             scale: [1.0, 1.0],
             clip: 0.0,
             clip_rect: [0.0, 0.0, 0.0, 0.0],
+            clip_radius: [0.0, 0.0, 0.0, 0.0],
             transform_1: [1.0, 0.0, 0.0, 1.0],
             transform_2: [0.0, 0.0],
             blur_rect: [0.0, 0.0, 0.0, 0.0],
             blur_radius: 0.0,
+            blur_corner_radius: 0.0,
+            blur_inset: 0.0,
+            circle: [0.0; 4],
+            arc: [0.0; 2],
+            focus_rect: [0.0; 4],
+            focus: [0.0; 4],
+            conic: [0.0; 4],
+            conic_color: [0.0; 4],
+            gradient: [0.0; 4],
+            gradient_lut: [0.0; 4],
+            focus_phase: 0.0,
+            clip_feather: 0.0,
+            _pad: [0.0; 2],
         }
     }
 }
@@ -95,9 +150,26 @@ impl Default for GpuVertex {
     }
 }
 
+/// What a frame is drawn into, which differs between the windows
+/// sharing a pipeline.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Viewport {
+    /// The size in physical pixels.
+    pub(crate) size: Size,
+    pub(crate) scale: f64,
+    /// Whether frames hold premultiplied alpha, for external compositors.
+    pub(crate) premultiplied: bool,
+}
+
 pub struct Pipeline {
     pub pipeline: wgpu::RenderPipeline,
+    /// The same as `pipeline`, blending premultiplied alpha.
+    premultiplied_pipeline: wgpu::RenderPipeline,
+    bind_group_layout: wgpu::BindGroupLayout,
     bind_group: wgpu::BindGroup,
+    sampler: wgpu::Sampler,
+    cache_generation: u64,
+    image_generation: u64,
     globals: wgpu::Buffer,
     primitives: wgpu::Buffer,
     vertices: wgpu::Buffer,
@@ -105,14 +177,18 @@ pub struct Pipeline {
     supported_primitives: usize,
     supported_vertices: usize,
     supported_indices: usize,
-    pub(crate) size: Size,
-    pub(crate) scale: f64,
+    backdrop: BackdropPipeline,
 }
 
 impl Pipeline {
-    pub fn new(device: &wgpu::Device, format: wgpu::TextureFormat, cache: &Cache) -> Self {
+    pub fn new(
+        device: &wgpu::Device,
+        format: wgpu::TextureFormat,
+        cache: &Cache,
+        images: &ImageStore,
+    ) -> Self {
         let globals_buffer_byte_size = std::mem::size_of::<Globals>() as u64;
-        let supported_primitives = 1000;
+        let supported_primitives = 1024;
         let primitives_buffer_byte_size =
             std::mem::size_of::<Primitive>() as u64 * supported_primitives as u64;
 
@@ -165,7 +241,7 @@ impl Pipeline {
             entries: &[
                 wgpu::BindGroupLayoutEntry {
                     binding: 0,
-                    visibility: wgpu::ShaderStages::VERTEX,
+                    visibility: wgpu::ShaderStages::VERTEX_FRAGMENT,
                     ty: wgpu::BindingType::Buffer {
                         ty: wgpu::BufferBindingType::Uniform,
                         has_dynamic_offset: false,
@@ -191,95 +267,79 @@ impl Pipeline {
                 },
                 wgpu::BindGroupLayoutEntry {
                     binding: 3,
-                    visibility: wgpu::ShaderStages::VERTEX,
+                    visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
                     ty: wgpu::BindingType::Buffer {
                         ty: wgpu::BufferBindingType::Storage { read_only: true },
                         has_dynamic_offset: false,
-                        min_binding_size: wgpu::BufferSize::new(primitives_buffer_byte_size),
+                        min_binding_size: wgpu::BufferSize::new(
+                            std::mem::size_of::<Primitive>() as u64
+                        ),
                     },
                     count: None,
                 },
-            ],
-        });
-
-        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: Some("Bind group"),
-            layout: &bind_group_layout,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: wgpu::BindingResource::Buffer(globals.as_entire_buffer_binding()),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: wgpu::BindingResource::Sampler(&sampler),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 2,
-                    resource: wgpu::BindingResource::TextureView(&cache.view),
+                wgpu::BindGroupLayoutEntry {
+                    binding: 4,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
                 },
-                wgpu::BindGroupEntry {
-                    binding: 3,
-                    resource: wgpu::BindingResource::Buffer(primitives.as_entire_buffer_binding()),
+                wgpu::BindGroupLayoutEntry {
+                    binding: 5,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
                 },
             ],
         });
 
+        let bind_group = create_bind_group(
+            device,
+            &bind_group_layout,
+            &globals,
+            &sampler,
+            cache.view(),
+            images.view(),
+            images.gray_view(),
+            &primitives,
+        );
+
         let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
             bind_group_layouts: &[&bind_group_layout],
             push_constant_ranges: &[],
             label: Some("pipeline layout"),
         });
 
-        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-            label: Some("pipeline descriptor"),
-            layout: Some(&pipeline_layout),
-            vertex: wgpu::VertexState {
-                module: &shader,
-                entry_point: "vs_main",
-                buffers: &[wgpu::VertexBufferLayout {
-                    array_stride: std::mem::size_of::<GpuVertex>() as u64,
-                    step_mode: wgpu::VertexStepMode::Vertex,
-                    attributes: &wgpu::vertex_attr_array!(
-                        0 => Float32x2,
-                        1 => Float32x2,
-                        2 => Float32x4,
-                        3 => Float32,
-                        4 => Float32x2,
-                        5 => Uint32,
-                    ),
-                }],
-            },
-            fragment: Some(wgpu::FragmentState {
-                module: &shader,
-                entry_point: "fs_main",
-                targets: &[wgpu::ColorTargetState {
-                    format,
-                    blend: Some(wgpu::BlendState::ALPHA_BLENDING),
-                    write_mask: wgpu::ColorWrites::ALL,
-                }],
-            }),
-            primitive: wgpu::PrimitiveState {
-                topology: wgpu::PrimitiveTopology::TriangleList,
-                polygon_mode: wgpu::PolygonMode::Fill,
-                front_face: wgpu::FrontFace::Ccw,
-                strip_index_format: None,
-                cull_mode: None,
-                unclipped_depth: false,
-                conservative: false,
-            },
-            depth_stencil: None,
-            multisample: wgpu::MultisampleState {
-                count: 4,
-                mask: !0,
-                alpha_to_coverage_enabled: false,
-            },
-            multiview: None,
-        });
+        let pipeline = create_render_pipeline(
+            device,
+            &pipeline_layout,
+            &shader,
+            format,
+            wgpu::BlendState::ALPHA_BLENDING,
+        );
+        let premultiplied_pipeline = create_render_pipeline(
+            device,
+            &pipeline_layout,
+            &shader,
+            format,
+            wgpu::BlendState::PREMULTIPLIED_ALPHA_BLENDING,
+        );
 
         Self {
             pipeline,
+            premultiplied_pipeline,
+            bind_group_layout,
             bind_group,
+            sampler,
+            cache_generation: cache.generation(),
+            image_generation: images.generation(),
             globals,
             vertices,
             indices,
@@ -287,22 +347,100 @@ impl Pipeline {
             supported_vertices: 1,
             supported_indices: 1,
             supported_primitives,
-            size: Size::ZERO,
-            scale: 1.0,
+            backdrop: BackdropPipeline::new(device, format),
         }
     }
 
-    pub fn upload_data(
+    /// Rebind the glyph and image atlases if they have been reallocated
+    /// since the bind group was created, and the primitives buffer if it
+    /// has to grow to hold `primitives`. It doubles as needed, up to the
+    /// device's `max_storage_buffer_binding_size`.
+    pub(crate) fn update_bindings(
+        &mut self,
+        device: &wgpu::Device,
+        cache: &Cache,
+        images: &ImageStore,
+        primitives: usize,
+    ) {
+        let primitive_size = std::mem::size_of::<Primitive>();
+        let max_primitives =
+            device.limits().max_storage_buffer_binding_size as usize / primitive_size;
+        let grow =
+            primitives > self.supported_primitives && self.supported_primitives < max_primitives;
+        if self.cache_generation == cache.generation()
+            && self.image_generation == images.generation()
+            && !grow
+        {
+            return;
+        }
+        if grow {
+            self.supported_primitives = primitives.next_power_of_two().min(max_primitives);
+            self.primitives.destroy();
+            self.primitives = device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("Pritives ubo"),
+                size: (primitive_size * self.supported_primitives) as u64,
+                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            });
+        }
+        self.bind_group = create_bind_group(
+            device,
+            &self.bind_group_layout,
+            &self.globals,
+            &self.sampler,
+            cache.view(),
+            images.view(),
+            images.gray_view(),
+            &self.primitives,
+        );
+        self.cache_generation = cache.generation();
+        self.image_generation = images.generation();
+    }
+
+    /// The bytes of the buffers the frame geometry is uploaded to.
+    pub(crate) fn buffer_bytes(&self) -> u64 {
+        (std::mem::size_of::<Globals>()
+            + std::mem::size_of::<Primitive>() * self.supported_primitives
+            + std::mem::size_of::<GpuVertex>() * self.supported_vertices
+            + std::mem::size_of::<u32>() * self.supported_indices) as u64
+    }
+
+    /// Release the vertex and index buffers, which only ever grow, so that
+    /// the next frame allocates them at the size it needs.
+    pub(crate) fn shrink_buffers(&mut self, device: &wgpu::Device) {
+        self.vertices.destroy();
+        self.vertices = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("vertices ubo"),
+            size: std::mem::size_of::<GpuVertex>() as u64,
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        self.supported_vertices = 1;
+        self.indices.destroy();
+        self.indices = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("indices ubo"),
+            size: std::mem::size_of::<u32>() as u64,
+            usage: wgpu::BufferUsages::INDEX | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        self.supported_indices = 1;
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn upload_data(
         &mut self,
         device: &wgpu::Device,
         staging_belt: &mut wgpu::util::StagingBelt,
         encoder: &mut wgpu::CommandEncoder,
+        viewport: &Viewport,
         geometry: &VertexBuffers<GpuVertex, u32>,
         primitives: &[Primitive],
+        backdrops: &[Backdrop],
     ) {
         if geometry.vertices.len() > self.supported_vertices {
             self.supported_vertices = geometry.vertices.len();
             let size = std::mem::size_of::<GpuVertex>() as u64 * self.supported_vertices as u64;
+            self.vertices.destroy();
             self.vertices = device.create_buffer(&wgpu::BufferDescriptor {
                 label: Some("vertices ubo"),
                 size,
@@ -313,6 +451,7 @@ impl Pipeline {
         if geometry.indices.len() > self.supported_indices {
             self.supported_indices = geometry.indices.len();
             let size = std::mem::size_of::<u32>() as u64 * self.supported_indices as u64;
+            self.indices.destroy();
             self.indices = device.create_buffer(&wgpu::BufferDescriptor {
                 label: Some("indices ubo"),
                 size,
@@ -346,9 +485,9 @@ impl Pipeline {
 
         {
             let globals = vec![Globals {
-                resolution: [self.size.width as f32, self.size.height as f32],
-                scale: self.scale as f32,
-                _pad: 0.0,
+                resolution: [viewport.size.width as f32, viewport.size.height as f32],
+                scale: viewport.scale as f32,
+                premultiplied: if viewport.premultiplied { 1.0 } else { 0.0 },
             }];
 
             let global_bytes = bytemuck::cast_slice(&globals);
@@ -364,9 +503,10 @@ impl Pipeline {
 
         {
             if primitives.len() > self.supported_primitives {
-                println!(
-                    "warning primities len {} more than supported",
-                    primitives.len()
+                log::debug!(
+                    "{} primitives, only {} are supported",
+                    primitives.len(),
+                    self.supported_primitives
                 );
             }
 
@@ -382,26 +522,45 @@ impl Pipeline {
             );
             primivites_buffer.copy_from_slice(primitives_bytes);
         }
+
+        self.backdrop.upload_data(
+            device,
+            staging_belt,
+            encoder,
+            backdrops,
+            viewport.size,
+            viewport.scale,
+        );
     }
 
-    pub fn draw(
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn draw(
         &mut self,
         device: &wgpu::Device,
         encoder: &mut wgpu::CommandEncoder,
         view: &wgpu::TextureView,
         msaa: &wgpu::TextureView,
+        viewport: &Viewport,
         geometry: &VertexBuffers<GpuVertex, u32>,
+        primitives: &[Primitive],
+        backdrops: &[Backdrop],
     ) {
-        let fill_range = 0..(geometry.indices.len() as u32);
-
         {
             let _ = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
                 label: None,
+                // The geometry passes load the multisampled frame, so it is
+                // cleared too, and resolved into `view` to clear that.
                 color_attachments: &[wgpu::RenderPassColorAttachment {
-                    view: &view,
-                    resolve_target: None,
+                    view: msaa,
+                    resolve_target: Some(view),
                     ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(wgpu::Color::WHITE),
+                        // A compositor shows what is behind transparent
+                        // areas, so frames for one start out empty.
+                        load: wgpu::LoadOp::Clear(if viewport.premultiplied {
+                            wgpu::Color::TRANSPARENT
+                        } else {
+                            wgpu::Color::WHITE
+                        }),
                         store: true,
                     },
                 }],
@@ -409,43 +568,224 @@ impl Pipeline {
             });
         }
 
-        {
-            let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                label: None,
-                color_attachments: &[wgpu::RenderPassColorAttachment {
-                    view: msaa,
-                    resolve_target: Some(&view),
-                    ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Load,
-                        store: true,
-                    },
-                }],
-                depth_stencil_attachment: None,
-            });
+        // Backdrop blurs need everything before them rendered, so the
+        // geometry is drawn in segments split at each backdrop.
+        let commands = command_list(backdrops, geometry.indices.len() as u32, |i| {
+            self.backdrop.is_visible(i)
+        });
+        for command in commands {
+            match command {
+                DrawCommand::Geometry(range) => {
+                    let runs =
+                        scissor_runs(range, geometry, primitives, viewport.size, viewport.scale);
+                    self.draw_range(encoder, view, msaa, viewport, &runs)
+                }
+                DrawCommand::Backdrop(i) => self.backdrop.draw(encoder, msaa, i),
+            }
+        }
+    }
 
-            pass.set_pipeline(&self.pipeline);
-            pass.set_bind_group(0, &self.bind_group, &[]);
-            pass.set_vertex_buffer(0, self.vertices.slice(..));
-            pass.set_index_buffer(self.indices.slice(..), wgpu::IndexFormat::Uint32);
+    fn draw_range(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        view: &wgpu::TextureView,
+        msaa: &wgpu::TextureView,
+        viewport: &Viewport,
+        runs: &[(std::ops::Range<u32>, Option<[u32; 4]>)],
+    ) {
+        if runs.is_empty() {
+            return;
+        }
+
+        let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: None,
+            color_attachments: &[wgpu::RenderPassColorAttachment {
+                view: msaa,
+                resolve_target: Some(view),
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: true,
+                },
+            }],
+            depth_stencil_attachment: None,
+        });
+
+        pass.set_pipeline(if viewport.premultiplied {
+            &self.premultiplied_pipeline
+        } else {
+            &self.pipeline
+        });
+        pass.set_bind_group(0, &self.bind_group, &[]);
+        pass.set_vertex_buffer(0, self.vertices.slice(..));
+        pass.set_index_buffer(self.indices.slice(..), wgpu::IndexFormat::Uint32);
 
-            pass.draw_indexed(fill_range.clone(), 0, 0..1);
+        for (range, scissor) in runs {
+            let [x, y, width, height] = scissor.unwrap_or([
+                0,
+                0,
+                viewport.size.width as u32,
+                viewport.size.height as u32,
+            ]);
+            pass.set_scissor_rect(x, y, width, height);
+            pass.draw_indexed(range.clone(), 0, 0..1);
         }
     }
 }
 
+fn create_render_pipeline(
+    device: &wgpu::Device,
+    layout: &wgpu::PipelineLayout,
+    shader: &wgpu::ShaderModule,
+    format: wgpu::TextureFormat,
+    blend: wgpu::BlendState,
+) -> wgpu::RenderPipeline {
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some("pipeline descriptor"),
+        layout: Some(layout),
+        vertex: wgpu::VertexState {
+            module: shader,
+            entry_point: "vs_main",
+            buffers: &[wgpu::VertexBufferLayout {
+                array_stride: std::mem::size_of::<GpuVertex>() as u64,
+                step_mode: wgpu::VertexStepMode::Vertex,
+                attributes: &wgpu::vertex_attr_array!(
+                    0 => Float32x2,
+                    1 => Float32x2,
+                    2 => Float32x4,
+                    3 => Float32,
+                    4 => Float32x2,
+                    5 => Uint32,
+                ),
+            }],
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: shader,
+            entry_point: "fs_main",
+            targets: &[wgpu::ColorTargetState {
+                format,
+                blend: Some(blend),
+                write_mask: wgpu::ColorWrites::ALL,
+            }],
+        }),
+        primitive: wgpu::PrimitiveState {
+            topology: wgpu::PrimitiveTopology::TriangleList,
+            polygon_mode: wgpu::PolygonMode::Fill,
+            front_face: wgpu::FrontFace::Ccw,
+            strip_index_format: None,
+            cull_mode: None,
+            unclipped_depth: false,
+            conservative: false,
+        },
+        depth_stencil: None,
+        multisample: wgpu::MultisampleState {
+            count: 4,
+            mask: !0,
+            alpha_to_coverage_enabled: false,
+        },
+        multiview: None,
+    })
+}
+
+#[allow(clippy::too_many_arguments)]
+fn create_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    globals: &wgpu::Buffer,
+    sampler: &wgpu::Sampler,
+    cache_view: &wgpu::TextureView,
+    image_view: &wgpu::TextureView,
+    gray_image_view: &wgpu::TextureView,
+    primitives: &wgpu::Buffer,
+) -> wgpu::BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("Bind group"),
+        layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::Buffer(globals.as_entire_buffer_binding()),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::Sampler(sampler),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: wgpu::BindingResource::TextureView(cache_view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 3,
+                resource: wgpu::BindingResource::Buffer(primitives.as_entire_buffer_binding()),
+            },
+            wgpu::BindGroupEntry {
+                binding: 4,
+                resource: wgpu::BindingResource::TextureView(image_view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 5,
+                resource: wgpu::BindingResource::TextureView(gray_image_view),
+            },
+        ],
+    })
+}
+
+impl Drop for Pipeline {
+    fn drop(&mut self) {
+        self.globals.destroy();
+        self.primitives.destroy();
+        self.vertices.destroy();
+        self.indices.destroy();
+    }
+}
+
 #[derive(Debug, Clone, Hash, PartialEq, Eq, Default)]
 pub(crate) struct GlyphInfo {
     font_id: usize,
     glyph_id: u32,
+    /// The font size in physical pixels, in `1 / FONT_SIZE_UNITS` steps so
+    /// that fractional scales get their own glyphs.
     font_size: u32,
+    /// How far the glyph is dilated for a text outline, in the units of
+    /// `font_size`; 0 for the glyph itself.
+    outline: u32,
+    /// Whether the glyph is drawn scaled from its distance field. The
+    /// distance field itself is keyed with a `font_size` of 0.
+    sdf: bool,
 }
 
+const FONT_SIZE_UNITS: f64 = 64.0;
+
+/// Empty pixels around each glyph bitmap, so that neighbours in the atlas
+/// don't bleed into each other.
+const GLYPH_PADDING: f32 = 2.0;
+
+/// The font size in physical pixels distance fields of glyphs are made at.
+const SDF_GLYPH_SIZE: f32 = 64.0;
+/// How many pixels of a distance field it takes for the distance to fall
+/// off from the edge to either end of its range. Matches `SDF_SPREAD` in
+/// the shader.
+const SDF_SPREAD: f32 = 8.0;
+
+/// The `tex` of glyph vertices, which sample the coverage in the glyph
+/// atlas.
+pub(crate) const GLYPH_TEX: f32 = 1.0;
+/// The `tex` of glyph vertices sampling a distance field.
+pub(crate) const SDF_GLYPH_TEX: f32 = 1.5;
+
+/// Bumped whenever the rasterization or the layout of the glyph cache file
+/// changes, which invalidates cache files on disk.
+const GLYPH_CACHE_VERSION: u32 = 1;
+const GLYPH_CACHE_FILE: &str = "piet-wgpu-glyphs.bin";
+
 #[derive(Default, Clone)]
 pub(crate) struct GlyphMetricInfo {
     pub(crate) ascent: f64,
     pub(crate) descent: f64,
     pub(crate) line_gap: f64,
     pub(crate) mono: bool,
+    /// The ink bounds of the glyph in logical pixels, relative to the top
+    /// left of its line box.
+    pub(crate) ink: Rect,
 }
 
 #[derive(Default, Clone)]
@@ -455,119 +795,186 @@ pub(crate) struct GlyphPosInfo {
     pub(crate) width: f64,
     pub(crate) rect: Rect,
     pub(crate) cache_rect: Rect,
+    /// Whether the glyph couldn't be loaded, and is drawn as a box in its
+    /// place.
+    pub(crate) missing: bool,
 }
 
 impl GlyphPosInfo {
+    /// How the shader draws the glyph from the atlas.
+    pub(crate) fn tex(&self) -> f32 {
+        if self.info.sdf {
+            SDF_GLYPH_TEX
+        } else {
+            GLYPH_TEX
+        }
+    }
+
     pub fn empty(width: f64) -> Self {
         GlyphPosInfo {
             info: GlyphInfo {
                 font_id: 0,
                 glyph_id: 0,
                 font_size: 0,
+                outline: 0,
+                sdf: false,
             },
             metric: GlyphMetricInfo {
                 ascent: 0.0,
                 descent: 0.0,
                 line_gap: 0.0,
                 mono: false,
+                ink: Rect::ZERO,
             },
             width: width,
             rect: Size::new(width, 0.0).to_rect(),
             cache_rect: Rect::ZERO,
+            missing: false,
         }
     }
 }
 
-struct Row {
-    y: u32,
-    height: u32,
+/// A rasterized glyph, in physical pixels, which any atlas can upload
+/// without going through font-kit again.
+struct GlyphBitmap {
     width: u32,
-    glyphs: Vec<GlyphPosInfo>,
+    height: u32,
+    pixels: Vec<u8>,
+    real_width: f32,
+    real_height: f32,
+    ascent: f32,
+    descent: f32,
+    line_gap: f32,
+    mono: bool,
+    /// The ink bounds relative to the top left of the line box.
+    ink: Rect,
 }
 
-pub struct Cache {
-    texture: wgpu::Texture,
-    pub(super) view: wgpu::TextureView,
-    upload_buffer: wgpu::Buffer,
-    upload_buffer_size: u64,
-    width: u32,
-    height: u32,
+/// A font digest, glyph id and font size, which identify a glyph bitmap
+/// across sessions.
+type PersistedGlyph = (Vec<u8>, u32, u32);
 
+/// A char in a font family, face and region, which maps to a font and
+/// glyph id.
+type GlyphKey = (char, FontFamily, FaceQuery, Option<HanRegion>);
+
+/// Loaded fonts and rasterized glyphs, shared by every renderer that holds
+/// the same [`TextCacheHandle`].
+struct FontCache {
     font_source: SystemSource,
     fonts: Vec<Font>,
     default_font: Font,
     fallback_fonts_range: std::ops::Range<usize>,
     fallback_fonts_loaded: bool,
-    font_families: HashMap<(FontFamily, FontWeight), usize>,
-
-    rows: LinkedHashMap<usize, Row>,
-    glyphs: HashMap<GlyphInfo, (usize, usize)>,
-    glyph_infos: HashMap<(char, FontFamily, FontWeight), (usize, u32)>,
-    pub(crate) scale: f64,
+    font_families: HashMap<(FontFamily, FaceQuery), usize>,
+    glyph_infos: HashMap<GlyphKey, (usize, u32)>,
+    bitmaps: HashMap<GlyphInfo, Rc<GlyphBitmap>>,
+    resolved_fonts: HashMap<(FontFamily, FontWeight, FontStyle), Option<usize>>,
+    resolved_postscript_names: HashMap<String, Option<usize>>,
+    strikes: HashMap<usize, Rc<Vec<f32>>>,
+    emoji_source: EmojiSource,
+    /// Glyphs loaded from disk which haven't been used yet.
+    persisted: HashMap<PersistedGlyph, Rc<GlyphBitmap>>,
+    font_digests: HashMap<usize, Vec<u8>>,
+    /// Faces registered with [`TextCacheHandle::load_font`], by lowercase
+    /// family name and face. They take precedence over installed fonts of
+    /// the same family.
+    registered: HashMap<(String, FaceQuery), usize>,
+    /// Installed fonts not searched for missing chars yet, listed on the
+    /// first char that no other font has.
+    system_fallback: Option<Vec<Handle>>,
+    /// Installed fonts found to have a missing char, searched first.
+    system_fallback_fonts: Vec<usize>,
+    /// Chars that no font has.
+    missing_chars: HashSet<char>,
+    region_fonts: HashMap<(HanRegion, FaceQuery), Option<usize>>,
 }
 
-fn get_fallback_fonts() -> Vec<Font> {
-    let mut fonts = Vec::new();
-    for file in FONTS_DIR.files() {
-        if let Ok(font) = Font::from_bytes(Arc::new(file.contents().to_vec()), 0) {
-            fonts.push(font);
-        }
-    }
-    fonts
+/// Which kind of font to prefer for emoji. The glyph atlas holds coverage
+/// only, so either kind is drawn in the text color.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EmojiSource {
+    /// Skip color bitmap fonts when another font has the glyph.
+    Outline,
+    /// Use color bitmap fonts, scaling their closest strike.
+    #[default]
+    Bitmap,
 }
 
-impl Cache {
-    const INITIAL_UPLOAD_BUFFER_SIZE: u64 = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT as u64 * 100;
-
-    pub fn new(device: &wgpu::Device, width: u32, height: u32) -> Cache {
-        let texture = device.create_texture(&wgpu::TextureDescriptor {
-            label: Some("wgpu_glyph::Cache"),
-            size: wgpu::Extent3d {
-                width,
-                height,
-                depth_or_array_layers: 1,
-            },
-            dimension: wgpu::TextureDimension::D2,
-            format: wgpu::TextureFormat::R8Unorm,
-            usage: wgpu::TextureUsages::COPY_DST | wgpu::TextureUsages::TEXTURE_BINDING,
-            mip_level_count: 1,
-            sample_count: 1,
-        });
-
-        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
-
-        let upload_buffer = device.create_buffer(&wgpu::BufferDescriptor {
-            label: Some("wgpu_glyph::Cache upload buffer"),
-            size: Self::INITIAL_UPLOAD_BUFFER_SIZE,
-            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::COPY_SRC,
-            mapped_at_creation: false,
-        });
-
+/// A font and glyph cache that can be shared between renderers, so that
+/// several windows only load fonts and rasterize glyphs once.
+///
+/// Every renderer still owns its glyph atlas texture, since textures
+/// cannot be shared across devices; the shared part is uploaded into each
+/// atlas on first use.
+#[derive(Clone)]
+pub struct TextCacheHandle(Rc<RefCell<FontCache>>);
+
+impl TextCacheHandle {
+    pub fn new() -> Self {
         let default_font = Font::from_bytes(Arc::new(DEFAULT_FONT.to_vec()), 0).unwrap();
-
-        Cache {
-            texture,
-            view,
-            upload_buffer,
-            upload_buffer_size: Self::INITIAL_UPLOAD_BUFFER_SIZE,
-            width,
-            height,
-
+        Self(Rc::new(RefCell::new(FontCache {
             font_source: SystemSource::new(),
-
-            font_families: HashMap::new(),
             fonts: Vec::new(),
             default_font,
             fallback_fonts_range: 0..0,
             fallback_fonts_loaded: false,
-
-            rows: LinkedHashMap::new(),
-            glyphs: HashMap::new(),
+            font_families: HashMap::new(),
             glyph_infos: HashMap::new(),
-            scale: 1.0,
+            bitmaps: HashMap::new(),
+            resolved_fonts: HashMap::new(),
+            resolved_postscript_names: HashMap::new(),
+            strikes: HashMap::new(),
+            emoji_source: EmojiSource::default(),
+            persisted: HashMap::new(),
+            font_digests: HashMap::new(),
+            registered: HashMap::new(),
+            system_fallback: None,
+            system_fallback_fonts: Vec::new(),
+            missing_chars: HashSet::new(),
+            region_fonts: HashMap::new(),
+        })))
+    }
+}
+
+impl Default for TextCacheHandle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct Cache {
+    fonts: TextCacheHandle,
+
+    atlas: GpuAtlas<GlyphInfo>,
+    glyphs: HashMap<GlyphInfo, GlyphPosInfo>,
+    /// The distance fields in the atlas, by glyph.
+    sdf_glyphs: HashMap<GlyphInfo, SdfGlyph>,
+    /// The font size in physical pixels from which glyphs are drawn from
+    /// distance fields, if at all.
+    pub(crate) sdf_min_size: Option<f64>,
+    pub(crate) scale: f64,
+}
+
+/// Where the distance field of a glyph is in the atlas, and the metrics of
+/// the glyph at [`SDF_GLYPH_SIZE`].
+struct SdfGlyph {
+    origin: Point,
+    /// The glyph the distance field was made from, without its pixels.
+    bitmap: GlyphBitmap,
+}
+
+fn get_fallback_fonts() -> Vec<Font> {
+    let mut fonts = Vec::new();
+    for file in FONTS_DIR.files() {
+        if let Ok(font) = Font::from_bytes(Arc::new(file.contents().to_vec()), 0) {
+            fonts.push(font);
         }
     }
+    fonts
+}
 
+impl FontCache {
     fn get_glyph_from_fallback_fonts(&mut self, c: char) -> Option<(usize, u32)> {
         if !self.fallback_fonts_loaded {
             self.fallback_fonts_loaded = true;
@@ -590,22 +997,99 @@ impl Cache {
         None
     }
 
+    /// Find `c` in the registered fonts, then in the installed ones. An
+    /// installed font that has it is loaded for good and searched first for
+    /// the next missing char.
+    fn get_glyph_from_system_fonts(&mut self, c: char) -> Option<(usize, u32)> {
+        if self.missing_chars.contains(&c) {
+            return None;
+        }
+        let mut font_ids = self.registered.values().copied().collect::<Vec<_>>();
+        font_ids.sort_unstable();
+        font_ids.extend_from_slice(&self.system_fallback_fonts);
+        for font_id in font_ids {
+            if let Some(glyph_id) = self.fonts[font_id].glyph_for_char(c) {
+                return Some((font_id, glyph_id));
+            }
+        }
+
+        let font_source = &self.font_source;
+        let handles = self
+            .system_fallback
+            .get_or_insert_with(|| font_source.all_fonts().unwrap_or_default());
+        let found = handles.iter().enumerate().find_map(|(i, handle)| {
+            let font = handle.load().ok()?;
+            let glyph_id = font.glyph_for_char(c)?;
+            Some((i, font, glyph_id))
+        });
+        match found {
+            Some((i, font, glyph_id)) => {
+                handles.remove(i);
+                let font_id = self.push_font(font);
+                self.system_fallback_fonts.push(font_id);
+                Some((font_id, glyph_id))
+            }
+            None => {
+                self.missing_chars.insert(c);
+                None
+            }
+        }
+    }
+
+    /// The font installed for the glyph forms of `region`, if any.
+    fn region_font(&mut self, region: HanRegion, face: FaceQuery) -> Option<usize> {
+        if let Some(font_id) = self.region_fonts.get(&(region, face)) {
+            return *font_id;
+        }
+        let families: Vec<FamilyName> = region
+            .families()
+            .iter()
+            .map(|name| FamilyName::Title(name.to_string()))
+            .collect();
+        let font = self
+            .font_source
+            .select_best_match(&families, &face.properties())
+            .ok()
+            .and_then(|h| h.load().ok());
+        let font_id = font.map(|font| self.push_font(font));
+        self.region_fonts.insert((region, face), font_id);
+        font_id
+    }
+
+    /// Look up `c` in `font_family`, then in the fallback fonts. With a
+    /// `region`, CJK chars prefer the font for its glyph forms over the
+    /// fallback fonts, and over generic families.
     fn get_glyph_info(
         &mut self,
         c: char,
         font_family: FontFamily,
-        font_weight: FontWeight,
+        face: FaceQuery,
         font_size: u32,
+        region: Option<HanRegion>,
     ) -> Result<GlyphInfo, piet::Error> {
-        let key = (c, font_family.clone(), font_weight);
+        let region = region.filter(|_| HanRegion::applies_to(c));
+        let key = (c, font_family.clone(), face, region);
         if !self.glyph_infos.contains_key(&key) {
-            let font_id = self.get_font_by_family(font_family.clone(), font_weight);
+            let region_glyph = |cache: &mut Self| {
+                let font_id = cache.region_font(region?, face)?;
+                Some((font_id, cache.fonts[font_id].glyph_for_char(c)?))
+            };
+            let generic = !matches!(font_family.inner(), piet::FontFamilyInner::Named(_));
+            let font_id = self.get_font_by_family(font_family.clone(), face);
             let font = &self.fonts[font_id];
 
-            let (font_id, glyph_id) = if let Some(glyph_id) = font.glyph_for_char(c) {
+            let glyph_id = font.glyph_for_char(c);
+            let skip_bitmaps =
+                self.emoji_source == EmojiSource::Outline && !self.strikes(font_id).is_empty();
+            let region_first = if generic { region_glyph(self) } else { None };
+            let (font_id, glyph_id) = if let Some(glyph) = region_first {
+                glyph
+            } else if let Some(glyph_id) = glyph_id.filter(|_| !skip_bitmaps) {
                 (font_id, glyph_id)
             } else {
-                self.get_glyph_from_fallback_fonts(c)
+                region_glyph(self)
+                    .or_else(|| self.get_glyph_from_fallback_fonts(c))
+                    .or_else(|| self.get_glyph_from_system_fonts(c))
                     .ok_or(piet::Error::MissingFont)?
             };
 
@@ -618,293 +1102,1225 @@ impl Cache {
             font_id: *font_id,
             font_size,
             glyph_id: *glyph_id,
+            outline: 0,
+            sdf: false,
         })
     }
 
-    pub(crate) fn get_glyph_pos(
+    /// Rasterize `glyph` at `font_size` physical pixels with `padding`
+    /// pixels around it, or return the bitmap rasterized earlier.
+    fn get_glyph_bitmap(
         &mut self,
-        c: char,
-        font_family: FontFamily,
+        glyph: &GlyphInfo,
         font_size: f32,
-        font_weight: FontWeight,
-        device: &wgpu::Device,
-        staging_belt: &mut wgpu::util::StagingBelt,
-        encoder: &mut wgpu::CommandEncoder,
-    ) -> Result<&GlyphPosInfo, piet::Error> {
-        let scale = self.scale;
-
-        let font_size = (font_size as f64 * scale).round() as u32;
-        let glyph = self.get_glyph_info(c, font_family.clone(), font_weight, font_size)?;
-
-        if let Some((row, index)) = self.glyphs.get(&glyph) {
-            let row = self.rows.get(row).unwrap();
-            return Ok(&row.glyphs[*index]);
+        padding: f32,
+    ) -> Result<Rc<GlyphBitmap>, piet::Error> {
+        if let Some(bitmap) = self.cached_glyph_bitmap(glyph) {
+            return Ok(bitmap);
         }
 
-        let padding = 2.0;
+        let strikes = self.strikes(glyph.font_id);
         let font = &self.fonts[glyph.font_id];
-        let font_metrics = font.metrics();
-        let units_per_em = font_metrics.units_per_em as f32;
-        let glyph_real_width =
-            font.advance(glyph.glyph_id).unwrap().x() / units_per_em * font_size as f32;
-        let glyph_real_height =
-            (font_metrics.ascent - font_metrics.descent + font_metrics.line_gap) / units_per_em
-                * font_size as f32;
-        let glyph_metric = GlyphMetricInfo {
-            ascent: (font_metrics.ascent / units_per_em * font_size as f32) as f64 / scale,
-            descent: (font_metrics.descent / units_per_em * font_size as f32) as f64 / scale,
-            line_gap: (font_metrics.line_gap / units_per_em * font_size as f32) as f64 / scale,
-            mono: font.is_monospace(),
-        };
-        let glyph_rect = Size::new(glyph_real_width as f64, glyph_real_height as f64).to_rect();
-
-        let glyph_width = glyph_real_width.ceil() as u32 + padding as u32;
-        let glyph_height = glyph_real_height.ceil() as u32 + padding as u32;
+        let bitmap = Rc::new(rasterize_glyph(
+            font,
+            glyph.glyph_id,
+            font_size,
+            padding,
+            &strikes,
+        )?);
+        self.bitmaps.insert(glyph.clone(), bitmap.clone());
+        Ok(bitmap)
+    }
 
-        let mut canvas = Canvas::new(
-            Vector2I::new(glyph_width as i32, glyph_height as i32),
-            Format::A8,
+    /// The bitmap of `glyph` if it was rasterized already, in this session
+    /// or a persisted one.
+    fn cached_glyph_bitmap(&mut self, glyph: &GlyphInfo) -> Option<Rc<GlyphBitmap>> {
+        if let Some(bitmap) = self.bitmaps.get(glyph) {
+            return Some(bitmap.clone());
+        }
+        if self.persisted.is_empty() {
+            return None;
+        }
+        let key = (
+            self.font_digest(glyph.font_id),
+            glyph.glyph_id,
+            glyph.font_size,
         );
+        let bitmap = self.persisted.remove(&key)?;
+        self.bitmaps.insert(glyph.clone(), bitmap.clone());
+        Some(bitmap)
+    }
 
-        #[cfg(target_os = "macos")]
-        let hinting_options = HintingOptions::None;
+    /// Rasterize those of `glyphs` that aren't cached yet on the rayon
+    /// thread pool. Fonts can't be sent between threads, so every task
+    /// loads its own copy of the fonts it needs from their handles.
+    #[cfg(feature = "rayon")]
+    fn rasterize_glyphs(&mut self, glyphs: &HashMap<GlyphInfo, f32>, padding: f32) {
+        use rayon::prelude::*;
 
-        #[cfg(target_os = "windows")]
-        let hinting_options = HintingOptions::VerticalSubpixel(font_size as f32);
+        const GLYPHS_PER_TASK: usize = 32;
 
-        #[cfg(target_os = "linux")]
-        let hinting_options = HintingOptions::Full(font_size as f32);
+        let mut jobs = Vec::new();
+        for (glyph, font_size) in glyphs {
+            if self.cached_glyph_bitmap(glyph).is_some() {
+                continue;
+            }
+            if let Some(handle) = self.fonts[glyph.font_id].handle() {
+                let strikes = self.strikes(glyph.font_id).to_vec();
+                jobs.push((glyph.clone(), *font_size, handle, strikes));
+            }
+        }
 
-        font.rasterize_glyph(
-            &mut canvas,
-            glyph.glyph_id,
-            font_size as f32,
-            Transform2F::from_translation(Vector2F::new(
-                padding / 2.0,
-                font_metrics.ascent / units_per_em * font_size as f32 + padding / 2.0,
-            )),
-            hinting_options,
-            RasterizationOptions::GrayscaleAa,
-        )
-        .map_err(|_| piet::Error::MissingFont)?;
-
-        let mut offset = [0, 0];
-        let mut inserted = false;
-        for (row_number, row) in self.rows.iter_mut().rev() {
-            if row.height == glyph_height {
-                if self.width - row.width > glyph_width {
-                    let origin = Point::new(
-                        row.width as f64 + padding as f64 / 2.0,
-                        row.y as f64 + padding as f64 / 2.0,
-                    );
-                    let glyph_pos = glyph_rect_to_pos(
-                        glyph_rect,
-                        origin,
-                        &glyph,
-                        &glyph_metric,
-                        scale,
-                        [self.width, self.height],
-                    );
-
-                    row.glyphs.push(glyph_pos);
-                    offset[0] = row.width;
-                    offset[1] = row.y;
-                    row.width += glyph_width;
-                    self.glyphs
-                        .insert(glyph.clone(), (*row_number, row.glyphs.len() - 1));
-                    inserted = true;
-                    break;
+        let bitmaps: Vec<(GlyphInfo, GlyphBitmap)> = jobs
+            .par_chunks(GLYPHS_PER_TASK)
+            .flat_map_iter(|jobs| {
+                let mut fonts: HashMap<usize, Option<Font>> = HashMap::new();
+                let mut bitmaps = Vec::with_capacity(jobs.len());
+                for (glyph, font_size, handle, strikes) in jobs {
+                    let font = fonts
+                        .entry(glyph.font_id)
+                        .or_insert_with(|| handle.load().ok());
+                    if let Some(font) = font.as_ref() {
+                        if let Ok(bitmap) =
+                            rasterize_glyph(font, glyph.glyph_id, *font_size, padding, strikes)
+                        {
+                            bitmaps.push((glyph.clone(), bitmap));
+                        }
+                    }
                 }
+                bitmaps
+            })
+            .collect();
+        for (glyph, bitmap) in bitmaps {
+            self.bitmaps.insert(glyph, Rc::new(bitmap));
+        }
+    }
+
+    /// The ppem sizes of the bitmap strikes of a color bitmap font, empty
+    /// for outline fonts.
+    fn strikes(&mut self, font_id: usize) -> Rc<Vec<f32>> {
+        if let Some(strikes) = self.strikes.get(&font_id) {
+            return strikes.clone();
+        }
+        let font = &self.fonts[font_id];
+        let mut strikes = font
+            .load_font_table(u32::from_be_bytes(*b"CBLC"))
+            .map(|table| cblc_strikes(&table))
+            .or_else(|| {
+                font.load_font_table(u32::from_be_bytes(*b"sbix"))
+                    .map(|table| sbix_strikes(&table))
+            })
+            .unwrap_or_default();
+        strikes.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let strikes = Rc::new(strikes);
+        self.strikes.insert(font_id, strikes.clone());
+        strikes
+    }
+
+    /// A digest of the data of a font, which identifies it across sessions.
+    fn font_digest(&mut self, font_id: usize) -> Vec<u8> {
+        if let Some(digest) = self.font_digests.get(&font_id) {
+            return digest.clone();
+        }
+        let mut hasher = Sha256::new();
+        if let Some(data) = self.fonts[font_id].copy_font_data() {
+            hasher.update(data.as_slice());
+        }
+        let digest = hasher.finalize().to_vec();
+        self.font_digests.insert(font_id, digest.clone());
+        digest
+    }
+
+    fn save(&mut self, path: &Path) -> io::Result<()> {
+        let bitmaps: Vec<(GlyphInfo, Rc<GlyphBitmap>)> = self
+            .bitmaps
+            .iter()
+            .map(|(glyph, bitmap)| (glyph.clone(), bitmap.clone()))
+            .collect();
+        let mut entries: Vec<(PersistedGlyph, Rc<GlyphBitmap>)> = bitmaps
+            .into_iter()
+            .map(|(glyph, bitmap)| {
+                let digest = self.font_digest(glyph.font_id);
+                ((digest, glyph.glyph_id, glyph.font_size), bitmap)
+            })
+            .collect();
+        entries.extend(self.persisted.iter().map(|(k, v)| (k.clone(), v.clone())));
+
+        let mut out = io::BufWriter::new(std::fs::File::create(path)?);
+        out.write_all(&glyph_cache_header())?;
+        out.write_all(&(entries.len() as u32).to_le_bytes())?;
+        for ((digest, glyph_id, font_size), bitmap) in entries {
+            out.write_all(&(digest.len() as u32).to_le_bytes())?;
+            out.write_all(&digest)?;
+            for n in [glyph_id, font_size, bitmap.width, bitmap.height] {
+                out.write_all(&n.to_le_bytes())?;
             }
+            for n in [
+                bitmap.real_width,
+                bitmap.real_height,
+                bitmap.ascent,
+                bitmap.descent,
+                bitmap.line_gap,
+            ] {
+                out.write_all(&n.to_le_bytes())?;
+            }
+            out.write_all(&[bitmap.mono as u8])?;
+            for n in [bitmap.ink.x0, bitmap.ink.y0, bitmap.ink.x1, bitmap.ink.y1] {
+                out.write_all(&n.to_le_bytes())?;
+            }
+            out.write_all(&bitmap.pixels)?;
         }
+        out.flush()
+    }
 
-        if !inserted {
-            let mut y = 0;
-            if self.rows.len() > 0 {
-                let last_row = self.rows.get(&(self.rows.len() - 1)).unwrap();
-                y = last_row.y + last_row.height;
+    fn load(&mut self, path: &Path) -> io::Result<usize> {
+        let data = std::fs::read(path)?;
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid glyph cache");
+        let mut reader = ByteReader(&data);
+        if reader
+            .take(glyph_cache_header().len())
+            .ok_or_else(invalid)?
+            != glyph_cache_header()
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "glyph cache from another version or platform",
+            ));
+        }
+        let count = reader.u32().ok_or_else(invalid)?;
+        for _ in 0..count {
+            let digest_len = reader.u32().ok_or_else(invalid)? as usize;
+            let digest = reader.take(digest_len).ok_or_else(invalid)?.to_vec();
+            let mut read = || reader.u32();
+            let (glyph_id, font_size, width, height) = match (read(), read(), read(), read()) {
+                (Some(a), Some(b), Some(c), Some(d)) => (a, b, c, d),
+                _ => return Err(invalid()),
+            };
+            let mut f = [0.0f32; 5];
+            for v in f.iter_mut() {
+                *v = reader.f32().ok_or_else(invalid)?;
             }
-            if self.height < y + glyph_height {
-                return Err(piet::Error::MissingFont);
+            let mono = reader.take(1).ok_or_else(invalid)?[0] != 0;
+            let mut ink = [0.0f64; 4];
+            for v in ink.iter_mut() {
+                *v = reader.f64().ok_or_else(invalid)?;
             }
+            let pixels = reader
+                .take(width as usize * height as usize)
+                .ok_or_else(invalid)?
+                .to_vec();
+            let bitmap = GlyphBitmap {
+                width,
+                height,
+                pixels,
+                real_width: f[0],
+                real_height: f[1],
+                ascent: f[2],
+                descent: f[3],
+                line_gap: f[4],
+                mono,
+                ink: Rect::new(ink[0], ink[1], ink[2], ink[3]),
+            };
+            self.persisted
+                .insert((digest, glyph_id, font_size), Rc::new(bitmap));
+        }
+        Ok(count as usize)
+    }
 
-            let origin = Point::new(0.0 + padding as f64 / 2.0, y as f64 + padding as f64 / 2.0);
-            let glyph_pos = glyph_rect_to_pos(
-                glyph_rect,
-                origin,
-                &glyph,
-                &glyph_metric,
-                scale,
-                [self.width, self.height],
-            );
+    fn set_emoji_source(&mut self, emoji_source: EmojiSource) {
+        if self.emoji_source != emoji_source {
+            self.emoji_source = emoji_source;
+            self.glyph_infos.clear();
+        }
+    }
 
-            offset[0] = 0;
-            offset[1] = y;
-            let new_row = self.rows.len();
-            let glyphs = vec![glyph_pos];
-            let row = Row {
-                y,
-                height: glyph_height,
-                width: glyph_width,
-                glyphs,
+    fn get_font_by_family(&mut self, family: FontFamily, face: FaceQuery) -> usize {
+        if !self.font_families.contains_key(&(family.clone(), face)) {
+            let font_id = match self.registered_face(&family, face) {
+                Some(font_id) => font_id,
+                None => {
+                    let font = self.get_new_font(&family, face);
+                    self.push_font(font)
+                }
             };
+            self.font_families.insert((family.clone(), face), font_id);
+        }
+
+        let font_id = self.font_families.get(&(family.clone(), face)).unwrap();
+        *font_id
+    }
 
-            self.rows.insert(new_row, row);
-            self.glyphs.insert(glyph.clone(), (new_row, 0));
+    /// The installed face of `family` closest to `face`, matched by
+    /// font-kit like CSS matches faces, or the default font.
+    fn get_new_font(&self, family: &FontFamily, face: FaceQuery) -> Font {
+        self.font_source
+            .select_best_match(&[family_name(family)], &face.properties())
+            .ok()
+            .and_then(|h| h.load().ok())
+            .unwrap_or(self.default_font.clone())
+    }
+
+    /// Load the closest installed face of `family`, without falling back
+    /// to the default font when the family isn't installed.
+    fn resolve(
+        &mut self,
+        family: &FontFamily,
+        weight: FontWeight,
+        style: FontStyle,
+    ) -> Option<usize> {
+        let key = (family.clone(), weight, style);
+        if let Some(font_id) = self.resolved_fonts.get(&key) {
+            return *font_id;
+        }
+        let face = FaceQuery::new(weight, style, FontStretch::NORMAL);
+        if let Some(font_id) = self.registered_face(family, face) {
+            return Some(font_id);
         }
+        let font = self
+            .font_source
+            .select_best_match(&[family_name(family)], &face.properties())
+            .ok()
+            .and_then(|h| h.load().ok());
+        let font_id = font.map(|font| self.push_font(font));
+        self.resolved_fonts.insert(key, font_id);
+        font_id
+    }
 
-        self.update(
-            device,
-            staging_belt,
-            encoder,
-            offset,
-            [glyph_width, glyph_height],
-            &canvas.pixels,
-        );
+    fn resolve_postscript_name(&mut self, name: &str) -> Option<usize> {
+        if let Some(font_id) = self.resolved_postscript_names.get(name) {
+            return *font_id;
+        }
+        let font = self
+            .font_source
+            .select_by_postscript_name(name)
+            .ok()
+            .and_then(|h| h.load().ok());
+        let font_id = font.map(|font| self.push_font(font));
+        self.resolved_postscript_names
+            .insert(name.to_string(), font_id);
+        font_id
+    }
 
-        let (row, index) = self.glyphs.get(&glyph).unwrap();
-        let row = self.rows.get(row).unwrap();
-        Ok(&row.glyphs[*index])
+    fn push_font(&mut self, font: Font) -> usize {
+        self.fonts.push(font);
+        self.fonts.len() - 1
     }
 
-    fn get_font_by_family(&mut self, family: FontFamily, weight: FontWeight) -> usize {
-        if !self.font_families.contains_key(&(family.clone(), weight)) {
-            let font = self.get_new_font(&family, weight);
-            let font_id = self.fonts.len();
-            self.font_families.insert((family.clone(), weight), font_id);
-            self.fonts.push(font);
+    /// Register every face in `data`, a font file or collection, skipping
+    /// faces of a family, weight and style that are registered already.
+    /// Returns the family of the first face.
+    fn load_font(&mut self, data: Arc<Vec<u8>>) -> Result<FontFamily, piet::Error> {
+        let count = match Font::analyze_bytes(data.clone()) {
+            Ok(FileType::Single) => 1,
+            Ok(FileType::Collection(count)) => count,
+            Err(_) => return Err(piet::Error::FontLoadingFailed),
+        };
+        let mut family = None;
+        for index in 0..count {
+            let font = Font::from_bytes(data.clone(), index)
+                .map_err(|_| piet::Error::FontLoadingFailed)?;
+            let name = font.family_name();
+            let key = (name.to_lowercase(), FaceQuery::of(&font.properties()));
+            if !self.registered.contains_key(&key) {
+                let font_id = self.push_font(font);
+                self.registered.insert(key, font_id);
+            }
+            family.get_or_insert_with(|| FontFamily::new_unchecked(name));
         }
 
-        let font_id = self.font_families.get(&(family.clone(), weight)).unwrap();
-        *font_id
+        // Text of the new families was drawn with other fonts so far.
+        self.font_families.clear();
+        self.glyph_infos.clear();
+        self.resolved_fonts.clear();
+        self.missing_chars.clear();
+        family.ok_or(piet::Error::FontLoadingFailed)
     }
 
-    fn get_new_font(&self, family: &FontFamily, weight: FontWeight) -> Font {
-        let family_name = match family.inner() {
-            piet::FontFamilyInner::Serif => FamilyName::Serif,
-            piet::FontFamilyInner::SansSerif => FamilyName::SansSerif,
-            piet::FontFamilyInner::Monospace => FamilyName::Monospace,
-            piet::FontFamilyInner::SystemUi => FamilyName::SansSerif,
-            piet::FontFamilyInner::Named(name) => {
-                font_kit::family_name::FamilyName::Title(name.to_string())
+    /// The registered face of `family` closest to `face`.
+    fn registered_face(&self, family: &FontFamily, face: FaceQuery) -> Option<usize> {
+        let name = family.name().to_lowercase();
+        self.registered
+            .iter()
+            .filter(|((family, _), _)| *family == name)
+            .min_by_key(|((_, registered), _)| face.distance(registered))
+            .map(|(_, font_id)| *font_id)
+    }
+}
+
+/// Identifies the version, platform and rasterization settings that wrote
+/// a glyph cache file.
+fn glyph_cache_header() -> Vec<u8> {
+    let mut header = b"PWGC".to_vec();
+    header.extend_from_slice(&GLYPH_CACHE_VERSION.to_le_bytes());
+    header.extend_from_slice(&GLYPH_PADDING.to_le_bytes());
+    header.extend_from_slice(&(FONT_SIZE_UNITS as u32).to_le_bytes());
+    header.extend_from_slice(std::env::consts::OS.as_bytes());
+    header
+}
+
+/// Reads little endian values off the front of a byte slice.
+struct ByteReader<'a>(&'a [u8]);
+
+impl<'a> ByteReader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (bytes, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(bytes)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.take(4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn f32(&mut self) -> Option<f32> {
+        self.u32().map(f32::from_bits)
+    }
+
+    fn f64(&mut self) -> Option<f64> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Some(f64::from_le_bytes(bytes))
+    }
+}
+
+/// Rasterize `glyph_id` of `font` at `font_size` physical pixels with
+/// `padding` pixels around it. Bitmap fonts are rasterized at the closest
+/// of their `strikes` and scaled.
+fn rasterize_glyph(
+    font: &Font,
+    glyph_id: u32,
+    font_size: f32,
+    padding: f32,
+    strikes: &[f32],
+) -> Result<GlyphBitmap, piet::Error> {
+    let font_metrics = font.metrics();
+    let units_per_em = font_metrics.units_per_em as f32;
+    let glyph_real_width = font.advance(glyph_id).unwrap().x() / units_per_em * font_size;
+    let glyph_real_height = (font_metrics.ascent - font_metrics.descent + font_metrics.line_gap)
+        / units_per_em
+        * font_size;
+
+    let glyph_width = glyph_real_width.ceil() as u32 + padding as u32;
+    let glyph_height = glyph_real_height.ceil() as u32 + padding as u32;
+
+    // Bitmap fonts only rasterize at the sizes of their strikes, so take
+    // the closest one and scale its bitmap to the requested size.
+    let raster_size = select_strike(strikes, font_size);
+    let raster_scale = raster_size.unwrap_or(font_size) / font_size;
+    let canvas_width = (glyph_width as f32 * raster_scale).ceil() as u32;
+    let canvas_height = (glyph_height as f32 * raster_scale).ceil() as u32;
+    let mut canvas = Canvas::new(
+        Vector2I::new(canvas_width as i32, canvas_height as i32),
+        Format::A8,
+    );
+
+    #[cfg(target_os = "macos")]
+    let hinting_options = HintingOptions::None;
+
+    #[cfg(target_os = "windows")]
+    let hinting_options = HintingOptions::VerticalSubpixel(font_size);
+
+    #[cfg(target_os = "linux")]
+    let hinting_options = HintingOptions::Full(font_size);
+
+    font.rasterize_glyph(
+        &mut canvas,
+        glyph_id,
+        font_size * raster_scale,
+        Transform2F::from_translation(
+            Vector2F::new(
+                padding / 2.0,
+                font_metrics.ascent / units_per_em * font_size + padding / 2.0,
+            ) * raster_scale,
+        ),
+        hinting_options,
+        RasterizationOptions::GrayscaleAa,
+    )
+    .map_err(|_| piet::Error::MissingFont)?;
+
+    let pixels = if raster_size.is_some() {
+        scale_coverage(
+            &canvas.pixels,
+            [canvas_width, canvas_height],
+            [glyph_width, glyph_height],
+        )
+    } else {
+        canvas.pixels
+    };
+
+    // Font units point up from the baseline.
+    let ink = font
+        .typographic_bounds(glyph_id)
+        .map(|b| {
+            let to_px = (font_size / units_per_em) as f64;
+            let ascent = font_metrics.ascent as f64 * to_px;
+            Rect::new(
+                b.min_x() as f64 * to_px,
+                ascent - b.max_y() as f64 * to_px,
+                b.max_x() as f64 * to_px,
+                ascent - b.min_y() as f64 * to_px,
+            )
+        })
+        .unwrap_or(Rect::ZERO);
+
+    Ok(GlyphBitmap {
+        width: glyph_width,
+        height: glyph_height,
+        pixels,
+        real_width: glyph_real_width,
+        real_height: glyph_real_height,
+        ink,
+        ascent: font_metrics.ascent / units_per_em * font_size,
+        descent: font_metrics.descent / units_per_em * font_size,
+        line_gap: font_metrics.line_gap / units_per_em * font_size,
+        mono: font.is_monospace(),
+    })
+}
+
+/// The strike to rasterize at for `font_size`: the smallest one at least as
+/// large, or else the largest. `None` for outline fonts.
+fn select_strike(strikes: &[f32], font_size: f32) -> Option<f32> {
+    strikes
+        .iter()
+        .copied()
+        .find(|ppem| *ppem >= font_size)
+        .or_else(|| strikes.last().copied())
+}
+
+/// The ppem of each `BitmapSize` record of a `CBLC` table.
+fn cblc_strikes(table: &[u8]) -> Vec<f32> {
+    const RECORD_SIZE: usize = 48;
+    const PPEM_OFFSET: usize = 44;
+    let count = table
+        .get(4..8)
+        .map(|n| u32::from_be_bytes([n[0], n[1], n[2], n[3]]) as usize)
+        .unwrap_or(0);
+    (0..count)
+        .filter_map(|i| table.get(8 + i * RECORD_SIZE + PPEM_OFFSET))
+        .map(|ppem| *ppem as f32)
+        .collect()
+}
+
+/// The ppem of each strike of an `sbix` table.
+fn sbix_strikes(table: &[u8]) -> Vec<f32> {
+    let read_u32 = |at: usize| {
+        table
+            .get(at..at + 4)
+            .map(|n| u32::from_be_bytes([n[0], n[1], n[2], n[3]]) as usize)
+    };
+    let count = read_u32(4).unwrap_or(0);
+    (0..count)
+        .filter_map(|i| read_u32(8 + i * 4))
+        .filter_map(|offset| table.get(offset..offset + 2))
+        .map(|ppem| u16::from_be_bytes([ppem[0], ppem[1]]) as f32)
+        .collect()
+}
+
+/// Box filter an 8 bit coverage bitmap of `from` size down (or up) to `to`.
+fn scale_coverage(pixels: &[u8], from: [u32; 2], to: [u32; 2]) -> Vec<u8> {
+    let [from_w, from_h] = [from[0] as usize, from[1] as usize];
+    let [to_w, to_h] = [to[0] as usize, to[1] as usize];
+    let mut scaled = Vec::with_capacity(to_w * to_h);
+    for y in 0..to_h {
+        let y0 = y * from_h / to_h;
+        let y1 = ((y + 1) * from_h / to_h).max(y0 + 1).min(from_h);
+        for x in 0..to_w {
+            let x0 = x * from_w / to_w;
+            let x1 = ((x + 1) * from_w / to_w).max(x0 + 1).min(from_w);
+            let mut sum = 0u32;
+            for row in y0..y1 {
+                for col in x0..x1 {
+                    sum += pixels[row * from_w + col] as u32;
+                }
             }
-            _ => FamilyName::SansSerif,
+            let count = ((y1 - y0) * (x1 - x0)).max(1) as u32;
+            scaled.push((sum / count) as u8);
+        }
+    }
+    scaled
+}
+
+fn family_name(family: &FontFamily) -> FamilyName {
+    match family.inner() {
+        piet::FontFamilyInner::Serif => FamilyName::Serif,
+        piet::FontFamilyInner::SansSerif => FamilyName::SansSerif,
+        piet::FontFamilyInner::Monospace => FamilyName::Monospace,
+        piet::FontFamilyInner::SystemUi => FamilyName::SansSerif,
+        piet::FontFamilyInner::Named(name) => FamilyName::Title(name.to_string()),
+        _ => FamilyName::SansSerif,
+    }
+}
+
+impl TextCacheHandle {
+    /// The bytes of the rasterized glyphs kept for filling atlases.
+    pub(crate) fn glyph_bitmap_bytes(&self) -> u64 {
+        let cache = self.0.borrow();
+        cache
+            .bitmaps
+            .values()
+            .chain(cache.persisted.values())
+            .map(|bitmap| bitmap.pixels.len() as u64)
+            .sum()
+    }
+
+    /// Drop the rasterized glyphs; glyphs not in an atlas yet are
+    /// rasterized again when they are drawn.
+    pub(crate) fn clear_glyph_bitmaps(&self) {
+        let mut cache = self.0.borrow_mut();
+        cache.bitmaps.clear();
+        cache.persisted.clear();
+    }
+
+    pub(crate) fn resolve(
+        &self,
+        family: &FontFamily,
+        weight: FontWeight,
+        style: FontStyle,
+    ) -> Option<FontId> {
+        self.0
+            .borrow_mut()
+            .resolve(family, weight, style)
+            .map(FontId)
+    }
+
+    pub(crate) fn resolve_postscript_name(&self, name: &str) -> Option<FontId> {
+        self.0
+            .borrow_mut()
+            .resolve_postscript_name(name)
+            .map(FontId)
+    }
+
+    pub(crate) fn set_emoji_source(&self, emoji_source: EmojiSource) {
+        self.0.borrow_mut().set_emoji_source(emoji_source);
+    }
+
+    /// Rasterize `glyph_id` of the font for `family` and `weight` at
+    /// `font_size` physical pixels, without padding and outside the atlas.
+    pub(crate) fn rasterize_glyph(
+        &self,
+        family: FontFamily,
+        weight: FontWeight,
+        font_size: f32,
+        glyph_id: u32,
+    ) -> Option<crate::text::GlyphBitmap> {
+        let mut fonts = self.0.borrow_mut();
+        let font_id = fonts.get_font_by_family(family, weight.into());
+        if glyph_id >= fonts.fonts[font_id].glyph_count() {
+            return None;
+        }
+        let strikes = fonts.strikes(font_id);
+        let bitmap =
+            rasterize_glyph(&fonts.fonts[font_id], glyph_id, font_size, 0.0, &strikes).ok()?;
+        Some(crate::text::GlyphBitmap {
+            width: bitmap.width,
+            height: bitmap.height,
+            pixels: bitmap.pixels,
+            content: crate::text::GlyphContent::Mask,
+            left: 0,
+            top: bitmap.ascent.round() as i32,
+            advance: bitmap.real_width,
+        })
+    }
+
+    /// Where the font of `glyph` puts underlines and strikethroughs, in
+    /// logical pixels for the renderer `scale`.
+    pub(crate) fn decoration_metrics(
+        &self,
+        glyph: &GlyphInfo,
+        scale: f64,
+    ) -> Option<DecorationMetrics> {
+        if glyph.font_size == 0 {
+            return None;
+        }
+        let fonts = self.0.borrow();
+        let metrics = fonts.fonts[glyph.font_id].metrics();
+        let units = glyph.font_size as f64 / FONT_SIZE_UNITS / scale / metrics.units_per_em as f64;
+        // At least a physical pixel, so that thin lines don't vanish.
+        let thickness = (metrics.underline_thickness as f64 * units).max(1.0 / scale);
+        let x_height = if metrics.x_height > 0.0 {
+            metrics.x_height
+        } else {
+            metrics.ascent / 2.0
         };
-        let font = self
-            .font_source
-            .select_best_match(
-                &[family_name],
-                &font_kit::properties::Properties::new()
-                    .weight(font_kit::properties::Weight(weight.to_raw() as f32)),
-            )
-            .ok()
-            .and_then(|h| h.load().ok())
-            .unwrap_or(self.default_font.clone());
-        font
+        Some(DecorationMetrics {
+            underline: -metrics.underline_position as f64 * units - thickness / 2.0,
+            strikethrough: -x_height as f64 / 2.0 * units - thickness / 2.0,
+            thickness,
+        })
+    }
+
+    /// The outline of `glyph` in logical pixels for the renderer `scale`,
+    /// y pointing down and the origin on the baseline. `None` for bitmap
+    /// glyphs and glyphs the font has no outline for.
+    pub(crate) fn glyph_outline(&self, glyph: &GlyphInfo, scale: f64) -> Option<BezPath> {
+        if glyph.font_size == 0 {
+            return None;
+        }
+        let fonts = self.0.borrow();
+        let font = &fonts.fonts[glyph.font_id];
+        let units_per_em = font.metrics().units_per_em as f64;
+        let mut sink = OutlinePath {
+            path: BezPath::new(),
+            scale: glyph.font_size as f64 / FONT_SIZE_UNITS / scale / units_per_em,
+        };
+        font.outline(glyph.glyph_id, HintingOptions::None, &mut sink)
+            .ok()?;
+        Some(sink.path)
+    }
+
+    /// Write every rasterized glyph to a cache file in `dir`, for
+    /// [`TextCacheHandle::load_from_dir`] to pick up in the next session.
+    pub fn save_to_dir(&self, dir: &Path) -> io::Result<()> {
+        std::fs::create_dir_all(dir)?;
+        self.0.borrow_mut().save(&dir.join(GLYPH_CACHE_FILE))
+    }
+
+    /// Load the glyphs saved with [`TextCacheHandle::save_to_dir`] and
+    /// return how many there were. Glyphs are matched by a digest of their
+    /// font's data, so glyphs of fonts that changed are never used, and
+    /// files written by another version or platform are rejected.
+    pub fn load_from_dir(&self, dir: &Path) -> io::Result<usize> {
+        self.0.borrow_mut().load(&dir.join(GLYPH_CACHE_FILE))
+    }
+
+    pub(crate) fn postscript_name(&self, font: FontId) -> Option<String> {
+        self.0.borrow().fonts.get(font.0)?.postscript_name()
+    }
+
+    pub(crate) fn family_name(&self, font: FontId) -> Option<String> {
+        Some(self.0.borrow().fonts.get(font.0)?.family_name())
+    }
+
+    pub(crate) fn load_font(&self, data: Arc<Vec<u8>>) -> Result<FontFamily, piet::Error> {
+        self.0.borrow_mut().load_font(data)
+    }
+
+    /// Whether a face of the family `name` was registered with
+    /// [`TextCacheHandle::load_font`].
+    pub(crate) fn has_registered_family(&self, name: &str) -> bool {
+        let name = name.to_lowercase();
+        self.0
+            .borrow()
+            .registered
+            .keys()
+            .any(|(family, _)| *family == name)
+    }
+}
+
+impl Cache {
+    pub fn new(device: &wgpu::Device, width: u32, height: u32, fonts: TextCacheHandle) -> Cache {
+        Cache {
+            fonts,
+
+            atlas: GpuAtlas::new(
+                device,
+                "wgpu_glyph::Cache",
+                wgpu::TextureFormat::R8Unorm,
+                width,
+                height,
+            ),
+            glyphs: HashMap::new(),
+            sdf_glyphs: HashMap::new(),
+            sdf_min_size: None,
+            scale: 1.0,
+        }
+    }
+
+    pub(crate) fn fonts(&self) -> TextCacheHandle {
+        self.fonts.clone()
+    }
+
+    pub(crate) fn view(&self) -> &wgpu::TextureView {
+        self.atlas.view()
+    }
+
+    pub(crate) fn atlas(&self) -> &GpuAtlas<GlyphInfo> {
+        &self.atlas
+    }
+
+    pub(crate) fn generation(&self) -> u64 {
+        self.atlas.generation()
+    }
+
+    pub(crate) fn stats(&self) -> AtlasStats {
+        self.atlas.stats()
+    }
+
+    pub(crate) fn atlas_bytes(&self) -> u64 {
+        self.atlas.memory_bytes()
     }
 
-    pub fn update(
+    /// Upload the glyphs rasterized since the last frame.
+    pub(crate) fn flush(
         &mut self,
         device: &wgpu::Device,
         staging_belt: &mut wgpu::util::StagingBelt,
         encoder: &mut wgpu::CommandEncoder,
-        offset: [u32; 2],
-        size: [u32; 2],
-        data: &[u8],
     ) {
-        let width = size[0] as usize;
-        let height = size[1] as usize;
+        self.atlas.flush(device, staging_belt, encoder);
+    }
 
-        if width == 0 || height == 0 {
-            return;
+    /// The advance of `c` in logical pixels, without rasterizing it.
+    pub(crate) fn get_glyph_advance(
+        &mut self,
+        c: char,
+        font_family: FontFamily,
+        font_size: f32,
+        font_weight: FontWeight,
+    ) -> Result<f64, piet::Error> {
+        let mut fonts = self.fonts.0.borrow_mut();
+        let glyph = fonts.get_glyph_info(c, font_family, font_weight.into(), 0, None)?;
+        let font = &fonts.fonts[glyph.font_id];
+        let units_per_em = font.metrics().units_per_em as f32;
+        let advance = font
+            .advance(glyph.glyph_id)
+            .map_err(|_| piet::Error::MissingFont)?
+            .x();
+        Ok((advance / units_per_em * font_size) as f64)
+    }
+
+    /// Rasterize the glyphs of `chars` that this atlas doesn't hold yet in
+    /// parallel, ahead of [`Cache::get_glyph_pos`] looking them up one by
+    /// one. Worth it for large layouts with many new glyphs only.
+    #[cfg(feature = "rayon")]
+    pub(crate) fn rasterize_parallel(
+        &mut self,
+        chars: impl Iterator<Item = (char, FontFamily, f32, FaceQuery, Option<HanRegion>)>,
+    ) {
+        let mut fonts = self.fonts.0.borrow_mut();
+        let mut glyphs = HashMap::new();
+        for (c, font_family, font_size, face, region) in chars {
+            let font_size = font_size as f64 * self.scale;
+            if let Ok(glyph) = fonts.get_glyph_info(
+                c,
+                font_family,
+                face,
+                (font_size * FONT_SIZE_UNITS).round() as u32,
+                region,
+            ) {
+                let sdf = self.sdf_min_size.is_some_and(|min| font_size >= min);
+                if !sdf && !self.glyphs.contains_key(&glyph) {
+                    glyphs.insert(glyph, font_size as f32);
+                }
+            }
+        }
+        fonts.rasterize_glyphs(&glyphs, GLYPH_PADDING);
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn get_glyph_pos(
+        &mut self,
+        c: char,
+        font_family: FontFamily,
+        font_size: f32,
+        face: FaceQuery,
+        region: Option<HanRegion>,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+    ) -> Result<&GlyphPosInfo, piet::Error> {
+        let scale = self.scale;
+
+        let font_size = font_size as f64 * scale;
+        let glyph = self.fonts.0.borrow_mut().get_glyph_info(
+            c,
+            font_family,
+            face,
+            (font_size * FONT_SIZE_UNITS).round() as u32,
+            region,
+        )?;
+        let font_size = font_size as f32;
+        let glyph = GlyphInfo {
+            sdf: self.draws_sdf(&glyph, font_size),
+            ..glyph
+        };
+
+        if self.glyphs.contains_key(&glyph) {
+            return Ok(&self.glyphs[&glyph]);
+        }
+        if glyph.sdf {
+            return self.get_sdf_glyph_pos(glyph, font_size, device, encoder);
         }
 
-        // It is a webgpu requirement that:
-        //  BufferCopyView.layout.bytes_per_row % wgpu::COPY_BYTES_PER_ROW_ALIGNMENT == 0
-        // So we calculate padded_width by rounding width
-        // up to the next multiple of wgpu::COPY_BYTES_PER_ROW_ALIGNMENT.
-        let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT as usize;
-        let padded_width_padding = (align - width % align) % align;
-        let padded_width = width + padded_width_padding;
+        let padding = GLYPH_PADDING;
+        let bitmap = self
+            .fonts
+            .0
+            .borrow_mut()
+            .get_glyph_bitmap(&glyph, font_size, padding)?;
+        let glyph_metric = GlyphMetricInfo {
+            ascent: bitmap.ascent as f64 / scale,
+            descent: bitmap.descent as f64 / scale,
+            line_gap: bitmap.line_gap as f64 / scale,
+            mono: bitmap.mono,
+            ink: bitmap.ink.scale_from_origin(1.0 / scale),
+        };
+        // The quad covers whole physical pixels so that snapping its corners
+        // to the pixel grid in the shader never stretches the bitmap.
+        let glyph_rect = Size::new(
+            bitmap.real_width.ceil() as f64,
+            bitmap.real_height.ceil() as f64,
+        )
+        .to_rect();
+        let advance = bitmap.real_width as f64 / scale;
+
+        let rect = self
+            .atlas
+            .insert(
+                device,
+                encoder,
+                glyph.clone(),
+                [bitmap.width, bitmap.height],
+                &bitmap.pixels,
+            )
+            .ok_or(piet::Error::MissingFont)?;
 
-        let padded_data_size = (padded_width * height) as u64;
+        let origin = Point::new(
+            rect.x as f64 + padding as f64 / 2.0,
+            rect.y as f64 + padding as f64 / 2.0,
+        );
+        let glyph_pos =
+            glyph_rect_to_pos(glyph_rect, advance, origin, &glyph, &glyph_metric, scale);
 
-        if self.upload_buffer_size < padded_data_size {
-            self.upload_buffer = device.create_buffer(&wgpu::BufferDescriptor {
-                label: Some("wgpu_glyph::Cache upload buffer"),
-                size: padded_data_size,
-                usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::COPY_SRC,
-                mapped_at_creation: false,
-            });
+        Ok(self.glyphs.entry(glyph).or_insert(glyph_pos))
+    }
 
-            self.upload_buffer_size = padded_data_size;
+    /// The glyph of `glyph_pos` grown by `width` logical pixels all around,
+    /// for drawing an outline behind it. The glyph's bitmap is dilated into
+    /// a separate atlas entry, so the outline is as cheap to draw as the
+    /// glyph.
+    pub(crate) fn get_outline_pos(
+        &mut self,
+        glyph_pos: &GlyphPosInfo,
+        width: f64,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+    ) -> Result<&GlyphPosInfo, piet::Error> {
+        let scale = self.scale;
+        let radius = width * scale;
+        // Outlines of glyphs drawn from distance fields are dilated from
+        // the glyph's bitmap too.
+        let source = GlyphInfo {
+            sdf: false,
+            ..glyph_pos.info.clone()
+        };
+        let glyph = GlyphInfo {
+            outline: (radius * FONT_SIZE_UNITS).round() as u32,
+            ..source.clone()
+        };
+        if glyph.outline == 0 {
+            return Err(piet::Error::InvalidInput);
+        }
+        if self.glyphs.contains_key(&glyph) {
+            return Ok(&self.glyphs[&glyph]);
         }
 
-        let mut padded_data = staging_belt.write_buffer(
-            encoder,
-            &self.upload_buffer,
-            0,
-            NonZeroU64::new(padded_data_size).unwrap(),
-            device,
+        let padding = GLYPH_PADDING;
+        let bitmap = self.fonts.0.borrow_mut().get_glyph_bitmap(
+            &source,
+            (glyph.font_size as f64 / FONT_SIZE_UNITS) as f32,
+            padding,
+        )?;
+        // The outline grows by whole pixels, keeping its quad on the pixel
+        // grid like that of the glyph.
+        let grow = radius.ceil() as u32;
+        let (width, height) = (bitmap.width + 2 * grow, bitmap.height + 2 * grow);
+        let pixels = dilate(&bitmap.pixels, bitmap.width, bitmap.height, radius, grow);
+        let rect = self
+            .atlas
+            .insert(device, encoder, glyph.clone(), [width, height], &pixels)
+            .ok_or(piet::Error::MissingFont)?;
+
+        let grow = grow as f64;
+        let size = Size::new(
+            bitmap.real_width.ceil() as f64,
+            bitmap.real_height.ceil() as f64,
+        );
+        let cache_rect = Rect::from_origin_size(
+            (
+                rect.x as f64 + padding as f64 / 2.0,
+                rect.y as f64 + padding as f64 / 2.0,
+            ),
+            size + Size::new(2.0 * grow, 2.0 * grow),
         );
+        let outline_pos = GlyphPosInfo {
+            info: glyph.clone(),
+            rect: Rect::from_origin_size(glyph_pos.rect.origin(), size / scale)
+                .inflate(grow / scale, grow / scale),
+            cache_rect,
+            ..glyph_pos.clone()
+        };
+        Ok(self.glyphs.entry(glyph).or_insert(outline_pos))
+    }
+
+    /// Whether `glyph` at `font_size` physical pixels is drawn from its
+    /// distance field. Color bitmap glyphs never are.
+    fn draws_sdf(&self, glyph: &GlyphInfo, font_size: f32) -> bool {
+        self.sdf_min_size.is_some_and(|min| font_size as f64 >= min)
+            && self.fonts.0.borrow_mut().strikes(glyph.font_id).is_empty()
+    }
 
-        for row in 0..height {
-            padded_data[row * padded_width..row * padded_width + width]
-                .copy_from_slice(&data[row * width..(row + 1) * width])
+    /// The position of `glyph` at `font_size` physical pixels, scaled from
+    /// its distance field, which is made at [`SDF_GLYPH_SIZE`] on first use
+    /// and then serves every size.
+    fn get_sdf_glyph_pos(
+        &mut self,
+        glyph: GlyphInfo,
+        font_size: f32,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+    ) -> Result<&GlyphPosInfo, piet::Error> {
+        let base = GlyphInfo {
+            font_size: 0,
+            ..glyph.clone()
+        };
+        if !self.sdf_glyphs.contains_key(&base) {
+            // The padding leaves room for the distance field to fall off
+            // around the glyph.
+            let padding = GLYPH_PADDING + 2.0 * SDF_SPREAD;
+            let bitmap = {
+                let fonts = self.fonts.0.borrow();
+                let font = &fonts.fonts[glyph.font_id];
+                rasterize_glyph(font, glyph.glyph_id, SDF_GLYPH_SIZE, padding, &[])?
+            };
+            let pixels = distance_field(&bitmap.pixels, bitmap.width, bitmap.height, SDF_SPREAD);
+            let rect = self
+                .atlas
+                .insert(
+                    device,
+                    encoder,
+                    base.clone(),
+                    [bitmap.width, bitmap.height],
+                    &pixels,
+                )
+                .ok_or(piet::Error::MissingFont)?;
+            let origin = Point::new(
+                rect.x as f64 + padding as f64 / 2.0,
+                rect.y as f64 + padding as f64 / 2.0,
+            );
+            let bitmap = GlyphBitmap {
+                pixels: Vec::new(),
+                ..bitmap
+            };
+            self.sdf_glyphs
+                .insert(base.clone(), SdfGlyph { origin, bitmap });
         }
 
-        // TODO: Move to use Queue for less buffer usage
-        encoder.copy_buffer_to_texture(
-            wgpu::ImageCopyBuffer {
-                buffer: &self.upload_buffer,
-                layout: wgpu::ImageDataLayout {
-                    offset: 0,
-                    bytes_per_row: NonZeroU32::new(padded_width as u32),
-                    rows_per_image: NonZeroU32::new(height as u32),
-                },
-            },
-            wgpu::ImageCopyTexture {
-                texture: &self.texture,
-                mip_level: 0,
-                origin: wgpu::Origin3d {
-                    x: u32::from(offset[0]),
-                    y: u32::from(offset[1]),
-                    z: 0,
-                },
-                aspect: wgpu::TextureAspect::All,
-            },
-            wgpu::Extent3d {
-                width: size[0] as u32,
-                height: size[1] as u32,
-                depth_or_array_layers: 1,
+        let sdf = &self.sdf_glyphs[&base];
+        let bitmap = &sdf.bitmap;
+        let to_logical = (font_size / SDF_GLYPH_SIZE) as f64 / self.scale;
+        let size = Size::new(bitmap.real_width as f64, bitmap.real_height as f64);
+        let glyph_pos = GlyphPosInfo {
+            info: glyph.clone(),
+            metric: GlyphMetricInfo {
+                ascent: bitmap.ascent as f64 * to_logical,
+                descent: bitmap.descent as f64 * to_logical,
+                line_gap: bitmap.line_gap as f64 * to_logical,
+                mono: bitmap.mono,
+                ink: bitmap.ink.scale_from_origin(to_logical),
             },
+            width: bitmap.real_width as f64 * to_logical,
+            rect: Rect::from_origin_size(sdf.origin, size * to_logical),
+            cache_rect: Rect::from_origin_size(sdf.origin, size),
+            missing: false,
+        };
+        Ok(self.glyphs.entry(glyph).or_insert(glyph_pos))
+    }
+}
+
+/// The signed distance field of the coverage `pixels` of a `width` by
+/// `height` bitmap: 0.5 on the edges of the shape, rising to 1 inside and
+/// falling to 0 outside over `spread` pixels.
+fn distance_field(pixels: &[u8], width: u32, height: u32, spread: f32) -> Vec<u8> {
+    let (width, height) = (width as usize, height as usize);
+    let inside = |i: usize| pixels[i] >= 128;
+    let to_outside = squared_distances(width, height, |i| !inside(i));
+    let to_inside = squared_distances(width, height, inside);
+    (0..width * height)
+        .map(|i| {
+            // In pixels, positive outside. Antialiased pixels are on the
+            // edge, and their coverage tells how far in.
+            let distance = match pixels[i] {
+                1..=254 => 0.5 - pixels[i] as f64 / 255.0,
+                _ if inside(i) => 0.5 - to_outside[i].sqrt(),
+                _ => to_inside[i].sqrt() - 0.5,
+            };
+            let value = 0.5 - distance / (2.0 * spread as f64);
+            (value.clamp(0.0, 1.0) * 255.0).round() as u8
+        })
+        .collect()
+}
+
+/// The squared distance from every pixel of a `width` by `height` grid to
+/// the nearest pixel that `is_target`, with the transform of Felzenszwalb
+/// and Huttenlocher.
+fn squared_distances(width: usize, height: usize, is_target: impl Fn(usize) -> bool) -> Vec<f64> {
+    const FAR: f64 = 1e20;
+    let mut grid: Vec<f64> = (0..width * height)
+        .map(|i| if is_target(i) { 0.0 } else { FAR })
+        .collect();
+    let n = width.max(height);
+    let mut f = vec![0.0; n];
+    let mut d = vec![0.0; n];
+    let mut v = vec![0; n];
+    let mut z = vec![0.0; n + 1];
+    for x in 0..width {
+        for y in 0..height {
+            f[y] = grid[y * width + x];
+        }
+        squared_distances_1d(&f[..height], &mut d[..height], &mut v, &mut z);
+        for y in 0..height {
+            grid[y * width + x] = d[y];
+        }
+    }
+    for row in grid.chunks_mut(width.max(1)) {
+        f[..width].copy_from_slice(row);
+        squared_distances_1d(&f[..width], &mut d[..width], &mut v, &mut z);
+        row.copy_from_slice(&d[..width]);
+    }
+    grid
+}
+
+/// The distance transform of the sampled function `f` into `d`: the lower
+/// envelope of the parabolas rooted at every sample. `v` and `z` are
+/// scratch space for the parabolas of the envelope and their boundaries.
+fn squared_distances_1d(f: &[f64], d: &mut [f64], v: &mut [usize], z: &mut [f64]) {
+    if f.is_empty() {
+        return;
+    }
+    let mut k = 0;
+    v[0] = 0;
+    z[0] = f64::NEG_INFINITY;
+    z[1] = f64::INFINITY;
+    for q in 1..f.len() {
+        loop {
+            let p = v[k];
+            let (qf, pf) = (q as f64, p as f64);
+            let s = ((f[q] + qf * qf) - (f[p] + pf * pf)) / (2.0 * (qf - pf));
+            if s <= z[k] {
+                k -= 1;
+                continue;
+            }
+            k += 1;
+            v[k] = q;
+            z[k] = s;
+            z[k + 1] = f64::INFINITY;
+            break;
+        }
+    }
+    k = 0;
+    for (q, d) in d.iter_mut().enumerate() {
+        while z[k + 1] < q as f64 {
+            k += 1;
+        }
+        let offset = q as f64 - v[k] as f64;
+        *d = offset * offset + f[v[k]];
+    }
+}
+
+/// Grow the coverage `pixels` of a `width` by `height` bitmap by `radius`
+/// pixels in every direction, into a bitmap `grow` pixels larger on each
+/// side. Every pixel takes the most coverage within the radius, fading out
+/// over its last pixel.
+fn dilate(pixels: &[u8], width: u32, height: u32, radius: f64, grow: u32) -> Vec<u8> {
+    let (width, height, grow) = (width as i64, height as i64, grow as i64);
+    let kernel: Vec<(i64, i64, f64)> = (-grow..=grow)
+        .flat_map(|dy| (-grow..=grow).map(move |dx| (dx, dy)))
+        .filter_map(|(dx, dy)| {
+            let weight = (radius + 0.5 - (dx as f64).hypot(dy as f64)).clamp(0.0, 1.0);
+            (weight > 0.0).then_some((dx, dy, weight))
+        })
+        .collect();
+    let out_width = width + 2 * grow;
+    let out_height = height + 2 * grow;
+    let mut out = vec![0; (out_width * out_height) as usize];
+    for y in 0..out_height {
+        for x in 0..out_width {
+            let mut coverage = 0.0_f64;
+            for (dx, dy, weight) in &kernel {
+                let (sx, sy) = (x - grow + dx, y - grow + dy);
+                if sx < 0 || sy < 0 || sx >= width || sy >= height {
+                    continue;
+                }
+                let value = pixels[(sy * width + sx) as usize] as f64 * weight;
+                coverage = coverage.max(value);
+            }
+            out[(y * out_width + x) as usize] = coverage.round() as u8;
+        }
+    }
+    out
+}
+
+/// The top edges of underlines and strikethroughs below the baseline and
+/// their thickness, in logical pixels.
+pub(crate) struct DecorationMetrics {
+    pub(crate) underline: f64,
+    pub(crate) strikethrough: f64,
+    pub(crate) thickness: f64,
+}
+
+/// Collects a glyph outline in font units into a [`BezPath`], scaled and
+/// flipped to y pointing down.
+struct OutlinePath {
+    path: BezPath,
+    scale: f64,
+}
+
+impl OutlinePath {
+    fn point(&self, v: Vector2F) -> Point {
+        Point::new(v.x() as f64 * self.scale, -v.y() as f64 * self.scale)
+    }
+}
+
+impl OutlineSink for OutlinePath {
+    fn move_to(&mut self, to: Vector2F) {
+        self.path.move_to(self.point(to));
+    }
+
+    fn line_to(&mut self, to: Vector2F) {
+        self.path.line_to(self.point(to));
+    }
+
+    fn quadratic_curve_to(&mut self, ctrl: Vector2F, to: Vector2F) {
+        self.path.quad_to(self.point(ctrl), self.point(to));
+    }
+
+    fn cubic_curve_to(&mut self, ctrl: LineSegment2F, to: Vector2F) {
+        self.path.curve_to(
+            self.point(ctrl.from()),
+            self.point(ctrl.to()),
+            self.point(to),
         );
     }
+
+    fn close(&mut self) {
+        self.path.close_path();
+    }
 }
 
 fn glyph_rect_to_pos(
     glyph_rect: Rect,
+    advance: f64,
     origin: Point,
     glyph: &GlyphInfo,
     glyph_metric: &GlyphMetricInfo,
     scale: f64,
-    size: [u32; 2],
 ) -> GlyphPosInfo {
     let glyph_rect = glyph_rect.with_origin(origin);
-    let mut cache_rect = glyph_rect.clone();
-    cache_rect.x0 /= size[0] as f64;
-    cache_rect.x1 /= size[0] as f64;
-    cache_rect.y0 /= size[1] as f64;
-    cache_rect.y1 /= size[1] as f64;
+    let cache_rect = glyph_rect;
     let glyph_pos = GlyphPosInfo {
         info: glyph.clone(),
         rect: glyph_rect.with_size(Size::new(
             glyph_rect.size().width / scale,
             glyph_rect.size().height / scale,
         )),
-        width: glyph_rect.size().width / scale,
+        width: advance,
         metric: glyph_metric.clone(),
         cache_rect,
+        missing: false,
     };
     glyph_pos
 }
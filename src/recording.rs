@@ -0,0 +1,513 @@
+//! A [`RenderContext`] that records the drawing as an SVG document instead
+//! of rendering it, for exporting a view or comparing what was drawn.
+
+use std::{borrow::Cow, fmt::Write};
+
+use piet::{
+    kurbo::{Affine, BezPath, Point, Rect, Shape, Size},
+    Color, Image, ImageFormat, InterpolationMode, IntoBrush, LineCap, LineJoin, RenderContext,
+    StrokeStyle, TextLayout,
+};
+
+use crate::{image, text::WgpuText, text::WgpuTextLayout, Brush};
+
+/// The tolerance for flattening shapes that have no exact path, like
+/// circles, in logical pixels.
+const TOLERANCE: f64 = 0.1;
+
+/// How [`RecordingContext`] writes text.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TextMode {
+    /// The outlines of the glyphs, which look the same everywhere.
+    Paths,
+    /// `<text>` elements, which stay selectable but are drawn with
+    /// whatever fonts the viewer has.
+    Elements,
+}
+
+/// Records drawing into an SVG document. Text layouts come from the
+/// renderer's [`WgpuText`], so that they measure the same as on screen.
+///
+/// Gradients aren't supported, gradient brushes made by a
+/// [`WgpuRenderContext`](crate::Piet) are drawn in the color halfway along
+/// them. Capturing image areas isn't supported, like in
+/// [`WgpuRenderContext`](crate::Piet).
+pub struct RecordingContext {
+    text: WgpuText,
+    size: Size,
+    text_mode: TextMode,
+    defs: String,
+    body: String,
+    next_id: usize,
+    cur_transform: Affine,
+    state_stack: Vec<State>,
+    /// The clip groups opened outside of any saved state.
+    n_clip: usize,
+}
+
+struct State {
+    transform: Affine,
+    /// The clip groups opened since the state was saved.
+    n_clip: usize,
+}
+
+/// An image for [`RecordingContext`], kept as an encoded PNG.
+#[derive(Clone)]
+pub struct RecordingImage {
+    width: usize,
+    height: usize,
+    data_url: String,
+}
+
+impl RecordingContext {
+    /// Record a drawing of `size` logical pixels.
+    pub fn new(text: WgpuText, size: Size) -> Self {
+        Self {
+            text,
+            size,
+            text_mode: TextMode::Paths,
+            defs: String::new(),
+            body: String::new(),
+            next_id: 0,
+            cur_transform: Affine::default(),
+            state_stack: Vec::new(),
+            n_clip: 0,
+        }
+    }
+
+    pub fn set_text_mode(&mut self, text_mode: TextMode) {
+        self.text_mode = text_mode;
+    }
+
+    /// The SVG document of everything drawn so far.
+    pub fn to_svg(&self) -> String {
+        let n_clip = self.n_clip + self.state_stack.iter().map(|s| s.n_clip).sum::<usize>();
+        let mut svg = format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" \
+             xmlns:xlink=\"http://www.w3.org/1999/xlink\" \
+             width=\"{w}\" height=\"{h}\" viewBox=\"0 0 {w} {h}\">\n",
+            w = self.size.width,
+            h = self.size.height,
+        );
+        if !self.defs.is_empty() {
+            svg.push_str("<defs>\n");
+            svg.push_str(&self.defs);
+            svg.push_str("</defs>\n");
+        }
+        svg.push_str(&self.body);
+        for _ in 0..n_clip {
+            svg.push_str("</g>\n");
+        }
+        svg.push_str("</svg>\n");
+        svg
+    }
+
+    fn id(&mut self, prefix: &str) -> String {
+        self.next_id += 1;
+        format!("{}{}", prefix, self.next_id)
+    }
+
+    /// The `transform` attribute for the current transform, if any.
+    fn transform_attr(&self) -> String {
+        transform_attr(self.cur_transform)
+    }
+
+    fn path(&mut self, shape: &impl Shape, attrs: &str) {
+        let d = shape.to_path(TOLERANCE).to_svg();
+        let transform = self.transform_attr();
+        let _ = writeln!(self.body, "<path d=\"{}\"{}{}/>", d, transform, attrs);
+    }
+
+    fn draw_text_paths(&mut self, layout: &WgpuTextLayout, pos: Point) {
+        // Neighbouring glyphs of the same color share a path element.
+        let mut run: Option<(Color, BezPath)> = None;
+        for glyph in layout.glyph_outlines() {
+            let (_, _, _, color) = layout.style_at(glyph.index);
+            match run.as_mut() {
+                Some((run_color, path)) if *run_color == color => {
+                    path.extend(glyph.path.elements().iter().copied());
+                }
+                _ => {
+                    if let Some((run_color, path)) = run.take() {
+                        self.text_run(pos, &path, &run_color);
+                    }
+                    run = Some((color, glyph.path));
+                }
+            }
+        }
+        if let Some((run_color, path)) = run {
+            self.text_run(pos, &path, &run_color);
+        }
+    }
+
+    fn text_run(&mut self, pos: Point, path: &BezPath, color: &Color) {
+        let transform = transform_attr(self.cur_transform * Affine::translate(pos.to_vec2()));
+        let _ = writeln!(
+            self.body,
+            "<path d=\"{}\"{}{}/>",
+            path.to_svg(),
+            transform,
+            paint("fill", color)
+        );
+    }
+
+    fn draw_text_elements(&mut self, layout: &WgpuTextLayout, pos: Point) {
+        let transform = transform_attr(self.cur_transform * Affine::translate(pos.to_vec2()));
+        let mut start = 0;
+        for line in 0..layout.line_count() {
+            let (text, metric) = match (layout.line_text(line), layout.line_metric(line)) {
+                (Some(text), Some(metric)) => (text, metric),
+                _ => break,
+            };
+            let x = layout.hit_test_text_position(start).point.x;
+            let y = metric.y_offset + metric.baseline;
+            let _ = write!(
+                self.body,
+                "<text x=\"{}\" y=\"{}\" xml:space=\"preserve\"{}>",
+                x, y, transform
+            );
+            // One tspan for every run of chars in the same style.
+            let mut run_start = 0;
+            let mut run_style = None;
+            for (i, _) in text.char_indices().chain(Some((text.len(), ' '))) {
+                let style = (i < text.len()).then(|| layout.style_at(start + i));
+                if style != run_style {
+                    if let Some((family, size, weight, color)) = run_style.take() {
+                        let _ = write!(
+                            self.body,
+                            "<tspan font-family=\"{}\" font-size=\"{}\" \
+                             font-weight=\"{}\"{}>{}</tspan>",
+                            escape(family.name()),
+                            size,
+                            weight.to_raw(),
+                            paint("fill", &color),
+                            escape(text[run_start..i].trim_end_matches(['\n', '\r'])),
+                        );
+                    }
+                    run_start = i;
+                    run_style = style;
+                }
+            }
+            self.body.push_str("</text>\n");
+            start += text.len();
+        }
+    }
+
+    fn image(&mut self, image: &RecordingImage, interp: InterpolationMode) -> String {
+        let rendering = match interp {
+            InterpolationMode::NearestNeighbor => " image-rendering=\"optimizeSpeed\"",
+            InterpolationMode::Bilinear => "",
+        };
+        format!(
+            "<image width=\"{}\" height=\"{}\" preserveAspectRatio=\"none\"{} xlink:href=\"{}\"/>",
+            image.width, image.height, rendering, image.data_url
+        )
+    }
+}
+
+impl RenderContext for RecordingContext {
+    type Brush = Brush;
+    type Text = WgpuText;
+    type TextLayout = WgpuTextLayout;
+    type Image = RecordingImage;
+
+    fn status(&mut self) -> Result<(), piet::Error> {
+        Ok(())
+    }
+
+    fn solid_brush(&mut self, color: Color) -> Self::Brush {
+        Brush::Solid(color)
+    }
+
+    fn gradient(
+        &mut self,
+        _gradient: impl Into<piet::FixedGradient>,
+    ) -> Result<Self::Brush, piet::Error> {
+        Err(piet::Error::NotSupported)
+    }
+
+    /// Fills `region` in device coordinates, but inside the clips in effect.
+    fn clear(&mut self, region: impl Into<Option<Rect>>, color: Color) {
+        let rect = region.into().unwrap_or_else(|| self.size.to_rect());
+        let _ = writeln!(
+            self.body,
+            "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\"{}/>",
+            rect.x0,
+            rect.y0,
+            rect.width(),
+            rect.height(),
+            paint("fill", &color)
+        );
+    }
+
+    fn stroke(&mut self, shape: impl Shape, brush: &impl IntoBrush<Self>, width: f64) {
+        self.stroke_styled(shape, brush, width, &StrokeStyle::new());
+    }
+
+    fn stroke_styled(
+        &mut self,
+        shape: impl Shape,
+        brush: &impl IntoBrush<Self>,
+        width: f64,
+        style: &StrokeStyle,
+    ) {
+        let brush = brush.make_brush(self, || shape.bounding_box()).into_owned();
+        let color = brush.solid_color();
+        let mut attrs = format!(
+            " fill=\"none\"{} stroke-width=\"{}\"",
+            paint("stroke", &color),
+            width
+        );
+        match style.line_join {
+            LineJoin::Miter { limit } => {
+                let _ = write!(attrs, " stroke-miterlimit=\"{}\"", limit);
+            }
+            LineJoin::Round => attrs.push_str(" stroke-linejoin=\"round\""),
+            LineJoin::Bevel => attrs.push_str(" stroke-linejoin=\"bevel\""),
+        }
+        match style.line_cap {
+            LineCap::Butt => {}
+            LineCap::Round => attrs.push_str(" stroke-linecap=\"round\""),
+            LineCap::Square => attrs.push_str(" stroke-linecap=\"square\""),
+        }
+        if !style.dash_pattern.is_empty() {
+            let dashes: Vec<String> = style.dash_pattern.iter().map(|d| d.to_string()).collect();
+            let _ = write!(
+                attrs,
+                " stroke-dasharray=\"{}\" stroke-dashoffset=\"{}\"",
+                dashes.join(" "),
+                style.dash_offset
+            );
+        }
+        self.path(&shape, &attrs);
+    }
+
+    fn fill(&mut self, shape: impl Shape, brush: &impl IntoBrush<Self>) {
+        let brush = brush.make_brush(self, || shape.bounding_box()).into_owned();
+        let color = brush.solid_color();
+        self.path(&shape, &paint("fill", &color));
+    }
+
+    fn fill_even_odd(&mut self, shape: impl Shape, brush: &impl IntoBrush<Self>) {
+        let brush = brush.make_brush(self, || shape.bounding_box()).into_owned();
+        let color = brush.solid_color();
+        let attrs = format!("{} fill-rule=\"evenodd\"", paint("fill", &color));
+        self.path(&shape, &attrs);
+    }
+
+    fn clip(&mut self, shape: impl Shape) {
+        let id = self.id("clip");
+        let _ = writeln!(
+            self.defs,
+            "<clipPath id=\"{}\" clipPathUnits=\"userSpaceOnUse\"><path d=\"{}\"{}/></clipPath>",
+            id,
+            shape.to_path(TOLERANCE).to_svg(),
+            self.transform_attr()
+        );
+        let _ = writeln!(self.body, "<g clip-path=\"url(#{})\">", id);
+        match self.state_stack.last_mut() {
+            Some(state) => state.n_clip += 1,
+            None => self.n_clip += 1,
+        }
+    }
+
+    fn text(&mut self) -> &mut Self::Text {
+        &mut self.text
+    }
+
+    fn draw_text(&mut self, layout: &Self::TextLayout, pos: impl Into<Point>) {
+        let pos = pos.into();
+        match self.text_mode {
+            TextMode::Paths => self.draw_text_paths(layout, pos),
+            TextMode::Elements => self.draw_text_elements(layout, pos),
+        }
+    }
+
+    fn save(&mut self) -> Result<(), piet::Error> {
+        self.state_stack.push(State {
+            transform: self.cur_transform,
+            n_clip: 0,
+        });
+        Ok(())
+    }
+
+    fn restore(&mut self) -> Result<(), piet::Error> {
+        if let Some(state) = self.state_stack.pop() {
+            self.cur_transform = state.transform;
+            for _ in 0..state.n_clip {
+                self.body.push_str("</g>\n");
+            }
+            Ok(())
+        } else {
+            Err(piet::Error::StackUnbalance)
+        }
+    }
+
+    fn finish(&mut self) -> Result<(), piet::Error> {
+        Ok(())
+    }
+
+    fn transform(&mut self, transform: Affine) {
+        self.cur_transform *= transform;
+    }
+
+    fn make_image(
+        &mut self,
+        width: usize,
+        height: usize,
+        buf: &[u8],
+        format: ImageFormat,
+    ) -> Result<Self::Image, piet::Error> {
+        if buf.len() < width * height * format.bytes_per_pixel() {
+            return Err(piet::Error::InvalidInput);
+        }
+        let pixels = image::to_rgba(buf, format)?;
+        let pixels = &pixels[..width * height * 4];
+        let png = image::encode_png(width as u32, height as u32, pixels)
+            .map_err(|e| piet::Error::BackendError(Box::new(e)))?;
+        Ok(RecordingImage {
+            width,
+            height,
+            data_url: format!("data:image/png;base64,{}", base64(&png)),
+        })
+    }
+
+    fn draw_image(
+        &mut self,
+        image: &Self::Image,
+        dst_rect: impl Into<Rect>,
+        interp: InterpolationMode,
+    ) {
+        let src_rect = Size::new(image.width as f64, image.height as f64).to_rect();
+        self.draw_image_area(image, src_rect, dst_rect, interp);
+    }
+
+    fn draw_image_area(
+        &mut self,
+        image: &Self::Image,
+        src_rect: impl Into<Rect>,
+        dst_rect: impl Into<Rect>,
+        interp: InterpolationMode,
+    ) {
+        let src = src_rect.into();
+        let dst = dst_rect.into();
+        // A nested svg maps the source area onto the destination and clips
+        // the rest of the image.
+        let image = self.image(image, interp);
+        let _ = writeln!(
+            self.body,
+            "<g{}><svg x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" \
+             viewBox=\"{} {} {} {}\" preserveAspectRatio=\"none\">{}</svg></g>",
+            self.transform_attr(),
+            dst.x0,
+            dst.y0,
+            dst.width(),
+            dst.height(),
+            src.x0,
+            src.y0,
+            src.width(),
+            src.height(),
+            image
+        );
+    }
+
+    fn capture_image_area(
+        &mut self,
+        _src_rect: impl Into<Rect>,
+    ) -> Result<Self::Image, piet::Error> {
+        Err(piet::Error::NotSupported)
+    }
+
+    fn blurred_rect(&mut self, rect: Rect, blur_radius: f64, brush: &impl IntoBrush<Self>) {
+        let brush = brush.make_brush(self, || rect).into_owned();
+        let color = brush.solid_color();
+        let id = self.id("blur");
+        let region = rect.inflate(3.0 * blur_radius, 3.0 * blur_radius);
+        let _ = writeln!(
+            self.defs,
+            "<filter id=\"{}\" filterUnits=\"userSpaceOnUse\" x=\"{}\" y=\"{}\" width=\"{}\" \
+             height=\"{}\"><feGaussianBlur stdDeviation=\"{}\"/></filter>",
+            id,
+            region.x0,
+            region.y0,
+            region.width(),
+            region.height(),
+            blur_radius
+        );
+        let attrs = format!("{} filter=\"url(#{})\"", paint("fill", &color), id);
+        self.path(&rect, &attrs);
+    }
+
+    fn current_transform(&self) -> Affine {
+        self.cur_transform
+    }
+}
+
+impl IntoBrush<RecordingContext> for Brush {
+    fn make_brush<'b>(
+        &'b self,
+        _piet: &mut RecordingContext,
+        _bbox: impl FnOnce() -> Rect,
+    ) -> Cow<'b, Brush> {
+        Cow::Borrowed(self)
+    }
+}
+
+impl Image for RecordingImage {
+    fn size(&self) -> Size {
+        Size::new(self.width as f64, self.height as f64)
+    }
+}
+
+fn transform_attr(transform: Affine) -> String {
+    if transform == Affine::default() {
+        return String::new();
+    }
+    let [a, b, c, d, e, f] = transform.as_coeffs();
+    format!(" transform=\"matrix({} {} {} {} {} {})\"", a, b, c, d, e, f)
+}
+
+/// The `fill` or `stroke` attributes for `color`.
+fn paint(attr: &str, color: &Color) -> String {
+    let (r, g, b, a) = color.as_rgba8();
+    let mut paint = format!(" {}=\"#{:02x}{:02x}{:02x}\"", attr, r, g, b);
+    if a != 255 {
+        let _ = write!(paint, " {}-opacity=\"{}\"", attr, a as f64 / 255.0);
+    }
+    paint
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[((n >> (18 - 6 * i)) & 63) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
@@ -0,0 +1,56 @@
+struct Params {
+    rect: vec4<f32>;
+    resolution: vec2<f32>;
+    direction: vec2<f32>;
+    sigma: f32;
+};
+
+[[group(0), binding(0)]] var<uniform> params: Params;
+[[group(0), binding(1)]] var src_sampler: sampler;
+[[group(0), binding(2)]] var src_tex: texture_2d<f32>;
+
+struct VertexOutput {
+    [[builtin(position)]] position: vec4<f32>;
+};
+
+[[stage(vertex)]]
+fn vs_main([[builtin(vertex_index)]] vertex_index: u32) -> VertexOutput {
+    var out: VertexOutput;
+
+    var pos: vec2<f32> = vec2<f32>(params.rect.x, params.rect.y);
+    switch (i32(vertex_index)) {
+        case 1: {
+            pos = vec2<f32>(params.rect.z, params.rect.y);
+        }
+        case 2: {
+            pos = vec2<f32>(params.rect.x, params.rect.w);
+        }
+        case 3: {
+            pos = vec2<f32>(params.rect.z, params.rect.w);
+        }
+        default: {}
+    }
+
+    var invert_y: vec2<f32> = vec2<f32>(1.0, -1.0);
+    out.position = vec4<f32>((pos / params.resolution * 2.0 - vec2<f32>(1.0, 1.0)) * invert_y, 0.0, 1.0);
+    return out;
+}
+
+// One direction of a separable gaussian blur, sampling the source at
+// physical pixel centers.
+[[stage(fragment)]]
+fn fs_main(input: VertexOutput) -> [[location(0)]] vec4<f32> {
+    let sigma = params.sigma;
+    let radius = min(i32(ceil(sigma * 3.0)), 64);
+
+    var color: vec4<f32> = vec4<f32>(0.0, 0.0, 0.0, 0.0);
+    var total: f32 = 0.0;
+    for (var i: i32 = -radius; i <= radius; i = i + 1) {
+        let offset = f32(i);
+        let weight = exp(-(offset * offset) / (2.0 * sigma * sigma));
+        let uv = (input.position.xy + params.direction * offset) / params.resolution;
+        color = color + textureSampleLevel(src_tex, src_sampler, uv, 0.0) * weight;
+        total = total + weight;
+    }
+    return color / total;
+}
@@ -1,17 +1,35 @@
 struct Primitive {
     u_clip_rect: vec4<f32>;
+    u_clip_radius: vec4<f32>;
     u_transform_1: vec4<f32>;
     u_blur_rect: vec4<f32>;
+    u_circle: vec4<f32>;
+    u_focus_rect: vec4<f32>;
+    u_focus: vec4<f32>;
+    u_conic: vec4<f32>;
+    u_conic_color: vec4<f32>;
+    u_gradient: vec4<f32>;
+    u_gradient_lut: vec4<f32>;
     u_transform_2: vec2<f32>;
     u_translate: vec2<f32>;
     u_scale: vec2<f32>;
     u_clip: f32;
     u_blur_radius: f32;
+    u_blur_corner_radius: f32;
+    u_blur_inset: f32;
+    u_arc: vec2<f32>;
+    u_focus_phase: f32;
+    u_clip_feather: f32;
 };
 
+// How many texels it takes for a glyph distance field to fall off from the
+// edge to either end of its range, SDF_SPREAD in pipeline.rs.
+let SDF_SPREAD: f32 = 8.0;
+
 struct Globals {
     u_resolution: vec2<f32>;
     u_scale: f32;
+    u_premultiplied: f32;
 };
 
 struct Primitives {
@@ -22,6 +40,8 @@ struct Primitives {
 [[group(0), binding(1)]] var font_sampler: sampler;
 [[group(0), binding(2)]] var font_tex: texture_2d<f32>;
 [[group(0), binding(3)]] var<storage> primitives: Primitives;
+[[group(0), binding(4)]] var image_tex: texture_2d<f32>;
+[[group(0), binding(5)]] var gray_image_tex: texture_2d<f32>;
     
 struct VertexInput {
     [[location(0)]] v_pos: vec2<f32>;
@@ -32,16 +52,16 @@ struct VertexInput {
     [[location(5)]] v_primitive_id: u32;
 };
 
+// Everything else about a vertex is the same for the whole primitive, so
+// the fragment shader reads it from `primitives` instead of it taking up
+// inter-stage locations.
 struct VertexOutput {
     [[builtin(position)]] position: vec4<f32>;
     [[location(0)]] color: vec4<f32>;
     [[location(1)]] pos: vec2<f32>;
-    [[location(2)]] rect: vec4<f32>;
-    [[location(3)]] blur_radius: f32;
-    [[location(4)]] tex: f32;
-    [[location(5)]] tex_pos: vec2<f32>;
-    [[location(6)]] clip: f32;
-    [[location(7)]] clip_rect: vec4<f32>;
+    [[location(2)]] tex: f32;
+    [[location(3)]] tex_pos: vec2<f32>;
+    [[location(4), interpolate(flat)]] primitive_id: u32;
 };
 
 [[stage(vertex)]]
@@ -63,27 +83,23 @@ fn vs_main(input: VertexInput) -> VertexOutput {
     var v_pos: vec2<f32> = vec2<f32>(transformed_pos.x, transformed_pos.y);
     
     var translated_pos: vec2<f32> = (v_pos * primitive.u_scale + primitive.u_translate + input.v_translate) * globals.u_scale;
+
+    // Glyph quads are whole physical pixels in size, so snapping every corner
+    // keeps the rasterized bitmap aligned with the pixel grid. Glyphs scaled
+    // from distance fields, with a `tex` of 1.5, and images, with a `tex` of
+    // 2 or more, are placed as they are.
+    if (input.v_tex > 0.0 && input.v_tex < 1.25) {
+        translated_pos = round(translated_pos);
+    }
     
     var pos: vec2<f32> = (translated_pos / globals.u_resolution * 2.0 - vec2<f32>(1.0, 1.0)) * invert_y;
     
     out.position = vec4<f32>(pos, 0.0, 1.0);
     out.color = input.v_color;
-    out.blur_radius = primitive.u_blur_radius;
-    out.rect = primitive.u_blur_rect;
     out.pos = input.v_pos;
     out.tex = input.v_tex;
     out.tex_pos = input.v_tex_pos;
-    out.clip = primitive.u_clip;
-    out.clip_rect = primitive.u_clip_rect;
-    
-    if (out.clip > 0.0) {
-        var left_top = vec2<f32>(primitive.u_clip_rect.x, primitive.u_clip_rect.y);
-        var left_top = left_top * globals.u_scale;
-        
-        var right_bottom = vec2<f32>(primitive.u_clip_rect.z, primitive.u_clip_rect.w);
-        var right_bottom = right_bottom * globals.u_scale;
-        out.clip_rect = vec4<f32>(left_top, right_bottom);
-    }
+    out.primitive_id = input.v_primitive_id;
     
     return out;
 }
@@ -102,36 +118,318 @@ fn box_shadow(lower: vec2<f32>, upper: vec2<f32>, point: vec2<f32>, radius: f32)
     return (integral.z - integral.x) * (integral.w - integral.y);
 }
 
+fn gaussian(x: f32, sigma: f32) -> f32 {
+    let pi: f32 = 3.141592653589793;
+    return exp(-(x * x) / (2.0 * sigma * sigma)) / (sqrt(2.0 * pi) * sigma);
+}
+
+// The shadow of a rounded rect has no closed form, so integrate the
+// horizontal erf profile over a few samples of the vertical gaussian.
+fn rounded_box_shadow_x(x: f32, y: f32, sigma: f32, corner: f32, half_size: vec2<f32>) -> f32 {
+    let delta = min(half_size.y - corner - abs(y), 0.0);
+    let curved = half_size.x - corner + sqrt(max(0.0, corner * corner - delta * delta));
+    let integral = 0.5 + 0.5 * erf(vec4<f32>(x - curved, x + curved, 0.0, 0.0) * (sqrt(0.5) / sigma));
+    return integral.y - integral.x;
+}
+
+fn rounded_box_shadow(lower: vec2<f32>, upper: vec2<f32>, point: vec2<f32>, sigma: f32, corner: f32) -> f32 {
+    let center = (lower + upper) * 0.5;
+    let half_size = (upper - lower) * 0.5;
+    let corner = min(corner, min(half_size.x, half_size.y));
+    let p = point - center;
+
+    let low = p.y - half_size.y;
+    let high = p.y + half_size.y;
+    let start = clamp(-3.0 * sigma, low, high);
+    let end = clamp(3.0 * sigma, low, high);
+
+    let step = (end - start) / 4.0;
+    var y: f32 = start + step * 0.5;
+    var value: f32 = 0.0;
+    for (var i: i32 = 0; i < 4; i = i + 1) {
+        value = value + rounded_box_shadow_x(p.x, p.y - y, sigma, corner, half_size) * gaussian(y, sigma) * step;
+        y = y + step;
+    }
+    return value;
+}
+
+// Signed distance from `point` to a rounded rect with per corner radii
+// (top left, top right, bottom right, bottom left).
+fn rounded_rect_distance(point: vec2<f32>, rect: vec4<f32>, radius: vec4<f32>) -> f32 {
+    let center = (rect.xy + rect.zw) * 0.5;
+    let half_size = (rect.zw - rect.xy) * 0.5;
+    let p = point - center;
+
+    var r: f32 = radius.x;
+    if (p.x > 0.0 && p.y <= 0.0) {
+        r = radius.y;
+    } else if (p.x > 0.0 && p.y > 0.0) {
+        r = radius.z;
+    } else if (p.x <= 0.0 && p.y > 0.0) {
+        r = radius.w;
+    }
+    r = min(r, min(half_size.x, half_size.y));
+
+    let q = abs(p) - half_size + vec2<f32>(r, r);
+    return min(max(q.x, q.y), 0.0) + length(max(q, vec2<f32>(0.0, 0.0))) - r;
+}
+
+// Signed distance from `p`, relative to the center, to a circle of
+// `circle.z` radius, or to a ring of `circle.w` width when it is stroked.
+// Strokes limited to an arc get round caps.
+fn circle_distance(p: vec2<f32>, circle: vec4<f32>, arc: vec2<f32>) -> f32 {
+    let d = length(p);
+    if (circle.w <= 0.0) {
+        return d - circle.z;
+    }
+    let tau: f32 = 6.283185307179586;
+    if (arc.y > 0.0 && arc.y < tau) {
+        var angle: f32 = atan2(p.y, p.x) - arc.x;
+        angle = angle - floor(angle / tau) * tau;
+        if (angle > arc.y) {
+            let start = vec2<f32>(cos(arc.x), sin(arc.x)) * circle.z;
+            let end = vec2<f32>(cos(arc.x + arc.y), sin(arc.x + arc.y)) * circle.z;
+            return min(length(p - start), length(p - end)) - circle.w * 0.5;
+        }
+    }
+    return abs(d - circle.z) - circle.w * 0.5;
+}
+
+// Distance along the outline of a rounded rect with `radius` corners,
+// clockwise from the left end of its top edge, of the point closest to `p`.
+fn rounded_rect_perimeter_position(point: vec2<f32>, rect: vec4<f32>, radius: f32) -> f32 {
+    let half_pi: f32 = 1.5707963267948966;
+    let center = (rect.xy + rect.zw) * 0.5;
+    let half_size = (rect.zw - rect.xy) * 0.5;
+    let r = min(radius, min(half_size.x, half_size.y));
+    let a = half_size.x - r;
+    let b = half_size.y - r;
+    let p = point - center;
+    let corner = r * half_pi;
+
+    let q = abs(p) - vec2<f32>(a, b);
+    if (q.x > 0.0 && q.y > 0.0) {
+        if (p.x > 0.0 && p.y < 0.0) {
+            let d = p - vec2<f32>(a, -b);
+            return 2.0 * a + r * atan2(d.x, -d.y);
+        } else if (p.x > 0.0) {
+            let d = p - vec2<f32>(a, b);
+            return 2.0 * a + 2.0 * b + corner + r * atan2(d.y, d.x);
+        } else if (p.y > 0.0) {
+            let d = p - vec2<f32>(-a, b);
+            return 4.0 * a + 2.0 * b + 2.0 * corner + r * atan2(-d.x, d.y);
+        }
+        let d = p - vec2<f32>(-a, -b);
+        return 4.0 * a + 4.0 * b + 3.0 * corner + r * atan2(-d.y, -d.x);
+    }
+    if (q.y >= q.x) {
+        if (p.y < 0.0) {
+            return p.x + a;
+        }
+        return 2.0 * a + 2.0 * b + 2.0 * corner + (a - p.x);
+    }
+    if (p.x > 0.0) {
+        return 2.0 * a + corner + (p.y + b);
+    }
+    return 4.0 * a + 2.0 * b + 3.0 * corner + (b - p.y);
+}
+
+// Position of `p`, relative to the center, along an arc from its start at 0
+// to its end at 1, or around the whole circle from the start of the arc.
+// The caps of the arc are below 0 and above 1.
+fn conic_position(p: vec2<f32>, arc: vec2<f32>) -> f32 {
+    let tau: f32 = 6.283185307179586;
+    var angle: f32 = atan2(p.y, p.x) - arc.x;
+    angle = angle - floor(angle / tau) * tau;
+    if (arc.y > 0.0 && arc.y < tau) {
+        // Past halfway through the gap is the cap at the start.
+        if (angle > (arc.y + tau) * 0.5) {
+            angle = angle - tau;
+        }
+        return angle / arc.y;
+    }
+    return angle / tau;
+}
+
+// Position of `p` along a gradient from 0 to 1: along the line from
+// `gradient.xy` to `gradient.zw` when `radius` is 0, or else out from the
+// focus at `gradient.zw` to the circle of `radius` around `gradient.xy`.
+fn gradient_position(p: vec2<f32>, gradient: vec4<f32>, radius: f32) -> f32 {
+    if (radius <= 0.0) {
+        let axis = gradient.zw - gradient.xy;
+        return dot(p - gradient.xy, axis) / max(dot(axis, axis), 0.000001);
+    }
+    let from_focus = p - gradient.zw;
+    let dist = length(from_focus);
+    if (dist <= 0.0) {
+        return 0.0;
+    }
+    // Where the ray from the focus through `p` leaves the circle.
+    let dir = from_focus / dist;
+    let focus = gradient.zw - gradient.xy;
+    let b = dot(dir, focus);
+    let c = dot(focus, focus) - radius * radius;
+    let edge = -b + sqrt(max(b * b - c, 0.0));
+    if (edge <= 0.0) {
+        return 1.0;
+    }
+    return dist / edge;
+}
+
+fn srgb_to_linear(c: f32) -> f32 {
+    if (c <= 0.04045) {
+        return c / 12.92;
+    }
+    return pow((c + 0.055) / 1.055, 2.4);
+}
+
 [[stage(fragment)]]
 fn fs_main(input: VertexOutput) -> [[location(0)]] vec4<f32> {
+    let primitive = primitives.data[input.primitive_id];
     var color: vec4<f32> = input.color;
     
-    if (input.blur_radius > 0.0) {
-        if (input.rect.x <= input.pos.x && input.pos.x <= input.rect.z && input.rect.y <= input.pos.y && input.pos.y <= input.rect.w) {
+    if (primitive.u_blur_radius > 0.0) {
+        let rect = primitive.u_blur_rect;
+        let lower = vec2<f32>(rect.x, rect.y);
+        let upper = vec2<f32>(rect.z, rect.w);
+        if (primitive.u_blur_inset > 0.0) {
+            // Inverted falloff: opaque at the edges of the rect, fading
+            // towards its center, and nothing outside of it.
+            if (rect.x <= input.pos.x && input.pos.x <= rect.z && rect.y <= input.pos.y && input.pos.y <= rect.w) {
+                color.w = color.w * (1.0 - box_shadow(lower, upper, input.pos, primitive.u_blur_radius));
+            } else {
+                color.w = 0.0;
+            }
+        } else if (primitive.u_blur_corner_radius > 0.0) {
+            let radius = vec4<f32>(primitive.u_blur_corner_radius);
+            if (rounded_rect_distance(input.pos, rect, radius) <= 0.0) {
+                color.w = 0.0;
+            } else {
+                color.w = color.w * rounded_box_shadow(lower, upper, input.pos, primitive.u_blur_radius, primitive.u_blur_corner_radius);
+            }
+        } else if (rect.x <= input.pos.x && input.pos.x <= rect.z && rect.y <= input.pos.y && input.pos.y <= rect.w) {
             color.w = 0.0; 
         } else {
-            color.w = color.w * box_shadow(
-               vec2<f32>(input.rect.x, input.rect.y),
-               vec2<f32>(input.rect.z, input.rect.w),
-               vec2<f32>(input.pos.x, input.pos.y),
-               input.blur_radius
-            );
+            color.w = color.w * box_shadow(lower, upper, input.pos, primitive.u_blur_radius);
         }
     }
 
-    var alpha: f32 = textureSample(font_tex, font_sampler, input.tex_pos).r;
-    if (input.tex > 0.0) {
+    // Glyph positions are in texels so they survive the atlas growing.
+    let font_tex_size = vec2<f32>(textureDimensions(font_tex));
+    var alpha: f32 = textureSample(font_tex, font_sampler, input.tex_pos / font_tex_size).r;
+    // Texels of the atlas per pixel, to scale distance fields with.
+    let texels_per_pixel = fwidth(input.tex_pos);
+    // Grayscale images have a `tex` 2 higher than the same RGBA image.
+    let gray_image = input.tex > 3.5;
+    var image_tex_kind: f32 = input.tex;
+    if (gray_image) {
+        image_tex_kind = image_tex_kind - 2.0;
+    }
+    // Nearest neighbour images sample the center of their texel.
+    var image_pos: vec2<f32> = input.tex_pos;
+    if (image_tex_kind > 2.5) {
+        image_pos = floor(image_pos) + vec2<f32>(0.5, 0.5);
+    }
+    let image_tex_size = vec2<f32>(textureDimensions(image_tex));
+    var texel: vec4<f32> = textureSample(image_tex, font_sampler, image_pos / image_tex_size);
+    // The grayscale atlas holds sRGB encoded luminance, which is decoded
+    // here since there is no sRGB R8 format.
+    let gray_image_tex_size = vec2<f32>(textureDimensions(gray_image_tex));
+    let luma = srgb_to_linear(textureSample(gray_image_tex, font_sampler, image_pos / gray_image_tex_size).r);
+    if (gray_image) {
+        texel = vec4<f32>(luma, luma, luma, 1.0);
+    }
+    // Gradients look up their colors in a strip of the image atlas, past
+    // whose ends they keep the color of the end.
+    let t = clamp(gradient_position(input.pos, primitive.u_gradient, primitive.u_gradient_lut.w), 0.0, 1.0);
+    let lut_pos = primitive.u_gradient_lut.xy + vec2<f32>(0.5 + t * (primitive.u_gradient_lut.z - 1.0), 0.5);
+    let gradient_texel = textureSample(image_tex, font_sampler, lut_pos / image_tex_size);
+    if (primitive.u_gradient_lut.z > 0.0) {
+        color = gradient_texel * color;
+    }
+    if (input.tex >= 2.0) {
+        // The vertex color tints the image.
+        color = texel * color;
+    } else if (input.tex > 0.0) {
+        if (input.tex > 1.25) {
+            // Distance fields are 0.5 on the edge of the glyph and fall off
+            // by 0.5 over SDF_SPREAD texels, turned into the pixel coverage.
+            let texels = max(0.5 * (texels_per_pixel.x + texels_per_pixel.y), 0.0001);
+            let dist = (0.5 - alpha) * 2.0 * SDF_SPREAD / texels;
+            alpha = clamp(0.5 - dist, 0.0, 1.0);
+        }
         if (alpha <= 0.0) {
             discard;
         }
         color.w = color.w * alpha;
     }
     
-    if (input.clip > 0.0) {
-        if (input.position.x < input.clip_rect.x || input.position.x > input.clip_rect.z || input.position.y < input.clip_rect.y || input.position.y > input.clip_rect.w) {
+    // Hairlines carry the distance across the line and half its width.
+    if (input.tex < 0.0) {
+        let d = abs(input.tex_pos.x) * globals.u_scale;
+        let half_width = input.tex_pos.y * globals.u_scale;
+        let coverage = min(d + 0.5, half_width) - max(d - 0.5, -half_width);
+        if (coverage <= 0.0) {
             discard;
         }
+        color.w = color.w * clamp(coverage, 0.0, 1.0);
+    }
+
+    if (primitive.u_conic.y > primitive.u_conic.x) {
+        let t = conic_position(input.pos - primitive.u_circle.xy, primitive.u_arc);
+        if (t < primitive.u_conic.x || t >= primitive.u_conic.y) {
+            discard;
+        }
+        var f: f32 = step(primitive.u_conic.w, t);
+        if (primitive.u_conic.w > primitive.u_conic.z) {
+            f = clamp((t - primitive.u_conic.z) / (primitive.u_conic.w - primitive.u_conic.z), 0.0, 1.0);
+        }
+        color = mix(color, primitive.u_conic_color, f);
+    }
+
+    if (primitive.u_circle.z > 0.0) {
+        let dist = circle_distance(input.pos - primitive.u_circle.xy, primitive.u_circle, primitive.u_arc) * globals.u_scale;
+        if (dist >= 0.5) {
+            discard;
+        }
+        color.w = color.w * clamp(0.5 - dist, 0.0, 1.0);
+    }
+
+    if (primitive.u_focus.y > 0.0) {
+        let radius = vec4<f32>(primitive.u_focus.x);
+        let edge = abs(rounded_rect_distance(input.pos, primitive.u_focus_rect, radius)) - primitive.u_focus.y * 0.5;
+        let along = rounded_rect_perimeter_position(input.pos, primitive.u_focus_rect, primitive.u_focus.x) + primitive.u_focus_phase;
+        let period = primitive.u_focus.z + primitive.u_focus.w;
+        let m = along - floor(along / period) * period;
+        // Distance outside of the dash along the outline, negative inside.
+        var gap: f32 = max(-m, m - primitive.u_focus.z);
+        if (m > primitive.u_focus.z) {
+            gap = min(gap, period - m);
+        }
+        let dist = max(edge, gap) * globals.u_scale;
+        if (dist >= 0.5) {
+            discard;
+        }
+        color.w = color.w * clamp(0.5 - dist, 0.0, 1.0);
+    }
+
+    if (primitive.u_clip > 0.0) {
+        // The clip is in logical pixels, the fragment position in physical.
+        let clip_rect = primitive.u_clip_rect * globals.u_scale;
+        let clip_radius = primitive.u_clip_radius * globals.u_scale;
+        let clip_feather = primitive.u_clip_feather * globals.u_scale;
+        let dist = rounded_rect_distance(input.position.xy, clip_rect, clip_radius);
+        if (dist >= 0.5) {
+            discard;
+        }
+        // A feathered clip ramps alpha down over the feather width inside
+        // the edge; an unfeathered one still gets a pixel of antialiasing.
+        let ramp = max(clip_feather, 1.0);
+        color.w = color.w * clamp((0.5 - dist) / ramp, 0.0, 1.0);
+    }
+
+    if (globals.u_premultiplied > 0.0) {
+        return vec4<f32>(color.xyz * color.w, color.w);
     }
-    
     return color;
 }
@@ -1,4 +1,4 @@
-use std::{collections::HashMap, f64::NAN, str::FromStr};
+use std::{collections::HashMap, f64::NAN, fmt, str::FromStr};
 
 use lyon::{
     lyon_tessellation::{
@@ -7,7 +7,7 @@ use lyon::{
     },
     math::{point, Point},
     path::PathEvent,
-    tessellation,
+    tessellation::{self, TessellationError},
 };
 use sha2::{Digest, Sha256};
 use usvg::NodeExt;
@@ -23,35 +23,117 @@ pub struct Svg {
 unsafe impl Sync for Svg {}
 unsafe impl Send for Svg {}
 
-impl FromStr for Svg {
-    type Err = Box<dyn std::error::Error>;
+impl Svg {
+    /// Parse `s` with `options`, for svgs with text or with images
+    /// referenced by a relative path. Set `options.fontdb` to the fonts text
+    /// elements are resolved with, `options.resources_dir` to the directory
+    /// image hrefs are relative to, and `options.dpi` for sizes given in
+    /// physical units. Loading fonts is slow, so make the options once and
+    /// parse every svg with them.
+    pub fn from_str_with_options(
+        s: &str,
+        options: &usvg::Options,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::from_data_with_options(s.as_bytes(), options)
+    }
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut re_opt = usvg::Options {
-            keep_named_groups: false,
-            ..usvg::Options::default()
-        };
+    /// Parse an svg document, or a gzip compressed one as in `.svgz` files,
+    /// from `data`, such as an icon embedded with `include_bytes!`.
+    pub fn from_data(data: &[u8]) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::from_data_with_options(data, &default_options())
+    }
 
+    /// Parse `data` like [`Svg::from_data`], with `options` like
+    /// [`Svg::from_str_with_options`].
+    pub fn from_data_with_options(
+        data: &[u8],
+        options: &usvg::Options,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
         let mut hasher = Sha256::new();
-        hasher.update(s);
+        hasher.update(data);
+        // The same source parses to another tree with other options.
+        if let Some(dir) = options.resources_dir.as_ref() {
+            hasher.update(dir.to_string_lossy().as_bytes());
+        }
+        hasher.update(options.dpi.to_le_bytes());
+        hasher.update(&options.font_family);
+        hasher.update(options.font_size.to_le_bytes());
+        for language in &options.languages {
+            hasher.update(language);
+        }
         let hash = hasher.finalize().to_vec();
 
-        re_opt.fontdb.load_system_fonts();
-
-        match usvg::Tree::from_str(s, &re_opt) {
+        match usvg::Tree::from_data(data, options) {
             Ok(tree) => Ok(Self { hash, tree }),
             Err(err) => Err(err.into()),
         }
     }
+
+    /// Wrap a tree which is already parsed or was built in code. It is
+    /// written out once to identify it, so that equal trees share their
+    /// tessellation.
+    pub fn from_tree(tree: usvg::Tree) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update(tree.to_string(usvg::XmlOptions::default()));
+        let hash = hasher.finalize().to_vec();
+        Self { hash, tree }
+    }
+}
+
+impl FromStr for Svg {
+    type Err = Box<dyn std::error::Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::from_str_with_options(s, &default_options())
+    }
+}
+
+/// The options svgs are parsed with when none are given, with the system
+/// fonts loaded.
+fn default_options() -> usvg::Options {
+    let mut re_opt = usvg::Options {
+        keep_named_groups: false,
+        ..usvg::Options::default()
+    };
+    re_opt.fontdb.load_system_fonts();
+    re_opt
+}
+
+/// Why an svg couldn't be drawn, reported by
+/// [`status`](piet::RenderContext::status) after the frame that drew it.
+#[derive(Clone, Debug)]
+pub enum SvgError {
+    /// `paths` of the paths of the svg couldn't be tessellated, the first
+    /// of them failing with `error`.
+    Tessellation {
+        paths: usize,
+        error: TessellationError,
+    },
+}
+
+impl fmt::Display for SvgError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SvgError::Tessellation { paths, error } => write!(
+                f,
+                "{} svg path(s) couldn't be tessellated: {:?}",
+                paths, error
+            ),
+        }
+    }
 }
 
+impl std::error::Error for SvgError {}
+
 pub(crate) struct SvgData {
     pub(crate) geometry: VertexBuffers<GpuVertex, u32>,
     pub(crate) transforms: Vec<[f32; 6]>,
 }
 
 pub(crate) struct SvgStore {
-    svgs: HashMap<Vec<u8>, SvgData>,
+    /// The tessellated svgs, or why they couldn't be, so a broken svg
+    /// isn't tessellated again every frame.
+    svgs: HashMap<Vec<u8>, Result<SvgData, SvgError>>,
     fill_tess: FillTessellator,
     stroke_tess: StrokeTessellator,
 }
@@ -65,15 +147,40 @@ impl SvgStore {
         }
     }
 
-    pub(crate) fn get_svg_data(&mut self, svg: &Svg) -> &SvgData {
+    /// The bytes of the tessellated svgs.
+    pub(crate) fn memory_bytes(&self) -> u64 {
+        self.svgs
+            .values()
+            .filter_map(|data| data.as_ref().ok())
+            .map(|data| {
+                (data.geometry.vertices.len() * std::mem::size_of::<GpuVertex>()
+                    + data.geometry.indices.len() * std::mem::size_of::<u32>()
+                    + data.transforms.len() * std::mem::size_of::<[f32; 6]>())
+                    as u64
+            })
+            .sum()
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.svgs.clear();
+    }
+
+    pub(crate) fn get_svg_data(&mut self, svg: &Svg) -> Result<&SvgData, SvgError> {
         if !self.svgs.contains_key(&svg.hash) {
             let data = self.new_svg_data(svg);
+            if let Err(err) = &data {
+                log::warn!("can't draw svg: {}", err);
+            }
             self.svgs.insert(svg.hash.clone(), data);
         }
-        self.svgs.get(&svg.hash).unwrap()
+        self.svgs
+            .get(&svg.hash)
+            .unwrap()
+            .as_ref()
+            .map_err(|err| err.clone())
     }
 
-    fn new_svg_data(&mut self, svg: &Svg) -> SvgData {
+    fn new_svg_data(&mut self, svg: &Svg) -> Result<SvgData, SvgError> {
         let mut prev_transform = usvg::Transform {
             a: 1.0,
             b: 0.0,
@@ -85,6 +192,14 @@ impl SvgStore {
         let mut geometry: VertexBuffers<GpuVertex, u32> = VertexBuffers::new();
         let mut transforms = Vec::new();
         transforms.push([1.0, 0.0, 0.0, 1.0, 0.0, 0.0]);
+        let mut failed = 0;
+        let mut first_error = None;
+        let mut record = |result: Result<tessellation::Count, TessellationError>| {
+            if let Err(err) = result {
+                failed += 1;
+                first_error.get_or_insert(err);
+            }
+        };
         for node in svg.tree.root().descendants() {
             if let usvg::NodeKind::Path(ref p) = *node.borrow() {
                 let t = node.transform();
@@ -105,7 +220,7 @@ impl SvgStore {
                         from_linear(color.blue as f32 / 255.0),
                         fill.opacity.value() as f32,
                     ];
-                    self.fill_tess.tessellate(
+                    record(self.fill_tess.tessellate(
                         convert_path(p),
                         &FillOptions::tolerance(0.01),
                         &mut BuffersBuilder::new(&mut geometry, |vertex: FillVertex| GpuVertex {
@@ -114,7 +229,7 @@ impl SvgStore {
                             primitive_id: transforms.len() as u32 - 1,
                             ..Default::default()
                         }),
-                    );
+                    ));
                 }
 
                 if let Some(ref stroke) = p.stroke {
@@ -125,7 +240,7 @@ impl SvgStore {
                         from_linear(stroke_color.blue as f32 / 255.0),
                         stroke_opacity.value() as f32,
                     ];
-                    let _ = self.stroke_tess.tessellate(
+                    record(self.stroke_tess.tessellate(
                         convert_path(p),
                         &stroke_opts.with_tolerance(0.01),
                         &mut BuffersBuilder::new(&mut geometry, |vertex: StrokeVertex| GpuVertex {
@@ -134,14 +249,20 @@ impl SvgStore {
                             primitive_id: transforms.len() as u32 - 1,
                             ..Default::default()
                         }),
-                    );
+                    ));
                 }
             }
         }
-        SvgData {
+        if let Some(error) = first_error {
+            return Err(SvgError::Tessellation {
+                paths: failed,
+                error,
+            });
+        }
+        Ok(SvgData {
             geometry,
             transforms,
-        }
+        })
     }
 }
 
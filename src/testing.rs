@@ -0,0 +1,286 @@
+//! Snapshot tests: render into a headless renderer and compare the result
+//! with a reference PNG.
+//!
+//! References are written instead of compared when the
+//! `PIET_WGPU_UPDATE_SNAPSHOTS` environment variable is set. On a mismatch
+//! the rendered image and a diff highlighting the differing pixels are
+//! written next to the reference, as `<name>.actual.png` and
+//! `<name>.diff.png`.
+
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+use piet::RenderContext;
+
+use crate::{image, Piet, WgpuRenderer};
+
+/// A rendered image as tightly packed RGBA rows, top row first.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Snapshot {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+impl Snapshot {
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut decoder = png::Decoder::new(fs::File::open(path)?);
+        decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+        let (info, mut reader) = decoder.read_info()?;
+        let mut data = vec![0; info.buffer_size()];
+        reader.next_frame(&mut data)?;
+        let pixels = match info.color_type {
+            png::ColorType::RGBA => data,
+            png::ColorType::RGB => data
+                .chunks(3)
+                .flat_map(|p| [p[0], p[1], p[2], 255])
+                .collect(),
+            png::ColorType::GrayscaleAlpha => data
+                .chunks(2)
+                .flat_map(|p| [p[0], p[0], p[0], p[1]])
+                .collect(),
+            png::ColorType::Grayscale => data.iter().flat_map(|&v| [v, v, v, 255]).collect(),
+            png::ColorType::Indexed => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "indexed PNGs should have been expanded",
+                ))
+            }
+        };
+        Ok(Self {
+            width: info.width,
+            height: info.height,
+            pixels,
+        })
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        fs::write(
+            path,
+            image::encode_png(self.width, self.height, &self.pixels)?,
+        )
+    }
+}
+
+/// How different a snapshot may be from its reference.
+#[derive(Clone, Copy, Debug)]
+pub struct SnapshotOptions {
+    /// The perceptual color difference, from 0 to 1, above which a pixel
+    /// counts as different.
+    pub threshold: f64,
+    /// How many pixels may differ before the snapshot fails.
+    pub max_different_pixels: usize,
+}
+
+impl Default for SnapshotOptions {
+    fn default() -> Self {
+        Self {
+            threshold: 0.1,
+            max_different_pixels: 0,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Render(piet::Error),
+    Io(io::Error),
+    /// There is no reference yet; the rendered image was written to
+    /// `actual` for review.
+    MissingReference { actual: PathBuf },
+    SizeMismatch {
+        expected: (u32, u32),
+        actual: (u32, u32),
+    },
+    Mismatch {
+        different_pixels: usize,
+        diff: PathBuf,
+    },
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Render(e) => write!(f, "rendering failed: {}", e),
+            SnapshotError::Io(e) => write!(f, "{}", e),
+            SnapshotError::MissingReference { actual } => write!(
+                f,
+                "no reference image, rendered image written to {}",
+                actual.display()
+            ),
+            SnapshotError::SizeMismatch { expected, actual } => write!(
+                f,
+                "expected a {}x{} image, got {}x{}",
+                expected.0, expected.1, actual.0, actual.1
+            ),
+            SnapshotError::Mismatch {
+                different_pixels,
+                diff,
+            } => write!(
+                f,
+                "{} pixels differ from the reference, see {}",
+                different_pixels,
+                diff.display()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<io::Error> for SnapshotError {
+    fn from(e: io::Error) -> Self {
+        SnapshotError::Io(e)
+    }
+}
+
+impl From<piet::Error> for SnapshotError {
+    fn from(e: piet::Error) -> Self {
+        SnapshotError::Render(e)
+    }
+}
+
+/// Render `f` into a `width` by `height` physical pixel image at `scale`.
+pub fn render(
+    width: u32,
+    height: u32,
+    scale: f64,
+    f: impl FnOnce(&mut Piet),
+) -> Result<Snapshot, piet::Error> {
+    let mut renderer = WgpuRenderer::new_headless(width, height)?;
+    renderer.set_scale(scale);
+    {
+        let mut ctx = Piet::new(&mut renderer);
+        f(&mut ctx);
+        ctx.finish()?;
+    }
+    Ok(Snapshot {
+        width,
+        height,
+        pixels: renderer.read_pixels()?,
+    })
+}
+
+/// Render `f` and compare it with the reference PNG at `reference`.
+pub fn assert_snapshot(
+    reference: impl AsRef<Path>,
+    width: u32,
+    height: u32,
+    options: SnapshotOptions,
+    f: impl FnOnce(&mut Piet),
+) -> Result<(), SnapshotError> {
+    let actual = render(width, height, 1.0, f)?;
+    compare(reference, &actual, options)
+}
+
+/// Compare `actual` with the reference PNG at `reference`.
+pub fn compare(
+    reference: impl AsRef<Path>,
+    actual: &Snapshot,
+    options: SnapshotOptions,
+) -> Result<(), SnapshotError> {
+    let reference = reference.as_ref();
+    if std::env::var_os("PIET_WGPU_UPDATE_SNAPSHOTS").is_some() {
+        if let Some(dir) = reference.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        actual.save(reference)?;
+        return Ok(());
+    }
+
+    let actual_path = reference.with_extension("actual.png");
+    let expected = match Snapshot::load(reference) {
+        Ok(expected) => expected,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            actual.save(&actual_path)?;
+            return Err(SnapshotError::MissingReference {
+                actual: actual_path,
+            });
+        }
+        Err(e) => return Err(e.into()),
+    };
+    if (expected.width, expected.height) != (actual.width, actual.height) {
+        actual.save(&actual_path)?;
+        return Err(SnapshotError::SizeMismatch {
+            expected: (expected.width, expected.height),
+            actual: (actual.width, actual.height),
+        });
+    }
+
+    let max_delta = options.threshold * options.threshold * MAX_YIQ_DELTA;
+    let mut diff = Vec::with_capacity(actual.pixels.len());
+    let mut different_pixels = 0;
+    for (a, b) in expected.pixels.chunks(4).zip(actual.pixels.chunks(4)) {
+        if yiq_delta(a, b) > max_delta {
+            different_pixels += 1;
+            diff.extend_from_slice(&[255, 0, 0, 255]);
+        } else {
+            // Faded grayscale of the reference for context.
+            let y = 255.0 - (255.0 - luma(a)) * 0.1;
+            diff.extend_from_slice(&[y as u8, y as u8, y as u8, 255]);
+        }
+    }
+    if different_pixels <= options.max_different_pixels {
+        return Ok(());
+    }
+
+    let diff_path = reference.with_extension("diff.png");
+    actual.save(&actual_path)?;
+    Snapshot {
+        width: actual.width,
+        height: actual.height,
+        pixels: diff,
+    }
+    .save(&diff_path)?;
+    Err(SnapshotError::Mismatch {
+        different_pixels,
+        diff: diff_path,
+    })
+}
+
+/// The largest value of [`yiq_delta`], between black and white.
+const MAX_YIQ_DELTA: f64 = 35215.0;
+
+/// Squared perceptual distance of two RGBA pixels in YIQ space, after
+/// blending them onto white.
+fn yiq_delta(a: &[u8], b: &[u8]) -> f64 {
+    let yiq = |p: &[u8]| {
+        let alpha = p[3] as f64 / 255.0;
+        let blend = |c: u8| 255.0 + (c as f64 - 255.0) * alpha;
+        let (r, g, b) = (blend(p[0]), blend(p[1]), blend(p[2]));
+        (
+            r * 0.29889531 + g * 0.58662247 + b * 0.11448223,
+            r * 0.59597799 - g * 0.2741761 - b * 0.32180189,
+            r * 0.21147017 - g * 0.52261711 + b * 0.31114694,
+        )
+    };
+    let (y1, i1, q1) = yiq(a);
+    let (y2, i2, q2) = yiq(b);
+    let (y, i, q) = (y1 - y2, i1 - i2, q1 - q2);
+    0.5053 * y * y + 0.299 * i * i + 0.1957 * q * q
+}
+
+fn luma(p: &[u8]) -> f64 {
+    p[0] as f64 * 0.29889531 + p[1] as f64 * 0.58662247 + p[2] as f64 * 0.11448223
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshots_survive_a_png_round_trip() {
+        let snapshot = Snapshot {
+            width: 3,
+            height: 2,
+            pixels: (0..24).map(|i| i * 10).collect(),
+        };
+        let path = std::env::temp_dir().join(format!("piet-wgpu-{}.png", std::process::id()));
+        snapshot.save(&path).unwrap();
+        let loaded = Snapshot::load(&path);
+        let _ = fs::remove_file(&path);
+        assert_eq!(loaded.unwrap(), snapshot);
+    }
+}
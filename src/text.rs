@@ -1,6 +1,16 @@
-use std::{cell::RefCell, collections::HashMap, ops::Range, rc::Rc};
+use std::{
+    cell::{RefCell, RefMut},
+    collections::{hash_map::DefaultHasher, HashMap, HashSet, VecDeque},
+    convert::TryInto,
+    fs,
+    hash::{Hash, Hasher},
+    io,
+    ops::Range,
+    path::Path,
+    rc::Rc,
+    sync::Arc,
+};
 
-use font_kit::source::SystemSource;
 use lyon::lyon_tessellation::{
     BuffersBuilder, FillOptions, FillTessellator, FillVertex, StrokeOptions, StrokeTessellator,
     StrokeVertex, VertexBuffers,
@@ -8,42 +18,142 @@ use lyon::lyon_tessellation::{
 use piet::kurbo::Line;
 use piet::Color;
 use piet::{
-    kurbo::{Point, Size},
+    kurbo::{Affine, BezPath, Point, Rect, Size, Vec2},
     FontFamily, FontStyle, FontWeight, HitTestPoint, HitTestPosition, LineMetric, Text,
-    TextAttribute, TextLayout, TextLayoutBuilder, TextStorage,
+    TextAlignment, TextAttribute, TextLayout, TextLayoutBuilder, TextStorage,
 };
+use unicode_segmentation::UnicodeSegmentation;
 use unicode_width::UnicodeWidthChar;
 
+use crate::atlas::AtlasStats;
 use crate::context::{format_color, from_linear, WgpuRenderContext};
-use crate::pipeline::{Cache, GlyphMetricInfo, GlyphPosInfo, GpuVertex};
+use crate::font::{FaceQuery, FamilyInfo, FontId, FontSource, FontStretch, HanRegion};
+use crate::pipeline::{
+    Cache, EmojiSource, GlyphMetricInfo, GlyphPosInfo, GpuVertex, TextCacheHandle,
+};
+
+#[cfg(feature = "serde")]
+mod layout_data;
+
+#[cfg(feature = "serde")]
+pub use layout_data::LayoutData;
+
+/// Advances in logical pixels, keyed by font family, font size bits and char.
+type AdvanceCache = HashMap<(FontFamily, u64, char), f64>;
+
+/// The length in bytes from which a layout rasterizes its new glyphs in
+/// parallel before shaping.
+#[cfg(feature = "rayon")]
+const PARALLEL_RASTERIZATION_MIN_LEN: usize = 16 * 1024;
+
+/// A run of text together with the hash of the attributes it was shaped
+/// with.
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct ShapeKey {
+    text: String,
+    style: u64,
+}
+
+/// A glyph with its advance, before it's positioned on a line.
+#[derive(Clone)]
+struct ShapedGlyph {
+    glyph: GlyphPosInfo,
+    tabular_offset: f64,
+}
+
+/// The shaped text of a layout and the options it was built with, so
+/// that [`WgpuTextLayout::rebreak`] only has to break lines again.
+struct ShapedLayout {
+    is_mono: bool,
+    tab_width: usize,
+    bounds: Option<[f64; 2]>,
+    /// Unset while only part of the text has been shaped.
+    glyphs: Option<Rc<[ShapedGlyph]>>,
+}
+
+/// Shaped runs of recent layouts, so that identical lines (blank lines,
+/// closing braces) are only shaped once. The oldest runs are dropped once
+/// `capacity` is reached.
+struct ShapeCache {
+    runs: HashMap<ShapeKey, Rc<[ShapedGlyph]>>,
+    order: VecDeque<ShapeKey>,
+    capacity: usize,
+}
+
+impl ShapeCache {
+    const DEFAULT_CAPACITY: usize = 4096;
+
+    fn new() -> Self {
+        Self {
+            runs: HashMap::new(),
+            order: VecDeque::new(),
+            capacity: Self::DEFAULT_CAPACITY,
+        }
+    }
+
+    fn get(&self, key: &ShapeKey) -> Option<Rc<[ShapedGlyph]>> {
+        self.runs.get(key).cloned()
+    }
+
+    fn insert(&mut self, key: ShapeKey, run: Rc<[ShapedGlyph]>) {
+        while self.runs.len() >= self.capacity {
+            match self.order.pop_front() {
+                Some(oldest) => {
+                    self.runs.remove(&oldest);
+                }
+                None => break,
+            }
+        }
+        if self.runs.insert(key.clone(), run).is_none() {
+            self.order.push_back(key);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.runs.clear();
+        self.order.clear();
+    }
+}
+
+type MissingGlyphCallback = Box<dyn FnMut(char, &piet::Error)>;
+
+/// Chars whose glyphs couldn't be loaded, each reported once, and who to
+/// report them to.
+#[derive(Default)]
+struct MissingGlyphs {
+    reported: HashSet<char>,
+    on_missing: Option<MissingGlyphCallback>,
+}
 
 #[derive(Clone)]
 pub struct WgpuText {
-    source: Rc<RefCell<SystemSource>>,
-    glyphs: Rc<RefCell<HashMap<FontFamily, HashMap<char, Rc<(Vec<[f32; 2]>, Vec<u32>)>>>>>,
+    source: Rc<FontSource>,
     pub(crate) cache: Rc<RefCell<Cache>>,
+    advances: Rc<RefCell<AdvanceCache>>,
+    shapes: Rc<RefCell<ShapeCache>>,
     device: Rc<wgpu::Device>,
-    staging_belt: Rc<RefCell<wgpu::util::StagingBelt>>,
     encoder: Rc<RefCell<Option<wgpu::CommandEncoder>>>,
     fill_tess: Rc<RefCell<FillTessellator>>,
     stroke_tess: Rc<RefCell<StrokeTessellator>>,
+    missing_glyphs: Rc<RefCell<MissingGlyphs>>,
 }
 
 impl WgpuText {
     pub(crate) fn new(
         device: Rc<wgpu::Device>,
-        staging_belt: Rc<RefCell<wgpu::util::StagingBelt>>,
         encoder: Rc<RefCell<Option<wgpu::CommandEncoder>>>,
+        fonts: TextCacheHandle,
     ) -> Self {
         Self {
-            source: Rc::new(RefCell::new(SystemSource::new())),
-            glyphs: Rc::new(RefCell::new(HashMap::new())),
-            cache: Rc::new(RefCell::new(Cache::new(&device, 2000, 2000))),
+            source: Rc::new(FontSource::new()),
+            cache: Rc::new(RefCell::new(Cache::new(&device, 2000, 2000, fonts))),
+            advances: Rc::new(RefCell::new(HashMap::new())),
+            shapes: Rc::new(RefCell::new(ShapeCache::new())),
             device,
-            staging_belt,
             encoder,
             fill_tess: Rc::new(RefCell::new(FillTessellator::new())),
             stroke_tess: Rc::new(RefCell::new(StrokeTessellator::new())),
+            missing_glyphs: Rc::new(RefCell::new(MissingGlyphs::default())),
         }
     }
 
@@ -52,31 +162,301 @@ impl WgpuText {
         c: char,
         font_family: FontFamily,
         font_size: f32,
-        font_weight: FontWeight,
+        face: FaceQuery,
+        region: Option<HanRegion>,
     ) -> Result<GlyphPosInfo, piet::Error> {
-        let mut encoder = self.encoder.borrow_mut();
-        if encoder.is_none() {
-            *encoder = Some(
-                self.device
-                    .create_command_encoder(&wgpu::CommandEncoderDescriptor {
-                        label: Some("render"),
-                    }),
-            );
-        }
-
+        let mut encoder = self.encoder();
         let mut cache = self.cache.borrow_mut();
-        cache
+        let glyph_pos = cache
             .get_glyph_pos(
                 c,
                 font_family,
                 font_size,
-                font_weight,
+                face,
+                region,
                 &self.device,
-                &mut self.staging_belt.borrow_mut(),
-                encoder.as_mut().unwrap(),
+                &mut encoder,
             )
-            .map(|p| p.clone())
+            .map(|p| p.clone());
+        if let Err(err) = &glyph_pos {
+            if is_visible_char(c) {
+                self.report_missing_glyph(c, err);
+            }
+        }
+        glyph_pos
+    }
+
+    /// The glyph of `glyph_pos` grown by `width` for an outline, see
+    /// [`WgpuTextLayoutBuilder::outline`].
+    pub(crate) fn get_outline_pos(
+        &self,
+        glyph_pos: &GlyphPosInfo,
+        width: f64,
+    ) -> Result<GlyphPosInfo, piet::Error> {
+        let mut encoder = self.encoder();
+        self.cache
+            .borrow_mut()
+            .get_outline_pos(glyph_pos, width, &self.device, &mut encoder)
+            .cloned()
+    }
+
+    /// The encoder glyph uploads are recorded in, created on first use in
+    /// a frame.
+    fn encoder(&self) -> RefMut<'_, wgpu::CommandEncoder> {
+        RefMut::map(self.encoder.borrow_mut(), |encoder| {
+            encoder.get_or_insert_with(|| {
+                self.device
+                    .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                        label: Some("render"),
+                    })
+            })
+        })
+    }
+
+    /// Have `f` called with every char whose glyph couldn't be loaded,
+    /// because no font has it or the glyph atlas is full, the first time it
+    /// is drawn. Such chars are drawn as a box instead. Without a callback
+    /// they are logged.
+    pub fn on_missing_glyph(&self, f: impl FnMut(char, &piet::Error) + 'static) {
+        self.missing_glyphs.borrow_mut().on_missing = Some(Box::new(f));
+    }
+
+    fn report_missing_glyph(&self, c: char, err: &piet::Error) {
+        let mut missing = self.missing_glyphs.borrow_mut();
+        if !missing.reported.insert(c) {
+            return;
+        }
+        match missing.on_missing.as_mut() {
+            Some(on_missing) => on_missing(c, err),
+            None => log::warn!("no glyph for {:?} (U+{:04X}): {}", c, c as u32, err),
+        }
     }
+
+    /// Draw glyphs of `min_size` physical pixels and larger scaled from
+    /// distance fields, or with `None`, the default, always from bitmaps
+    /// rasterized at their size. A distance field serves a glyph at every
+    /// size, so zooming doesn't fill the glyph atlas with every size in
+    /// between; set a low `min_size` for the length of a scale animation.
+    /// Large text looks a little softer that way than when rasterized.
+    pub fn set_sdf_glyphs(&self, min_size: Option<f64>) {
+        let mut cache = self.cache.borrow_mut();
+        if cache.sdf_min_size != min_size {
+            cache.sdf_min_size = min_size;
+            drop(cache);
+            // Shaped runs hold glyphs drawn the other way.
+            self.clear_shape_cache();
+        }
+    }
+
+    /// Rasterize the glyphs of `chars` ahead of time, so that the first
+    /// frames drawing them don't stall on rasterization. The uploads go
+    /// out with the next frame.
+    pub fn prewarm_glyphs(
+        &self,
+        font_family: FontFamily,
+        font_size: f64,
+        chars: impl Iterator<Item = char>,
+    ) {
+        for c in chars {
+            if c.is_whitespace() {
+                continue;
+            }
+            let _ = self.get_glyph_pos(
+                c,
+                font_family.clone(),
+                font_size as f32,
+                FontWeight::REGULAR.into(),
+                None,
+            );
+        }
+    }
+
+    /// The advance width of `c`, without building a text layout.
+    pub fn char_width(&self, font_family: FontFamily, font_size: f64, c: char) -> f64 {
+        let key = (font_family, font_size.to_bits(), c);
+        if let Some(width) = self.advances.borrow().get(&key) {
+            return *width;
+        }
+        let width = self
+            .cache
+            .borrow_mut()
+            .get_glyph_advance(c, key.0.clone(), font_size as f32, FontWeight::REGULAR)
+            .unwrap_or(0.0);
+        self.advances.borrow_mut().insert(key, width);
+        width
+    }
+
+    /// Whether emoji come from color bitmap fonts or outline fonts. This
+    /// applies to every renderer sharing the glyph cache.
+    pub fn set_emoji_source(&self, emoji_source: EmojiSource) {
+        self.cache.borrow().fonts().set_emoji_source(emoji_source);
+        self.advances.borrow_mut().clear();
+        self.shapes.borrow_mut().clear();
+    }
+
+    /// Register the fonts in the font file or collection at `path`, like
+    /// [`Text::load_font`]. Faces already registered are skipped.
+    pub fn load_font_file(&mut self, path: impl AsRef<Path>) -> Result<FontFamily, piet::Error> {
+        let path = path.as_ref();
+        let data = fs::read(path).map_err(|e| font_file_error(path, e))?;
+        self.register_font(data).map_err(|e| match e {
+            piet::Error::FontLoadingFailed => font_file_error(
+                path,
+                io::Error::new(io::ErrorKind::InvalidData, "not a supported font"),
+            ),
+            e => e,
+        })
+    }
+
+    /// Register every font file in `dir` and its subdirectories, e.g. the
+    /// fonts bundled with an application. Returns the families found,
+    /// without duplicates.
+    pub fn load_fonts_dir(
+        &mut self,
+        dir: impl AsRef<Path>,
+    ) -> Result<Vec<FontFamily>, piet::Error> {
+        let mut paths = Vec::new();
+        let mut dirs = vec![dir.as_ref().to_path_buf()];
+        while let Some(dir) = dirs.pop() {
+            for entry in fs::read_dir(&dir).map_err(|e| font_file_error(&dir, e))? {
+                let path = entry.map_err(|e| font_file_error(&dir, e))?.path();
+                if path.is_dir() {
+                    dirs.push(path);
+                } else if is_font_file(&path) {
+                    paths.push(path);
+                }
+            }
+        }
+        // The first file of a family names it, so load in a stable order.
+        paths.sort();
+
+        let mut families: Vec<FontFamily> = Vec::new();
+        for path in paths {
+            let family = self.load_font_file(&path)?;
+            if !families.contains(&family) {
+                families.push(family);
+            }
+        }
+        Ok(families)
+    }
+
+    fn register_font(&mut self, data: Vec<u8>) -> Result<FontFamily, piet::Error> {
+        let family = self.cache.borrow().fonts().load_font(Arc::new(data))?;
+        self.advances.borrow_mut().clear();
+        self.shapes.borrow_mut().clear();
+        Ok(family)
+    }
+
+    pub(crate) fn clear_shape_cache(&self) {
+        self.shapes.borrow_mut().clear();
+    }
+
+    /// The bytes of the shaped runs and glyph advances kept for later
+    /// layouts.
+    pub(crate) fn layout_cache_bytes(&self) -> u64 {
+        let runs: usize = self
+            .shapes
+            .borrow()
+            .runs
+            .values()
+            .map(|run| run.len() * std::mem::size_of::<ShapedGlyph>())
+            .sum();
+        let advances =
+            self.advances.borrow().len() * std::mem::size_of::<((FontFamily, u64, char), f64)>();
+        (runs + advances) as u64
+    }
+
+    pub(crate) fn clear_layout_caches(&self) {
+        self.shapes.borrow_mut().clear();
+        self.advances.borrow_mut().clear();
+    }
+
+    /// How much of the glyph atlas is in use. The atlas grows up to
+    /// `max_size` when it runs out of rows.
+    pub fn atlas_stats(&self) -> AtlasStats {
+        self.cache.borrow().stats()
+    }
+
+    /// All installed font families and their faces.
+    pub fn families(&self) -> Vec<FamilyInfo> {
+        self.source.families()
+    }
+
+    /// Load the installed face of `family` closest to `weight` and
+    /// `style`, or `None` if the family isn't installed.
+    pub fn resolve(
+        &self,
+        family: &FontFamily,
+        weight: FontWeight,
+        style: FontStyle,
+    ) -> Option<FontId> {
+        self.cache.borrow().fonts().resolve(family, weight, style)
+    }
+
+    /// Load the installed face with the given postscript name.
+    pub fn resolve_postscript_name(&self, name: &str) -> Option<FontId> {
+        self.cache.borrow().fonts().resolve_postscript_name(name)
+    }
+
+    pub fn postscript_name(&self, font: FontId) -> Option<String> {
+        self.cache.borrow().fonts().postscript_name(font)
+    }
+
+    pub fn family_name(&self, font: FontId) -> Option<String> {
+        self.cache.borrow().fonts().family_name(font)
+    }
+
+    /// The sum of the advance widths of the chars in `s`, without building
+    /// a text layout.
+    pub fn string_width(&self, font_family: FontFamily, font_size: f64, s: &str) -> f64 {
+        s.chars()
+            .map(|c| self.char_width(font_family.clone(), font_size, c))
+            .sum()
+    }
+
+    /// Rasterize glyph `glyph_id` of the regular face of `font_family` at
+    /// `font_size` logical pixels, in the renderer's scale. `None` if the
+    /// font has no such glyph.
+    pub fn rasterize_glyph(
+        &self,
+        font_family: FontFamily,
+        font_size: f64,
+        glyph_id: u32,
+    ) -> Option<GlyphBitmap> {
+        let cache = self.cache.borrow();
+        cache.fonts().rasterize_glyph(
+            font_family,
+            FontWeight::REGULAR,
+            (font_size * cache.scale) as f32,
+            glyph_id,
+        )
+    }
+}
+
+/// The kind of pixels in a [`GlyphBitmap`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum GlyphContent {
+    /// One coverage byte per pixel. Color bitmap fonts are rasterized to
+    /// coverage too, like in the glyph atlas.
+    Mask,
+}
+
+/// A glyph rasterized with [`WgpuText::rasterize_glyph`], in physical
+/// pixels.
+#[derive(Clone, Debug)]
+pub struct GlyphBitmap {
+    pub width: u32,
+    pub height: u32,
+    /// Rows of `width` pixels, top row first.
+    pub pixels: Vec<u8>,
+    pub content: GlyphContent,
+    /// The offset of the left edge of the bitmap from the glyph origin.
+    pub left: i32,
+    /// The distance from the baseline up to the top edge of the bitmap.
+    pub top: i32,
+    /// The advance of the glyph.
+    pub advance: f32,
 }
 
 #[derive(Clone)]
@@ -87,6 +467,20 @@ pub struct WgpuTextLayout {
     attrs: Rc<Attributes>,
     ref_glyph: Rc<RefCell<GlyphPosInfo>>,
     glyphs: Rc<RefCell<Vec<GlyphPosInfo>>>,
+    ellipsis: Rc<RefCell<Option<GlyphPosInfo>>>,
+    lines: Rc<RefCell<Vec<LineInfo>>>,
+    rtl: Rc<RefCell<Vec<bool>>>,
+    alignment: TextAlignment,
+    direction: TextDirection,
+    wrap_mode: WrapMode,
+    overflow: TextOverflow,
+    max_lines: Option<usize>,
+    max_height: Option<f64>,
+    truncated: Rc<RefCell<bool>>,
+    block_glyphs_below: Option<f64>,
+    inline_boxes: Rc<Vec<InlineBox>>,
+    inline_box_rects: Rc<RefCell<Vec<(InlineBoxId, Rect)>>>,
+    shaped: Rc<RefCell<Option<ShapedLayout>>>,
     geometry: Rc<RefCell<VertexBuffers<GpuVertex, u32>>>,
 }
 
@@ -101,6 +495,20 @@ impl WgpuTextLayout {
             width: f64::MAX,
             attrs: Rc::new(Attributes::default()),
             glyphs: Rc::new(RefCell::new(Vec::new())),
+            ellipsis: Rc::new(RefCell::new(None)),
+            lines: Rc::new(RefCell::new(Vec::new())),
+            rtl: Rc::new(RefCell::new(Vec::new())),
+            alignment: TextAlignment::Start,
+            direction: TextDirection::default(),
+            wrap_mode: WrapMode::default(),
+            overflow: TextOverflow::default(),
+            max_lines: None,
+            max_height: None,
+            truncated: Rc::new(RefCell::new(false)),
+            block_glyphs_below: None,
+            inline_boxes: Rc::new(Vec::new()),
+            inline_box_rects: Rc::new(RefCell::new(Vec::new())),
+            shaped: Rc::new(RefCell::new(None)),
             ref_glyph: Rc::new(RefCell::new(GlyphPosInfo::default())),
             geometry: Rc::new(RefCell::new(VertexBuffers::with_capacity(
                 num_vertices,
@@ -117,6 +525,19 @@ impl WgpuTextLayout {
         self.attrs = Rc::new(attrs);
     }
 
+    /// Break the lines again at `max_width` and align them, reusing the
+    /// shaping of the text. With [`WgpuTextLayoutBuilder::build_unbroken`]
+    /// this lays out text at a width that depends on its natural width.
+    pub fn rebreak(&mut self, max_width: f64, alignment: TextAlignment) {
+        self.width = max_width;
+        self.alignment = alignment;
+        let (is_mono, tab_width, bounds) = match self.shaped.borrow().as_ref() {
+            Some(shaped) => (shaped.is_mono, shaped.tab_width, shaped.bounds),
+            None => (false, 8, None),
+        };
+        self.rebuild(is_mono, tab_width, bounds);
+    }
+
     pub fn set_color(&self, color: &Color) {
         let color = format_color(&color);
         for v in self.geometry.borrow_mut().vertices.iter_mut() {
@@ -124,41 +545,548 @@ impl WgpuTextLayout {
         }
     }
 
-    pub(crate) fn rebuild(&self, is_mono: bool, tab_width: usize, bounds: Option<[f64; 2]>) {
-        let font_family = self.attrs.defaults.font.clone();
-        let font_size = self.attrs.defaults.font_size;
-        let font_weight = self.attrs.defaults.weight;
-        if let Ok(glyph_pos) =
-            self.state
-                .get_glyph_pos('W', font_family.clone(), font_size as f32, font_weight)
-        {
-            *self.ref_glyph.borrow_mut() = glyph_pos.clone();
+    /// Look up the reference glyph that mono widths, blocks and missing
+    /// glyphs are measured by, and return its advance.
+    fn update_ref_glyph(&self) -> f64 {
+        if let Ok(glyph_pos) = self.state.get_glyph_pos(
+            'W',
+            self.attrs.defaults.font.clone(),
+            self.attrs.defaults.font_size as f32,
+            self.attrs.default_face(),
+            None,
+        ) {
+            *self.ref_glyph.borrow_mut() = glyph_pos;
         }
+        self.ref_glyph.borrow().width
+    }
 
-        let mono_width = self.ref_glyph.borrow().rect.width();
+    pub(crate) fn rebuild(&self, is_mono: bool, tab_width: usize, bounds: Option<[f64; 2]>) {
+        let mono_width = self.update_ref_glyph();
 
         let len = self.text.chars().count();
 
         let mut glyphs = self.glyphs.borrow_mut();
         glyphs.clear();
         glyphs.reserve(len);
+        let mut lines = self.lines.borrow_mut();
+        lines.clear();
+        lines.push(LineInfo { start: 0, y: 0.0 });
         let mut geometry = self.geometry.borrow_mut();
         geometry.vertices.clear();
         geometry.indices.clear();
         geometry.vertices.reserve(4 * len);
         geometry.indices.reserve(6 * len);
 
+        let cached = self
+            .shaped
+            .borrow()
+            .as_ref()
+            .filter(|shaped| shaped.is_mono == is_mono && shaped.tab_width == tab_width)
+            .and_then(|shaped| shaped.glyphs.clone());
+        // Layouts limited to a few lines only shape the glyphs they break,
+        // unless the whole text was shaped before.
+        let limited = self.max_lines.is_some() || self.max_height.is_some();
+        let shaped = match cached {
+            Some(glyphs) => Some(glyphs),
+            None if limited => self
+                .state
+                .shapes
+                .borrow()
+                .get(&self.shape_key(is_mono, tab_width)),
+            None => Some(self.shape(is_mono, tab_width, mono_width)),
+        };
+        *self.shaped.borrow_mut() = Some(ShapedLayout {
+            is_mono,
+            tab_width,
+            bounds,
+            glyphs: shaped.clone(),
+        });
+        let shaped: Box<dyn Iterator<Item = ShapedGlyph>> = match shaped {
+            Some(glyphs) => Box::new((0..glyphs.len()).map(move |i| glyphs[i].clone())),
+            None => Box::new(
+                self.shape_glyphs(is_mono, tab_width, mono_width)
+                    .map(|(glyph, _)| glyph),
+            ),
+        };
+
         let mut x = 0.0;
         let mut y = 0.0;
         let mut max_height = 0.0;
         let mut index = 0;
+        let mut line_start = 0;
+        let mut break_at = 0;
+        let mut overflow_at = None;
+        let mut next_box = 0;
+        // Inline boxes with the glyph they're placed in front of and their
+        // offset from its origin.
+        let mut placed_boxes = Vec::new();
+        let mut last_tabular_offset = 0.0;
+        for (c, shaped) in self.text.chars().zip(shaped) {
+            let boxes_start = next_box;
+            while next_box < self.inline_boxes.len() && self.inline_boxes[next_box].index <= index {
+                next_box += 1;
+            }
+            let boxes = &self.inline_boxes[boxes_start..next_box];
+            index += c.len_utf8();
+
+            let mut glyph_pos = shaped.glyph;
+            let tabular_offset = shaped.tabular_offset;
+            last_tabular_offset = tabular_offset;
+            let boxes_width = boxes.iter().map(|b| b.size.width).sum::<f64>() as f32;
+            let width = glyph_pos.width as f32 + boxes_width;
+
+            // A line may break before and after wide (CJK) chars, and
+            // before inline boxes.
+            let wide = UnicodeWidthChar::width(c) == Some(2);
+            if wide || !boxes.is_empty() {
+                break_at = glyphs.len();
+            }
+
+            // Once the last allowed line is full the rest of the text stays
+            // on it, for the overflow handling below to elide.
+            if overflow_at.is_none() && (x + width) as f64 > self.width {
+                // The next line would have to fit below the current one.
+                let last_line = self.wrap_mode == WrapMode::NoWrap
+                    || self.max_lines.is_some_and(|max| lines.len() >= max)
+                    || self
+                        .max_height
+                        .is_some_and(|max| (y + 2.0 * max_height) as f64 > max);
+                if last_line {
+                    if limited {
+                        overflow_at = Some(glyphs.len());
+                    }
+                } else {
+                    // Carry the word being broken over to the new line, or
+                    // break right here if it takes up the whole line.
+                    let start = match self.wrap_mode {
+                        WrapMode::WordBoundary if break_at > line_start => break_at,
+                        _ => glyphs.len(),
+                    };
+                    let dx = if start < glyphs.len() {
+                        -(glyphs[start - 1].rect.x0 + glyphs[start - 1].width)
+                    } else {
+                        -x as f64
+                    };
+                    y += max_height;
+                    let dy = y as f64 - glyph_y(&glyphs, start);
+                    shift_glyphs(&mut glyphs[start..], Vec2::new(dx, dy));
+                    x += dx as f32;
+                    lines.push(LineInfo { start, y: y as f64 });
+                    line_start = start;
+                }
+            }
+
+            // The boxes take up the space in front of the glyph.
+            let mut box_x = -tabular_offset - boxes_width as f64;
+            for inline_box in boxes {
+                placed_boxes.push((inline_box, glyphs.len(), box_x));
+                box_x += inline_box.size.width;
+            }
+            x += boxes_width;
+            glyph_pos.rect = glyph_pos
+                .rect
+                .with_origin((x as f64 + tabular_offset, y as f64));
+
+            let box_height = boxes.iter().map(|b| b.size.height).fold(0.0, f64::max);
+            let height = glyph_pos.rect.height().max(box_height) as f32;
+            if height > max_height {
+                max_height = height;
+            }
+
+            if let Some(bounds) = bounds.as_ref() {
+                if x > bounds[1] as f32 {
+                    break;
+                }
+            }
+
+            x += glyph_pos.width as f32;
+            glyphs.push(glyph_pos);
+            if c == ' ' || c == '\t' || wide {
+                break_at = glyphs.len();
+            }
+            // A newline ends its line, so text ending in one has an empty
+            // last line, unless the line limit is reached.
+            if c == '\n' && overflow_at.is_none() {
+                let last_line = self.max_lines.is_some_and(|max| lines.len() >= max)
+                    || self
+                        .max_height
+                        .is_some_and(|max| (y + 2.0 * max_height) as f64 > max);
+                if !last_line {
+                    y += max_height;
+                    x = 0.0;
+                    lines.push(LineInfo {
+                        start: glyphs.len(),
+                        y: y as f64,
+                    });
+                    line_start = glyphs.len();
+                    break_at = glyphs.len();
+                } else if limited {
+                    overflow_at = Some(glyphs.len());
+                }
+            }
+            // The rest of the text is cut off without being laid out, unless
+            // the ellipsis goes before the end of the line.
+            if overflow_at.is_some() && self.ellipsis_position(line_start) == EllipsisPosition::End
+            {
+                break;
+            }
+        }
+        *self.truncated.borrow_mut() = overflow_at.is_some_and(|at| at < glyphs.len());
+
+        // Boxes at the end of the text widen the last glyph instead.
+        let trailing_boxes = &self.inline_boxes[next_box..];
+        if glyphs.len() == len && !trailing_boxes.is_empty() {
+            let last = &mut glyphs[len - 1];
+            let mut box_x = last.width - last_tabular_offset;
+            for inline_box in trailing_boxes {
+                placed_boxes.push((inline_box, len - 1, box_x));
+                box_x += inline_box.size.width;
+            }
+            last.width += trailing_boxes.iter().map(|b| b.size.width).sum::<f64>();
+        }
+        let mut ellipsis = overflow_at
+            .and_then(|overflow_at| self.apply_overflow(&mut glyphs, line_start, overflow_at));
+        *self.rtl.borrow_mut() = self.align_lines(&mut glyphs, &lines, ellipsis.as_mut());
+        // The shader snaps glyph quads to the pixel grid, so the positions
+        // carets and hit testing go by are snapped the same way here, once.
+        let scale = self.state.cache.borrow().scale;
+        snap_glyphs(&mut glyphs, scale);
+        if let Some(ellipsis) = ellipsis.as_mut() {
+            snap_glyphs(std::slice::from_mut(ellipsis), scale);
+        }
+        *self.ellipsis.borrow_mut() = ellipsis;
+        *self.inline_box_rects.borrow_mut() = placed_boxes
+            .into_iter()
+            .filter_map(|(inline_box, i, dx)| {
+                let glyph = &glyphs[i];
+                // Boxes go along with their elided glyph.
+                if glyph.width == 0.0 && glyph.rect.width() == 0.0 {
+                    return None;
+                }
+                let origin = glyph.rect.origin() + Vec2::new(dx, 0.0);
+                Some((
+                    inline_box.id,
+                    Rect::from_origin_size(origin, inline_box.size),
+                ))
+            })
+            .collect();
+
+        self.add_geometry(&mut geometry, &glyphs, &lines, bounds);
+    }
+
+    /// Add the backgrounds, glyphs, decorations and ellipsis of the laid
+    /// out `glyphs` to `geometry`.
+    fn add_geometry(
+        &self,
+        geometry: &mut VertexBuffers<GpuVertex, u32>,
+        glyphs: &[GlyphPosInfo],
+        lines: &[LineInfo],
+        bounds: Option<[f64; 2]>,
+    ) {
+        self.add_backgrounds(geometry, glyphs, lines);
+        let blocks = self.draws_blocks();
+        if !blocks {
+            self.add_outlines(geometry, glyphs, bounds);
+        }
+        let mut block: Option<(Rect, [f32; 4])> = None;
+        let colors = self
+            .text
+            .char_indices()
+            .map(|(index, _)| format_color(self.attrs.color(index)));
+        for ((c, glyph_pos), color) in self.text.chars().zip(glyphs.iter()).zip(colors) {
+            if c == ' ' || c == '\n' || c == '\t' || glyph_pos.rect.width() == 0.0 {
+                continue;
+            }
+            if let Some(bounds) = bounds.as_ref() {
+                if glyph_pos.rect.x0 + glyph_pos.width < bounds[0] {
+                    continue;
+                }
+            }
+            if glyph_pos.missing {
+                let rect = glyph_pos.metric.ink + glyph_pos.rect.origin().to_vec2();
+                for side in missing_glyph_box(rect) {
+                    add_block_quad(geometry, &side, color);
+                }
+                continue;
+            }
+            if blocks {
+                // Runs of touching glyphs of the same color become one block.
+                let rect = glyph_pos.metric.ink + glyph_pos.rect.origin().to_vec2();
+                block = match block {
+                    Some((run, run_color))
+                        if run_color == color
+                            && run.y0 == rect.y0
+                            && ((run.x1 - rect.x0).abs() < 0.01
+                                || (rect.x1 - run.x0).abs() < 0.01) =>
+                    {
+                        Some((run.union(rect), color))
+                    }
+                    run => {
+                        if let Some((run, run_color)) = run {
+                            add_block_quad(geometry, &run, run_color);
+                        }
+                        Some((rect, color))
+                    }
+                };
+                continue;
+            }
+            add_glyph_quad(geometry, &glyph_pos.rect, glyph_pos, color, [0.0, 0.0], 0);
+        }
+        if let Some((run, run_color)) = block {
+            add_block_quad(geometry, &run, run_color);
+        }
+        self.add_decorations(geometry, glyphs);
+
+        if let Some(ellipsis) = self.ellipsis.borrow().as_ref() {
+            add_glyph_quad(
+                geometry,
+                &ellipsis.rect,
+                ellipsis,
+                format_color(&self.attrs.defaults.fg_color),
+                [0.0, 0.0],
+                0,
+            );
+        }
+    }
+
+    /// Draw the outlines of the glyphs that have one, all before the
+    /// glyphs so that no outline covers a neighbouring glyph.
+    fn add_outlines(
+        &self,
+        geometry: &mut VertexBuffers<GpuVertex, u32>,
+        glyphs: &[GlyphPosInfo],
+        bounds: Option<[f64; 2]>,
+    ) {
+        if !self.attrs.has_outlines() {
+            return;
+        }
+        let mut add_outline = |glyph_pos: &GlyphPosInfo, (color, width): &(Color, f64)| {
+            if let Ok(outline) = self.state.get_outline_pos(glyph_pos, *width) {
+                add_glyph_quad(
+                    geometry,
+                    &outline.rect,
+                    &outline,
+                    format_color(color),
+                    [0.0, 0.0],
+                    0,
+                );
+            }
+        };
+        for ((index, c), glyph_pos) in self.text.char_indices().zip(glyphs.iter()) {
+            if c == ' ' || c == '\n' || c == '\t' || glyph_pos.rect.width() == 0.0 {
+                continue;
+            }
+            if glyph_pos.missing {
+                continue;
+            }
+            if let Some(bounds) = bounds.as_ref() {
+                if glyph_pos.rect.x0 + glyph_pos.width < bounds[0] {
+                    continue;
+                }
+            }
+            if let Some(outline) = self.attrs.outline(index) {
+                add_outline(glyph_pos, outline);
+            }
+        }
+        if let Some(ellipsis) = self.ellipsis.borrow().as_ref() {
+            if let Some(outline) = self.attrs.default_outline.as_ref() {
+                if outline.1 > 0.0 {
+                    add_outline(ellipsis, outline);
+                }
+            }
+        }
+    }
+
+    /// Draw the background colors behind their glyphs, the full height of
+    /// the line, as one quad for every run of touching glyphs of a color.
+    fn add_backgrounds(
+        &self,
+        geometry: &mut VertexBuffers<GpuVertex, u32>,
+        glyphs: &[GlyphPosInfo],
+        lines: &[LineInfo],
+    ) {
+        if self.attrs.background.is_empty() {
+            return;
+        }
+        // The bottom of a line is the top of the next, or for the last line
+        // the bottom of its tallest glyph.
+        let last_bottom = lines
+            .last()
+            .map(|last| glyphs[last.start.min(glyphs.len())..].iter())
+            .into_iter()
+            .flatten()
+            .map(|g| g.rect.y1)
+            .fold(0.0, f64::max);
+        let mut runs: Vec<(Rect, [f32; 4])> = Vec::new();
+        let mut line = 0;
+        for (i, ((index, c), glyph)) in self.text.char_indices().zip(glyphs).enumerate() {
+            while lines.get(line + 1).is_some_and(|l| l.start <= i) {
+                line += 1;
+            }
+            // Elided glyphs have no advance.
+            if c == '\n' || glyph.width == 0.0 {
+                continue;
+            }
+            let color = match last_span(&self.attrs.background, index) {
+                Some(color) => format_color(color),
+                None => continue,
+            };
+            let bottom = lines.get(line + 1).map(|l| l.y).unwrap_or(last_bottom);
+            let rect = Rect::new(
+                glyph.rect.x0,
+                lines[line].y,
+                glyph.rect.x0 + glyph.width,
+                bottom,
+            );
+            match runs.last_mut() {
+                Some((run, run_color))
+                    if *run_color == color
+                        && run.y0 == rect.y0
+                        && ((run.x1 - rect.x0).abs() < 0.01 || (rect.x1 - run.x0).abs() < 0.01) =>
+                {
+                    *run = run.union(rect);
+                }
+                _ => runs.push((rect, color)),
+            }
+        }
+        for (rect, color) in runs {
+            add_block_quad(geometry, &rect, color);
+        }
+    }
+
+    /// Draw underlines and strikethroughs, as one quad for every run of
+    /// touching glyphs with the same decoration.
+    fn add_decorations(
+        &self,
+        geometry: &mut VertexBuffers<GpuVertex, u32>,
+        glyphs: &[GlyphPosInfo],
+    ) {
+        if !self.attrs.has_decorations() {
+            return;
+        }
+        let cache = self.state.cache.borrow();
+        let fonts = cache.fonts();
+        let mut runs: Vec<(Rect, [f32; 4])> = Vec::new();
+        for ((index, c), glyph) in self.text.char_indices().zip(glyphs) {
+            // Elided glyphs have no advance.
+            if c == '\n' || glyph.width == 0.0 {
+                continue;
+            }
+            let underline = self.attrs.underline(index);
+            let strikethrough = self.attrs.strikethrough(index);
+            if !underline && !strikethrough {
+                continue;
+            }
+            let metrics = match fonts.decoration_metrics(&glyph.info, cache.scale) {
+                Some(metrics) => metrics,
+                None => continue,
+            };
+            let baseline = glyph.rect.y0 + glyph.metric.ascent;
+            let line = |offset: f64| {
+                Rect::new(
+                    glyph.rect.x0,
+                    baseline + offset,
+                    glyph.rect.x0 + glyph.width,
+                    baseline + offset + metrics.thickness,
+                )
+            };
+            let mut decorations = Vec::with_capacity(2);
+            if underline {
+                let color = format_color(self.attrs.underline_color(index));
+                decorations.push((line(metrics.underline), color));
+            }
+            if strikethrough {
+                let color = format_color(self.attrs.strikethrough_color(index));
+                decorations.push((line(metrics.strikethrough), color));
+            }
+            for (rect, color) in decorations {
+                let touching = runs.iter_mut().rev().take(2).find(|(run, run_color)| {
+                    *run_color == color
+                        && run.y0 == rect.y0
+                        && run.y1 == rect.y1
+                        && ((run.x1 - rect.x0).abs() < 0.01 || (rect.x1 - run.x0).abs() < 0.01)
+                });
+                match touching {
+                    Some((run, _)) => *run = run.union(rect),
+                    None => runs.push((rect, color)),
+                }
+            }
+        }
+        for (rect, color) in runs {
+            add_block_quad(geometry, &rect, color);
+        }
+    }
+
+    fn shape_key(&self, is_mono: bool, tab_width: usize) -> ShapeKey {
+        ShapeKey {
+            text: self.text.clone(),
+            style: self
+                .attrs
+                .shape_hash(is_mono, tab_width, self.draws_blocks()),
+        }
+    }
+
+    /// The glyphs of the text with their advances, before line breaking.
+    /// Identical runs are shaped once and shared through the [`WgpuText`].
+    fn shape(&self, is_mono: bool, tab_width: usize, mono_width: f64) -> Rc<[ShapedGlyph]> {
+        let key = self.shape_key(is_mono, tab_width);
+        if let Some(shaped) = self.state.shapes.borrow().get(&key) {
+            return shaped;
+        }
+
+        #[cfg(feature = "rayon")]
+        if !self.draws_blocks() && self.text.len() >= PARALLEL_RASTERIZATION_MIN_LEN {
+            let mut index = 0;
+            self.state
+                .cache
+                .borrow_mut()
+                .rasterize_parallel(self.text.chars().map(|c| {
+                    let glyph = (
+                        c,
+                        self.attrs.font(index),
+                        self.attrs.size(index) as f32,
+                        self.attrs.face(index),
+                        self.attrs.region(index),
+                    );
+                    index += c.len_utf8();
+                    glyph
+                }));
+        }
+
+        let mut complete = true;
+        let shaped: Rc<[ShapedGlyph]> = self
+            .shape_glyphs(is_mono, tab_width, mono_width)
+            .map(|(glyph, loaded)| {
+                complete &= loaded;
+                glyph
+            })
+            .collect();
+        // Runs with glyphs that failed to load are shaped again next time,
+        // once there may be room for them in the atlas.
+        if complete {
+            self.state.shapes.borrow_mut().insert(key, shaped.clone());
+        }
+        shaped
+    }
+
+    /// Shape the glyphs of the text one by one, as they are needed, along
+    /// with whether each glyph loaded.
+    fn shape_glyphs(
+        &self,
+        is_mono: bool,
+        tab_width: usize,
+        mono_width: f64,
+    ) -> impl Iterator<Item = (ShapedGlyph, bool)> + '_ {
+        let blocks = self.draws_blocks();
         let mut mono_char_widths = 0;
-        for c in self.text.chars() {
+        self.text.char_indices().map(move |(index, c)| {
             let font_family = self.attrs.font(index);
             let font_size = self.attrs.size(index) as f32;
-            let font_weight = self.attrs.font_weight(index);
-            let color = self.attrs.color(index);
-            index += c.len_utf8();
+            let face = self.attrs.face(index);
+            let region = self.attrs.region(index);
+            let tabular = !is_mono
+                && c.is_ascii_digit()
+                && self.attrs.feature(index, b"tnum").unwrap_or(0) > 0;
 
             let default_width = if is_mono {
                 let char_width = if c == '\t' {
@@ -167,146 +1095,1009 @@ impl WgpuTextLayout {
                     UnicodeWidthChar::width(c).unwrap_or(1)
                 };
                 mono_char_widths += char_width;
-                let width = char_width as f32 * mono_width as f32;
-                width
+                char_width as f32 * mono_width as f32
             } else {
                 let char_width = if c == '\t' {
                     tab_width
                 } else {
                     UnicodeWidthChar::width(c).unwrap_or(1)
                 };
-                let width = char_width as f32 * mono_width as f32;
-                width
+                char_width as f32 * mono_width as f32
             };
 
-            let color = format_color(&color);
-            let mut glyph_pos = self
-                .state
-                .get_glyph_pos(c, font_family, font_size, font_weight)
-                .map(|g| g.clone())
-                .unwrap_or_else(|_| GlyphPosInfo::empty(default_width as f64));
-            let width = if is_mono {
-                glyph_pos.width = default_width as f64;
-                default_width
+            let mut loaded = true;
+            let mut glyph_pos = if blocks {
+                self.block_glyph(c, font_family.clone(), font_size, default_width)
             } else {
-                glyph_pos.rect.width() as f32
+                self.state
+                    .get_glyph_pos(c, font_family.clone(), font_size, face, region)
+                    .unwrap_or_else(|_| {
+                        loaded = false;
+                        if is_visible_char(c) {
+                            self.missing_glyph(font_size, default_width)
+                        } else {
+                            GlyphPosInfo::empty(default_width as f64)
+                        }
+                    })
+            };
+            // Tabular digits all take the advance of '0', with the glyph
+            // centered in it.
+            let mut tabular_offset = 0.0;
+            if tabular {
+                if let Ok(zero) = self
+                    .state
+                    .get_glyph_pos('0', font_family, font_size, face, None)
+                {
+                    tabular_offset = ((zero.width - glyph_pos.width) / 2.0).round();
+                    glyph_pos.width = zero.width;
+                }
+            }
+            if is_mono {
+                glyph_pos.width = default_width as f64;
+            }
+            let shaped = ShapedGlyph {
+                glyph: glyph_pos,
+                tabular_offset,
+            };
+            (shaped, loaded)
+        })
+    }
+
+    /// Whether the text is drawn as blocks, see
+    /// [`WgpuTextLayoutBuilder::block_glyphs_below`].
+    fn draws_blocks(&self) -> bool {
+        self.block_glyphs_below
+            .is_some_and(|size| self.attrs.defaults.font_size < size)
+    }
+
+    /// A glyph drawn as a block: the advance of `c` and the ink height of
+    /// the reference glyph, without rasterizing `c`.
+    fn block_glyph(
+        &self,
+        c: char,
+        font_family: FontFamily,
+        font_size: f32,
+        default_width: f32,
+    ) -> GlyphPosInfo {
+        let width = if c == '\t' {
+            default_width as f64
+        } else {
+            self.state.char_width(font_family, font_size as f64, c)
+        };
+        let mut glyph = self.ref_glyph.borrow().clone();
+        glyph.width = width;
+        glyph.rect = Size::new(width, glyph.rect.height()).to_rect();
+        glyph.cache_rect = Rect::ZERO;
+        glyph.metric.ink = Rect::new(0.0, glyph.metric.ink.y0, width, glyph.metric.ink.y1);
+        glyph
+    }
+
+    /// A box `width` wide standing in for a glyph that couldn't be loaded,
+    /// with the line metrics of the layout's reference glyph, or made up
+    /// from `font_size` if that didn't load either. It is drawn without the
+    /// glyph atlas, so it shows even when the atlas is full.
+    fn missing_glyph(&self, font_size: f32, width: f32) -> GlyphPosInfo {
+        let font_size = font_size as f64;
+        let width = width as f64;
+        let mut glyph = self.ref_glyph.borrow().clone();
+        if glyph.rect.height() == 0.0 {
+            glyph.metric.ascent = (font_size * 0.8).round();
+            glyph.metric.descent = (font_size * 0.2).round();
+            glyph.rect = Size::new(width, glyph.metric.ascent + glyph.metric.descent).to_rect();
+        }
+        glyph.width = width;
+        glyph.rect = Size::new(width, glyph.rect.height()).to_rect();
+        glyph.cache_rect = Rect::ZERO;
+        // From about the cap height down to the baseline.
+        let baseline = glyph.metric.ascent;
+        glyph.metric.ink = Rect::new(
+            (width * 0.1).round(),
+            (baseline - font_size * 0.7).round().max(0.0),
+            (width * 0.9).round(),
+            baseline,
+        );
+        glyph.missing = true;
+        glyph
+    }
+
+    /// Truncate the last line, which starts at `line_start` and no longer
+    /// fits from `overflow_at` on, according to the layout's
+    /// [`TextOverflow`]. Elided glyphs keep their place in `glyphs` with a
+    /// zero width, so hit testing maps them to the ellipsis.
+    fn apply_overflow(
+        &self,
+        glyphs: &mut [GlyphPosInfo],
+        line_start: usize,
+        overflow_at: usize,
+    ) -> Option<GlyphPosInfo> {
+        if overflow_at >= glyphs.len() {
+            return None;
+        }
+        let end_x = |g: &GlyphPosInfo| g.rect.x0 + g.width;
+        let line_y = glyphs[line_start].rect.y0;
+        if self.overflow == TextOverflow::Clip {
+            let x = glyphs[overflow_at].rect.x0;
+            elide_glyphs(&mut glyphs[overflow_at..], x);
+            return None;
+        }
+        let position = self.ellipsis_position(line_start);
+
+        let mut ellipsis = self.ellipsis_glyph()?;
+        let available = self.width - ellipsis.width;
+        let line_end = end_x(&glyphs[glyphs.len() - 1]);
+
+        let ellipsis_x = match position {
+            EllipsisPosition::End => {
+                let mut end = overflow_at;
+                while end > line_start && end_x(&glyphs[end - 1]) > available {
+                    end -= 1;
+                }
+                let x = if end > line_start {
+                    end_x(&glyphs[end - 1])
+                } else {
+                    0.0
+                };
+                elide_glyphs(&mut glyphs[end..], x);
+                x
+            }
+            EllipsisPosition::Start => {
+                let mut start = line_start;
+                while start < glyphs.len() && line_end - glyphs[start].rect.x0 > available {
+                    start += 1;
+                }
+                let dx = ellipsis.width - glyph_x(glyphs, start);
+                shift_glyphs(&mut glyphs[start..], Vec2::new(dx, 0.0));
+                elide_glyphs(&mut glyphs[line_start..start], 0.0);
+                0.0
+            }
+            EllipsisPosition::Middle => {
+                let mut end = line_start;
+                while end < glyphs.len() && end_x(&glyphs[end]) <= available / 2.0 {
+                    end += 1;
+                }
+                let x = if end > line_start {
+                    end_x(&glyphs[end - 1])
+                } else {
+                    0.0
+                };
+                let mut start = end;
+                while start < glyphs.len() && line_end - glyphs[start].rect.x0 > available - x {
+                    start += 1;
+                }
+                let dx = x + ellipsis.width - glyph_x(glyphs, start);
+                shift_glyphs(&mut glyphs[start..], Vec2::new(dx, 0.0));
+                elide_glyphs(&mut glyphs[end..start], x);
+                x
+            }
+        };
+
+        ellipsis.rect = ellipsis.rect.with_origin((ellipsis_x, line_y));
+        Some(ellipsis)
+    }
+
+    fn ellipsis_glyph(&self) -> Option<GlyphPosInfo> {
+        self.state
+            .get_glyph_pos(
+                '\u{2026}',
+                self.attrs.defaults.font.clone(),
+                self.attrs.defaults.font_size as f32,
+                self.attrs.default_face(),
+                None,
+            )
+            .ok()
+    }
+
+    /// Where the last line, starting at `line_start`, is elided. Clipping
+    /// counts as eliding the end.
+    fn ellipsis_position(&self, line_start: usize) -> EllipsisPosition {
+        match self.overflow {
+            TextOverflow::Clip => EllipsisPosition::End,
+            // Eliding at the start or in the middle only makes sense for a
+            // single line.
+            TextOverflow::Ellipsis(_) if line_start > 0 => EllipsisPosition::End,
+            TextOverflow::Ellipsis(position) => position,
+        }
+    }
+
+    /// Lay out right-to-left runs in visual order and apply the alignment
+    /// to every line. Returns whether each glyph runs right-to-left.
+    ///
+    /// Runs are found with a simplified bidi resolution: chars from RTL
+    /// scripts are RTL, other letters and digits LTR, and neutrals take the
+    /// direction of their surroundings or else of the paragraph.
+    fn align_lines(
+        &self,
+        glyphs: &mut [GlyphPosInfo],
+        lines: &[LineInfo],
+        mut ellipsis: Option<&mut GlyphPosInfo>,
+    ) -> Vec<bool> {
+        let base_rtl = self.direction.is_rtl(&self.text);
+        let mut rtl = resolve_directions(&self.text, base_rtl);
+        rtl.truncate(glyphs.len());
+        let alignment = match (self.alignment, base_rtl) {
+            (TextAlignment::Center, _) => TextAlignment::Center,
+            (TextAlignment::End, false)
+            | (TextAlignment::Start | TextAlignment::Justified, true) => TextAlignment::End,
+            _ => TextAlignment::Start,
+        };
+
+        for (i, line) in lines.iter().enumerate() {
+            let end = lines.get(i + 1).map(|l| l.start).unwrap_or(glyphs.len());
+            if line.start >= end {
+                continue;
+            }
+            let line_glyphs = &mut glyphs[line.start..end];
+
+            if base_rtl || rtl[line.start..end].iter().any(|r| *r) {
+                let mut order: Vec<usize> = (0..line_glyphs.len()).collect();
+                let line_rtl = &rtl[line.start..end];
+                if base_rtl {
+                    order.reverse();
+                    // LTR runs keep their order inside the reversed line.
+                    let mut run = 0;
+                    while run < order.len() {
+                        let mut run_end = run;
+                        while run_end < order.len() && !line_rtl[order[run_end]] {
+                            run_end += 1;
+                        }
+                        order[run..run_end].reverse();
+                        run = run_end + 1;
+                    }
+                } else {
+                    let mut run = 0;
+                    while run < order.len() {
+                        let mut run_end = run;
+                        while run_end < order.len() && line_rtl[order[run_end]] {
+                            run_end += 1;
+                        }
+                        order[run..run_end].reverse();
+                        run = run_end + 1;
+                    }
+                }
+                let mut x = 0.0;
+                for index in order {
+                    let glyph = &mut line_glyphs[index];
+                    glyph.rect = glyph.rect.with_origin((x, glyph.rect.y0));
+                    x += glyph.width;
+                }
+            }
+
+            if self.width >= f64::MAX / 2.0 {
+                continue;
+            }
+            let mut line_width = line_glyphs
+                .iter()
+                .map(|g| g.rect.x0 + g.width)
+                .fold(0.0, f64::max);
+            let is_last = i + 1 == lines.len();
+            if let Some(ellipsis) = ellipsis.as_ref().filter(|_| is_last) {
+                line_width = line_width.max(ellipsis.rect.x0 + ellipsis.width);
+            }
+            let dx = match alignment {
+                TextAlignment::End => self.width - line_width,
+                TextAlignment::Center => (self.width - line_width) / 2.0,
+                _ => 0.0,
             };
+            if dx > 0.0 {
+                shift_glyphs(line_glyphs, Vec2::new(dx, 0.0));
+                if let Some(ellipsis) = ellipsis.as_mut().filter(|_| is_last) {
+                    ellipsis.rect = ellipsis.rect + Vec2::new(dx, 0.0);
+                }
+            }
+        }
+        rtl
+    }
+
+    pub(crate) fn draw_text(&self, ctx: &mut WgpuRenderContext, translate: [f32; 2]) {
+        let geometry = self.geometry.borrow();
+        if geometry.vertices.len() == 0 {
+            return;
+        }
+
+        let offset = ctx.geometry.vertices.len() as u32;
+        let primivite_id = (ctx.primitives.len() - 1) as u32;
+        let mut vertices = geometry
+            .vertices
+            .iter()
+            .map(|v| {
+                let mut v = v.clone();
+                v.translate = translate;
+                v.primitive_id = primivite_id;
+                v
+            })
+            .collect();
+        let mut indices = geometry.indices.iter().map(|i| *i + offset).collect();
+        ctx.geometry.vertices.append(&mut vertices);
+        ctx.geometry.indices.append(&mut indices);
+    }
+
+    /// The region tagged with [`WgpuTextLayoutBuilder::range_region`]
+    /// under `point`, if any. Overlapping regions resolve to the one added
+    /// last.
+    pub fn region_at_point(&self, point: Point) -> Option<RegionId> {
+        let glyphs = self.glyphs.borrow();
+        let ((offset, _), _) = self
+            .text
+            .char_indices()
+            .zip(glyphs.iter())
+            .find(|(_, glyph)| {
+                point.x >= glyph.rect.x0
+                    && point.x < glyph.rect.x0 + glyph.width
+                    && point.y >= glyph.rect.y0
+                    && point.y < glyph.rect.y1
+            })?;
+        self.attrs
+            .regions
+            .iter()
+            .rev()
+            .find(|r| r.range.contains(&offset))
+            .map(|r| r.payload)
+    }
+
+    /// The rects covered by `region`, one per visual line it spans.
+    pub fn region_bounds(&self, region: RegionId) -> Vec<Rect> {
+        let glyphs = self.glyphs.borrow();
+        let mut rects: Vec<Rect> = Vec::new();
+        for ((offset, _), glyph) in self.text.char_indices().zip(glyphs.iter()) {
+            let in_region = self
+                .attrs
+                .regions
+                .iter()
+                .any(|r| r.payload == region && r.range.contains(&offset));
+            if !in_region {
+                continue;
+            }
+            let rect = Rect::new(
+                glyph.rect.x0,
+                glyph.rect.y0,
+                glyph.rect.x0 + glyph.width,
+                glyph.rect.y1,
+            );
+            match rects.last_mut() {
+                Some(last) if last.y0 == rect.y0 && last.x1 == rect.x0 => {
+                    *last = last.union(rect);
+                }
+                _ => rects.push(rect),
+            }
+        }
+        rects
+    }
+
+    /// Whether text was cut off by [`WgpuTextLayoutBuilder::max_lines`] or
+    /// [`WgpuTextLayoutBuilder::max_height`].
+    pub fn is_truncated(&self) -> bool {
+        *self.truncated.borrow()
+    }
+
+    /// The boxes added with [`WgpuTextLayoutBuilder::inline_box`] where
+    /// they ended up in the layout, for the caller to draw into. Boxes
+    /// elided with their text are left out.
+    pub fn inline_boxes(&self) -> Vec<(InlineBoxId, Rect)> {
+        self.inline_box_rects.borrow().clone()
+    }
+
+    fn is_rtl(&self, index: usize) -> bool {
+        self.rtl.borrow().get(index).copied().unwrap_or(false)
+    }
+
+    /// The x of the caret before the glyph at `index`, or after it when
+    /// `after` is set, taking its direction into account.
+    fn caret_x(&self, glyphs: &[GlyphPosInfo], index: usize, after: bool) -> f64 {
+        let glyph = &glyphs[index];
+        if after != self.is_rtl(index) {
+            glyph.rect.x0 + glyph.width
+        } else {
+            glyph.rect.x0
+        }
+    }
+
+    /// The char range of the grapheme cluster containing the char at
+    /// `idx`, or `(idx, idx)` past the end of the text.
+    fn cluster_at(&self, idx: usize) -> (usize, usize) {
+        let mut start = 0;
+        for grapheme in self.text.graphemes(true) {
+            let end = start + grapheme.chars().count();
+            if idx < end {
+                return (start, end);
+            }
+            start = end;
+        }
+        (idx, idx)
+    }
+
+    /// The index of the char, and so of the glyph, at byte `offset`.
+    fn char_index(&self, offset: usize) -> usize {
+        self.text[..floor_char_boundary(&self.text, offset)]
+            .chars()
+            .count()
+    }
+
+    /// The byte offset of the char at `index`, or the length of the text
+    /// for one past its end.
+    fn byte_offset(&self, index: usize) -> usize {
+        self.text
+            .char_indices()
+            .nth(index)
+            .map_or(self.text.len(), |(offset, _)| offset)
+    }
+
+    /// The width of the widest line, with or without the whitespace at the
+    /// end of lines.
+    fn line_width(&self, trailing_whitespace: bool) -> f64 {
+        let glyphs = self.glyphs.borrow();
+        let width = self
+            .text
+            .chars()
+            .zip(glyphs.iter())
+            .filter(|(c, _)| trailing_whitespace || !c.is_whitespace())
+            .map(|(_, g)| g.rect.x0 + g.width)
+            .fold(0.0, f64::max);
+        match self.ellipsis.borrow().as_ref() {
+            Some(ellipsis) => width.max(ellipsis.rect.x0 + ellipsis.width),
+            None => width,
+        }
+    }
+
+    /// The offset of the baseline of `line` from the top of the layout.
+    pub fn line_baseline(&self, line: usize) -> Option<f64> {
+        let metric = self.line_metric(line)?;
+        Some(metric.y_offset + metric.baseline)
+    }
+
+    /// The x of the caret in front of the char at byte `offset` on `line`,
+    /// taken from the laid out glyphs, so that it matches what is drawn
+    /// with tabular digits, inline boxes and alignment, unlike a sum of
+    /// char widths. Offsets outside of the line clamp to its ends.
+    pub fn x_for_offset(&self, line: usize, offset: usize) -> Option<f64> {
+        let (start, end) = self.line_caret_range(line)?;
+        let glyphs = self.glyphs.borrow();
+        if start >= end {
+            // An empty line, the caret goes where its line break is.
+            return Some(match glyphs.get(start) {
+                Some(_) => self.caret_x(&glyphs, start, false),
+                None => 0.0,
+            });
+        }
+        let (index, _) = self.cluster_at(self.char_index(offset).clamp(start, end));
+        let x = if index >= end {
+            self.caret_x(&glyphs, end - 1, true)
+        } else {
+            self.caret_x(&glyphs, index.max(start), false)
+        };
+        Some(x)
+    }
+
+    /// The byte offset of the caret position on `line` closest to `x`,
+    /// between grapheme clusters, the inverse of
+    /// [`WgpuTextLayout::x_for_offset`].
+    pub fn offset_for_x(&self, line: usize, x: f64) -> Option<usize> {
+        let (start, end) = self.line_caret_range(line)?;
+        let y = self.lines.borrow()[line].y;
+        let (index, _) = self.hit_index(Point::new(x, y));
+        Some(self.byte_offset(index.clamp(start, end)))
+    }
+
+    /// Like [`TextLayout::hit_test_text_position`], but at a soft line
+    /// break the caret goes to the end of the earlier line when `affinity`
+    /// is [`Affinity::Upstream`], rather than always the start of the later
+    /// one. The point is on the baseline of the line the caret is on, and
+    /// `idx` a byte offset into the text, like everywhere in piet.
+    pub fn hit_test_text_position_with_affinity(
+        &self,
+        idx: usize,
+        affinity: Affinity,
+    ) -> HitTestPosition {
+        let glyphs = self.glyphs.borrow();
+        if glyphs.is_empty() {
+            return HitTestPosition::default();
+        }
+
+        let (idx, _) = self.cluster_at(self.char_index(idx));
+        let lines = self.lines.borrow();
+        let mut line = lines.iter().rposition(|l| l.start <= idx).unwrap_or(0);
+        let x = if affinity == Affinity::Upstream && self.is_soft_break(line, idx) {
+            line -= 1;
+            self.caret_x(&glyphs, idx - 1, true)
+        } else if idx >= glyphs.len() && lines[line].start >= glyphs.len() {
+            // The empty line after a trailing newline.
+            0.0
+        } else if idx >= glyphs.len() {
+            self.caret_x(&glyphs, glyphs.len() - 1, true)
+        } else {
+            self.caret_x(&glyphs, idx, false)
+        };
+        drop(lines);
+        drop(glyphs);
+
+        let mut pos = HitTestPosition::default();
+        let y = self.line_baseline(line).unwrap_or(0.0);
+        pos.point = Point::new(x, y);
+        pos.line = line;
+        pos
+    }
+
+    /// Like [`TextLayout::hit_test_point`], together with the affinity the
+    /// caret should be drawn with: [`Affinity::Upstream`] for a point past
+    /// the end of a line that wraps, whose index is also the start of the
+    /// next line.
+    pub fn hit_test_point_with_affinity(&self, point: Point) -> (HitTestPoint, Affinity) {
+        let hit = self.hit_test_point(point);
+        let lines = self.lines.borrow();
+        let line = lines.iter().rposition(|l| l.y <= point.y).unwrap_or(0);
+        let affinity = if self.is_soft_break(line + 1, self.char_index(hit.idx)) {
+            Affinity::Upstream
+        } else {
+            Affinity::Downstream
+        };
+        (hit, affinity)
+    }
+
+    /// Whether the glyph index `idx` is the start of `line`, which is
+    /// wrapped onto a line of its own rather than after a newline.
+    fn is_soft_break(&self, line: usize, idx: usize) -> bool {
+        let lines = self.lines.borrow();
+        match lines.get(line) {
+            Some(l) if line > 0 && l.start == idx && idx > lines[line - 1].start => {
+                self.text.chars().nth(idx - 1) != Some('\n')
+            }
+            _ => false,
+        }
+    }
+
+    /// The glyph index of the caret position closest to `point`, and
+    /// whether the point is on a glyph.
+    fn hit_index(&self, point: Point) -> (usize, bool) {
+        if self.glyphs.borrow().len() == 0 {
+            return (0, false);
+        }
+
+        let glyphs = self.glyphs.borrow();
+        let line = self
+            .lines
+            .borrow()
+            .iter()
+            .rposition(|l| l.y <= point.y)
+            .unwrap_or(0);
+        // The caret can't go past the newline ending the line.
+        let (start, end) = self.line_caret_range(line).unwrap_or((0, 0));
+        if start >= end {
+            return (start.min(glyphs.len()), false);
+        }
+
+        let mut index = None;
+        for (i, glyph) in glyphs.iter().enumerate().take(end).skip(start) {
+            if glyph.width > 0.0
+                && point.x >= glyph.rect.x0
+                && point.x < glyph.rect.x0 + glyph.width
+            {
+                let left_half = point.x < glyph.rect.x0 + glyph.width / 2.0;
+                index = Some(if left_half != self.is_rtl(i) {
+                    i
+                } else {
+                    i + 1
+                });
+                break;
+            }
+        }
+        let closer = |a: usize, b: usize| {
+            let line_end = self.caret_x(&glyphs, end - 1, true);
+            let x = |i: usize| {
+                if i == end {
+                    line_end
+                } else {
+                    self.caret_x(&glyphs, i, false)
+                }
+            };
+            if (point.x - x(a)).abs() <= (point.x - x(b)).abs() {
+                a
+            } else {
+                b
+            }
+        };
+        // Carets go between grapheme clusters only, so a point inside a
+        // cluster snaps to whichever of its edges is closer.
+        let mut idx = index.unwrap_or_else(|| closer(start, end));
+        let (cluster_start, cluster_end) = self.cluster_at(idx);
+        if idx != cluster_start {
+            idx = closer(cluster_start, cluster_end.min(end));
+        }
+        (idx, index.is_some())
+    }
+
+    /// The glyph indices the caret can be placed at on `line`: from its
+    /// start up to its end, or up to its line break.
+    fn line_caret_range(&self, line: usize) -> Option<(usize, usize)> {
+        let lines = self.lines.borrow();
+        let glyphs = self.glyphs.borrow();
+        let start = lines.get(line)?.start.min(glyphs.len());
+        let mut end = lines
+            .get(line + 1)
+            .map_or(glyphs.len(), |next| next.start)
+            .max(start);
+        if end > start && self.text.chars().nth(end - 1) == Some('\n') {
+            end -= 1;
+        }
+        Some((start, end))
+    }
+
+    /// The font, size, weight and color of the char at byte `index`.
+    #[cfg(feature = "svg-export")]
+    pub(crate) fn style_at(&self, index: usize) -> (FontFamily, f64, FontWeight, Color) {
+        (
+            self.attrs.font(index),
+            self.attrs.size(index),
+            self.attrs.font_weight(index),
+            self.attrs.color(index).clone(),
+        )
+    }
+
+    /// The outline of every visible glyph, positioned in the layout, e.g.
+    /// for text effects or to export the text as paths. Whitespace,
+    /// glyphs elided by the overflow and bitmap glyphs have none.
+    pub fn glyph_outlines(&self) -> Vec<GlyphOutline> {
+        let cache = self.state.cache.borrow();
+        let fonts = cache.fonts();
+        let outline = |glyph: &GlyphPosInfo| {
+            let mut path = fonts.glyph_outline(&glyph.info, cache.scale)?;
+            let origin = glyph.rect.origin() + Vec2::new(0.0, glyph.metric.ascent);
+            path.apply_affine(Affine::translate(origin.to_vec2()));
+            Some(path)
+        };
+
+        let glyphs = self.glyphs.borrow();
+        let mut outlines: Vec<GlyphOutline> = self
+            .text
+            .char_indices()
+            .zip(glyphs.iter())
+            .filter(|((_, c), glyph)| !c.is_whitespace() && glyph.rect.width() > 0.0)
+            .filter_map(|((index, _), glyph)| {
+                Some(GlyphOutline {
+                    index,
+                    path: outline(glyph)?,
+                })
+            })
+            .collect();
+        if let Some(ellipsis) = self.ellipsis.borrow().as_ref() {
+            if let Some(path) = outline(ellipsis) {
+                outlines.push(GlyphOutline {
+                    index: self.text.len(),
+                    path,
+                });
+            }
+        }
+        outlines
+    }
+
+    pub fn cursor_line_for_text_position(&self, text_pos: usize) -> Line {
+        let pos = self.hit_test_text_position(text_pos);
+        let line_metric = self.line_metric(0).unwrap();
+        let p0 = (pos.point.x, line_metric.y_offset);
+        let p1 = (pos.point.x, line_metric.y_offset + line_metric.height);
+        Line::new(p0, p1)
+    }
+}
+
+/// The largest char boundary of `text` no greater than `offset`.
+fn floor_char_boundary(text: &str, offset: usize) -> usize {
+    let mut offset = offset.min(text.len());
+    while !text.is_char_boundary(offset) {
+        offset -= 1;
+    }
+    offset
+}
+
+fn font_file_error(path: &Path, e: io::Error) -> piet::Error {
+    piet::Error::BackendError(Box::new(io::Error::new(
+        e.kind(),
+        format!("{}: {}", path.display(), e),
+    )))
+}
+
+fn is_font_file(path: &Path) -> bool {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_lowercase());
+    matches!(
+        extension.as_deref(),
+        Some("ttf") | Some("otf") | Some("ttc") | Some("otc")
+    )
+}
+
+/// The outline of a glyph, from [`WgpuTextLayout::glyph_outlines`].
+#[derive(Clone, Debug)]
+pub struct GlyphOutline {
+    /// The byte offset of the char of the glyph in the text, or the length
+    /// of the text for the ellipsis.
+    pub index: usize,
+    /// The outline in the coordinates of the layout.
+    pub path: BezPath,
+}
+
+/// Append the two triangles of a quad at `rect` drawing `glyph_pos` from
+/// the glyph atlas.
+pub(crate) fn add_glyph_quad(
+    geometry: &mut VertexBuffers<GpuVertex, u32>,
+    rect: &Rect,
+    glyph_pos: &GlyphPosInfo,
+    color: [f32; 4],
+    translate: [f32; 2],
+    primitive_id: u32,
+) {
+    let cache_rect = &glyph_pos.cache_rect;
+    let tex = glyph_pos.tex();
+    let corners = [
+        (rect.x0, rect.y0, cache_rect.x0, cache_rect.y0),
+        (rect.x0, rect.y1, cache_rect.x0, cache_rect.y1),
+        (rect.x1, rect.y1, cache_rect.x1, cache_rect.y1),
+        (rect.x1, rect.y0, cache_rect.x1, cache_rect.y0),
+    ];
+    let offset = geometry.vertices.len() as u32;
+    geometry
+        .vertices
+        .extend(corners.iter().map(|(x, y, tex_x, tex_y)| GpuVertex {
+            pos: [*x as f32, *y as f32],
+            translate,
+            tex,
+            tex_pos: [*tex_x as f32, *tex_y as f32],
+            color,
+            primitive_id,
+        }));
+    geometry.indices.extend_from_slice(&[
+        offset,
+        offset + 1,
+        offset + 2,
+        offset,
+        offset + 2,
+        offset + 3,
+    ]);
+}
+
+/// Whether `c` is expected to leave a mark, so that a missing glyph for it
+/// is worth a box. Whitespace, control and zero width chars aren't.
+pub(crate) fn is_visible_char(c: char) -> bool {
+    !c.is_whitespace() && UnicodeWidthChar::width(c).is_some_and(|width| width > 0)
+}
+
+/// The sides of the box drawn for a missing glyph with the ink bounds
+/// `rect`.
+pub(crate) fn missing_glyph_box(rect: Rect) -> [Rect; 4] {
+    let t = (rect.height() / 12.0).round().max(1.0);
+    [
+        Rect::new(rect.x0, rect.y0, rect.x1, rect.y0 + t),
+        Rect::new(rect.x0, rect.y1 - t, rect.x1, rect.y1),
+        Rect::new(rect.x0, rect.y0 + t, rect.x0 + t, rect.y1 - t),
+        Rect::new(rect.x1 - t, rect.y0 + t, rect.x1, rect.y1 - t),
+    ]
+}
+
+/// A solid quad in `color`, drawn without the glyph atlas.
+fn add_block_quad(geometry: &mut VertexBuffers<GpuVertex, u32>, rect: &Rect, color: [f32; 4]) {
+    let corners = [
+        (rect.x0, rect.y0),
+        (rect.x0, rect.y1),
+        (rect.x1, rect.y1),
+        (rect.x1, rect.y0),
+    ];
+    let offset = geometry.vertices.len() as u32;
+    geometry
+        .vertices
+        .extend(corners.iter().map(|(x, y)| GpuVertex {
+            pos: [*x as f32, *y as f32],
+            color,
+            ..Default::default()
+        }));
+    geometry.indices.extend_from_slice(&[
+        offset,
+        offset + 1,
+        offset + 2,
+        offset,
+        offset + 2,
+        offset + 3,
+    ]);
+}
+
+/// Where a layout may break lines that are wider than its max width.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum WrapMode {
+    /// Break at spaces and around wide (CJK) chars, falling back to
+    /// `Anywhere` for words longer than a line.
+    #[default]
+    WordBoundary,
+    /// Break between any two chars.
+    Anywhere,
+    /// Keep everything on one line.
+    NoWrap,
+}
+
+/// The start of a visual line of a layout.
+#[derive(Clone, Copy, Debug)]
+struct LineInfo {
+    /// The index of the first glyph on the line.
+    start: usize,
+    y: f64,
+}
+
+/// The base direction of a paragraph.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum TextDirection {
+    /// Take the direction of the first strong char.
+    #[default]
+    Auto,
+    Ltr,
+    Rtl,
+}
+
+impl TextDirection {
+    fn is_rtl(self, text: &str) -> bool {
+        match self {
+            TextDirection::Ltr => false,
+            TextDirection::Rtl => true,
+            TextDirection::Auto => text.chars().find_map(strong_direction).unwrap_or(false),
+        }
+    }
+}
+
+/// `Some(true)` for chars of right-to-left scripts, `Some(false)` for other
+/// letters and digits and `None` for neutrals.
+fn strong_direction(c: char) -> Option<bool> {
+    let rtl = matches!(
+        c as u32,
+        0x0590..=0x08FF | 0xFB1D..=0xFDFF | 0xFE70..=0xFEFF | 0x10800..=0x10FFF | 0x1E800..=0x1EFFF
+    );
+    if rtl {
+        Some(true)
+    } else if c.is_alphanumeric() {
+        Some(false)
+    } else {
+        None
+    }
+}
 
-            if (x + width) as f64 > self.width {
-                x = 0.0;
-                y += max_height;
+/// Whether each char of `text` runs right-to-left. Neutrals between two
+/// LTR chars are LTR, other neutrals follow the paragraph.
+fn resolve_directions(text: &str, base_rtl: bool) -> Vec<bool> {
+    let strong: Vec<Option<bool>> = text.chars().map(strong_direction).collect();
+    let mut resolved = Vec::with_capacity(strong.len());
+    let mut prev = base_rtl;
+    for (i, dir) in strong.iter().enumerate() {
+        match dir {
+            Some(rtl) => {
+                prev = *rtl;
+                resolved.push(*rtl);
             }
+            None => {
+                let next = strong[i..].iter().find_map(|d| *d).unwrap_or(base_rtl);
+                resolved.push(if !prev && !next { false } else { base_rtl });
+            }
+        }
+    }
+    resolved
+}
 
-            glyph_pos.rect = glyph_pos.rect.with_origin((x as f64, y as f64));
+/// How a layout handles text that doesn't fit in its `max_lines` or
+/// `max_height`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum TextOverflow {
+    /// Drop the text which doesn't fit.
+    #[default]
+    Clip,
+    /// Replace the text which doesn't fit with an ellipsis.
+    Ellipsis(EllipsisPosition),
+}
 
-            let new_x = x + width;
+/// Where an ellipsis goes. `Start` and `Middle` apply to single line
+/// layouts, longer layouts always elide the end of their last line.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum EllipsisPosition {
+    Start,
+    Middle,
+    End,
+}
 
-            let height = glyph_pos.rect.height() as f32;
-            if height > max_height {
-                max_height = height;
-            }
+/// Which line a caret at a soft line break is on: the end of the line
+/// before the break or the start of the line after it. Everywhere else
+/// both are the same place.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Affinity {
+    /// With the text before the caret, at the end of the earlier line,
+    /// e.g. after pressing End or clicking past the end of a line.
+    Upstream,
+    /// With the text after the caret, at the start of the later line.
+    #[default]
+    Downstream,
+}
 
-            if let Some(bounds) = bounds.as_ref() {
-                if x > bounds[1] as f32 {
-                    return;
-                }
-                if new_x < bounds[0] as f32 {
-                    x = new_x;
-                    glyphs.push(glyph_pos);
-                    continue;
-                }
-            }
+/// The x of `glyphs[index]`, or the end of the last glyph when `index` is
+/// past the end.
+fn glyph_x(glyphs: &[GlyphPosInfo], index: usize) -> f64 {
+    match glyphs.get(index) {
+        Some(glyph) => glyph.rect.x0,
+        None => glyphs.last().map(|g| g.rect.x0 + g.width).unwrap_or(0.0),
+    }
+}
 
-            if c == ' ' || c == '\n' || c == '\t' {
-                x = new_x;
-                glyphs.push(glyph_pos);
-                continue;
-            }
+/// The y of `glyphs[index]`, or of the last glyph when `index` is past
+/// the end.
+fn glyph_y(glyphs: &[GlyphPosInfo], index: usize) -> f64 {
+    glyphs
+        .get(index)
+        .or_else(|| glyphs.last())
+        .map(|g| g.rect.y0)
+        .unwrap_or(0.0)
+}
 
-            let rect = &glyph_pos.rect;
-            let cache_rect = &glyph_pos.cache_rect;
-            let mut vertices = vec![
-                GpuVertex {
-                    pos: [rect.x0 as f32, rect.y0 as f32],
-                    tex: 1.0,
-                    tex_pos: [cache_rect.x0 as f32, cache_rect.y0 as f32],
-                    color,
-                    ..Default::default()
-                },
-                GpuVertex {
-                    pos: [rect.x0 as f32, rect.y1 as f32],
-                    tex: 1.0,
-                    tex_pos: [cache_rect.x0 as f32, cache_rect.y1 as f32],
-                    color,
-                    ..Default::default()
-                },
-                GpuVertex {
-                    pos: [rect.x1 as f32, rect.y1 as f32],
-                    tex: 1.0,
-                    tex_pos: [cache_rect.x1 as f32, cache_rect.y1 as f32],
-                    color,
-                    ..Default::default()
-                },
-                GpuVertex {
-                    pos: [rect.x1 as f32, rect.y0 as f32],
-                    tex: 1.0,
-                    tex_pos: [cache_rect.x1 as f32, cache_rect.y0 as f32],
-                    color,
-                    ..Default::default()
-                },
-            ];
-            let offset = geometry.vertices.len() as u32;
-            let mut indices = vec![
-                offset + 0,
-                offset + 1,
-                offset + 2,
-                offset + 0,
-                offset + 2,
-                offset + 3,
-            ];
-
-            geometry.vertices.append(&mut vertices);
-            geometry.indices.append(&mut indices);
-
-            x = new_x;
-            glyphs.push(glyph_pos);
-        }
+/// Move the start and end of the advance of each of `glyphs` to whole
+/// physical pixels at `scale`. The end of a glyph stays the start of the
+/// glyph after it, and a layout drawn at a whole pixel has its glyphs
+/// exactly where carets between them go.
+fn snap_glyphs(glyphs: &mut [GlyphPosInfo], scale: f64) {
+    let snap = |x: f64| (x * scale).round() / scale;
+    for glyph in glyphs {
+        let x0 = snap(glyph.rect.x0);
+        let x1 = snap(glyph.rect.x0 + glyph.width);
+        glyph.rect = glyph.rect.with_origin((x0, glyph.rect.y0));
+        glyph.width = x1 - x0;
     }
+}
 
-    pub(crate) fn draw_text(&self, ctx: &mut WgpuRenderContext, translate: [f32; 2]) {
-        let geometry = self.geometry.borrow();
-        if geometry.vertices.len() == 0 {
-            return;
-        }
-
-        let offset = ctx.geometry.vertices.len() as u32;
-        let primivite_id = (ctx.primitives.len() - 1) as u32;
-        let mut vertices = geometry
-            .vertices
-            .iter()
-            .map(|v| {
-                let mut v = v.clone();
-                v.translate = translate;
-                v.primitive_id = primivite_id;
-                v
-            })
-            .collect();
-        let mut indices = geometry.indices.iter().map(|i| *i + offset).collect();
-        ctx.geometry.vertices.append(&mut vertices);
-        ctx.geometry.indices.append(&mut indices);
+fn shift_glyphs(glyphs: &mut [GlyphPosInfo], offset: Vec2) {
+    for glyph in glyphs {
+        glyph.rect = glyph.rect + offset;
     }
+}
 
-    pub fn cursor_line_for_text_position(&self, text_pos: usize) -> Line {
-        let pos = self.hit_test_text_position(text_pos);
-        let line_metric = self.line_metric(0).unwrap();
-        let p0 = (pos.point.x, line_metric.y_offset);
-        let p1 = (pos.point.x, line_metric.y_offset + line_metric.height);
-        Line::new(p0, p1)
+/// Collapse `glyphs` to zero width at `x`, so they are neither drawn nor
+/// take up space.
+fn elide_glyphs(glyphs: &mut [GlyphPosInfo], x: f64) {
+    for glyph in glyphs {
+        glyph.rect = Rect::new(x, glyph.rect.y0, x, glyph.rect.y1);
+        glyph.width = 0.0;
     }
 }
 
+/// Identifies a range of text tagged with
+/// [`WgpuTextLayoutBuilder::range_region`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RegionId(pub u64);
+
+/// Identifies a box reserved with [`WgpuTextLayoutBuilder::inline_box`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct InlineBoxId(pub u64);
+
+/// Space reserved in front of the char at byte offset `index`.
+struct InlineBox {
+    index: usize,
+    size: Size,
+    id: InlineBoxId,
+}
+
+/// A single character cell for [`WgpuRenderContext::draw_text_grid`].
+#[derive(Clone)]
+pub struct Cell {
+    pub row: usize,
+    pub col: usize,
+    pub ch: char,
+    pub color: Color,
+    pub weight: FontWeight,
+}
+
 pub struct WgpuTextLayoutBuilder {
     width: f64,
+    alignment: TextAlignment,
+    direction: TextDirection,
+    wrap_mode: WrapMode,
+    overflow: TextOverflow,
+    max_lines: Option<usize>,
+    max_height: Option<f64>,
+    block_glyphs_below: Option<f64>,
+    inline_boxes: Vec<InlineBox>,
     state: WgpuText,
     text: String,
     attrs: Attributes,
@@ -316,6 +2107,14 @@ impl WgpuTextLayoutBuilder {
     pub(crate) fn new(text: impl TextStorage, state: WgpuText) -> Self {
         Self {
             width: f64::MAX,
+            alignment: TextAlignment::Start,
+            direction: TextDirection::default(),
+            wrap_mode: WrapMode::default(),
+            overflow: TextOverflow::default(),
+            max_lines: None,
+            max_height: None,
+            block_glyphs_below: None,
+            inline_boxes: Vec::new(),
             text: text.as_str().to_string(),
             attrs: Default::default(),
             state,
@@ -326,25 +2125,244 @@ impl WgpuTextLayoutBuilder {
         self.attrs.add(range, attr);
     }
 
+    /// The base direction of the text, which `TextAlignment::Start` and
+    /// `TextAlignment::End` resolve against.
+    pub fn set_text_direction(mut self, direction: TextDirection) -> Self {
+        self.direction = direction;
+        self
+    }
+
+    /// Where lines wider than `max_width` may break.
+    pub fn wrap_mode(mut self, wrap_mode: WrapMode) -> Self {
+        self.wrap_mode = wrap_mode;
+        self
+    }
+
+    /// How to handle text beyond `max_lines` or `max_height`. Setting an
+    /// ellipsis also limits the layout to a single line unless either is
+    /// set.
+    pub fn overflow(mut self, overflow: TextOverflow) -> Self {
+        self.overflow = overflow;
+        self
+    }
+
+    /// Limit the layout to `max_lines` lines, truncating the rest according
+    /// to [`WgpuTextLayoutBuilder::overflow`].
+    pub fn max_lines(mut self, max_lines: usize) -> Self {
+        self.max_lines = Some(max_lines.max(1));
+        self
+    }
+
+    /// Limit the layout to the lines that fit in `max_height` logical
+    /// pixels, always keeping the first, and truncate the rest like
+    /// [`WgpuTextLayoutBuilder::max_lines`].
+    pub fn max_height(mut self, max_height: f64) -> Self {
+        self.max_height = Some(max_height);
+        self
+    }
+
+    /// Draw the text as solid blocks in its colors instead of glyphs when
+    /// its font size is below `font_size` logical pixels, for minimaps.
+    /// Glyphs aren't rasterized then, only their advances are looked up.
+    pub fn block_glyphs_below(mut self, font_size: f64) -> Self {
+        self.block_glyphs_below = Some(font_size);
+        self
+    }
+
+    /// Reserve a box of `size` in front of the char at byte offset `index`,
+    /// or at the end of the text, for inline widgets like inlay hints. The
+    /// box takes up space like a glyph: lines may break before it and grow
+    /// to its height. Its position is found with
+    /// [`WgpuTextLayout::inline_boxes`]; drawing into it is up to the caller.
+    pub fn inline_box(mut self, index: usize, size: Size, id: InlineBoxId) -> Self {
+        self.inline_boxes.push(InlineBox { index, size, id });
+        self
+    }
+
+    fn layout(mut self) -> WgpuTextLayout {
+        let mut text_layout = WgpuTextLayout::new(self.text, self.state);
+        text_layout.set_attrs(self.attrs);
+        text_layout.set_width(self.width);
+        text_layout.alignment = self.alignment;
+        text_layout.direction = self.direction;
+        text_layout.wrap_mode = self.wrap_mode;
+        text_layout.overflow = self.overflow;
+        text_layout.block_glyphs_below = self.block_glyphs_below;
+        self.inline_boxes.sort_by_key(|b| b.index);
+        text_layout.inline_boxes = Rc::new(self.inline_boxes);
+        text_layout.max_height = self.max_height;
+        text_layout.max_lines = match (self.max_lines, self.max_height, self.overflow) {
+            (None, None, TextOverflow::Ellipsis(_)) => Some(1),
+            (max_lines, _, _) => max_lines,
+        };
+        text_layout
+    }
+
+    /// Tag `range` with `region`, to be found again with
+    /// [`WgpuTextLayout::region_at_point`] and
+    /// [`WgpuTextLayout::region_bounds`], e.g. for links.
+    pub fn range_region(
+        mut self,
+        range: impl std::ops::RangeBounds<usize>,
+        region: RegionId,
+    ) -> Self {
+        let range = piet::util::resolve_range(range, self.text.len());
+        self.attrs.regions.push(Span::new(region, range));
+        self
+    }
+
+    /// Set OpenType features for the whole layout, as `(tag, value)`
+    /// pairs such as `("liga", 0)` or `("tnum", 1)`.
+    ///
+    /// Layouts map each char to a single glyph without shaping, so only
+    /// `tnum` changes the result; ligatures are never formed, which makes
+    /// `liga` and `calt` set to `0` hold already.
+    pub fn set_font_features(mut self, features: &[(&str, u32)]) -> Self {
+        self.attrs.default_features = parse_features(features);
+        self
+    }
+
+    /// Override OpenType features for `range`, see
+    /// [`WgpuTextLayoutBuilder::set_font_features`].
+    pub fn range_font_features(
+        mut self,
+        range: impl std::ops::RangeBounds<usize>,
+        features: &[(&str, u32)],
+    ) -> Self {
+        let range = piet::util::resolve_range(range, self.text.len());
+        self.attrs
+            .features
+            .push(Span::new(parse_features(features), range));
+        self
+    }
+
+    /// Fill the lines behind `range` with `color`, e.g. to highlight
+    /// search matches. Where ranges overlap, the one added last wins.
+    pub fn range_background_color(
+        mut self,
+        range: impl std::ops::RangeBounds<usize>,
+        color: Color,
+    ) -> Self {
+        let range = piet::util::resolve_range(range, self.text.len());
+        self.attrs.background.push(Span::new(color, range));
+        self
+    }
+
+    /// Draw underlines in `color` instead of the text color.
+    pub fn underline_color(mut self, color: Color) -> Self {
+        self.attrs.default_underline_color = Some(color);
+        self
+    }
+
+    /// Draw the underlines of `range` in `color`.
+    pub fn range_underline_color(
+        mut self,
+        range: impl std::ops::RangeBounds<usize>,
+        color: Color,
+    ) -> Self {
+        let range = piet::util::resolve_range(range, self.text.len());
+        self.attrs.underline_color.push(Span::new(color, range));
+        self
+    }
+
+    /// Draw strikethroughs in `color` instead of the text color.
+    pub fn strikethrough_color(mut self, color: Color) -> Self {
+        self.attrs.default_strikethrough_color = Some(color);
+        self
+    }
+
+    /// Draw the strikethroughs of `range` in `color`.
+    pub fn range_strikethrough_color(
+        mut self,
+        range: impl std::ops::RangeBounds<usize>,
+        color: Color,
+    ) -> Self {
+        let range = piet::util::resolve_range(range, self.text.len());
+        self.attrs.strikethrough_color.push(Span::new(color, range));
+        self
+    }
+
+    /// Outline the glyphs with `color`, `width` logical pixels wide around
+    /// their edges, e.g. to keep text readable over images. The outline is
+    /// drawn behind the glyphs of the whole layout.
+    pub fn outline(mut self, color: Color, width: f64) -> Self {
+        self.attrs.default_outline = Some((color, width));
+        self
+    }
+
+    /// Outline the glyphs of `range`, or with `None` leave them without
+    /// one, see [`WgpuTextLayoutBuilder::outline`].
+    pub fn range_outline(
+        mut self,
+        range: impl std::ops::RangeBounds<usize>,
+        outline: Option<(Color, f64)>,
+    ) -> Self {
+        let range = piet::util::resolve_range(range, self.text.len());
+        self.attrs.outline.push(Span::new(outline, range));
+        self
+    }
+
+    /// Pick faces of `stretch` from the font families, e.g. a condensed
+    /// face for dense tables. Families without such a face fall back to
+    /// their closest one.
+    pub fn set_font_stretch(mut self, stretch: FontStretch) -> Self {
+        self.attrs.default_stretch = stretch;
+        self
+    }
+
+    /// Override the stretch for `range`, see
+    /// [`WgpuTextLayoutBuilder::set_font_stretch`].
+    pub fn range_font_stretch(
+        mut self,
+        range: impl std::ops::RangeBounds<usize>,
+        stretch: FontStretch,
+    ) -> Self {
+        let range = piet::util::resolve_range(range, self.text.len());
+        self.attrs.stretch.push(Span::new(stretch, range));
+        self
+    }
+
+    /// Set the language of the text as a BCP 47 locale such as `ja-JP` or
+    /// `zh-Hant`. Han ideographs and other CJK chars take the glyph forms
+    /// of the language, using a font installed for it when the layout's
+    /// font is generic or lacks the char.
+    pub fn set_locale(mut self, locale: &str) -> Self {
+        self.attrs.default_han_region = HanRegion::from_locale(locale);
+        self
+    }
+
+    /// Override the locale for `range`, see
+    /// [`WgpuTextLayoutBuilder::set_locale`].
+    pub fn range_locale(mut self, range: impl std::ops::RangeBounds<usize>, locale: &str) -> Self {
+        let range = piet::util::resolve_range(range, self.text.len());
+        self.attrs
+            .han_regions
+            .push(Span::new(HanRegion::from_locale(locale), range));
+        self
+    }
+
     pub fn build_with_info(
         self,
         is_mono: bool,
         tab_width: usize,
         bounds: Option<[f64; 2]>,
     ) -> WgpuTextLayout {
-        let state = self.state.clone();
-        let mut text_layout = WgpuTextLayout::new(self.text, state);
-        text_layout.set_attrs(self.attrs);
-        text_layout.set_width(self.width);
+        let text_layout = self.layout();
         text_layout.rebuild(is_mono, tab_width, bounds);
         text_layout
     }
 
+    /// Build the layout on a single line, ignoring the max width, to
+    /// measure its natural width before [`WgpuTextLayout::rebreak`]ing it.
+    pub fn build_unbroken(mut self) -> WgpuTextLayout {
+        self.width = f64::MAX;
+        let text_layout = self.layout();
+        text_layout.rebuild(false, 8, None);
+        text_layout
+    }
+
     pub fn build_with_bounds(self, bounds: [f64; 2]) -> WgpuTextLayout {
-        let state = self.state.clone();
-        let mut text_layout = WgpuTextLayout::new(self.text, state);
-        text_layout.set_attrs(self.attrs);
-        text_layout.set_width(self.width);
+        let text_layout = self.layout();
         text_layout.rebuild(false, 8, Some(bounds));
         text_layout
     }
@@ -355,11 +2373,20 @@ impl Text for WgpuText {
     type TextLayout = WgpuTextLayout;
 
     fn font_family(&mut self, family_name: &str) -> Option<FontFamily> {
-        todo!()
+        let registered = self
+            .cache
+            .borrow()
+            .fonts()
+            .has_registered_family(family_name);
+        if registered || self.source.has_family(family_name) {
+            Some(FontFamily::new_unchecked(family_name))
+        } else {
+            None
+        }
     }
 
     fn load_font(&mut self, data: &[u8]) -> Result<piet::FontFamily, piet::Error> {
-        todo!()
+        self.register_font(data.to_vec())
     }
 
     fn new_text_layout(&mut self, text: impl piet::TextStorage) -> Self::TextLayoutBuilder {
@@ -376,7 +2403,8 @@ impl TextLayoutBuilder for WgpuTextLayoutBuilder {
         self
     }
 
-    fn alignment(self, alignment: piet::TextAlignment) -> Self {
+    fn alignment(mut self, alignment: piet::TextAlignment) -> Self {
+        self.alignment = alignment;
         self
     }
 
@@ -398,10 +2426,7 @@ impl TextLayoutBuilder for WgpuTextLayoutBuilder {
     }
 
     fn build(self) -> Result<Self::Out, piet::Error> {
-        let state = self.state.clone();
-        let mut text_layout = WgpuTextLayout::new(self.text, state);
-        text_layout.set_attrs(self.attrs);
-        text_layout.set_width(self.width);
+        let text_layout = self.layout();
         text_layout.rebuild(false, 8, None);
         Ok(text_layout)
     }
@@ -415,19 +2440,33 @@ impl TextLayout for WgpuTextLayout {
         } else {
             let glyphs = self.glyphs.borrow();
 
-            let last_glyph = &glyphs[glyphs.len() - 1];
-            let width = last_glyph.rect.x0 + last_glyph.width;
-            let height = last_glyph.rect.y1;
-            Size::new(width as f64, height as f64)
+            let width = self.line_width(false);
+            let height = glyphs
+                .iter()
+                .map(|g| g.rect.y1)
+                .chain(self.inline_box_rects.borrow().iter().map(|(_, r)| r.y1))
+                .fold(0.0, f64::max);
+            Size::new(width, height)
         }
     }
 
     fn trailing_whitespace_width(&self) -> f64 {
-        0.0
+        self.line_width(true)
     }
 
     fn image_bounds(&self) -> piet::kurbo::Rect {
-        Size::ZERO.to_rect()
+        let glyphs = self.glyphs.borrow();
+        let ellipsis = self.ellipsis.borrow();
+        self.text
+            .chars()
+            .zip(glyphs.iter())
+            .filter(|(c, g)| !c.is_whitespace() && g.rect.width() > 0.0)
+            .map(|(_, g)| g)
+            .chain(ellipsis.as_ref())
+            .map(|g| g.metric.ink + g.rect.origin().to_vec2())
+            .filter(|ink| ink.area() > 0.0)
+            .reduce(|a, b| a.union(b))
+            .unwrap_or(Rect::ZERO)
     }
 
     fn text(&self) -> &str {
@@ -435,67 +2474,66 @@ impl TextLayout for WgpuTextLayout {
     }
 
     fn line_text(&self, line_number: usize) -> Option<&str> {
-        Some(&self.text)
+        let metric = self.line_metric(line_number)?;
+        Some(&self.text[metric.start_offset..metric.end_offset])
     }
 
     fn line_metric(&self, line_number: usize) -> Option<LineMetric> {
-        let mut metric = LineMetric {
-            start_offset: 0,
-            end_offset: self.text.len(),
-            trailing_whitespace: 0,
-            baseline: 0.0,
-            height: 0.0,
-            y_offset: 0.0,
+        let lines = self.lines.borrow();
+        let glyphs = self.glyphs.borrow();
+        let line = lines.get(line_number)?;
+        let next = lines.get(line_number + 1);
+        let end = next.map(|l| l.start).unwrap_or(glyphs.len());
+        let line_glyphs = &glyphs[line.start.min(end)..end];
+
+        // Text past the last laid out glyph belongs to the last line.
+        let start_offset = self.byte_offset(line.start);
+        let end_offset = match next {
+            Some(next) => self.byte_offset(next.start),
+            None => self.text.len(),
+        };
+        let text = &self.text[start_offset..end_offset];
+
+        // Glyphs sit at the top of their line, so the baseline is that of
+        // the tallest font on it.
+        let ref_glyph = self.ref_glyph.borrow();
+        let baseline = line_glyphs
+            .iter()
+            .map(|g| g.metric.ascent)
+            .reduce(f64::max)
+            .unwrap_or(ref_glyph.metric.ascent);
+        let height = match next {
+            Some(next) => next.y - line.y,
+            None => line_glyphs
+                .iter()
+                .map(|g| g.rect.y1 - line.y)
+                .reduce(f64::max)
+                .unwrap_or_else(|| ref_glyph.rect.height()),
         };
-        let glyph = &self.ref_glyph.borrow();
-        metric.baseline = glyph.metric.ascent;
-        metric.height = glyph.metric.ascent - glyph.metric.descent + glyph.metric.line_gap;
-        Some(metric)
+        Some(LineMetric {
+            start_offset,
+            end_offset,
+            trailing_whitespace: text.len() - text.trim_end().len(),
+            baseline,
+            height,
+            y_offset: line.y,
+        })
     }
 
     fn line_count(&self) -> usize {
-        0
+        self.lines.borrow().len()
     }
 
     fn hit_test_point(&self, point: Point) -> HitTestPoint {
+        let (idx, is_inside) = self.hit_index(point);
         let mut hit = HitTestPoint::default();
-        if self.glyphs.borrow().len() == 0 {
-            return hit;
-        }
-
-        let glyphs = self.glyphs.borrow();
-        let mut index = None;
-        for (i, glyph) in glyphs.iter().enumerate() {
-            if point.x < glyph.rect.x0 + glyph.rect.width() / 2.0 {
-                index = Some(i);
-                break;
-            }
-            if point.x < glyph.rect.x1 {
-                index = Some(i + 1);
-                break;
-            }
-        }
-        hit.idx = index.unwrap_or(glyphs.len());
-        hit.is_inside = index.is_some();
+        hit.idx = self.byte_offset(idx);
+        hit.is_inside = is_inside;
         hit
     }
 
     fn hit_test_text_position(&self, idx: usize) -> HitTestPosition {
-        if self.glyphs.borrow().len() == 0 {
-            return HitTestPosition::default();
-        }
-
-        let glyphs = self.glyphs.borrow();
-
-        let cur_glyph = &glyphs[idx.min(glyphs.len() - 1)];
-        let mut x = cur_glyph.rect.x0;
-        if idx >= glyphs.len() {
-            x = cur_glyph.rect.x1;
-        }
-
-        let mut pos = HitTestPosition::default();
-        pos.point = Point::new(x as f64, 0.0);
-        pos
+        self.hit_test_text_position_with_affinity(idx, Affinity::Downstream)
     }
 }
 
@@ -506,7 +2544,38 @@ struct Attributes {
     font: Vec<Span<FontFamily>>,
     size: Vec<Span<f64>>,
     weight: Vec<Span<FontWeight>>,
-    style: Option<Span<FontStyle>>,
+    style: Vec<Span<FontStyle>>,
+    default_stretch: FontStretch,
+    stretch: Vec<Span<FontStretch>>,
+    default_features: Vec<FontFeature>,
+    features: Vec<Span<Vec<FontFeature>>>,
+    regions: Vec<Span<RegionId>>,
+    background: Vec<Span<Color>>,
+    underline: Vec<Span<bool>>,
+    strikethrough: Vec<Span<bool>>,
+    default_underline_color: Option<Color>,
+    underline_color: Vec<Span<Color>>,
+    default_strikethrough_color: Option<Color>,
+    strikethrough_color: Vec<Span<Color>>,
+    /// The color and width of the outline around glyphs.
+    default_outline: Option<(Color, f64)>,
+    outline: Vec<Span<Option<(Color, f64)>>>,
+    /// The glyph forms for CJK chars, from the locale.
+    default_han_region: Option<HanRegion>,
+    han_regions: Vec<Span<Option<HanRegion>>>,
+}
+
+/// An OpenType feature tag and its value.
+type FontFeature = ([u8; 4], u32);
+
+fn parse_features(features: &[(&str, u32)]) -> Vec<FontFeature> {
+    features
+        .iter()
+        .filter_map(|(tag, value)| {
+            let tag: [u8; 4] = tag.as_bytes().try_into().ok()?;
+            Some((tag, *value))
+        })
+        .collect()
 }
 
 /// during construction, `Span`s represent font attributes that have been applied
@@ -527,11 +2596,59 @@ impl<T> Span<T> {
     }
 }
 
+/// The payload of the last span in `spans` that covers `index`.
+fn last_span<T>(spans: &[Span<T>], index: usize) -> Option<&T> {
+    spans
+        .iter()
+        .rev()
+        .find(|r| r.range.contains(&index))
+        .map(|r| &r.payload)
+}
+
 impl Attributes {
+    /// A hash of everything shaping depends on. Colors are left out, they
+    /// are applied after shaping.
+    fn shape_hash(&self, is_mono: bool, tab_width: usize, blocks: bool) -> u64 {
+        fn spans<T>(
+            spans: &[Span<T>],
+            hasher: &mut DefaultHasher,
+            f: impl Fn(&T, &mut DefaultHasher),
+        ) {
+            spans.len().hash(hasher);
+            for span in spans {
+                f(&span.payload, hasher);
+                span.range.hash(hasher);
+            }
+        }
+
+        let mut hasher = DefaultHasher::new();
+        is_mono.hash(&mut hasher);
+        tab_width.hash(&mut hasher);
+        blocks.hash(&mut hasher);
+        self.defaults.font.hash(&mut hasher);
+        self.defaults.font_size.to_bits().hash(&mut hasher);
+        self.defaults.weight.hash(&mut hasher);
+        self.defaults.style.hash(&mut hasher);
+        self.default_stretch.hash(&mut hasher);
+        self.default_features.hash(&mut hasher);
+        spans(&self.font, &mut hasher, |font, h| font.hash(h));
+        spans(&self.size, &mut hasher, |size, h| size.to_bits().hash(h));
+        spans(&self.weight, &mut hasher, |weight, h| weight.hash(h));
+        spans(&self.style, &mut hasher, |style, h| style.hash(h));
+        spans(&self.stretch, &mut hasher, |stretch, h| stretch.hash(h));
+        spans(&self.features, &mut hasher, |features, h| features.hash(h));
+        self.default_han_region.hash(&mut hasher);
+        spans(&self.han_regions, &mut hasher, |region, h| region.hash(h));
+        hasher.finish()
+    }
+
     fn add(&mut self, range: Range<usize>, attr: TextAttribute) {
         match attr {
             TextAttribute::TextColor(color) => self.color.push(Span::new(color, range)),
             TextAttribute::Weight(weight) => self.weight.push(Span::new(weight, range)),
+            TextAttribute::Style(style) => self.style.push(Span::new(style, range)),
+            TextAttribute::Underline(flag) => self.underline.push(Span::new(flag, range)),
+            TextAttribute::Strikethrough(flag) => self.strikethrough.push(Span::new(flag, range)),
             _ => {}
         }
     }
@@ -554,13 +2671,24 @@ impl Attributes {
         self.defaults.font_size
     }
 
-    fn italic(&self) -> bool {
-        matches!(
-            self.style
-                .as_ref()
-                .map(|t| t.payload)
+    /// The face to look up the glyph of the char at `index` in.
+    fn face(&self, index: usize) -> FaceQuery {
+        FaceQuery::new(
+            self.font_weight(index),
+            last_span(&self.style, index)
+                .copied()
                 .unwrap_or(self.defaults.style),
-            FontStyle::Italic
+            last_span(&self.stretch, index)
+                .copied()
+                .unwrap_or(self.default_stretch),
+        )
+    }
+
+    fn default_face(&self) -> FaceQuery {
+        FaceQuery::new(
+            self.defaults.weight,
+            self.defaults.style,
+            self.default_stretch,
         )
     }
 
@@ -573,6 +2701,78 @@ impl Attributes {
         self.defaults.font.clone()
     }
 
+    /// The value of the OpenType feature `tag` at `index`, with later
+    /// range overrides taking precedence.
+    fn feature(&self, index: usize, tag: &[u8; 4]) -> Option<u32> {
+        let find = |features: &[FontFeature]| {
+            features
+                .iter()
+                .rev()
+                .find(|(t, _)| t == tag)
+                .map(|(_, value)| *value)
+        };
+        self.features
+            .iter()
+            .rev()
+            .filter(|r| r.range.contains(&index))
+            .find_map(|r| find(&r.payload))
+            .or_else(|| find(&self.default_features))
+    }
+
+    fn has_decorations(&self) -> bool {
+        self.defaults.underline
+            || self.defaults.strikethrough
+            || !self.underline.is_empty()
+            || !self.strikethrough.is_empty()
+    }
+
+    fn underline(&self, index: usize) -> bool {
+        last_span(&self.underline, index)
+            .copied()
+            .unwrap_or(self.defaults.underline)
+    }
+
+    fn strikethrough(&self, index: usize) -> bool {
+        last_span(&self.strikethrough, index)
+            .copied()
+            .unwrap_or(self.defaults.strikethrough)
+    }
+
+    /// The underline color at `index`, the text color unless set.
+    fn underline_color(&self, index: usize) -> &Color {
+        last_span(&self.underline_color, index)
+            .or(self.default_underline_color.as_ref())
+            .unwrap_or_else(|| self.color(index))
+    }
+
+    /// The strikethrough color at `index`, the text color unless set.
+    fn strikethrough_color(&self, index: usize) -> &Color {
+        last_span(&self.strikethrough_color, index)
+            .or(self.default_strikethrough_color.as_ref())
+            .unwrap_or_else(|| self.color(index))
+    }
+
+    fn has_outlines(&self) -> bool {
+        self.default_outline.is_some() || !self.outline.is_empty()
+    }
+
+    /// The color and width of the outline of the glyph at `index`, if any.
+    fn outline(&self, index: usize) -> Option<&(Color, f64)> {
+        last_span(&self.outline, index)
+            .unwrap_or(&self.default_outline)
+            .as_ref()
+            .filter(|(_, width)| *width > 0.0)
+    }
+
+    fn region(&self, index: usize) -> Option<HanRegion> {
+        self.han_regions
+            .iter()
+            .rev()
+            .find(|r| r.range.contains(&index))
+            .map(|r| r.payload)
+            .unwrap_or(self.default_han_region)
+    }
+
     fn font_weight(&self, index: usize) -> FontWeight {
         for r in &self.weight {
             if r.range.contains(&index) {
@@ -582,3 +2782,203 @@ impl Attributes {
         self.defaults.weight
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::atlas::tests::device;
+
+    /// A text factory for the tests that lay out text. Glyphs are cached
+    /// in GPU textures, so those tests need a GPU adapter and are ignored
+    /// unless run with `cargo test -- --ignored`.
+    fn text_factory() -> WgpuText {
+        let (device, _queue) = device().expect("no GPU adapter");
+        WgpuText::new(
+            Rc::new(device),
+            Rc::new(RefCell::new(None)),
+            TextCacheHandle::new(),
+        )
+    }
+
+    fn layout(text: &str) -> WgpuTextLayout {
+        text_factory()
+            .new_text_layout(text.to_string())
+            .font(FontFamily::SANS_SERIF, 12.0)
+            .build()
+            .unwrap()
+    }
+
+    /// The start and end offsets and trailing whitespace of a line.
+    type LineOffsets = (usize, usize, usize);
+
+    /// The offsets and trailing whitespace of every line, which don't
+    /// depend on the font.
+    const LINES: &[(&str, &[LineOffsets])] = &[
+        ("", &[(0, 0, 0)]),
+        ("ab", &[(0, 2, 0)]),
+        ("ab\ncd", &[(0, 3, 1), (3, 5, 0)]),
+        ("ab  \ncd ", &[(0, 5, 3), (5, 8, 1)]),
+        ("ab\n", &[(0, 3, 1), (3, 3, 0)]),
+        ("ab\n\ncd", &[(0, 3, 1), (3, 4, 1), (4, 6, 0)]),
+    ];
+
+    #[test]
+    #[ignore = "needs a GPU adapter"]
+    fn line_metrics() {
+        for &(text, expected) in LINES {
+            let layout = layout(text);
+            assert_eq!(layout.line_count(), expected.len(), "{:?}", text);
+            let mut y_offset = 0.0;
+            for (i, &(start, end, whitespace)) in expected.iter().enumerate() {
+                let metric = layout.line_metric(i).unwrap();
+                assert_eq!(
+                    (
+                        metric.start_offset,
+                        metric.end_offset,
+                        metric.trailing_whitespace
+                    ),
+                    (start, end, whitespace),
+                    "line {} of {:?}",
+                    i,
+                    text
+                );
+                assert_eq!(layout.line_text(i), Some(&text[start..end]));
+                // Lines are stacked without gaps, with the baseline inside.
+                assert!((metric.y_offset - y_offset).abs() < 1e-6, "{:?}", text);
+                assert!(metric.baseline > 0.0 && metric.baseline < metric.height);
+                y_offset += metric.height;
+            }
+            assert!(layout.line_metric(expected.len()).is_none());
+        }
+    }
+
+    /// A text position with the line its caret is on, and whether it is at
+    /// the start of that line.
+    const POSITIONS: &[(&str, usize, usize, bool)] = &[
+        ("ab", 0, 0, true),
+        ("ab", 2, 0, false),
+        ("ab\ncd", 2, 0, false),
+        ("ab\ncd", 3, 1, true),
+        ("ab\ncd", 5, 1, false),
+        ("ab  \ncd", 4, 0, false),
+        ("ab\n", 3, 1, true),
+        ("ab\n\ncd", 3, 1, true),
+        ("ab\n\ncd", 4, 2, true),
+    ];
+
+    #[test]
+    #[ignore = "needs a GPU adapter"]
+    fn hit_test_text_positions() {
+        for &(text, idx, line, line_start) in POSITIONS {
+            let layout = layout(text);
+            let pos = layout.hit_test_text_position(idx);
+            assert_eq!(pos.line, line, "{} in {:?}", idx, text);
+            assert_eq!(pos.point.y, layout.line_baseline(line).unwrap());
+            assert_eq!(pos.point.x == 0.0, line_start, "{} in {:?}", idx, text);
+        }
+    }
+
+    /// Where to hit test: a line, and left of, inside the first glyph of,
+    /// or right of the text on it. Then the text position and whether the
+    /// point is inside the text.
+    #[derive(Clone, Copy)]
+    enum At {
+        Left,
+        FirstGlyph,
+        Right,
+    }
+
+    type PointCase = (&'static str, usize, At, usize, bool);
+
+    const POINTS: &[PointCase] = &[
+        ("ab", 0, At::Left, 0, false),
+        ("ab", 0, At::FirstGlyph, 0, true),
+        ("ab", 0, At::Right, 2, false),
+        ("ab\ncd", 0, At::Right, 2, false),
+        ("ab\ncd", 1, At::Left, 3, false),
+        ("ab\ncd", 1, At::FirstGlyph, 3, true),
+        ("ab\ncd", 1, At::Right, 5, false),
+        ("ab  \ncd", 0, At::Right, 4, false),
+        ("ab\n", 1, At::Left, 3, false),
+        ("ab\n", 1, At::Right, 3, false),
+        ("ab\n\ncd", 1, At::Right, 3, false),
+    ];
+
+    #[test]
+    #[ignore = "needs a GPU adapter"]
+    fn hit_test_points() {
+        for &(text, line, at, idx, is_inside) in POINTS {
+            let layout = layout(text);
+            let metric = layout.line_metric(line).unwrap();
+            let x = match at {
+                At::Left => -10.0,
+                At::FirstGlyph => 1.0,
+                At::Right => layout.size().width + 10.0,
+            };
+            let point = Point::new(x, metric.y_offset + metric.height / 2.0);
+            let hit = layout.hit_test_point(point);
+            assert_eq!(
+                (hit.idx, hit.is_inside),
+                (idx, is_inside),
+                "line {} of {:?}",
+                line,
+                text
+            );
+        }
+    }
+
+    #[test]
+    #[ignore = "needs a GPU adapter"]
+    fn offsets_are_in_bytes() {
+        let text = "héllo👍🏽";
+        let layout = layout(text);
+        let metric = layout.line_metric(0).unwrap();
+        assert_eq!((metric.start_offset, metric.end_offset), (0, text.len()));
+
+        // The caret positions are between grapheme clusters, at byte offsets.
+        let boundaries = [0, 1, 3, 4, 5, 6, text.len()];
+        let x = |idx| layout.hit_test_text_position(idx).point.x;
+        for pair in boundaries.windows(2) {
+            assert!(x(pair[0]) < x(pair[1]), "{:?}", pair);
+        }
+        // Inside the emoji and its skin tone modifier.
+        assert_eq!(x(10), x(6));
+
+        let y = metric.y_offset + metric.height / 2.0;
+        for &idx in &boundaries {
+            let hit = layout.hit_test_point(Point::new(x(idx), y));
+            assert_eq!(hit.idx, idx);
+        }
+    }
+
+    /// Reference line metrics at 20px: the font file in `fonts/`, then the
+    /// ascent and line height from its hhea table, scaled from font units,
+    /// which FreeType, and so cairo, reports for it too.
+    const REFERENCE_METRICS: &[(&str, f64, f64)] = &[
+        ("CascadiaCode-Regular.otf", 18.5546875, 23.2421875),
+        ("NotoSansSC-Regular.otf", 23.2, 28.96),
+    ];
+
+    #[test]
+    #[ignore = "needs a GPU adapter"]
+    fn line_metrics_match_the_font() {
+        let mut text_factory = text_factory();
+        for &(file, ascent, height) in REFERENCE_METRICS {
+            let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("fonts")
+                .join(file);
+            let font = text_factory.load_font_file(path).unwrap();
+            let layout = text_factory
+                .new_text_layout("Hxg\nHxg")
+                .font(font, 20.0)
+                .build()
+                .unwrap();
+            for line in 0..2 {
+                let metric = layout.line_metric(line).unwrap();
+                assert!((metric.baseline - ascent).abs() < 1e-3, "{}", file);
+                // Glyph quads cover whole pixels, so lines are rounded up.
+                assert_eq!(metric.height, height.ceil(), "{}", file);
+            }
+        }
+    }
+}
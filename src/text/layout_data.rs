@@ -0,0 +1,364 @@
+use std::{ops::Range, rc::Rc};
+
+use piet::{
+    kurbo::{Rect, Size},
+    util::LayoutDefaults,
+    Color, FontFamily, FontStyle, FontWeight, TextAlignment,
+};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    Attributes, FontFeature, InlineBox, InlineBoxId, LineInfo, RegionId, ShapedLayout, Span,
+    TextDirection, TextOverflow, WgpuText, WgpuTextLayout, WrapMode,
+};
+use crate::font::{FontStretch, HanRegion};
+
+/// A laid out [`WgpuTextLayout`] as plain data: its text, attributes,
+/// options and where its glyphs and lines ended up. Serialized in a layout
+/// worker with [`WgpuTextLayout::to_data`] and drawn by the renderer after
+/// [`WgpuText::layout_from_data`], without breaking or aligning the text
+/// again.
+///
+/// Fonts are referred to by family name, so the renderer must have the
+/// fonts the layout was built with registered as well. The atlas positions
+/// of the glyphs are process local and are looked up again.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LayoutData {
+    text: String,
+    width: f64,
+    alignment: Alignment,
+    direction: TextDirection,
+    wrap_mode: WrapMode,
+    overflow: TextOverflow,
+    max_lines: Option<usize>,
+    max_height: Option<f64>,
+    block_glyphs_below: Option<f64>,
+    /// The byte offset, size and id of each inline box.
+    inline_boxes: Vec<(usize, [f64; 2], InlineBoxId)>,
+    attrs: AttributesData,
+    is_mono: bool,
+    tab_width: usize,
+    bounds: Option<[f64; 2]>,
+    /// The rect and advance of each glyph that was laid out, in order.
+    glyphs: Vec<([f64; 4], f64)>,
+    /// The origin of the ellipsis, if there is one.
+    ellipsis: Option<[f64; 2]>,
+    /// The first glyph and the top of each line.
+    lines: Vec<(usize, f64)>,
+    rtl: Vec<bool>,
+    truncated: bool,
+    inline_box_rects: Vec<(InlineBoxId, [f64; 4])>,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+enum Alignment {
+    Start,
+    End,
+    Center,
+    Justified,
+}
+
+/// [`Attributes`] with colors as RGBA, families by name and the other piet
+/// types by their raw values.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct AttributesData {
+    font: String,
+    font_size: f64,
+    weight: u16,
+    italic: bool,
+    color: u32,
+    underline: bool,
+    strikethrough: bool,
+    colors: Vec<(u32, Range<usize>)>,
+    fonts: Vec<(String, Range<usize>)>,
+    sizes: Vec<(f64, Range<usize>)>,
+    weights: Vec<(u16, Range<usize>)>,
+    styles: Vec<(bool, Range<usize>)>,
+    default_stretch: FontStretch,
+    stretches: Vec<(FontStretch, Range<usize>)>,
+    default_features: Vec<FontFeature>,
+    features: Vec<(Vec<FontFeature>, Range<usize>)>,
+    regions: Vec<(RegionId, Range<usize>)>,
+    backgrounds: Vec<(u32, Range<usize>)>,
+    underlines: Vec<(bool, Range<usize>)>,
+    strikethroughs: Vec<(bool, Range<usize>)>,
+    default_underline_color: Option<u32>,
+    underline_colors: Vec<(u32, Range<usize>)>,
+    default_strikethrough_color: Option<u32>,
+    strikethrough_colors: Vec<(u32, Range<usize>)>,
+    default_outline: Option<OutlineData>,
+    outlines: Vec<(Option<OutlineData>, Range<usize>)>,
+    default_han_region: Option<HanRegion>,
+    han_regions: Vec<(Option<HanRegion>, Range<usize>)>,
+}
+
+impl WgpuText {
+    /// Rebuild a layout from the [`LayoutData`] of
+    /// [`WgpuTextLayout::to_data`], which may come from another process.
+    pub fn layout_from_data(&self, data: LayoutData) -> WgpuTextLayout {
+        let mut layout = WgpuTextLayout::new(data.text, self.clone());
+        layout.set_attrs(data.attrs.into_attributes());
+        layout.set_width(data.width);
+        layout.alignment = match data.alignment {
+            Alignment::Start => TextAlignment::Start,
+            Alignment::End => TextAlignment::End,
+            Alignment::Center => TextAlignment::Center,
+            Alignment::Justified => TextAlignment::Justified,
+        };
+        layout.direction = data.direction;
+        layout.wrap_mode = data.wrap_mode;
+        layout.overflow = data.overflow;
+        layout.max_lines = data.max_lines;
+        layout.max_height = data.max_height;
+        layout.block_glyphs_below = data.block_glyphs_below;
+        layout.inline_boxes = Rc::new(
+            data.inline_boxes
+                .into_iter()
+                .map(|(index, [width, height], id)| InlineBox {
+                    index,
+                    size: Size::new(width, height),
+                    id,
+                })
+                .collect(),
+        );
+
+        // The glyphs are looked up again for their place in this atlas, and
+        // put where the layout placed them.
+        let mono_width = layout.update_ref_glyph();
+        let glyphs: Vec<_> = layout
+            .shape_glyphs(data.is_mono, data.tab_width, mono_width)
+            .zip(&data.glyphs)
+            .map(|((shaped, _), (rect, width))| {
+                let mut glyph = shaped.glyph;
+                glyph.rect = to_rect(*rect);
+                glyph.width = *width;
+                glyph
+            })
+            .collect();
+        let ellipsis = data.ellipsis.and_then(|origin| {
+            let mut ellipsis = layout.ellipsis_glyph()?;
+            ellipsis.rect = ellipsis.rect.with_origin((origin[0], origin[1]));
+            Some(ellipsis)
+        });
+        let lines: Vec<_> = data
+            .lines
+            .into_iter()
+            .map(|(start, y)| LineInfo { start, y })
+            .collect();
+
+        *layout.shaped.borrow_mut() = Some(ShapedLayout {
+            is_mono: data.is_mono,
+            tab_width: data.tab_width,
+            bounds: data.bounds,
+            glyphs: None,
+        });
+        *layout.ellipsis.borrow_mut() = ellipsis;
+        *layout.rtl.borrow_mut() = data.rtl;
+        *layout.truncated.borrow_mut() = data.truncated;
+        *layout.inline_box_rects.borrow_mut() = data
+            .inline_box_rects
+            .into_iter()
+            .map(|(id, rect)| (id, to_rect(rect)))
+            .collect();
+        layout.add_geometry(
+            &mut layout.geometry.borrow_mut(),
+            &glyphs,
+            &lines,
+            data.bounds,
+        );
+        *layout.glyphs.borrow_mut() = glyphs;
+        *layout.lines.borrow_mut() = lines;
+        layout
+    }
+}
+
+impl WgpuTextLayout {
+    /// The layout as [`LayoutData`], to be serialized and drawn elsewhere.
+    pub fn to_data(&self) -> LayoutData {
+        let (is_mono, tab_width, bounds) = match self.shaped.borrow().as_ref() {
+            Some(shaped) => (shaped.is_mono, shaped.tab_width, shaped.bounds),
+            None => (false, 8, None),
+        };
+        LayoutData {
+            text: self.text.clone(),
+            width: self.width,
+            alignment: match self.alignment {
+                TextAlignment::Start => Alignment::Start,
+                TextAlignment::End => Alignment::End,
+                TextAlignment::Center => Alignment::Center,
+                TextAlignment::Justified => Alignment::Justified,
+            },
+            direction: self.direction,
+            wrap_mode: self.wrap_mode,
+            overflow: self.overflow,
+            max_lines: self.max_lines,
+            max_height: self.max_height,
+            block_glyphs_below: self.block_glyphs_below,
+            inline_boxes: self
+                .inline_boxes
+                .iter()
+                .map(|b| (b.index, [b.size.width, b.size.height], b.id))
+                .collect(),
+            attrs: AttributesData::new(&self.attrs),
+            is_mono,
+            tab_width,
+            bounds,
+            glyphs: self
+                .glyphs
+                .borrow()
+                .iter()
+                .map(|glyph| (from_rect(glyph.rect), glyph.width))
+                .collect(),
+            ellipsis: self
+                .ellipsis
+                .borrow()
+                .as_ref()
+                .map(|ellipsis| [ellipsis.rect.x0, ellipsis.rect.y0]),
+            lines: self
+                .lines
+                .borrow()
+                .iter()
+                .map(|line| (line.start, line.y))
+                .collect(),
+            rtl: self.rtl.borrow().clone(),
+            truncated: *self.truncated.borrow(),
+            inline_box_rects: self
+                .inline_box_rects
+                .borrow()
+                .iter()
+                .map(|(id, rect)| (*id, from_rect(*rect)))
+                .collect(),
+        }
+    }
+}
+
+impl AttributesData {
+    fn new(attrs: &Attributes) -> Self {
+        let defaults = &attrs.defaults;
+        AttributesData {
+            font: defaults.font.name().to_string(),
+            font_size: defaults.font_size,
+            weight: defaults.weight.to_raw(),
+            italic: defaults.style == FontStyle::Italic,
+            color: defaults.fg_color.as_rgba_u32(),
+            underline: defaults.underline,
+            strikethrough: defaults.strikethrough,
+            colors: to_spans(&attrs.color, Color::as_rgba_u32),
+            fonts: to_spans(&attrs.font, |font| font.name().to_string()),
+            sizes: to_spans(&attrs.size, |size| *size),
+            weights: to_spans(&attrs.weight, |weight| weight.to_raw()),
+            styles: to_spans(&attrs.style, |style| *style == FontStyle::Italic),
+            default_stretch: attrs.default_stretch,
+            stretches: to_spans(&attrs.stretch, |stretch| *stretch),
+            default_features: attrs.default_features.clone(),
+            features: to_spans(&attrs.features, |features| features.clone()),
+            regions: to_spans(&attrs.regions, |region| *region),
+            backgrounds: to_spans(&attrs.background, Color::as_rgba_u32),
+            underlines: to_spans(&attrs.underline, |underline| *underline),
+            strikethroughs: to_spans(&attrs.strikethrough, |strikethrough| *strikethrough),
+            default_underline_color: attrs
+                .default_underline_color
+                .as_ref()
+                .map(Color::as_rgba_u32),
+            underline_colors: to_spans(&attrs.underline_color, Color::as_rgba_u32),
+            default_strikethrough_color: attrs
+                .default_strikethrough_color
+                .as_ref()
+                .map(Color::as_rgba_u32),
+            strikethrough_colors: to_spans(&attrs.strikethrough_color, Color::as_rgba_u32),
+            default_outline: attrs.default_outline.as_ref().map(outline_data),
+            outlines: to_spans(&attrs.outline, |outline| outline.as_ref().map(outline_data)),
+            default_han_region: attrs.default_han_region,
+            han_regions: to_spans(&attrs.han_regions, |region| *region),
+        }
+    }
+
+    fn into_attributes(self) -> Attributes {
+        let style = |italic| {
+            if italic {
+                FontStyle::Italic
+            } else {
+                FontStyle::Regular
+            }
+        };
+        let mut defaults = LayoutDefaults::default();
+        defaults.font = family(self.font);
+        defaults.font_size = self.font_size;
+        defaults.weight = FontWeight::new(self.weight);
+        defaults.fg_color = Color::from_rgba32_u32(self.color);
+        defaults.style = style(self.italic);
+        defaults.underline = self.underline;
+        defaults.strikethrough = self.strikethrough;
+        Attributes {
+            defaults,
+            color: from_spans(self.colors, Color::from_rgba32_u32),
+            font: from_spans(self.fonts, family),
+            size: from_spans(self.sizes, |size| size),
+            weight: from_spans(self.weights, FontWeight::new),
+            style: from_spans(self.styles, style),
+            default_stretch: self.default_stretch,
+            stretch: from_spans(self.stretches, |stretch| stretch),
+            default_features: self.default_features,
+            features: from_spans(self.features, |features| features),
+            regions: from_spans(self.regions, |region| region),
+            background: from_spans(self.backgrounds, Color::from_rgba32_u32),
+            underline: from_spans(self.underlines, |underline| underline),
+            strikethrough: from_spans(self.strikethroughs, |strikethrough| strikethrough),
+            default_underline_color: self.default_underline_color.map(Color::from_rgba32_u32),
+            underline_color: from_spans(self.underline_colors, Color::from_rgba32_u32),
+            default_strikethrough_color: self
+                .default_strikethrough_color
+                .map(Color::from_rgba32_u32),
+            strikethrough_color: from_spans(self.strikethrough_colors, Color::from_rgba32_u32),
+            default_outline: self.default_outline.map(outline),
+            outline: from_spans(self.outlines, |data| data.map(outline)),
+            default_han_region: self.default_han_region,
+            han_regions: from_spans(self.han_regions, |region| region),
+        }
+    }
+}
+
+/// The color and width of a text outline.
+type OutlineData = (u32, f64);
+
+fn outline_data((color, width): &(Color, f64)) -> OutlineData {
+    (color.as_rgba_u32(), *width)
+}
+
+fn outline((color, width): OutlineData) -> (Color, f64) {
+    (Color::from_rgba32_u32(color), width)
+}
+
+/// The family named `name`, which is one of the generic families for their
+/// names.
+fn family(name: String) -> FontFamily {
+    match name.as_str() {
+        "serif" => FontFamily::SERIF,
+        "sans-serif" => FontFamily::SANS_SERIF,
+        "monospace" => FontFamily::MONOSPACE,
+        "system-ui" => FontFamily::SYSTEM_UI,
+        _ => FontFamily::new_unchecked(name),
+    }
+}
+
+fn to_spans<T, U>(spans: &[Span<T>], f: impl Fn(&T) -> U) -> Vec<(U, Range<usize>)> {
+    spans
+        .iter()
+        .map(|span| (f(&span.payload), span.range.clone()))
+        .collect()
+}
+
+fn from_spans<T, U>(spans: Vec<(U, Range<usize>)>, f: impl Fn(U) -> T) -> Vec<Span<T>> {
+    spans
+        .into_iter()
+        .map(|(payload, range)| Span::new(f(payload), range))
+        .collect()
+}
+
+fn from_rect(rect: Rect) -> [f64; 4] {
+    [rect.x0, rect.y0, rect.x1, rect.y1]
+}
+
+fn to_rect([x0, y0, x1, y1]: [f64; 4]) -> Rect {
+    Rect::new(x0, y0, x1, y1)
+}